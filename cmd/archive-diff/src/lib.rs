@@ -0,0 +1,710 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility archive-diff`
+//!
+//! `humility archive-diff` compares two Hubris archives, reporting what has
+//! changed between them.  It does not connect to a Hubris target to operate.
+//! The archives may be specified as two arguments, or (if only one archive
+//! is specified) the archive specified via `-a` (or via an environment) will
+//! be compared against it:
+//!
+//! ```console
+//! % humility archive-diff build-gimlet-a.zip build-gimlet-b.zip
+//!    archive A => build-gimlet-a.zip
+//!      git rev => 753a57169eba699e73ee59e0cf5345eb1d6e1ae2
+//!    archive B => build-gimlet-b.zip
+//!      git rev => 2a1c1f0e5c0a7e4ac0d5cf1d4b3f1e07f6f1bd22-dirty
+//!       kernel => differs (30.1K -> 30.4K)
+//!
+//! TASK                 A SIZE   B SIZE    DELTA IMAGE
+//! jefe                   6.7K     6.7K       +0 identical
+//! net                   80.2K    81.0K     +816 differs
+//! sensor                 9.3K        -        - removed
+//! sensor_polling            -     9.6K        - added
+//! idle                   0.1K     0.1K       +0 identical
+//!
+//! FEATURES
+//! kernel               + dump
+//! net                  - vlan
+//!
+//! MEMORY LAYOUT
+//! net                  - 0x24000000 32K rw-
+//! net                  + 0x24000000 64K rw-
+//!
+//! INTERFACES
+//! net                  + op get_mac_address
+//! net                  ~ op send_packet (leases)
+//!
+//! TYPES
+//! task_net::server::ServerImpl 1456 -> 1472
+//! ```
+//!
+//! The task table reports the size of each task in memory, and whether the
+//! loadable image of the task differs between the archives; the kernel is
+//! reported similarly.  (Note that because the kernel contains the table of
+//! tasks, a change to the tasks will generally result in a different kernel
+//! image.)  Features that have been added or removed are reported for the
+//! kernel and for each task, along with any regions that have changed in the
+//! memory layout.  For Idol interfaces, operations that have been added,
+//! removed, or changed are reported -- with a change in the arguments,
+//! leases, or reply of an operation noted.  Finally, structures that are
+//! present in both archives but with different layouts are listed; to see
+//! the layouts themselves, use `--verbose`:
+//!
+//! ```console
+//! % humility archive-diff --verbose build-gimlet-a.zip build-gimlet-b.zip
+//! ...
+//! TYPES
+//! task_net::server::ServerImpl 1456 -> 1472
+//!                     - 1456: eth@0 drv_stm32h7_eth::Ethernet, ...
+//!                     + 1472: eth@0 drv_stm32h7_eth::Ethernet, ...
+//! ```
+//!
+//! Sections in which nothing has changed are omitted.
+//!
+
+use ::idol::syntax::{Encoding, Error, Operation, RecvStrategy, Reply};
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::cli::Subcommand;
+use humility::hubris::*;
+use humility_cmd::{load_archive, Archive, Command};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Parser, Debug)]
+#[clap(name = "archive-diff", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ArchiveDiffArgs {
+    /// display type layouts that differ
+    #[clap(long, short)]
+    verbose: bool,
+
+    /// archives to compare (if only one is specified, it is compared
+    /// against the archive specified via -a)
+    #[clap(value_name = "ARCHIVE", min_values = 1, max_values = 2)]
+    archives: Vec<String>,
+}
+
+fn size(bytes: u32) -> String {
+    format!("{:.1}K", bytes as f64 / 1024_f64)
+}
+
+//
+// Returns the contents of each loadable segment of the specified module.
+//
+fn image(
+    hubris: &HubrisArchive,
+    module: &HubrisModule,
+) -> Result<Vec<(u64, Vec<u8>)>> {
+    let buffer = hubris.module_elf(module)?;
+    let elf = goblin::elf::Elf::parse(&buffer).with_context(|| {
+        format!("failed to parse ELF object for {}", module.name)
+    })?;
+
+    let mut rval = vec![];
+
+    for h in elf
+        .program_headers
+        .iter()
+        .filter(|h| h.p_type == goblin::elf::program_header::PT_LOAD)
+    {
+        let offset = h.p_offset as usize;
+        let data = offset
+            .checked_add(h.p_filesz as usize)
+            .and_then(|end| buffer.get(offset..end))
+            .with_context(|| {
+                format!("bad segment at 0x{:x} in {}", h.p_vaddr, module.name)
+            })?;
+
+        rval.push((h.p_vaddr, data.to_vec()));
+    }
+
+    Ok(rval)
+}
+
+fn identical(
+    a: (&HubrisArchive, &HubrisModule),
+    b: (&HubrisArchive, &HubrisModule),
+) -> Result<bool> {
+    Ok(image(a.0, a.1)? == image(b.0, b.1)?)
+}
+
+//
+// Returns the names of the modules in either archive, in the order in which
+// they appear in A -- with any modules only present in B following.  The
+// kernel is not included.
+//
+fn names<'a>(a: &'a HubrisArchive, b: &'a HubrisArchive) -> Vec<&'a str> {
+    let mut rval: Vec<&str> = vec![];
+
+    for module in a.modules().chain(b.modules()) {
+        if module.task != HubrisTask::Kernel
+            && !rval.contains(&module.name.as_str())
+        {
+            rval.push(&module.name);
+        }
+    }
+
+    rval
+}
+
+fn kernel(hubris: &HubrisArchive) -> Result<&HubrisModule> {
+    hubris.lookup_module(HubrisTask::Kernel)
+}
+
+fn header(
+    a: &str,
+    b: &str,
+    a_hubris: &HubrisArchive,
+    b_hubris: &HubrisArchive,
+) {
+    let print = |what, val: &str| {
+        println!("{:>12} => {}", what, val);
+    };
+
+    for (archive, hubris, what) in
+        [(a, a_hubris, "archive A"), (b, b_hubris, "archive B")]
+    {
+        print(what, archive);
+
+        if let Some(gitrev) = hubris.manifest.gitrev() {
+            print("git rev", gitrev);
+        }
+
+        if let Some(image_id) = hubris.image_id() {
+            print("image id", &format!("{:x?}", image_id));
+        }
+    }
+}
+
+fn diff_kernel(a: &HubrisArchive, b: &HubrisArchive) -> Result<()> {
+    let (ka, kb) = (kernel(a)?, kernel(b)?);
+
+    let result = if identical((a, ka), (b, kb))? {
+        "identical".to_string()
+    } else {
+        format!("differs ({} -> {})", size(ka.memsize), size(kb.memsize))
+    };
+
+    println!("{:>12} => {}", "kernel", result);
+
+    Ok(())
+}
+
+fn diff_tasks(a: &HubrisArchive, b: &HubrisArchive) -> Result<()> {
+    println!(
+        "\n{:18} {:>8} {:>8} {:>8} {}",
+        "TASK", "A SIZE", "B SIZE", "DELTA", "IMAGE"
+    );
+
+    for name in names(a, b) {
+        let (ma, mb) =
+            (a.lookup_module_byname(name), b.lookup_module_byname(name));
+
+        let sz = |m: Option<&HubrisModule>| match m {
+            Some(m) => size(m.memsize),
+            None => "-".to_string(),
+        };
+
+        let (delta, image) = match (ma, mb) {
+            (Some(ma), Some(mb)) => (
+                format!("{:+}", mb.memsize as i64 - ma.memsize as i64),
+                if identical((a, ma), (b, mb))? {
+                    "identical"
+                } else {
+                    "differs"
+                },
+            ),
+            (Some(_), None) => ("-".to_string(), "removed"),
+            (None, _) => ("-".to_string(), "added"),
+        };
+
+        println!(
+            "{:18} {:>8} {:>8} {:>8} {}",
+            name,
+            sz(ma),
+            sz(mb),
+            delta,
+            image
+        );
+    }
+
+    Ok(())
+}
+
+//
+// Prints the added and removed elements of a pair of sets, returning the
+// number of lines printed.
+//
+fn changes<T: Ord + std::fmt::Display>(
+    what: &str,
+    a: &BTreeSet<T>,
+    b: &BTreeSet<T>,
+) -> usize {
+    let mut rval = 0;
+
+    for removed in a.difference(b) {
+        println!("{:20} - {}", what, removed);
+        rval += 1;
+    }
+
+    for added in b.difference(a) {
+        println!("{:20} + {}", what, added);
+        rval += 1;
+    }
+
+    rval
+}
+
+fn section(title: &str, printed: &mut bool) {
+    if !*printed {
+        println!("\n{}", title);
+        *printed = true;
+    }
+}
+
+fn diff_features(a: &HubrisArchive, b: &HubrisArchive) {
+    let set = |features: Option<&[String]>| -> BTreeSet<String> {
+        features.into_iter().flatten().cloned().collect()
+    };
+
+    let mut printed = false;
+
+    let mut diff = |what: &str, fa: BTreeSet<String>, fb: BTreeSet<String>| {
+        if fa != fb {
+            section("FEATURES", &mut printed);
+            changes(what, &fa, &fb);
+        }
+    };
+
+    diff(
+        "kernel",
+        set(Some(&a.manifest.features)),
+        set(Some(&b.manifest.features)),
+    );
+
+    for name in names(a, b) {
+        if a.lookup_module_byname(name).is_none()
+            || b.lookup_module_byname(name).is_none()
+        {
+            continue;
+        }
+
+        diff(
+            name,
+            set(a.manifest.task_features(name)),
+            set(b.manifest.task_features(name)),
+        );
+    }
+}
+
+//
+// Returns a description of each region loaded for the specified module.
+//
+fn regions(hubris: &HubrisArchive, module: &HubrisModule) -> BTreeSet<String> {
+    hubris
+        .loaded_regions()
+        .filter(|r| r.tasks.contains(&module.task))
+        .map(|r| {
+            format!(
+                "0x{:08x} {}{} {}{}{}",
+                r.base,
+                if r.size >= 1024 { r.size / 1024 } else { r.size },
+                if r.size >= 1024 { "K" } else { "B" },
+                if r.attr.read { "r" } else { "-" },
+                if r.attr.write { "w" } else { "-" },
+                if r.attr.execute { "x" } else { "-" },
+            )
+        })
+        .collect()
+}
+
+fn diff_regions(a: &HubrisArchive, b: &HubrisArchive) -> Result<()> {
+    let mut printed = false;
+    let mut modules = vec![("kernel", kernel(a)?, kernel(b)?)];
+
+    for name in names(a, b) {
+        if let (Some(ma), Some(mb)) =
+            (a.lookup_module_byname(name), b.lookup_module_byname(name))
+        {
+            modules.push((name, ma, mb));
+        }
+    }
+
+    for (name, ma, mb) in modules {
+        let (ra, rb) = (regions(a, ma), regions(b, mb));
+
+        if ra != rb {
+            section("MEMORY LAYOUT", &mut printed);
+            changes(name, &ra, &rb);
+        }
+    }
+
+    Ok(())
+}
+
+//
+// The signature of an Idol operation:  everything about it that a client
+// depends on, such that two operations with the same signature can be
+// called in the same way.
+//
+#[derive(Debug, PartialEq, Eq)]
+struct OpSignature {
+    args: Vec<ArgSignature>,
+    leases: Vec<LeaseSignature>,
+    reply: ReplySignature,
+    encoding: &'static str,
+    idempotent: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ArgSignature {
+    name: String,
+    ty: String,
+    from_bytes: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct LeaseSignature {
+    name: String,
+    ty: String,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ReplySignature {
+    Simple(String),
+    Result { ok: String, err: Option<String> },
+}
+
+impl From<&Operation> for OpSignature {
+    fn from(op: &Operation) -> Self {
+        let encoding = if matches!(op.encoding, Encoding::Zerocopy) {
+            "zerocopy"
+        } else if matches!(op.encoding, Encoding::Ssmarshal) {
+            "ssmarshal"
+        } else {
+            "other"
+        };
+
+        Self {
+            args: op
+                .args
+                .iter()
+                .map(|(name, arg)| ArgSignature {
+                    name: name.clone(),
+                    ty: arg.ty.0.clone(),
+                    from_bytes: matches!(arg.recv, RecvStrategy::FromBytes),
+                })
+                .collect(),
+            leases: op
+                .leases
+                .iter()
+                .map(|(name, lease)| LeaseSignature {
+                    name: name.clone(),
+                    ty: lease.ty.0.clone(),
+                    read: lease.read,
+                    write: lease.write,
+                })
+                .collect(),
+            reply: match &op.reply {
+                Reply::Simple(ok) => ReplySignature::Simple(ok.ty.0.clone()),
+                Reply::Result { ok, err } => ReplySignature::Result {
+                    ok: ok.ty.0.clone(),
+                    err: match err {
+                        Error::CLike(t) => Some(t.0.clone()),
+                        Error::ServerDeath => None,
+                    },
+                },
+            },
+            encoding,
+            idempotent: op.idempotent,
+        }
+    }
+}
+
+fn diff_interfaces(a: &HubrisArchive, b: &HubrisArchive) {
+    let mut printed = false;
+
+    for name in names(a, b) {
+        let (ia, ib) = match (
+            a.lookup_module_byname(name),
+            b.lookup_module_byname(name),
+        ) {
+            (Some(ma), Some(mb)) => (ma.iface.as_ref(), mb.iface.as_ref()),
+            _ => continue,
+        };
+
+        let (ia, ib) = match (ia, ib) {
+            (None, None) => continue,
+            (Some(ia), None) => {
+                section("INTERFACES", &mut printed);
+                println!("{:20} - interface {}", name, ia.name);
+                continue;
+            }
+            (None, Some(ib)) => {
+                section("INTERFACES", &mut printed);
+                println!("{:20} + interface {}", name, ib.name);
+                continue;
+            }
+            (Some(ia), Some(ib)) => (ia, ib),
+        };
+
+        if ia.name != ib.name {
+            section("INTERFACES", &mut printed);
+            println!("{:20} ~ interface {} -> {}", name, ia.name, ib.name);
+        }
+
+        let opsa: BTreeSet<String> =
+            ia.ops.keys().map(|op| format!("op {}", op)).collect();
+        let opsb: BTreeSet<String> =
+            ib.ops.keys().map(|op| format!("op {}", op)).collect();
+
+        if opsa != opsb {
+            section("INTERFACES", &mut printed);
+            changes(name, &opsa, &opsb);
+        }
+
+        for (op, opa) in &ia.ops {
+            let opb = match ib.ops.get(op) {
+                Some(opb) => opb,
+                None => continue,
+            };
+
+            let (sa, sb) = (OpSignature::from(opa), OpSignature::from(opb));
+
+            if sa == sb {
+                continue;
+            }
+
+            let mut what = vec![];
+
+            if sa.args != sb.args {
+                what.push("arguments");
+            }
+
+            if sa.leases != sb.leases {
+                what.push("leases");
+            }
+
+            if sa.reply != sb.reply {
+                what.push("reply");
+            }
+
+            if what.is_empty() {
+                what.push("attributes");
+            }
+
+            section("INTERFACES", &mut printed);
+            println!("{:20} ~ op {} ({})", name, op, what.join(", "));
+        }
+    }
+}
+
+//
+// Returns the layouts of every structure in the archive, indexed by name.
+// A name may have more than one layout (e.g., for a generic structure).
+//
+fn layouts(hubris: &HubrisArchive) -> BTreeMap<String, BTreeSet<String>> {
+    let mut rval: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    for s in hubris.structs() {
+        //
+        // Skip anonymous types like closures, which have names that are
+        // unstable from build to build.
+        //
+        if s.name.contains('{') {
+            continue;
+        }
+
+        let members = s
+            .members
+            .iter()
+            .map(|m| {
+                let ty = hubris
+                    .lookup_type(m.goff)
+                    .and_then(|t| t.name(hubris).map(|n| n.to_string()))
+                    .unwrap_or_else(|_| "<unknown>".to_string());
+
+                format!("{}@{} {}", m.name, m.offset, ty)
+            })
+            .collect::<Vec<_>>();
+
+        rval.entry(s.name.clone()).or_default().insert(format!(
+            "{}: {}",
+            s.size,
+            members.join(", ")
+        ));
+    }
+
+    rval
+}
+
+fn diff_types(a: &HubrisArchive, b: &HubrisArchive, verbose: bool) {
+    let (la, lb) = (layouts(a), layouts(b));
+    let mut printed = false;
+
+    let sizes = |layouts: &BTreeSet<String>| {
+        layouts
+            .iter()
+            .map(|l| l.split(':').next().unwrap())
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+
+    for (name, layouts_a) in &la {
+        let layouts_b = match lb.get(name) {
+            Some(layouts_b) if layouts_b != layouts_a => layouts_b,
+            _ => continue,
+        };
+
+        section("TYPES", &mut printed);
+        println!("{} {} -> {}", name, sizes(layouts_a), sizes(layouts_b));
+
+        if verbose {
+            changes("", layouts_a, layouts_b);
+        }
+    }
+}
+
+fn archive_diff(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let subargs = ArchiveDiffArgs::try_parse_from(subargs)?;
+
+    let (a, b) = match subargs.archives.as_slice() {
+        [a, b] => (a.clone(), b.clone()),
+        [b] => match &context.cli.archive {
+            Some(a) => (a.clone(), b.clone()),
+            None => bail!("must specify two archives (or one archive and -a)"),
+        },
+        _ => bail!("must specify two archives (or one archive and -a)"),
+    };
+
+    let (a_hubris, b_hubris) = (load_archive(&a)?, load_archive(&b)?);
+    let (a_hubris, b_hubris) = (&a_hubris, &b_hubris);
+
+    header(&a, &b, a_hubris, b_hubris);
+    diff_kernel(a_hubris, b_hubris)?;
+    diff_tasks(a_hubris, b_hubris)?;
+    diff_features(a_hubris, b_hubris);
+    diff_regions(a_hubris, b_hubris)?;
+    diff_interfaces(a_hubris, b_hubris);
+    diff_types(a_hubris, b_hubris, subargs.verbose);
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "archive-diff",
+            archive: Archive::Ignored,
+            run: archive_diff,
+        },
+        ArchiveDiffArgs::command(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMPS: &str = "../../tests/cmd/cores";
+
+    fn dump(name: &str) -> Result<HubrisArchive> {
+        let mut hubris = HubrisArchive::new()?;
+        let path = format!("{}/hubris.core.{}", DUMPS, name);
+        hubris.load_dump(&path, HubrisArchiveDoneness::Cook)?;
+        Ok(hubris)
+    }
+
+    #[test]
+    fn same() -> Result<()> {
+        let (a, b) = (dump("kiowa.23")?, dump("kiowa.23")?);
+
+        assert!(identical((&a, kernel(&a)?), (&b, kernel(&b)?))?);
+
+        for name in names(&a, &b) {
+            let (ma, mb) = (
+                a.lookup_module_byname(name).unwrap(),
+                b.lookup_module_byname(name).unwrap(),
+            );
+            assert!(identical((&a, ma), (&b, mb))?, "{} differs", name);
+            assert_eq!(regions(&a, ma), regions(&b, mb));
+
+            if let (Some(ia), Some(ib)) = (&ma.iface, &mb.iface) {
+                for (op, opa) in &ia.ops {
+                    assert_eq!(
+                        OpSignature::from(opa),
+                        OpSignature::from(&ib.ops[op])
+                    );
+                }
+            }
+        }
+
+        assert_eq!(layouts(&a), layouts(&b));
+
+        Ok(())
+    }
+
+    #[test]
+    fn different() -> Result<()> {
+        let (a, b) = (dump("kiowa.0")?, dump("kiowa.23")?);
+        let names = names(&a, &b);
+
+        assert!(!names.contains(&kernel(&a)?.name.as_str()));
+
+        let ours = a
+            .modules()
+            .filter(|m| m.task != HubrisTask::Kernel)
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names[..ours.len()], ours[..]);
+
+        for name in &names[ours.len()..] {
+            assert!(a.lookup_module_byname(name).is_none());
+            assert!(b.lookup_module_byname(name).is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn signatures() -> Result<()> {
+        let hubris = dump("kiowa.23")?;
+        let iface =
+            hubris.lookup_module_byname("user_leds").unwrap().iface.as_ref();
+        let ops = &iface.unwrap().ops;
+
+        let on = OpSignature::from(&ops["led_on"]);
+
+        assert_eq!(
+            on.args,
+            vec![ArgSignature {
+                name: "index".to_string(),
+                ty: "usize".to_string(),
+                from_bytes: true,
+            }]
+        );
+        assert!(on.leases.is_empty());
+        assert_eq!(
+            on.reply,
+            ReplySignature::Result {
+                ok: "()".to_string(),
+                err: Some("LedError".to_string()),
+            }
+        );
+        assert!(on.idempotent);
+
+        //
+        // Operations are compared by their signatures, not their names.
+        //
+        assert_eq!(on, OpSignature::from(&ops["led_off"]));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,607 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Dashboard layouts, as loaded from a TOML file.  A layout consists of any
+// number of status items (each the result of an Idol operation that takes
+// no arguments) and any number of graphs; each graph consists of series
+// that are either sensors (selected by kind and optionally name and device),
+// ring buffers (graphed as the number of entries recorded per sample,
+// optionally restricted to a particular entry variant) or variables
+// (optionally a field within a structure).  If no layout is provided, a
+// default layout is used that graphs temperature, fan speed and output
+// current, with the sequencer state as status.
+//
+
+use anyhow::{anyhow, bail, Result};
+use humility::hubris::*;
+use humility::reflect::{Base, Value};
+use humility_cmd::ringbuf::RingbufVariable;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusSpec {
+    pub label: String,
+    pub idol: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeriesSpec {
+    pub sensor: Option<String>,
+    pub name: Option<String>,
+    pub device: Option<String>,
+    pub ringbuf: Option<String>,
+    pub entry: Option<String>,
+    pub variable: Option<String>,
+    pub field: Option<String>,
+    pub label: Option<String>,
+}
+
+fn default_height() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraphSpec {
+    pub label: String,
+    pub legend: Option<String>,
+    pub units: Option<String>,
+    #[serde(default = "default_height")]
+    pub height: u32,
+    pub series: Vec<SeriesSpec>,
+
+    //
+    // The graphs in the default layout are graphed with the attributes
+    // specific to their sensor kind.
+    //
+    #[serde(skip)]
+    pub preset: Option<HubrisSensorKind>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    #[serde(default)]
+    pub status: Vec<StatusSpec>,
+    #[serde(default)]
+    pub graph: Vec<GraphSpec>,
+
+    //
+    // Power and fan controls are specific to the sequencer and thermal
+    // tasks of the boards that the default layout was designed for, and
+    // are only available when using it.
+    //
+    #[serde(skip)]
+    pub controls: bool,
+}
+
+///
+/// The source of the data for a series.
+///
+pub enum Source<'a> {
+    /// A sensor, read via the `Sensor` Idol interface
+    Sensor(usize),
+    /// A ring buffer, along with the entry variant to count (if any) and
+    /// the line and count of each slot as of the last sample
+    Ringbuf(RingbufVariable<'a>, Option<String>, Option<Vec<(u16, u32)>>),
+    /// A variable, along with the path of the field to graph
+    Variable(&'a HubrisVariable, Vec<String>),
+}
+
+pub struct Series<'a> {
+    /// Key identifying the series in a recording, e.g. `temp:Southwest`
+    pub key: String,
+    /// Name of the series in the legend
+    pub name: String,
+    pub source: Source<'a>,
+}
+
+impl SeriesSpec {
+    fn validate(&self, graph: &str) -> Result<()> {
+        let sources = [&self.sensor, &self.ringbuf, &self.variable];
+
+        if sources.iter().filter(|s| s.is_some()).count() != 1 {
+            bail!(
+                "graph \"{}\": each series must specify exactly one of \
+                sensor, ringbuf or variable",
+                graph
+            );
+        }
+
+        if let Some(kind) = &self.sensor {
+            if HubrisSensorKind::from_string(kind).is_none() {
+                bail!(
+                    "graph \"{}\": unrecognized sensor kind \"{}\"",
+                    graph,
+                    kind
+                );
+            }
+
+            if self.label.is_some() {
+                bail!("graph \"{}\": sensors are labeled by name", graph);
+            }
+        } else if self.name.is_some() || self.device.is_some() {
+            bail!("graph \"{}\": name and device only apply to sensors", graph);
+        }
+
+        if self.entry.is_some() && self.ringbuf.is_none() {
+            bail!("graph \"{}\": entry only applies to ring buffers", graph);
+        }
+
+        if self.field.is_some() && self.variable.is_none() {
+            bail!("graph \"{}\": field only applies to variables", graph);
+        }
+
+        Ok(())
+    }
+
+    fn ringbuf_key(&self, ringbuf: &str) -> String {
+        match &self.entry {
+            Some(entry) => format!("ringbuf:{}:{}", ringbuf, entry),
+            None => format!("ringbuf:{}", ringbuf),
+        }
+    }
+
+    fn variable_key(&self, variable: &str) -> String {
+        match &self.field {
+            Some(field) => format!("var:{}.{}", variable, field),
+            None => format!("var:{}", variable),
+        }
+    }
+
+    fn name(&self, key: &str) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => key.split_once(':').map_or(key, |(_, n)| n).to_string(),
+        }
+    }
+
+    ///
+    /// Resolves this series specification against the archive, returning
+    /// one series for each sensor that matches (or the single ring buffer
+    /// or variable that is specified).
+    ///
+    fn resolve<'a>(
+        &self,
+        hubris: &'a HubrisArchive,
+    ) -> Result<Vec<Series<'a>>> {
+        let mut rval = vec![];
+
+        if let Some(kind) = &self.sensor {
+            let kind = HubrisSensorKind::from_string(kind).unwrap();
+
+            for (i, s) in hubris.manifest.sensors.iter().enumerate() {
+                let device = &hubris.manifest.i2c_devices[s.device].device;
+
+                if s.kind != kind
+                    || self.name.as_ref().map_or(false, |n| *n != s.name)
+                    || self.device.as_ref().map_or(false, |d| d != device)
+                {
+                    continue;
+                }
+
+                let key = format!("{}:{}", kind.to_string(), s.name);
+
+                rval.push(Series {
+                    name: s.name.clone(),
+                    key,
+                    source: Source::Sensor(i),
+                });
+            }
+        }
+
+        if let Some(name) = &self.ringbuf {
+            let mut matches = vec![];
+
+            for r in humility_cmd::ringbuf::ringbufs(hubris) {
+                if r.name == name {
+                    matches = vec![r];
+                    break;
+                }
+
+                if r.taskname(hubris)? == name {
+                    matches.push(r);
+                }
+            }
+
+            let r = match matches.len() {
+                0 => {
+                    bail!("no ring buffer named \"{}\" (see ringbuf -l)", name)
+                }
+                1 => matches.pop().unwrap(),
+                _ => bail!("task {} has multiple ring buffers", name),
+            };
+
+            let key = self.ringbuf_key(name);
+
+            rval.push(Series {
+                name: self.name(&key),
+                key,
+                source: Source::Ringbuf(r, self.entry.clone(), None),
+            });
+        }
+
+        if let Some(name) = &self.variable {
+            let variable = hubris.lookup_variable(name)?;
+
+            let field = match &self.field {
+                Some(field) => field.split('.').map(String::from).collect(),
+                None => vec![],
+            };
+
+            let key = self.variable_key(name);
+
+            rval.push(Series {
+                name: self.name(&key),
+                key,
+                source: Source::Variable(variable, field),
+            });
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Matches this series specification against the keys of a recording,
+    /// returning the name of each matching series and the column that
+    /// contains it.
+    ///
+    pub fn matches(&self, keys: &[&str]) -> Vec<(String, usize)> {
+        let mut rval = vec![];
+
+        for (col, key) in keys.iter().enumerate() {
+            let matched = if let Some(kind) = &self.sensor {
+                match key.split_once(':') {
+                    Some((k, n)) => {
+                        k == kind && self.name.as_ref().map_or(true, |s| s == n)
+                    }
+                    None => false,
+                }
+            } else if let Some(ringbuf) = &self.ringbuf {
+                *key == self.ringbuf_key(ringbuf)
+            } else if let Some(variable) = &self.variable {
+                *key == self.variable_key(variable)
+            } else {
+                false
+            };
+
+            if matched {
+                rval.push((self.name(key), col));
+            }
+        }
+
+        rval
+    }
+}
+
+impl GraphSpec {
+    ///
+    /// Resolves the series of this graph against the archive.
+    ///
+    pub fn resolve<'a>(
+        &self,
+        hubris: &'a HubrisArchive,
+    ) -> Result<Vec<Series<'a>>> {
+        let mut rval = vec![];
+
+        for series in &self.series {
+            rval.extend(series.resolve(hubris)?);
+        }
+
+        Ok(rval)
+    }
+}
+
+impl Layout {
+    pub fn load(filename: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(filename)
+            .map_err(|e| anyhow!("failed to read {}: {}", filename, e))?;
+
+        Self::parse(&contents, filename)
+    }
+
+    fn parse(contents: &str, filename: &str) -> Result<Self> {
+        let layout: Layout = toml::from_str(contents)
+            .map_err(|e| anyhow!("failed to parse {}: {}", filename, e))?;
+
+        if layout.graph.is_empty() {
+            bail!("{}: layout must specify at least one graph", filename);
+        }
+
+        for graph in &layout.graph {
+            if graph.height == 0 {
+                bail!("graph \"{}\": height must be non-zero", graph.label);
+            }
+
+            for series in &graph.series {
+                series.validate(&graph.label)?;
+            }
+        }
+
+        for status in &layout.status {
+            if status.idol.split_once('.').is_none() {
+                bail!(
+                    "status \"{}\": idol operation must be specified as \
+                    Interface.operation",
+                    status.label
+                );
+            }
+        }
+
+        Ok(layout)
+    }
+
+    ///
+    /// The default layout:  graphs of temperature, fan speed and output
+    /// current, with the sequencer state as status.
+    ///
+    pub fn default_layout() -> Self {
+        let graph = |kind: HubrisSensorKind, label: &str, height| GraphSpec {
+            label: label.to_string(),
+            legend: None,
+            units: None,
+            height,
+            series: vec![SeriesSpec {
+                sensor: Some(kind.to_string().to_string()),
+                ..SeriesSpec::default()
+            }],
+            preset: Some(kind),
+        };
+
+        Layout {
+            status: vec![StatusSpec {
+                label: "Power state".to_string(),
+                idol: "Sequencer.get_state".to_string(),
+            }],
+            graph: vec![
+                graph(HubrisSensorKind::Temperature, "Temperature", 2),
+                graph(HubrisSensorKind::Speed, "Fan speed", 1),
+                graph(HubrisSensorKind::Current, "Output current", 1),
+            ],
+            controls: true,
+        }
+    }
+}
+
+fn base_value(base: &Base) -> Option<f32> {
+    Some(match *base {
+        Base::I8(v) => v as f32,
+        Base::I16(v) => v as f32,
+        Base::I32(v) => v as f32,
+        Base::I64(v) => v as f32,
+        Base::I128(v) => v as f32,
+        Base::U8(v) => v as f32,
+        Base::U16(v) => v as f32,
+        Base::U32(v) => v as f32,
+        Base::U64(v) => v as f32,
+        Base::U128(v) => v as f32,
+        Base::Bool(v) => v as u8 as f32,
+        Base::F32(v) => v,
+        Base::F64(v) => v as f32,
+        Base::U0 => return None,
+    })
+}
+
+impl<'a> Source<'a> {
+    ///
+    /// Reads the current value of a ring buffer or variable from the core.
+    /// (Sensors are read via HIF, and are not read here.)
+    ///
+    pub fn read(
+        &mut self,
+        hubris: &HubrisArchive,
+        core: &mut dyn humility::core::Core,
+    ) -> Result<Option<f32>> {
+        match self {
+            Source::Sensor(_) => Ok(None),
+
+            Source::Ringbuf(r, entry, last) => {
+                let ringbuf = r.read(hubris, core)?;
+
+                //
+                // Determine the number of entries that have been recorded
+                // since the last sample:  a slot that has the same line as
+                // it did contributes the increase in its count (if any); a
+                // slot that has changed contributes its entire count.
+                //
+                let mut total = 0;
+
+                let current = ringbuf
+                    .buffer
+                    .iter()
+                    .map(|e| (e.line, e.count))
+                    .collect::<Vec<_>>();
+
+                for (slot, e) in ringbuf.entries() {
+                    if let Some(entry) = entry {
+                        match &e.payload {
+                            Value::Enum(payload)
+                                if payload.disc() == entry.as_str() => {}
+                            _ => continue,
+                        }
+                    }
+
+                    total += match last.as_ref().and_then(|l| l.get(slot)) {
+                        Some(&(line, count)) if line == e.line => {
+                            e.count.saturating_sub(count)
+                        }
+                        _ => e.count,
+                    };
+                }
+
+                //
+                // On the first sample, we have nothing to compare against.
+                //
+                let rval = last.as_ref().map(|_| total as f32);
+                *last = Some(current);
+
+                Ok(rval)
+            }
+
+            Source::Variable(variable, field) => {
+                let mut buf: Vec<u8> = vec![];
+                buf.resize_with(variable.size, Default::default);
+
+                core.op_start()?;
+                let rval = core.read_8(variable.addr, buf.as_mut_slice());
+                core.op_done()?;
+                rval?;
+
+                let ty = hubris.lookup_type(variable.goff)?;
+                let mut value =
+                    humility::reflect::load_value(hubris, &buf, ty, 0)?;
+
+                for member in field.iter() {
+                    let s = value.as_struct()?;
+
+                    value = match s.iter().find(|(n, _)| n == member) {
+                        Some((_, v)) => v.clone(),
+                        None => bail!("{} has no member {}", s.name(), member),
+                    };
+                }
+
+                match value {
+                    Value::Base(base) => Ok(base_value(&base)),
+                    _ => bail!(
+                        "variable at 0x{:x} is not a primitive value; \
+                        specify a field",
+                        variable.addr
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: [&str; 6] = [
+        "temp:T1",
+        "temp:T2",
+        "speed:FAN0",
+        "ringbuf:thermal",
+        "ringbuf:thermal:Fault",
+        "var:FOO.bar",
+    ];
+
+    fn matches(spec: &str) -> Result<Vec<(String, usize)>> {
+        let spec: SeriesSpec = toml::from_str(spec)?;
+        spec.validate("test")?;
+        Ok(spec.matches(&KEYS))
+    }
+
+    fn err(contents: &str) -> String {
+        Layout::parse(contents, "layout.toml").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn series_matches() -> Result<()> {
+        assert_eq!(
+            matches(r#"sensor = "temp""#)?,
+            [("T1".to_string(), 0), ("T2".to_string(), 1)]
+        );
+
+        assert_eq!(
+            matches("sensor = \"temp\"\nname = \"T2\"")?,
+            [("T2".to_string(), 1)]
+        );
+
+        assert!(matches(r#"sensor = "voltage""#)?.is_empty());
+
+        assert_eq!(
+            matches(r#"ringbuf = "thermal""#)?,
+            [("thermal".to_string(), 3)]
+        );
+
+        assert_eq!(
+            matches("ringbuf = \"thermal\"\nentry = \"Fault\"")?,
+            [("thermal:Fault".to_string(), 4)]
+        );
+
+        assert_eq!(
+            matches("variable = \"FOO\"\nfield = \"bar\"\nlabel = \"Bar\"")?,
+            [("Bar".to_string(), 5)]
+        );
+
+        assert!(matches(r#"variable = "FOO""#)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn load() -> Result<()> {
+        let layout = Layout::parse(
+            r#"
+            [[status]]
+            label = "Power"
+            idol = "Sequencer.get_state"
+
+            [[graph]]
+            label = "Temperature"
+            series = [
+                { sensor = "temp", device = "tmp117" },
+                { variable = "CONTROL_STATE", field = "count" },
+            ]
+
+            [[graph]]
+            label = "Faults"
+            height = 2
+            series = [ { ringbuf = "thermal", entry = "Fault" } ]
+            "#,
+            "layout.toml",
+        )?;
+
+        assert_eq!(layout.status.len(), 1);
+        assert_eq!(layout.graph.len(), 2);
+        assert_eq!(layout.graph[0].height, 1);
+        assert_eq!(layout.graph[0].series.len(), 2);
+        assert_eq!(layout.graph[1].height, 2);
+        assert!(!layout.controls);
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_errors() {
+        assert_eq!(
+            err("status = []"),
+            "layout.toml: layout must specify at least one graph"
+        );
+
+        assert_eq!(
+            err("[[graph]]\nlabel = \"g\"\nheight = 0\nseries = []"),
+            "graph \"g\": height must be non-zero"
+        );
+
+        let series = |s: &str| {
+            err(&format!("[[graph]]\nlabel = \"g\"\nseries = [ {{ {} }} ]", s))
+        };
+
+        assert!(series(r#"sensor = "temp", ringbuf = "thermal""#)
+            .contains("exactly one of"));
+        assert!(series("").contains("exactly one of"));
+        assert!(
+            series(r#"sensor = "bogus""#).contains("unrecognized sensor kind")
+        );
+        assert!(series(r#"sensor = "temp", label = "T""#)
+            .contains("sensors are labeled by name"));
+        assert!(series(r#"variable = "FOO", name = "T1""#)
+            .contains("only apply to sensors"));
+        assert!(series(r#"variable = "FOO", entry = "Fault""#)
+            .contains("only applies to ring buffers"));
+        assert!(series(r#"ringbuf = "thermal", field = "x""#)
+            .contains("only applies to variables"));
+        assert!(series(r#"variable = "FOO", bogus = 1"#)
+            .starts_with("failed to parse layout.toml"));
+
+        assert!(err("[[status]]\nlabel = \"s\"\nidol = \"get_state\"\n\
+            [[graph]]\nlabel = \"g\"\nseries = [ { sensor = \"temp\" } ]")
+        .contains("Interface.operation"));
+    }
+}
@@ -8,11 +8,82 @@
 //! `sensor` task is required for operation; see the documentation for
 //! `humility sensors` for more details.)
 //!
+//! By default, the dashboard graphs temperature, fan speed and output
+//! current, and displays the state of the sequencer; on such systems, `0`
+//! and `2` set the power state to A0 and A2, `F` and `f` turn the fans on
+//! and off, and `<` and `>` (with a fan selected) change its PWM duty cycle.
+//! To graph something else, use `-l` (`--layout`) to specify a TOML file
+//! that describes the status and graphs to display.  Each `[[status]]` item
+//! has a `label` and an `idol` operation (taking no arguments) whose result
+//! is displayed, and each `[[graph]]` has a `label`, optional `legend` and
+//! `units` labels, an optional relative `height`, and any number of
+//! `series`.  A series is one of:
+//!
+//! - `sensor`: all sensors of the specified kind, optionally restricted by
+//!   `name` and `device`
+//! - `ringbuf`: a ring buffer (by name or by task), graphed as the number
+//!   of entries recorded per sample, optionally restricted to entries of
+//!   the specified `entry` variant
+//! - `variable`: a variable (as with `humility readvar`), optionally with a
+//!   dot-separated `field` within it
+//!
+//! Ring buffer and variable series can also have a `label` in the legend.
+//! For example:
+//!
+//! ```toml
+//! [[status]]
+//! label = "Power state"
+//! idol = "Sequencer.get_state"
+//!
+//! [[graph]]
+//! label = "Temperature"
+//! units = "Degrees Celsius"
+//! height = 2
+//! series = [ { sensor = "temp" } ]
+//!
+//! [[graph]]
+//! label = "Rails"
+//! legend = "Rails"
+//! units = "Volts"
+//! series = [
+//!     { sensor = "voltage", device = "raa229618" },
+//!     { sensor = "voltage", name = "V12_SYS_A2" },
+//! ]
+//!
+//! [[graph]]
+//! label = "Thermal events"
+//! legend = "Events"
+//! series = [
+//!     { ringbuf = "thermal", label = "all" },
+//!     { ringbuf = "thermal", entry = "ControlError", label = "errors" },
+//!     { variable = "CONTROL_STATE", field = "count" },
+//! ]
+//! ```
+//!
 //! If `-o` is provided, it specifies an output file for any raw sensor data
-//! graphed by the dashboard.
+//! graphed by the dashboard.  The first line of this CSV file names each
+//! series (prefixed with its kind, e.g. `temp:Southwest`, `ringbuf:thermal`
+//! or `var:CONTROL_STATE.count`); each subsequent line has the values of the
+//! series at one sample, with samples taken once per second.
+//!
+//! A file written with `-o` can be replayed with `--replay`, which doesn't
+//! require an attached target or (with the exception of files written by
+//! older versions of Humility, which only name temperature sensors) an
+//! archive.  When replaying with `--layout`, the series in the file are
+//! matched against those in the layout.  When replaying, the following keys
+//! are available:
+//!
+//! - `space`: pause or resume playback
+//! - `left`/`right`: seek backward or forward by 10 samples
+//! - `page up`/`page down`: seek backward or forward by the graph width
+//! - `home`/`end`: seek to the beginning or end of the recording
+//! - `[`/`]`: halve or double the playback speed
+//!
+//! The other keys (`+`/`-` to zoom, `up`/`down` to select a sensor, `tab` to
+//! select a graph, and `q` to quit) operate as they do on a live system.
 //!
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use crossterm::{
@@ -34,9 +105,12 @@ use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::time::{Duration, Instant};
+
+mod layout;
+use layout::{GraphSpec, Layout, Source};
 use tui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    layout::{Alignment, Constraint, Direction, Rect},
     style::{Color, Modifier, Style},
     symbols,
     text::{Span, Spans},
@@ -58,10 +132,29 @@ struct DashboardArgs {
     timeout: u32,
 
     /// CSV output file
-    #[clap(long, short)]
+    #[clap(long, short, conflicts_with = "replay")]
     output: Option<String>,
+
+    /// replay a CSV file previously written with --output
+    #[clap(long, value_name = "filename")]
+    replay: Option<String>,
+
+    /// TOML file specifying the graphs and status to display
+    #[clap(long, short, value_name = "filename")]
+    layout: Option<String>,
 }
 
+//
+// The interval between samples, both when polling a live system and when
+// replaying a recording.
+//
+const DASHBOARD_INTERVAL_MS: u32 = 1000;
+
+//
+// The maximum replay speed, as a multiple of the recording speed.
+//
+const DASHBOARD_REPLAY_MAX_SPEED: u32 = 1024;
+
 struct StatefulList {
     state: ListState,
     n: usize,
@@ -69,6 +162,10 @@ struct StatefulList {
 
 impl StatefulList {
     fn next(&mut self) {
+        if self.n == 0 {
+            return;
+        }
+
         self.state.select(match self.state.selected() {
             Some(ndx) => Some((ndx + 1) % self.n),
             None => Some(0),
@@ -76,6 +173,10 @@ impl StatefulList {
     }
 
     fn previous(&mut self) {
+        if self.n == 0 {
+            return;
+        }
+
         self.state.select(match self.state.selected() {
             Some(ndx) if ndx == 0 => Some(self.n - 1),
             Some(ndx) => Some(ndx - 1),
@@ -214,6 +315,46 @@ impl Attributes for CurrentGraph {
     }
 }
 
+struct LayoutGraph {
+    label: String,
+    legend: String,
+    units: String,
+}
+
+impl Attributes for LayoutGraph {
+    fn label(&self) -> String {
+        self.label.clone()
+    }
+    fn legend_label(&self) -> String {
+        self.legend.clone()
+    }
+
+    fn y_axis_label(&self) -> String {
+        self.units.clone()
+    }
+
+    fn axis_value(&self, val: f64) -> String {
+        format!("{:.1}", val)
+    }
+
+    fn legend_value(&self, val: f64) -> String {
+        format!("{:.2}", val)
+    }
+}
+
+fn attributes(spec: &GraphSpec, len: usize) -> Box<dyn Attributes> {
+    match spec.preset {
+        Some(HubrisSensorKind::Temperature) => Box::new(TempGraph),
+        Some(HubrisSensorKind::Speed) => Box::new(FanGraph::new(len)),
+        Some(HubrisSensorKind::Current) => Box::new(CurrentGraph),
+        _ => Box::new(LayoutGraph {
+            label: spec.label.clone(),
+            legend: spec.legend.clone().unwrap_or_else(|| "Series".to_string()),
+            units: spec.units.clone().unwrap_or_default(),
+        }),
+    }
+}
+
 struct Graph {
     series: Vec<Series>,
     legend: StatefulList,
@@ -221,11 +362,12 @@ struct Graph {
     width: usize,
     interpolate: usize,
     bounds: [f64; 2],
+    height: u32,
     attributes: Box<dyn Attributes>,
 }
 
 impl Graph {
-    fn new(all: &[String], attr: Box<dyn Attributes>) -> Result<Self> {
+    fn new(all: &[String], spec: &GraphSpec) -> Result<Self> {
         let mut series = vec![];
 
         let colors = [
@@ -260,7 +402,8 @@ impl Graph {
             width: 600,
             interpolate: 0,
             bounds: [20.0, 120.0],
-            attributes: attr,
+            height: spec.height,
+            attributes: attributes(spec, all.len()),
         })
     }
 
@@ -382,6 +525,16 @@ impl Graph {
         self.width = (self.width as f64 * 1.25) as usize;
         self.set_interpolate();
     }
+
+    //
+    // The most recent value of a series, as of the graph's current time.
+    //
+    fn latest(&self, s: &Series) -> Option<f32> {
+        match self.time {
+            0 => None,
+            time => s.raw.get(time - 1).copied().flatten(),
+        }
+    }
 }
 
 struct Dashboard<'a> {
@@ -389,6 +542,8 @@ struct Dashboard<'a> {
     context: HiffyContext<'a>,
     ops: Vec<Op>,
     status_ops: Vec<idol::IdolOperation<'a>>,
+    status_labels: Vec<String>,
+    sources: Vec<Source<'a>>,
     graphs: Vec<Graph>,
     current: usize,
     work: Vec<Vec<Op>>,
@@ -397,6 +552,7 @@ struct Dashboard<'a> {
     outstanding: bool,
     status: Vec<String>,
     output: Option<File>,
+    controls: bool,
 }
 
 impl<'a> Dashboard<'a> {
@@ -404,27 +560,54 @@ impl<'a> Dashboard<'a> {
         hubris: &'a HubrisArchive,
         core: &mut dyn Core,
         subargs: &DashboardArgs,
+        layout: &Layout,
     ) -> Result<Dashboard<'a>> {
         let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
         let mut ops = vec![];
 
         let mut status_ops = vec![];
+        let mut status_labels = vec![];
         let mut status = vec![];
 
-        status_ops.push(sequencer_state_ops(hubris, &mut context, &mut ops)?);
-        status.push("".to_string());
+        for spec in &layout.status {
+            let (interface, operation) = spec.idol.split_once('.').unwrap();
+
+            status_ops.push(status_op(
+                hubris,
+                &mut context,
+                &mut ops,
+                interface,
+                operation,
+            )?);
+            status_labels.push(spec.label.clone());
+            status.push("".to_string());
+        }
+
+        let mut sources = vec![];
+        let mut sensors = vec![];
+        let mut graphs = vec![];
+        let mut header = vec![];
+
+        for spec in &layout.graph {
+            let series = spec.resolve(hubris)?;
+            let names =
+                series.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
 
-        let temps = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Temperature
-        })?;
+            for s in series {
+                if let Source::Sensor(i) = s.source {
+                    sensors.push(i);
+                }
 
-        let fans = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Speed
-        })?;
+                header.push(s.key);
+                sources.push(s.source);
+            }
 
-        let current = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Current
-        })?;
+            graphs.push(Graph::new(&names, spec)?);
+        }
+
+        if !sensors.is_empty() {
+            sensor_ops(hubris, &mut context, &mut ops, &sensors)?;
+        }
 
         ops.push(Op::Done);
 
@@ -432,31 +615,28 @@ impl<'a> Dashboard<'a> {
 
         let output = if let Some(output) = &subargs.output {
             let mut f = File::create(output)?;
-            writeln!(&mut f, "{}", temps.join(","))?;
+            writeln!(&mut f, "{}", header.join(","))?;
             Some(f)
         } else {
             None
         };
 
-        let graphs = vec![
-            Graph::new(&temps, Box::new(TempGraph))?,
-            Graph::new(&fans, Box::new(FanGraph::new(fans.len())))?,
-            Graph::new(&current, Box::new(CurrentGraph))?,
-        ];
-
         Ok(Dashboard {
             hubris,
             context,
             ops,
             status_ops,
+            status_labels,
+            sources,
             graphs,
             current: 0,
             outstanding: true,
             last: Instant::now(),
-            interval: 1000,
+            interval: DASHBOARD_INTERVAL_MS,
             work: Vec::new(),
             status,
             output,
+            controls: layout.controls,
         })
     }
 
@@ -511,8 +691,12 @@ impl<'a> Dashboard<'a> {
         Ok(())
     }
 
-    fn status(&self) -> Vec<(&str, &str)> {
-        vec![("Power state", &self.status[0])]
+    fn status(&self) -> Vec<(String, String)> {
+        self.status_labels
+            .iter()
+            .cloned()
+            .zip(self.status.iter().cloned())
+            .collect()
     }
 
     fn need_update(&mut self, core: &mut dyn Core) -> Result<bool> {
@@ -527,11 +711,22 @@ impl<'a> Dashboard<'a> {
                     }
                 }
 
-                for r in &results[self.status.len()..] {
-                    raw.push(if let Ok(val) = r {
-                        Some(f32::from_le_bytes(val[0..4].try_into()?))
-                    } else {
-                        None
+                let mut sensors = results[self.status.len()..].iter();
+
+                //
+                // Sensors are read via HIF (and their results are in the
+                // order in which they appear); everything else is read
+                // directly from the core.
+                //
+                for source in self.sources.iter_mut() {
+                    raw.push(match source {
+                        Source::Sensor(_) => match sensors.next() {
+                            Some(Ok(val)) => {
+                                Some(f32::from_le_bytes(val[0..4].try_into()?))
+                            }
+                            _ => None,
+                        },
+                        _ => source.read(self.hubris, core)?,
                     });
                 }
 
@@ -679,10 +874,18 @@ fn run_dashboard<B: Backend>(
             if let Event::Key(key) = event::read()? {
                 match key.code {
                     KeyCode::Char('q') => return Ok(()),
-                    KeyCode::Char('2') => dashboard.set_a2(core)?,
-                    KeyCode::Char('0') => dashboard.set_a0(core)?,
-                    KeyCode::Char('F') => dashboard.fans_on(core)?,
-                    KeyCode::Char('f') => dashboard.fans_off(core)?,
+                    KeyCode::Char('2') if dashboard.controls => {
+                        dashboard.set_a2(core)?
+                    }
+                    KeyCode::Char('0') if dashboard.controls => {
+                        dashboard.set_a0(core)?
+                    }
+                    KeyCode::Char('F') if dashboard.controls => {
+                        dashboard.fans_on(core)?
+                    }
+                    KeyCode::Char('f') if dashboard.controls => {
+                        dashboard.fans_off(core)?
+                    }
                     KeyCode::Char('+') => dashboard.zoom_in(),
                     KeyCode::Char('-') => dashboard.zoom_out(),
                     KeyCode::Char('>') => dashboard.increase(core),
@@ -702,22 +905,314 @@ fn run_dashboard<B: Backend>(
 
         if update {
             dashboard.update_data();
-            terminal.draw(|f| draw(f, &mut dashboard))?;
+            let status = dashboard.status();
+            terminal.draw(|f| draw(f, &mut dashboard.graphs, &status))?;
         }
 
         last_tick = Instant::now();
     }
 }
 
-fn dashboard(context: &mut humility::ExecutionContext) -> Result<()> {
-    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
-    let hubris = context.archive.as_ref().unwrap();
+struct Replay {
+    filename: String,
+    graphs: Vec<Graph>,
+    current: usize,
+    len: usize,
+    time: usize,
+    playing: bool,
+    speed: u32,
+    last: Instant,
+}
 
-    let core = &mut **context.core.as_mut().unwrap();
+impl Replay {
+    fn new(
+        hubris: &HubrisArchive,
+        filename: &str,
+        layout: &Layout,
+    ) -> Result<Replay> {
+        let contents = std::fs::read_to_string(filename)?;
+        let mut lines = contents.lines();
+
+        let header = match lines.next() {
+            Some(header) => header.split(',').collect::<Vec<_>>(),
+            None => bail!("{} is empty", filename),
+        };
 
-    let subargs = DashboardArgs::try_parse_from(subargs)?;
-    let dashboard = Dashboard::new(hubris, core, &subargs)?;
+        //
+        // For each of our graphs, determine the names of its series and the
+        // columns that contain them.
+        //
+        let columns = if header.iter().all(|h| h.contains(':')) {
+            let columns = layout
+                .graph
+                .iter()
+                .map(|spec| {
+                    spec.series
+                        .iter()
+                        .flat_map(|s| s.matches(&header))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            let mut matched = vec![false; header.len()];
+
+            for (_, col) in columns.iter().flatten() {
+                matched[*col] = true;
+            }
+
+            let unmatched = matched.iter().filter(|m| !**m).count();
+
+            if unmatched != 0 {
+                humility::msg!(
+                    "{} of {} series in {} are not in the layout",
+                    unmatched,
+                    header.len(),
+                    filename
+                );
+            }
+
+            columns
+        } else {
+            //
+            // This was written by an older Humility that only named the
+            // temperature sensors (but recorded all sensors in the order
+            // of the default layout); we need the archive to know what the
+            // other columns are.
+            //
+            if !layout.controls {
+                bail!(
+                    "{} was written by an older version of Humility, and \
+                    can only be replayed with the default layout",
+                    filename
+                );
+            }
+
+            let kinds = [
+                HubrisSensorKind::Temperature,
+                HubrisSensorKind::Speed,
+                HubrisSensorKind::Current,
+            ];
+
+            let mut columns: Vec<Vec<(String, usize)>> =
+                vec![vec![]; kinds.len()];
+
+            for (col, h) in header.iter().enumerate() {
+                columns[0].push((h.to_string(), col));
+            }
+
+            if hubris.loaded() {
+                let mut col = header.len();
+
+                for (ndx, kind) in kinds.iter().enumerate().skip(1) {
+                    for s in hubris.manifest.sensors.iter() {
+                        if s.kind == *kind {
+                            columns[ndx].push((s.name.clone(), col));
+                            col += 1;
+                        }
+                    }
+                }
+            } else {
+                humility::msg!(
+                    "{} only names temperature sensors; \
+                    specify an archive to replay other sensors",
+                    filename
+                );
+            }
+
+            columns
+        };
+
+        let mut graphs = vec![];
+
+        for (spec, cols) in layout.graph.iter().zip(columns.iter()) {
+            let names = cols.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+            graphs.push(Graph::new(&names, spec)?);
+        }
+
+        let mut len = 0;
+
+        for (lineno, line) in lines.enumerate() {
+            let mut raw = vec![];
+
+            for val in line.split(',') {
+                raw.push(match val.trim() {
+                    "" => None,
+                    val => Some(val.parse::<f32>().map_err(|e| {
+                        anyhow!(
+                            "{}: bad value \"{}\" on line {}: {}",
+                            filename,
+                            val,
+                            lineno + 2,
+                            e
+                        )
+                    })?),
+                });
+            }
+
+            for (graph, cols) in graphs.iter_mut().zip(columns.iter()) {
+                let data = cols
+                    .iter()
+                    .map(|(_, col)| raw.get(*col).copied().flatten())
+                    .collect::<Vec<_>>();
+
+                graph.data(&data);
+            }
 
+            len += 1;
+        }
+
+        humility::msg!("replaying {} samples from {}", len, filename);
+
+        let mut replay = Replay {
+            filename: filename.to_string(),
+            graphs,
+            current: 0,
+            len,
+            time: 0,
+            playing: true,
+            speed: 1,
+            last: Instant::now(),
+        };
+
+        replay.seek_to(0);
+
+        Ok(replay)
+    }
+
+    fn seek_to(&mut self, time: usize) {
+        self.time = std::cmp::min(time, self.len);
+
+        for graph in self.graphs.iter_mut() {
+            graph.time = self.time;
+        }
+    }
+
+    fn seek(&mut self, delta: isize) {
+        let time = if delta < 0 {
+            self.time.saturating_sub(delta.unsigned_abs())
+        } else {
+            self.time.saturating_add(delta as usize)
+        };
+
+        self.seek_to(time);
+    }
+
+    fn width(&self) -> isize {
+        self.graphs[self.current].width as isize
+    }
+
+    fn need_update(&mut self) -> bool {
+        //
+        // At higher speeds, we may need to advance by more than one sample
+        // per tick.
+        //
+        let elapsed = self.last.elapsed().as_millis() as u64;
+        let samples =
+            (elapsed * self.speed as u64) / DASHBOARD_INTERVAL_MS as u64;
+
+        if !self.playing || samples == 0 {
+            return false;
+        }
+
+        self.last = Instant::now();
+
+        if self.time < self.len {
+            self.seek(samples as isize);
+        } else {
+            self.playing = false;
+        }
+
+        true
+    }
+
+    fn status(&self) -> Vec<(String, String)> {
+        let state = if self.playing {
+            format!("playing ({}x)", self.speed)
+        } else {
+            "paused".to_string()
+        };
+
+        vec![
+            ("Replay".to_string(), self.filename.clone()),
+            ("Sample".to_string(), format!("{}/{}", self.time, self.len)),
+            ("State".to_string(), state),
+        ]
+    }
+}
+
+fn run_replay<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut replay: Replay,
+) -> Result<()> {
+    let tick_rate = Duration::from_millis(100);
+
+    loop {
+        let update = if crossterm::event::poll(tick_rate)? {
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Char(' ') => {
+                        if !replay.playing && replay.time == replay.len {
+                            replay.seek_to(0);
+                        }
+
+                        replay.playing = !replay.playing;
+                        replay.last = Instant::now();
+                    }
+                    KeyCode::Char(']') => {
+                        replay.speed = std::cmp::min(
+                            replay.speed * 2,
+                            DASHBOARD_REPLAY_MAX_SPEED,
+                        );
+                    }
+                    KeyCode::Char('[') => {
+                        replay.speed = std::cmp::max(replay.speed / 2, 1);
+                    }
+                    KeyCode::Left => replay.seek(-10),
+                    KeyCode::Right => replay.seek(10),
+                    KeyCode::PageUp => replay.seek(-replay.width()),
+                    KeyCode::PageDown => replay.seek(replay.width()),
+                    KeyCode::Home => replay.seek_to(0),
+                    KeyCode::End => replay.seek_to(replay.len),
+                    KeyCode::Char('+') => {
+                        replay.graphs.iter_mut().for_each(|g| g.zoom_in())
+                    }
+                    KeyCode::Char('-') => {
+                        replay.graphs.iter_mut().for_each(|g| g.zoom_out())
+                    }
+                    KeyCode::Up => replay.graphs[replay.current].previous(),
+                    KeyCode::Down => replay.graphs[replay.current].next(),
+                    KeyCode::Esc => replay.graphs[replay.current].unselect(),
+                    KeyCode::Tab => {
+                        replay.current =
+                            (replay.current + 1) % replay.graphs.len();
+                    }
+                    _ => {}
+                }
+            }
+            true
+        } else {
+            replay.need_update()
+        };
+
+        if update {
+            for graph in replay.graphs.iter_mut() {
+                graph.update_data();
+            }
+
+            let status = replay.status();
+            terminal.draw(|f| draw(f, &mut replay.graphs, &status))?;
+        }
+    }
+}
+
+//
+// Sets up the terminal, runs the specified function, and restores the
+// terminal.
+//
+fn with_terminal(
+    run: impl FnOnce(&mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()>,
+) -> Result<()> {
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -725,7 +1220,7 @@ fn dashboard(context: &mut humility::ExecutionContext) -> Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let res = run_dashboard(&mut terminal, dashboard, core);
+    let res = run(&mut terminal);
 
     // restore terminal
     disable_raw_mode()?;
@@ -736,18 +1231,51 @@ fn dashboard(context: &mut humility::ExecutionContext) -> Result<()> {
     )?;
     terminal.show_cursor()?;
 
-    res?;
+    res
+}
 
-    Ok(())
+fn dashboard_live(
+    context: &mut humility::ExecutionContext,
+    subargs: &DashboardArgs,
+    layout: &Layout,
+) -> Result<()> {
+    let hubris = context.archive.as_ref().unwrap();
+    let core = &mut **context.core.as_mut().unwrap();
+
+    let dashboard = Dashboard::new(hubris, core, subargs, layout)?;
+
+    with_terminal(|terminal| run_dashboard(terminal, dashboard, core))
+}
+
+fn dashboard(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let subargs = DashboardArgs::try_parse_from(subargs)?;
+    let hubris = context.archive.as_ref().unwrap();
+
+    let layout = match &subargs.layout {
+        Some(filename) => Layout::load(filename)?,
+        None => Layout::default_layout(),
+    };
+
+    if let Some(filename) = &subargs.replay {
+        let replay = Replay::new(hubris, filename, &layout)?;
+        return with_terminal(|terminal| run_replay(terminal, replay));
+    }
+
+    if !hubris.loaded() {
+        bail!("must provide an archive unless replaying");
+    }
+
+    humility_cmd::attach(context, Attach::LiveOnly, Validate::Booted, |c| {
+        dashboard_live(c, &subargs, &layout)
+    })
 }
 
 pub fn init() -> (Command, ClapCommand<'static>) {
     (
-        Command::Attached {
+        Command::Unattached {
             name: "dashboard",
-            archive: Archive::Required,
-            attach: Attach::LiveOnly,
-            validate: Validate::Booted,
+            archive: Archive::Optional,
             run: dashboard,
         },
         DashboardArgs::command(),
@@ -758,9 +1286,8 @@ fn sensor_ops(
     hubris: &HubrisArchive,
     context: &mut HiffyContext,
     ops: &mut Vec<Op>,
-    capture: impl Fn(&HubrisSensor) -> bool,
-) -> Result<Vec<String>> {
-    let mut sensors = vec![];
+    sensors: &[usize],
+) -> Result<()> {
     let funcs = context.functions()?;
     let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)?;
 
@@ -774,27 +1301,24 @@ fn sensor_ops(
         bail!("expected return value of read_sensor() to be an f32");
     }
 
-    for (i, s) in hubris.manifest.sensors.iter().enumerate() {
-        if !capture(s) {
-            continue;
-        }
-
+    for i in sensors {
         let payload =
-            op.payload(&[("id", idol::IdolArgument::Scalar(i as u64))])?;
+            op.payload(&[("id", idol::IdolArgument::Scalar(*i as u64))])?;
         context.idol_call_ops(&funcs, &op, &payload, ops)?;
-        sensors.push(s.name.clone());
     }
 
-    Ok(sensors)
+    Ok(())
 }
 
-fn sequencer_state_ops<'a>(
+fn status_op<'a>(
     hubris: &'a HubrisArchive,
     context: &mut HiffyContext,
     ops: &mut Vec<Op>,
+    interface: &str,
+    operation: &str,
 ) -> Result<idol::IdolOperation<'a>> {
     let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Sequencer", "get_state", None)?;
+    let op = idol::IdolOperation::new(hubris, interface, operation, None)?;
     context.idol_call_ops(&funcs, &op, &[], ops)?;
     Ok(op)
 }
@@ -866,7 +1390,7 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
     //
     let r = std::cmp::min((30 * 100) / parent.width, 80);
 
-    let chunks = Layout::default()
+    let chunks = tui::layout::Layout::default()
         .direction(Direction::Horizontal)
         .constraints(
             [Constraint::Percentage(100 - r), Constraint::Percentage(r)]
@@ -944,9 +1468,9 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
     let mut rows = vec![];
 
     for s in &graph.series {
-        let val = match s.raw.last() {
-            None | Some(None) => "-".to_string(),
-            Some(Some(val)) => graph.attributes.legend_value((*val).into()),
+        let val = match graph.latest(s) {
+            None => "-".to_string(),
+            Some(val) => graph.attributes.legend_value(val.into()),
         };
 
         rows.push(ListItem::new(Spans::from(vec![
@@ -978,29 +1502,29 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
 fn draw_graphs<B: Backend>(
     f: &mut Frame<B>,
     parent: Rect,
-    dashboard: &mut Dashboard,
+    graphs: &mut [Graph],
 ) {
-    let screen = Layout::default()
+    let total = graphs.iter().map(|g| g.height).sum();
+
+    let constraints = graphs
+        .iter()
+        .map(|g| Constraint::Ratio(g.height, total))
+        .collect::<Vec<_>>();
+
+    let screen = tui::layout::Layout::default()
         .direction(Direction::Vertical)
-        .constraints(
-            [
-                Constraint::Ratio(1, 2),
-                Constraint::Ratio(1, 4),
-                Constraint::Ratio(1, 4),
-            ]
-            .as_ref(),
-        )
+        .constraints(constraints.as_ref())
         .split(parent);
 
-    draw_graph(f, screen[0], &mut dashboard.graphs[0]);
-    draw_graph(f, screen[1], &mut dashboard.graphs[1]);
-    draw_graph(f, screen[2], &mut dashboard.graphs[2]);
+    for (area, graph) in screen.into_iter().zip(graphs.iter_mut()) {
+        draw_graph(f, area, graph);
+    }
 }
 
 fn draw_status<B: Backend>(
     f: &mut Frame<B>,
     parent: Rect,
-    status: &[(&str, &str)],
+    status: &[(String, String)],
 ) {
     let mut bar = vec![];
 
@@ -1008,7 +1532,7 @@ fn draw_status<B: Backend>(
         let s = &status[i];
 
         bar.push(Span::styled(
-            s.0,
+            s.0.as_str(),
             Style::default().add_modifier(Modifier::BOLD),
         ));
 
@@ -1017,7 +1541,7 @@ fn draw_status<B: Backend>(
             Style::default().add_modifier(Modifier::BOLD),
         ));
 
-        bar.push(Span::raw(s.1));
+        bar.push(Span::raw(s.1.as_str()));
 
         if i < status.len() - 1 {
             bar.push(Span::raw(" | "));
@@ -1033,14 +1557,18 @@ fn draw_status<B: Backend>(
     f.render_widget(para, parent);
 }
 
-fn draw<B: Backend>(f: &mut Frame<B>, dashboard: &mut Dashboard) {
+fn draw<B: Backend>(
+    f: &mut Frame<B>,
+    graphs: &mut [Graph],
+    status: &[(String, String)],
+) {
     let size = f.size();
 
-    let screen = Layout::default()
+    let screen = tui::layout::Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
         .split(size);
 
-    draw_graphs(f, screen[0], dashboard);
-    draw_status(f, screen[1], &dashboard.status());
+    draw_graphs(f, screen[0], graphs);
+    draw_status(f, screen[1], status);
 }
@@ -2,6 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! ## `humility etm`
+//!
+//! `humility etm` configures and consumes instruction trace from the
+//! Embedded Trace Macrocell (ETM) present in some ARM Cortex-M variants.
+//! ETMv3.5 (as found on ARMv7-M parts like the STM32F4) and ETMv4 (as found
+//! on ARMv8-M parts like the Cortex-M33 in the LPC55) are both supported;
+//! the version is determined from the trace unit itself when attached, and
+//! from the target of the archive when ingesting trace.
+//!
+//! To enable ETM on the attached device, use `--enable` (`-e`); trace is
+//! then emitted via the TPIU, and can be captured with a logic analyzer
+//! (or, where the debugger supports it, with `--output`).  A capture saved
+//! as CSV (e.g., as exported from the Saleae async serial analyzer) can be
+//! decoded against the archive with `--ingest` (`-i`):
+//!
+//! ```console
+//! % humility -a /path/to/my/hubris-archive.zip etm --ingest ./trace.csv
+//! ```
+//!
+//! Each executed instruction is displayed along with its containing function;
+//! use `--flowindent` (`-F`) to instead indent by call depth.  To decode a
+//! capture from a part other than that implied by the archive, specify the
+//! ETM version explicitly with `--etm-version`.
+//!
+
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
@@ -14,6 +39,7 @@ use humility_cmd::attach_live;
 use humility_cmd::{Archive, Command};
 use humility_cortex::debug::*;
 use humility_cortex::etm::*;
+use humility_cortex::etm4::*;
 use humility_cortex::scs::*;
 use humility_cortex::tpiu::*;
 use std::fs::File;
@@ -42,6 +68,13 @@ struct EtmArgs {
     /// ingest ETM data as CSV
     #[clap(long, short, value_name = "filename")]
     ingest: Option<String>,
+    /// ETM architecture version of ingested data (default: inferred from
+    /// the archive's target)
+    #[clap(
+        long, value_name = "version", requires = "ingest",
+        possible_values = &["3", "4"], parse(try_from_str = parse_int::parse)
+    )]
+    etm_version: Option<u8>,
     /// flowindent ingested data
     #[clap(long, short = 'F')]
     flowindent: bool,
@@ -64,9 +97,12 @@ struct TraceInstruction {
     skipped: bool,
 }
 
-struct TraceException {
+//
+// An exception in the trace, as decoded by either ETMv3 or ETMv4.
+//
+struct TraceException<E> {
     nsecs: u64,
-    exception: ETM3Exception,
+    exception: E,
 }
 
 #[derive(Debug)]
@@ -106,6 +142,16 @@ fn etmcmd_probe(core: &mut dyn Core) -> Result<()> {
         );
     }
 
+    if etmcmd_is_etm4(core)? {
+        humility::msg!("{:#x?}", TRCIDR0::read(core)?);
+        humility::msg!("{:#x?}", TRCIDR1::read(core)?);
+        humility::msg!("{:#x?}", TRCIDR2::read(core)?);
+        humility::msg!("{:#x?}", TRCIDR3::read(core)?);
+        humility::msg!("{:#x?}", TRCIDR4::read(core)?);
+        humility::msg!("{:#x?}", TRCIDR5::read(core)?);
+        return Ok(());
+    }
+
     let etmccr = ETMCCR::read(core)?;
     humility::msg!("{:#x?}", etmccr);
 
@@ -123,11 +169,129 @@ fn etmcmd_probe(core: &mut dyn Core) -> Result<()> {
     Ok(())
 }
 
+//
+// TRCIDR1 is at the same offset as the ETMv3 ETMIDR; its architecture
+// major version field will be 4 if (and only if) this is an ETMv4 trace
+// unit.
+//
+fn etmcmd_is_etm4(core: &mut dyn Core) -> Result<bool> {
+    Ok(TRCIDR1::read(core)?.trcarchmaj() == 4)
+}
+
+fn etmcmd_tpiu_setup(
+    core: &mut dyn Core,
+    clockscaler: Option<u16>,
+) -> Result<()> {
+    let mut val = TPIU_SPPR::read(core)?;
+    val.set_txmode(TPIUMode::NRZ);
+    val.write(core)?;
+
+    let mut val = TPIU_FFCR::read(core)?;
+    val.set_continuous_formatting(true);
+    val.write(core)?;
+
+    let mut acpr = TPIU_ACPR::read(core)?;
+    acpr.set_swoscaler(clockscaler.unwrap_or(HUMILITY_ETM_SWOSCALER).into());
+    acpr.write(core)?;
+    log::trace!("{:#x?}", TPIU_ACPR::read(core)?);
+
+    Ok(())
+}
+
+fn etmcmd_enable4(
+    core: &mut dyn Core,
+    clockscaler: Option<u16>,
+    traceid: u8,
+) -> Result<()> {
+    log::trace!("{:#x?}", TRCIDR0::read(core)?);
+
+    let mut val = DEMCR::read(core)?;
+    val.set_trcena(true);
+    val.write(core)?;
+
+    //
+    // Unlock the trace unit, and clear the OS lock.
+    //
+    TRCLAR::unlock(core)?;
+    TRCOSLAR::from(0).write(core)?;
+
+    //
+    // The trace unit must be disabled (and idle) before it can be
+    // programmed.
+    //
+    let mut prgctlr = TRCPRGCTLR::read(core)?;
+    prgctlr.set_enable(false);
+    prgctlr.write(core)?;
+
+    let mut idle = false;
+
+    for _ in 0..100 {
+        if TRCSTATR::read(core)?.idle() {
+            idle = true;
+            break;
+        }
+    }
+
+    if !idle {
+        bail!("trace unit failed to become idle");
+    }
+
+    etmcmd_tpiu_setup(core, clockscaler)?;
+
+    //
+    // We want instruction trace only:  no cycle counting, no timestamps, no
+    // branch broadcasting, and no return stack (which would otherwise elide
+    // the addresses of returns).  We ask for a synchronization packet every
+    // 4K bytes of trace.
+    //
+    TRCCONFIGR::from(0).write(core)?;
+    TRCEVENTCTL0R::from(0).write(core)?;
+    TRCEVENTCTL1R::from(0).write(core)?;
+    TRCSTALLCTLR::from(0).write(core)?;
+    TRCTSCTLR::from(0).write(core)?;
+    TRCCCCTLR::from(0).write(core)?;
+    TRCBBCTLR::from(0).write(core)?;
+
+    let mut val = TRCSYNCPR::from(0);
+    val.set_period(0xc);
+    val.write(core)?;
+
+    let mut val = TRCTRACEIDR::from(0);
+    val.set_traceid(traceid.into());
+    val.write(core)?;
+
+    //
+    // Trace everything:  resource selector 1 is always true, and we leave
+    // the start/stop logic in the started state.
+    //
+    let mut victlr = TRCVICTLR::from(0);
+    victlr.set_event(1);
+    victlr.set_start_stop_status(true);
+    victlr.write(core)?;
+
+    TRCVIIECTLR::from(0).write(core)?;
+    TRCVISSCTLR::from(0).write(core)?;
+
+    log::trace!("{:#x?}", TRCCONFIGR::read(core)?);
+    log::trace!("{:#x?}", TRCVICTLR::read(core)?);
+
+    prgctlr.set_enable(true);
+    prgctlr.write(core)?;
+
+    humility::msg!("ETMv4 enabled");
+
+    Ok(())
+}
+
 fn etmcmd_enable(
     core: &mut dyn Core,
     clockscaler: Option<u16>,
     traceid: u8,
 ) -> Result<()> {
+    if etmcmd_is_etm4(core)? {
+        return etmcmd_enable4(core, clockscaler, traceid);
+    }
+
     let etmccr = ETMCCR::read(core)?;
 
     if !etmccr.has_etmidr() {
@@ -175,18 +339,7 @@ fn etmcmd_enable(
     //
     // Now setup the TPIU.
     //
-    let mut val = TPIU_SPPR::read(core)?;
-    val.set_txmode(TPIUMode::NRZ);
-    val.write(core)?;
-
-    let mut val = TPIU_FFCR::read(core)?;
-    val.set_continuous_formatting(true);
-    val.write(core)?;
-
-    let mut acpr = TPIU_ACPR::read(core)?;
-    acpr.set_swoscaler(clockscaler.unwrap_or(HUMILITY_ETM_SWOSCALER).into());
-    acpr.write(core)?;
-    log::trace!("{:#x?}", TPIU_ACPR::read(core)?);
+    etmcmd_tpiu_setup(core, clockscaler)?;
 
     //
     // We are now ready to enable ETM.  There are a bunch of steps involved
@@ -250,6 +403,21 @@ fn etmcmd_enable(
 }
 
 fn etmcmd_disable(core: &mut dyn Core) -> Result<()> {
+    if etmcmd_is_etm4(core)? {
+        let mut prgctlr = TRCPRGCTLR::read(core)?;
+
+        if !prgctlr.enable() {
+            humility::msg!("ETM not enabled");
+            return Ok(());
+        }
+
+        prgctlr.set_enable(false);
+        prgctlr.write(core)?;
+
+        humility::msg!("ETM disabled");
+        return Ok(());
+    }
+
     let mut etmcr = ETMCR::read(core)?;
 
     if etmcr.power_down() {
@@ -360,9 +528,9 @@ fn etmcmd_trace(
     Ok(())
 }
 
-fn etmcmd_trace_exception(
+fn etmcmd_trace_exception<E: std::fmt::Debug>(
     _config: &TraceConfig,
-    exception: &TraceException,
+    exception: &TraceException<E>,
     _state: &mut TraceState,
 ) -> Result<()> {
     println!("{:-10} {:8} X {:?}", exception.nsecs, "-", exception.exception);
@@ -542,6 +710,169 @@ fn etmcmd_ingest(config: &TraceConfig, filename: &str) -> Result<()> {
     Ok(())
 }
 
+fn etmcmd_ingest4(config: &TraceConfig, filename: &str) -> Result<()> {
+    let file = File::open(filename)?;
+    let mut rdr = csv::Reader::from_reader(file);
+    let hubris = config.hubris;
+
+    let econfig = &ETM4Config {
+        traceid: config.traceid,
+        commopt: false,
+        context_id: 0,
+        vmid: 0,
+    };
+
+    type SaleaeTraceRecord = (f64, u8, Option<String>, Option<String>);
+
+    let mut iter = rdr.deserialize();
+
+    //
+    // Our current address is None when we are awaiting an address packet
+    // (that is, after a trace info packet, an indirect branch, or an
+    // exception).
+    //
+    let mut curaddr: Option<u32> = None;
+    let mut exception: Option<ETM4Exception> = None;
+    let mut state = TraceState::default();
+
+    etm4_ingest(
+        econfig,
+        || {
+            if let Some(line) = iter.next() {
+                let record: SaleaeTraceRecord = line?;
+                Ok(Some((record.1, record.0)))
+            } else {
+                Ok(None)
+            }
+        },
+        |packet| {
+            let nsecs = (packet.time * 1_000_000_000_f64) as u64;
+
+            log::trace!("{:x?}", packet);
+
+            //
+            // Emits instructions starting at the specified address up to and
+            // including the next branch (if `upto` is None) or up to but not
+            // including the specified address, returning the branch (if
+            // any) and its target.
+            //
+            let mut walk =
+                |from: Option<u32>,
+                 upto: Option<u32>,
+                 skipped: bool|
+                 -> Result<Option<(u32, u32, HubrisTarget)>> {
+                    let mut addr = match from {
+                        Some(addr) => addr,
+                        None => return Ok(None),
+                    };
+
+                    while Some(addr) != upto {
+                        let len = match hubris.instr_len(addr) {
+                            Some(len) => len,
+                            None => {
+                                warn!(
+                                    "unknown instruction length at {:x}!",
+                                    addr
+                                );
+                                return Ok(None);
+                            }
+                        };
+
+                        let target = arm_instr_target(hubris, addr);
+
+                        etmcmd_trace(
+                            config,
+                            &TraceInstruction {
+                                nsecs,
+                                addr,
+                                _len: len,
+                                target,
+                                skipped: target.is_some() && skipped,
+                            },
+                            &mut state,
+                        )?;
+
+                        if let Some(target) = target {
+                            if let Some(upto) = upto {
+                                warn!(
+                                    "branch at {:x} before exception return \
+                                    address {:x}",
+                                    addr, upto
+                                );
+                            }
+
+                            return Ok(Some((addr, len, target)));
+                        }
+
+                        addr += len;
+                    }
+
+                    Ok(None)
+                };
+
+            match (packet.header, packet.payload) {
+                (ETM4Header::Atoms { n, pattern }, _) => {
+                    for i in 0..n {
+                        let taken = pattern & (1 << i) != 0;
+
+                        curaddr = match walk(curaddr, None, !taken)? {
+                            Some((addr, len, _)) if !taken => Some(addr + len),
+                            Some((_, _, HubrisTarget::Direct(dest)))
+                            | Some((_, _, HubrisTarget::Call(dest))) => {
+                                Some(dest)
+                            }
+                            _ => None,
+                        };
+                    }
+                }
+
+                (_, ETM4Payload::Address { addr, .. }) => {
+                    match exception.take() {
+                        Some(exception) => {
+                            //
+                            // The address of an exception packet is the
+                            // preferred return address:  everything up to
+                            // it was executed before the exception was
+                            // taken.
+                            //
+                            walk(curaddr, Some(addr), false)?;
+
+                            etmcmd_trace_exception(
+                                config,
+                                &TraceException { nsecs, exception },
+                                &mut state,
+                            )?;
+
+                            curaddr = None;
+                        }
+                        None => curaddr = Some(addr),
+                    }
+                }
+
+                (_, ETM4Payload::Exception { exception: e, .. }) => {
+                    exception = Some(e);
+                }
+
+                (_, ETM4Payload::Overflow) | (_, ETM4Payload::Discard) => {
+                    warn!("trace discontinuity at offset {}", packet.offset);
+                    curaddr = None;
+                    exception = None;
+                }
+
+                (ETM4Header::TraceInfo, _) | (ETM4Header::TraceOn, _) => {
+                    curaddr = None;
+                }
+
+                _ => {}
+            }
+
+            Ok(())
+        },
+    )?;
+
+    Ok(())
+}
+
 fn etmcmd_output(core: &mut dyn Core) -> Result<()> {
     let start = Instant::now();
 
@@ -576,7 +907,23 @@ fn etmcmd(context: &mut humility::ExecutionContext) -> Result<()> {
             traceid: subargs.traceid,
         };
 
-        match etmcmd_ingest(&config, ingest) {
+        //
+        // ARMv8-M parts have an ETMv4 trace unit; ARMv7-M parts have ETMv3.
+        //
+        let version = match subargs.etm_version {
+            Some(version) => version,
+            None => match &hubris.manifest.target {
+                Some(target) if target.starts_with("thumbv8m") => 4,
+                _ => 3,
+            },
+        };
+
+        let rval = match version {
+            4 => etmcmd_ingest4(&config, ingest),
+            _ => etmcmd_ingest(&config, ingest),
+        };
+
+        match rval {
             Err(e) => {
                 bail!("failed to ingest {}: {}", ingest, e);
             }
@@ -0,0 +1,538 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility etrace`
+//!
+//! `humility etrace` decodes instruction trace from a RISC-V trace encoder
+//! that implements the E-Trace (Efficient Trace) specification.  E-Trace
+//! encodes only what cannot be inferred from the program itself -- the
+//! outcome of each conditional branch, the targets of uninferable jumps, and
+//! exceptions -- and `humility etrace` reconstructs the instruction stream by
+//! following control flow through the archive's program text.
+//!
+//! A capture is a raw binary file of te_inst packets in the RISC-V
+//! unformatted trace encapsulation, as saved from the trace buffer (or
+//! transport) of the target.  To decode it, use `--ingest` (`-i`):
+//!
+//! ```console
+//! % humility -a /path/to/my/hubris-archive.zip etrace -i ./trace.bin
+//! 2000a1c2 ping:main+3a None
+//! 2000a1c4 ping:main+3c None
+//! 2000a1c8 ping:main+40 Some(Call(2000a2f0))
+//! 2000a2f0 ping:userlib::sys_send+0 None
+//! ...
+//! ```
+//!
+//! Each instruction is shown with the task that contains it; use
+//! `--flowindent` (`-F`) to instead show calls and returns indented by call
+//! depth, as with `humility etm`.  To see the decoded packets themselves,
+//! use `--packets`.
+//!
+//! The widths of fields within packets are parameters of the trace
+//! encoder; by default, we assume the address width of the archive, with
+//! compressed instructions (that is, with addresses sent without their
+//! least significant bit), a 2-bit privilege, a 6-bit exception cause, no
+//! time or context, differential addresses, and an encapsulation without a
+//! source ID or timestamp.  Each of these can be overridden to match the
+//! encoder's configuration.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::arch::rv::rv_instr_target;
+use humility::cli::Subcommand;
+use humility::hubris::*;
+use humility::warn;
+use humility_cmd::{Archive, Command};
+use std::collections::VecDeque;
+use std::fs;
+
+mod packet;
+use packet::*;
+
+#[derive(Parser, Debug)]
+#[clap(name = "etrace", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ETraceArgs {
+    /// ingest E-Trace packets from a raw capture
+    #[clap(long, short, value_name = "filename")]
+    ingest: String,
+
+    /// flowindent ingested data
+    #[clap(long, short = 'F')]
+    flowindent: bool,
+
+    /// display decoded packets rather than instructions
+    #[clap(long, conflicts_with = "flowindent")]
+    packets: bool,
+
+    /// width of instruction addresses, in bits (default: that of the archive)
+    #[clap(long, value_name = "bits")]
+    iaddress_width: Option<u32>,
+
+    /// number of least significant address bits not sent
+    #[clap(long, value_name = "bits", default_value = "1")]
+    iaddress_lsb: u32,
+
+    /// width of the privilege field, in bits
+    #[clap(long, value_name = "bits", default_value = "2")]
+    privilege_width: u32,
+
+    /// width of the exception cause field, in bits
+    #[clap(long, value_name = "bits", default_value = "6")]
+    ecause_width: u32,
+
+    /// width of the context field, in bits
+    #[clap(long, value_name = "bits", default_value = "0")]
+    context_width: u32,
+
+    /// width of the time field, in bits
+    #[clap(long, value_name = "bits", default_value = "0")]
+    time_width: u32,
+
+    /// encoder sends full rather than differential addresses
+    #[clap(long)]
+    full_address: bool,
+
+    /// size of the encapsulation source ID, in bytes
+    #[clap(long, value_name = "bytes", default_value = "0")]
+    srcid_bytes: usize,
+
+    /// size of the encapsulation timestamp, in bytes
+    #[clap(long, value_name = "bytes", default_value = "0")]
+    timestamp_bytes: usize,
+}
+
+//
+// If we walk this many instructions without consuming a branch or arriving
+// at a reported address, we have lost our way.
+//
+const ETRACE_MAX_WALK: usize = 1_000_000;
+
+#[derive(Debug, Default)]
+struct TraceState {
+    indent: usize,
+    target: Option<HubrisTarget>,
+    inlined: Vec<HubrisGoff>,
+    stack: Vec<(usize, Vec<HubrisGoff>)>,
+}
+
+struct Decoder<'a> {
+    hubris: &'a HubrisArchive,
+    flowindent: bool,
+    state: TraceState,
+
+    /// the last instruction that we know to have been executed
+    pc: Option<u32>,
+
+    /// outcomes of conditional branches not yet walked, oldest first
+    branches: VecDeque<bool>,
+
+    /// the last address sent, for differential addresses
+    last: u64,
+}
+
+fn exception_name(ecause: u32, interrupt: bool) -> String {
+    let name = match (interrupt, ecause) {
+        (false, 0) => "instruction address misaligned",
+        (false, 1) => "instruction access fault",
+        (false, 2) => "illegal instruction",
+        (false, 3) => "breakpoint",
+        (false, 4) => "load address misaligned",
+        (false, 5) => "load access fault",
+        (false, 6) => "store/AMO address misaligned",
+        (false, 7) => "store/AMO access fault",
+        (false, 8) => "environment call from U-mode",
+        (false, 9) => "environment call from S-mode",
+        (false, 11) => "environment call from M-mode",
+        (false, 12) => "instruction page fault",
+        (false, 13) => "load page fault",
+        (false, 15) => "store/AMO page fault",
+        (true, 1) => "supervisor software interrupt",
+        (true, 3) => "machine software interrupt",
+        (true, 5) => "supervisor timer interrupt",
+        (true, 7) => "machine timer interrupt",
+        (true, 9) => "supervisor external interrupt",
+        (true, 11) => "machine external interrupt",
+        (true, ecause) => return format!("interrupt {}", ecause),
+        (false, ecause) => return format!("exception {}", ecause),
+    };
+
+    name.to_string()
+}
+
+impl<'a> Decoder<'a> {
+    fn report(&mut self, addr: u32) {
+        let hubris = self.hubris;
+        let target = rv_instr_target(hubris, addr);
+        let module = hubris.instr_mod(addr).unwrap_or("<unknown>");
+        let sym = hubris.instr_sym(addr).unwrap_or(("<unknown>", addr));
+        let sigil = 2;
+
+        self.pc = Some(addr);
+
+        if !self.flowindent {
+            println!(
+                "{:08x} {}:{}+{:x} {:x?}",
+                addr,
+                module,
+                sym.0,
+                addr - sym.1,
+                target
+            );
+            return;
+        }
+
+        let state = &mut self.state;
+        let inlined = hubris.instr_inlined(addr, sym.1);
+
+        if let Some(HubrisTarget::Call(_)) | Some(HubrisTarget::IndirectCall) =
+            state.target
+        {
+            state.indent += 2;
+            println!(
+                "{:width$}-> {}:{}",
+                "",
+                module,
+                sym.0,
+                width = state.indent
+            );
+        }
+
+        for (i, element) in inlined.iter().enumerate() {
+            if i < state.inlined.len() && element.id == state.inlined[i] {
+                continue;
+            }
+
+            println!(
+                "{:width$} | {}:{} {}",
+                "",
+                module,
+                element.name,
+                element.id,
+                width = state.indent + (i * 2) + sigil
+            );
+        }
+
+        state.inlined.clear();
+        state.target = target;
+
+        match target {
+            Some(HubrisTarget::Call(_)) | Some(HubrisTarget::IndirectCall) => {
+                state.stack.push((
+                    state.indent,
+                    inlined.iter().map(|i| i.id).collect(),
+                ));
+
+                if !inlined.is_empty() {
+                    state.indent += (inlined.len() * 2) + 1;
+                }
+            }
+
+            Some(HubrisTarget::Return) => {
+                println!(
+                    "{:width$}<- {}:{}",
+                    "",
+                    module,
+                    sym.0,
+                    width = state.indent
+                );
+
+                match state.stack.pop() {
+                    Some((indent, inlined)) => {
+                        state.indent = indent;
+                        state.inlined = inlined;
+                    }
+                    None => state.indent = 0,
+                }
+            }
+
+            _ => {
+                state.inlined = inlined.iter().map(|i| i.id).collect();
+            }
+        }
+    }
+
+    fn lost(&mut self, why: &str) {
+        warn!("lost trace: {}", why);
+        self.pc = None;
+        self.branches.clear();
+        self.state = TraceState::default();
+    }
+
+    ///
+    /// Follows execution from the last executed instruction until we arrive
+    /// at `target` with no branch outcomes left to consume -- or, if there
+    /// is no target, until we have consumed all of them.  An uninferable
+    /// discontinuity along the way takes us to the target.
+    ///
+    fn follow(&mut self, target: Option<u32>) {
+        let mut pc = match self.pc {
+            Some(pc) => pc,
+            None => {
+                if let Some(target) = target {
+                    self.report(target);
+                }
+
+                return;
+            }
+        };
+
+        for _ in 0..ETRACE_MAX_WALK {
+            if Some(pc) == target && self.branches.is_empty() {
+                return;
+            }
+
+            let len = match self.hubris.instr_len(pc) {
+                Some(len) => len,
+                None => {
+                    self.lost(&format!("unknown instruction at {:x}", pc));
+                    return;
+                }
+            };
+
+            let next = match rv_instr_target(self.hubris, pc) {
+                Some(HubrisTarget::Conditional(dest)) => {
+                    match self.branches.pop_front() {
+                        Some(true) => dest,
+                        Some(false) => pc + len,
+                        None if target.is_none() => return,
+                        None => {
+                            self.lost(&format!(
+                                "no branch outcome for branch at {:x}",
+                                pc
+                            ));
+                            return;
+                        }
+                    }
+                }
+
+                Some(HubrisTarget::Direct(dest))
+                | Some(HubrisTarget::Call(dest)) => dest,
+
+                Some(_) => match target {
+                    Some(target) => {
+                        if !self.branches.is_empty() {
+                            warn!(
+                                "{} branch outcomes unconsumed at {:x}",
+                                self.branches.len(),
+                                pc
+                            );
+                            self.branches.clear();
+                        }
+
+                        self.report(target);
+                        return;
+                    }
+                    None => return,
+                },
+
+                None => pc + len,
+            };
+
+            self.report(next);
+            pc = next;
+        }
+
+        self.lost("walked too far without a reported address");
+    }
+
+    fn process(
+        &mut self,
+        config: &ETraceConfig,
+        packet: &ETracePacket,
+    ) -> Result<()> {
+        let mask = if config.iaddress_width >= 64 {
+            u64::MAX
+        } else {
+            (1u64 << config.iaddress_width) - 1
+        };
+
+        match packet.payload {
+            ETracePayload::Start { branch, address, .. } => {
+                self.last = address;
+                let address = address as u32;
+
+                //
+                // At the start of trace, we begin at the address; otherwise,
+                // this is a periodic resynchronization, and we follow
+                // execution to it.
+                //
+                if self.pc.is_none() {
+                    self.branches.clear();
+                    self.report(address);
+                } else {
+                    self.follow(Some(address));
+                }
+
+                //
+                // If the instruction at the address is a branch, the packet
+                // indicates whether it was taken.
+                //
+                if let Some(HubrisTarget::Conditional(_)) =
+                    rv_instr_target(self.hubris, address)
+                {
+                    self.branches.push_back(!branch);
+                }
+            }
+
+            ETracePayload::Trap {
+                ecause,
+                interrupt,
+                thaddr,
+                address,
+                tval,
+                ..
+            } => {
+                println!(
+                    "{:8} X {} (tval 0x{:x})",
+                    "-",
+                    exception_name(ecause, interrupt),
+                    tval
+                );
+
+                self.last = address;
+                self.branches.clear();
+                self.state.target = None;
+                self.pc = None;
+
+                //
+                // If the address isn't that of the trap handler, it is that
+                // of the instruction that took the exception (which did not
+                // retire); we await the handler.
+                //
+                if thaddr {
+                    self.report(address as u32);
+                }
+            }
+
+            ETracePayload::Branches { .. } | ETracePayload::Address { .. }
+                if self.pc.is_none() =>
+            {
+                //
+                // Until we have synchronized, there is nothing to follow.
+                //
+            }
+
+            ETracePayload::Branches { count, taken, address, .. } => {
+                for i in 0..count {
+                    self.branches.push_back(taken & (1 << i) != 0);
+                }
+
+                match address {
+                    Some(address) => {
+                        let address = if config.full_address {
+                            address
+                        } else {
+                            self.last.wrapping_add(address) & mask
+                        };
+
+                        self.last = address;
+                        self.follow(Some(address as u32));
+                    }
+                    None => self.follow(None),
+                }
+            }
+
+            ETracePayload::Address { address, .. } => {
+                let address = if config.full_address {
+                    address
+                } else {
+                    self.last.wrapping_add(address) & mask
+                };
+
+                self.last = address;
+                self.follow(Some(address as u32));
+            }
+
+            ETracePayload::Support { qual_status, .. } => match qual_status {
+                QualStatus::TraceLost => {
+                    self.lost(&format!("at offset {}", packet.offset))
+                }
+                QualStatus::EndedRep | QualStatus::EndedNtr => {
+                    humility::msg!("trace ended at offset {}", packet.offset);
+                    self.pc = None;
+                    self.branches.clear();
+                }
+                QualStatus::NoChange => {}
+            },
+
+            ETracePayload::Extension => {
+                bail!(
+                    "unsupported format 0 packet at offset {}",
+                    packet.offset
+                );
+            }
+
+            ETracePayload::Context { .. } => {}
+        }
+
+        Ok(())
+    }
+}
+
+fn etrace(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let hubris = context.archive.as_ref().unwrap();
+    let subargs = ETraceArgs::try_parse_from(subargs)?;
+
+    match &hubris.manifest.target {
+        Some(target) if target.starts_with("riscv") => {}
+        _ => bail!("E-Trace requires a RISC-V archive; see humility etm"),
+    }
+
+    let config = ETraceConfig {
+        iaddress_width: subargs.iaddress_width.unwrap_or_else(|| {
+            hubris.arch.as_ref().unwrap().get_abi_size() as u32
+        }),
+        iaddress_lsb: subargs.iaddress_lsb,
+        privilege_width: subargs.privilege_width,
+        ecause_width: subargs.ecause_width,
+        context_width: subargs.context_width,
+        time_width: subargs.time_width,
+        full_address: subargs.full_address,
+        srcid_bytes: subargs.srcid_bytes,
+        timestamp_bytes: subargs.timestamp_bytes,
+    };
+
+    if config.iaddress_lsb >= config.iaddress_width
+        || config.iaddress_width > 64
+    {
+        bail!("invalid instruction address width");
+    }
+
+    let data = fs::read(&subargs.ingest)
+        .with_context(|| format!("failed to read {}", subargs.ingest))?;
+
+    let mut decoder = Decoder {
+        hubris,
+        flowindent: subargs.flowindent,
+        state: TraceState::default(),
+        pc: None,
+        branches: VecDeque::new(),
+        last: 0,
+    };
+
+    ingest(&config, &data, |packet| {
+        if subargs.packets {
+            println!("{:x?}", packet);
+            return Ok(());
+        }
+
+        log::trace!("{:x?}", packet);
+        decoder.process(&config, packet)
+    })
+    .with_context(|| format!("failed to ingest {}", subargs.ingest))
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "etrace",
+            archive: Archive::Required,
+            run: etrace,
+        },
+        ETraceArgs::command(),
+    )
+}
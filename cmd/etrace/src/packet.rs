@@ -0,0 +1,578 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// E-Trace instruction trace (te_inst) packets, as delivered in the RISC-V
+// unformatted trace encapsulation.  Each encapsulated packet consists of a
+// header byte (giving the length of the payload in bytes, a flow indicator
+// and an extend bit), an optional source ID and an optional timestamp,
+// followed by the payload.  The payload itself is a bit stream, least
+// significant bit first, whose fields have widths determined by the
+// encoder's parameters.  Payloads are compressed by dropping any most
+// significant bits that are identical to the last bit sent; when reading
+// past the end of a payload, we therefore replicate its last bit.
+//
+
+use anyhow::{bail, Result};
+
+#[derive(Clone, Debug)]
+pub struct ETraceConfig {
+    /// width of instruction addresses, in bits
+    pub iaddress_width: u32,
+    /// number of low-order address bits that are never sent
+    pub iaddress_lsb: u32,
+    pub privilege_width: u32,
+    pub ecause_width: u32,
+    pub context_width: u32,
+    pub time_width: u32,
+    /// addresses in format 1 and 2 packets are full rather than differential
+    pub full_address: bool,
+    /// size of the source ID in the encapsulation, in bytes
+    pub srcid_bytes: usize,
+    /// size of the timestamp in the encapsulation, in bytes
+    pub timestamp_bytes: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QualStatus {
+    NoChange,
+    EndedRep,
+    TraceLost,
+    EndedNtr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ETracePayload {
+    /// Format 0:  optional extensions, which we do not decode
+    Extension,
+
+    /// Format 1:  a branch map (bit i set if branch i was taken) and an
+    /// optional address
+    Branches {
+        count: u32,
+        taken: u32,
+        address: Option<u64>,
+        notify: bool,
+        updiscon: bool,
+    },
+
+    /// Format 2:  an address
+    Address { address: u64, notify: bool, updiscon: bool },
+
+    /// Format 3, subformat 0:  start of trace (or resynchronization)
+    Start { branch: bool, privilege: u8, address: u64 },
+
+    /// Format 3, subformat 1:  exception or interrupt
+    Trap {
+        branch: bool,
+        privilege: u8,
+        ecause: u32,
+        interrupt: bool,
+        thaddr: bool,
+        address: u64,
+        tval: u64,
+    },
+
+    /// Format 3, subformat 2:  context change
+    Context { privilege: u8 },
+
+    /// Format 3, subformat 3:  encoder status
+    Support { ienable: bool, qual_status: QualStatus },
+}
+
+#[derive(Clone, Debug)]
+pub struct ETracePacket {
+    pub offset: usize,
+    pub srcid: Option<u32>,
+    pub timestamp: Option<u64>,
+    pub payload: ETracePayload,
+}
+
+struct BitReader<'a> {
+    payload: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(payload: &'a [u8]) -> Self {
+        Self { payload, pos: 0 }
+    }
+
+    fn field(&mut self, width: u32) -> u64 {
+        let nbits = self.payload.len() * 8;
+        let last = self.payload.last().map_or(0, |b| (b >> 7) as u64);
+        let mut val = 0;
+
+        for i in 0..width {
+            let bit = if self.pos < nbits {
+                ((self.payload[self.pos / 8] >> (self.pos % 8)) & 1) as u64
+            } else {
+                last
+            };
+
+            val |= bit << i;
+            self.pos += 1;
+        }
+
+        val
+    }
+
+    fn bit(&mut self) -> bool {
+        self.field(1) != 0
+    }
+}
+
+fn sext(val: u64, width: u32) -> u64 {
+    if width == 0 || width >= 64 {
+        val
+    } else {
+        (((val << (64 - width)) as i64) >> (64 - width)) as u64
+    }
+}
+
+impl ETraceConfig {
+    fn address_width(&self) -> u32 {
+        self.iaddress_width - self.iaddress_lsb
+    }
+
+    fn address(&self, r: &mut BitReader) -> u64 {
+        r.field(self.address_width()) << self.iaddress_lsb
+    }
+
+    //
+    // Differential addresses are sign extended, and relative to the last
+    // address sent.
+    //
+    fn relative_address(&self, r: &mut BitReader) -> u64 {
+        let width = self.address_width();
+
+        if self.full_address {
+            self.address(r)
+        } else {
+            sext(r.field(width), width) << self.iaddress_lsb
+        }
+    }
+
+    fn skip_optional(&self, r: &mut BitReader) {
+        r.field(self.time_width);
+        r.field(self.context_width);
+    }
+}
+
+//
+// The width of the branch map in a format 1 packet, given the branch count.
+// A count of 0 denotes a full branch map (of 31 branches) without an
+// address.
+//
+fn branch_map_width(count: u32) -> u32 {
+    match count {
+        0 => 31,
+        1 => 1,
+        2..=9 => 9,
+        10..=17 => 17,
+        18..=25 => 25,
+        _ => 31,
+    }
+}
+
+pub fn decode_payload(
+    config: &ETraceConfig,
+    payload: &[u8],
+) -> Result<ETracePayload> {
+    let mut r = BitReader::new(payload);
+
+    Ok(match r.field(2) {
+        0 => ETracePayload::Extension,
+
+        1 => {
+            let count = r.field(5) as u32;
+            let map = r.field(branch_map_width(count)) as u32;
+
+            //
+            // In the branch map, a set bit denotes a branch *not* taken;
+            // we invert it to have bits denote taken branches.  A count of
+            // 0 denotes a full map without an address.
+            //
+            if count != 0 {
+                let taken = !map & ((1 << count) - 1);
+                let address = config.relative_address(&mut r);
+
+                ETracePayload::Branches {
+                    count,
+                    taken,
+                    address: Some(address),
+                    notify: r.bit(),
+                    updiscon: r.bit(),
+                }
+            } else {
+                ETracePayload::Branches {
+                    count: 31,
+                    taken: !map & 0x7fff_ffff,
+                    address: None,
+                    notify: false,
+                    updiscon: false,
+                }
+            }
+        }
+
+        2 => {
+            let address = config.relative_address(&mut r);
+
+            ETracePayload::Address {
+                address,
+                notify: r.bit(),
+                updiscon: r.bit(),
+            }
+        }
+
+        _ => match r.field(2) {
+            0 => {
+                let branch = r.bit();
+                let privilege = r.field(config.privilege_width) as u8;
+                config.skip_optional(&mut r);
+
+                ETracePayload::Start {
+                    branch,
+                    privilege,
+                    address: config.address(&mut r),
+                }
+            }
+
+            1 => {
+                let branch = r.bit();
+                let privilege = r.field(config.privilege_width) as u8;
+                config.skip_optional(&mut r);
+                let ecause = r.field(config.ecause_width) as u32;
+                let interrupt = r.bit();
+                let thaddr = r.bit();
+                let address = config.address(&mut r);
+                let tval = r.field(config.iaddress_width);
+
+                ETracePayload::Trap {
+                    branch,
+                    privilege,
+                    ecause,
+                    interrupt,
+                    thaddr,
+                    address,
+                    tval,
+                }
+            }
+
+            2 => {
+                let privilege = r.field(config.privilege_width) as u8;
+                ETracePayload::Context { privilege }
+            }
+
+            _ => {
+                let ienable = r.bit();
+                let _encoder_mode = r.bit();
+
+                let qual_status = match r.field(2) {
+                    0 => QualStatus::NoChange,
+                    1 => QualStatus::EndedRep,
+                    2 => QualStatus::TraceLost,
+                    _ => QualStatus::EndedNtr,
+                };
+
+                ETracePayload::Support { ienable, qual_status }
+            }
+        },
+    })
+}
+
+///
+/// Splits the specified capture into encapsulated packets, decoding each
+/// payload.
+///
+pub fn ingest(
+    config: &ETraceConfig,
+    data: &[u8],
+    mut callback: impl FnMut(&ETracePacket) -> Result<()>,
+) -> Result<()> {
+    let mut offset = 0;
+
+    let le = |bytes: &[u8]| {
+        bytes.iter().rev().fold(0u64, |v, b| (v << 8) | *b as u64)
+    };
+
+    while offset < data.len() {
+        let hdr = data[offset];
+        let len = (hdr & 0x1f) as usize;
+        let extend = hdr & 0x80 != 0;
+
+        //
+        // A zero-length packet is a null (idle) packet.
+        //
+        if len == 0 {
+            offset += 1;
+            continue;
+        }
+
+        let tsbytes = if extend { config.timestamp_bytes } else { 0 };
+        let total = 1 + config.srcid_bytes + tsbytes + len;
+
+        if offset + total > data.len() {
+            bail!(
+                "truncated packet at offset {}: expected {} bytes, found {}",
+                offset,
+                total,
+                data.len() - offset
+            );
+        }
+
+        let mut o = offset + 1;
+
+        let srcid = if config.srcid_bytes != 0 {
+            let srcid = le(&data[o..o + config.srcid_bytes]) as u32;
+            o += config.srcid_bytes;
+            Some(srcid)
+        } else {
+            None
+        };
+
+        let timestamp = if tsbytes != 0 {
+            let ts = le(&data[o..o + tsbytes]);
+            o += tsbytes;
+            Some(ts)
+        } else {
+            None
+        };
+
+        let payload = decode_payload(config, &data[o..o + len])?;
+
+        callback(&ETracePacket { offset, srcid, timestamp, payload })?;
+
+        offset += total;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ETraceConfig {
+        ETraceConfig {
+            iaddress_width: 32,
+            iaddress_lsb: 1,
+            privilege_width: 2,
+            ecause_width: 5,
+            context_width: 0,
+            time_width: 0,
+            full_address: false,
+            srcid_bytes: 1,
+            timestamp_bytes: 2,
+        }
+    }
+
+    //
+    // Packs the specified fields (as value and width) into a payload, least
+    // significant bit first.
+    //
+    fn pack(fields: &[(u64, u32)]) -> Vec<u8> {
+        let mut rval = vec![];
+        let mut pos = 0;
+
+        for &(val, width) in fields {
+            for i in 0..width {
+                if pos % 8 == 0 {
+                    rval.push(0);
+                }
+
+                if (val >> i) & 1 != 0 {
+                    *rval.last_mut().unwrap() |= 1 << (pos % 8);
+                }
+
+                pos += 1;
+            }
+        }
+
+        rval
+    }
+
+    fn start() -> Vec<u8> {
+        pack(&[(3, 2), (0, 2), (1, 1), (3, 2), (0x4000_0080, 31)])
+    }
+
+    fn support() -> Vec<u8> {
+        pack(&[(3, 2), (3, 2), (1, 1), (0, 1), (2, 2)])
+    }
+
+    #[test]
+    fn payloads() -> Result<()> {
+        let config = config();
+
+        assert_eq!(
+            decode_payload(&config, &start())?,
+            ETracePayload::Start {
+                branch: true,
+                privilege: 3,
+                address: 0x8000_0100
+            }
+        );
+
+        let trap = pack(&[
+            (3, 2),
+            (1, 2),
+            (0, 1),
+            (3, 2),
+            (7, 5),
+            (1, 1),
+            (0, 1),
+            (0x4000_0100, 31),
+            (0, 32),
+        ]);
+
+        assert_eq!(
+            decode_payload(&config, &trap)?,
+            ETracePayload::Trap {
+                branch: false,
+                privilege: 3,
+                ecause: 7,
+                interrupt: true,
+                thaddr: false,
+                address: 0x8000_0200,
+                tval: 0,
+            }
+        );
+
+        assert_eq!(
+            decode_payload(&config, &pack(&[(3, 2), (2, 2), (1, 2)]))?,
+            ETracePayload::Context { privilege: 1 }
+        );
+
+        assert_eq!(
+            decode_payload(&config, &support())?,
+            ETracePayload::Support {
+                ienable: true,
+                qual_status: QualStatus::TraceLost
+            }
+        );
+
+        assert_eq!(decode_payload(&config, &[0b00])?, ETracePayload::Extension);
+
+        Ok(())
+    }
+
+    #[test]
+    fn branches() -> Result<()> {
+        let config = config();
+
+        //
+        // In the branch map, a set bit denotes a branch not taken.
+        //
+        let branches =
+            pack(&[(1, 2), (3, 5), (0b010, 9), (8, 31), (0, 1), (0, 1)]);
+
+        assert_eq!(
+            decode_payload(&config, &branches)?,
+            ETracePayload::Branches {
+                count: 3,
+                taken: 0b101,
+                address: Some(16),
+                notify: false,
+                updiscon: false,
+            }
+        );
+
+        //
+        // A count of 0 is a full branch map, without an address.
+        //
+        let full = pack(&[(1, 2), (0, 5), (0b100, 31)]);
+
+        assert_eq!(
+            decode_payload(&config, &full)?,
+            ETracePayload::Branches {
+                count: 31,
+                taken: 0x7fff_fffb,
+                address: None,
+                notify: false,
+                updiscon: false,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed() -> Result<()> {
+        //
+        // A differential address of -8, compressed to its low-order bits:
+        // the bits beyond the end of the payload replicate its last bit.
+        //
+        assert_eq!(
+            decode_payload(&config(), &[0b1110_0010])?,
+            ETracePayload::Address {
+                address: (-8i64) as u64,
+                notify: true,
+                updiscon: true,
+            }
+        );
+
+        //
+        // With full addresses, the same payload is an absolute address.
+        //
+        let config = ETraceConfig { full_address: true, ..config() };
+
+        assert_eq!(
+            decode_payload(&config, &[0b1110_0010])?,
+            ETracePayload::Address {
+                address: 0xffff_fff8,
+                notify: true,
+                updiscon: true,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn encapsulation() -> Result<()> {
+        let start = start();
+        let support = support();
+
+        //
+        // A null packet, a packet with an extended header (and therefore a
+        // timestamp), and a packet without.
+        //
+        let mut data = vec![0x00, 0x80 | start.len() as u8, 0x05, 0x34, 0x12];
+        data.extend(&start);
+        data.extend([support.len() as u8, 0x06]);
+        data.extend(&support);
+
+        let mut packets = vec![];
+
+        ingest(&config(), &data, |packet| {
+            packets.push(packet.clone());
+            Ok(())
+        })?;
+
+        assert_eq!(packets.len(), 2);
+
+        assert_eq!(packets[0].offset, 1);
+        assert_eq!(packets[0].srcid, Some(5));
+        assert_eq!(packets[0].timestamp, Some(0x1234));
+        assert_eq!(packets[0].payload, decode_payload(&config(), &start)?);
+
+        assert_eq!(packets[1].offset, 10);
+        assert_eq!(packets[1].srcid, Some(6));
+        assert_eq!(packets[1].timestamp, None);
+        assert_eq!(packets[1].payload, decode_payload(&config(), &support)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated() {
+        let data = [0x04, 0x05, 0x00, 0x00];
+        let err = ingest(&config(), &data, |_| Ok(())).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "truncated packet at offset 0: expected 6 bytes, found 4"
+        );
+    }
+}
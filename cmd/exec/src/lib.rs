@@ -27,8 +27,6 @@ use clap::{CommandFactory, Parser};
 use humility::cli::Subcommand;
 use humility::ExecutionContext;
 use humility_cmd::{Archive, Command};
-use serde_json::Value;
-use std::collections::BTreeMap;
 
 #[derive(Parser, Debug)]
 #[clap(name = "exec", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -42,36 +40,6 @@ struct ExecArgs {
     cmd: Option<String>,
 }
 
-fn load_cmds<'a>(
-    target: &str,
-    cmds: &'a Value,
-    stack: &mut Vec<&'a str>,
-    rval: &mut BTreeMap<String, String>,
-) -> Result<()> {
-    match cmds {
-        Value::Object(obj) => {
-            for (key, value) in obj.iter() {
-                stack.push(key);
-                load_cmds(target, value, stack, rval)?;
-                stack.pop();
-            }
-        }
-        Value::String(cmd) => {
-            rval.insert(stack.join("."), cmd.to_string());
-        }
-        _ => {
-            bail!(
-                "illegal command for target {} at {}: {:?}",
-                target,
-                stack.join("."),
-                cmds
-            );
-        }
-    }
-
-    Ok(())
-}
-
 fn exec(context: &mut ExecutionContext) -> Result<()> {
     let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
     let subargs = ExecArgs::try_parse_from(subargs)?;
@@ -89,16 +57,7 @@ fn exec(context: &mut ExecutionContext) -> Result<()> {
     //
     let target = context.cli.target.as_ref().unwrap();
 
-    let cmds = match env.cmds {
-        Some(ref cmds) => cmds,
-        None => {
-            bail!("target {} has no defined commands", target);
-        }
-    };
-
-    let mut avail = BTreeMap::new();
-    let mut stack = Vec::new();
-    load_cmds(target, cmds, &mut stack, &mut avail)?;
+    let avail = env.commands(target)?;
 
     if subargs.list {
         let printcmd = |target, cmd| {
@@ -118,29 +77,7 @@ fn exec(context: &mut ExecutionContext) -> Result<()> {
             }
         };
 
-        if let Some(cmdline) = avail.get(&cmd) {
-            let args = splitty::split_unquoted_char(cmdline, ' ')
-                .unwrap_quotes(true)
-                .collect::<Vec<_>>();
-
-            humility::msg!("{} {}: executing: '{}' ...", target, cmd, cmdline);
-
-            let status = std::process::Command::new(args[0])
-                .args(&args[1..])
-                .status()?;
-
-            humility::msg!(
-                "{} {}: done ({})",
-                target,
-                cmd,
-                match status.code() {
-                    Some(code) => format!("status code {code}"),
-                    None => "terminated by signal".to_string(),
-                }
-            );
-        } else {
-            bail!("unknown command \"{}\"; --list for commands", cmd);
-        }
+        env.exec(target, &cmd)?;
     }
 
     Ok(())
@@ -0,0 +1,583 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility exporter`
+//!
+//! `humility exporter` is a long-running command that periodically polls a
+//! live system and serves the results as
+//! [Prometheus](https://prometheus.io) metrics from an HTTP `/metrics`
+//! endpoint (by default, on `127.0.0.1:9364`; use `--listen` to specify a
+//! different address).  The following metrics are exported:
+//!
+//! - `humility_sensor_value`: the most recent reading of each sensor, as
+//!   read from the `sensor` task (see `humility sensors`), labeled by kind,
+//!   device and name
+//!
+//! - `humility_power_rail_volts`, `humility_power_rail_amperes` and
+//!   `humility_power_rail_celsius`:  the voltage, current and temperature of
+//!   each power rail (see `humility power`)
+//!
+//! - `humility_task_generation`, `humility_task_faulted`,
+//!   `humility_task_faults_total` and `humility_task_restarts_total`:  per
+//!   task generation and whether the task is currently faulted (as read from
+//!   the task table), along with counts of faults and restarts observed
+//!   while exporting
+//!
+//! - `humility_exporter_up`, `humility_exporter_polls_total` and
+//!   `humility_exporter_poll_errors_total`: the health of the exporter itself
+//!
+//! The system is polled every five seconds by default; use `--interval` to
+//! specify a different interval (in milliseconds).  If a poll fails (e.g.,
+//! because the HIF execution facility is busy), the interval is doubled on
+//! each subsequent failure up to a maximum (specified via `--max-backoff`),
+//! and restored upon success.  While polls are failing, sensor and power
+//! metrics are not exported and `humility_exporter_up` is 0.
+//!
+//! ```console
+//! % humility exporter --interval 1000 &
+//! humility: attached via ST-Link V3
+//! humility: serving metrics on http://127.0.0.1:9364/metrics
+//! % curl -s http://127.0.0.1:9364/metrics | grep temp
+//! humility_sensor_value{id="0",kind="temp",device="tmp117",name="Southwest"} 24.1875
+//! humility_sensor_value{id="1",kind="temp",device="tmp117",name="South"} 24.390625
+//! ...
+//! ```
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use hif::*;
+use humility::cli::Subcommand;
+use humility::core::Core;
+use humility::hubris::*;
+use humility::reflect::{self, Load};
+use humility_cmd::doppel::{GenOrRestartCount, Task, TaskState};
+use humility_cmd::hiffy::*;
+use humility_cmd::idol;
+use humility_cmd::{Archive, Attach, Command, Validate};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[clap(name = "exporter", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ExporterArgs {
+    /// sets timeout
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// address on which to serve metrics
+    #[clap(long, short, default_value = "127.0.0.1:9364", value_name = "addr")]
+    listen: String,
+
+    /// interval between polls
+    #[clap(
+        long, short, default_value = "5000", value_name = "interval_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// maximum interval between polls when backing off after a failure
+    #[clap(
+        long, default_value = "60000", value_name = "interval_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    max_backoff: u64,
+}
+
+#[derive(Default)]
+struct TaskCounters {
+    generation: Option<u32>,
+    faulted: bool,
+    faults: u64,
+    restarts: u64,
+}
+
+struct Exporter<'a> {
+    hubris: &'a HubrisArchive,
+    sensors: Vec<(usize, &'a HubrisSensor)>,
+    ops: Vec<Vec<Op>>,
+    readings: Vec<Option<f32>>,
+    tasks: Vec<TaskCounters>,
+    up: bool,
+    polls: u64,
+    errors: u64,
+}
+
+//
+// Escapes a label value as required by the Prometheus exposition format.
+//
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs = pairs
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(String, f64)],
+) {
+    if samples.is_empty() {
+        return;
+    }
+
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+
+    for (labels, value) in samples {
+        writeln!(out, "{}{} {}", name, labels, value).unwrap();
+    }
+}
+
+impl<'a> Exporter<'a> {
+    fn new(
+        hubris: &'a HubrisArchive,
+        context: Option<&mut HiffyContext>,
+    ) -> Self {
+        let mut exporter = Self {
+            hubris,
+            sensors: vec![],
+            ops: vec![],
+            readings: vec![],
+            tasks: vec![],
+            up: false,
+            polls: 0,
+            errors: 0,
+        };
+
+        if let Some(context) = context {
+            if let Err(e) = exporter.sensor_ops(context) {
+                humility::msg!("not exporting sensors: {:?}", e);
+                exporter.ops.clear();
+            }
+        }
+
+        exporter
+    }
+
+    //
+    // Builds the HIF programs to read all sensors.
+    //
+    fn sensor_ops(&mut self, context: &mut HiffyContext) -> Result<()> {
+        let hubris = self.hubris;
+        let funcs = context.functions()?;
+        let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)
+            .context("is the 'sensor' task present?")?;
+
+        let ok = hubris.lookup_basetype(op.ok)?;
+
+        if ok.encoding != HubrisEncoding::Float || ok.size != 4 {
+            bail!("expected return value of read_sensors() to be an f32");
+        }
+
+        let sensors =
+            hubris.manifest.sensors.iter().enumerate().collect::<Vec<_>>();
+
+        for s in sensors.chunks(100) {
+            let mut ops = vec![];
+
+            for (i, _) in s {
+                let payload = op.payload(&[(
+                    "id",
+                    idol::IdolArgument::Scalar(*i as u64),
+                )])?;
+                context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+            }
+
+            ops.push(Op::Done);
+            self.ops.push(ops);
+        }
+
+        self.sensors = sensors;
+
+        Ok(())
+    }
+
+    fn poll_sensors(
+        &mut self,
+        core: &mut dyn Core,
+        context: &mut HiffyContext,
+    ) -> Result<()> {
+        let mut rval = vec![];
+
+        for ops in &self.ops {
+            for r in context.run(core, ops.as_slice(), None)? {
+                if let Ok(val) = r {
+                    rval.push(Some(f32::from_le_bytes(val[0..4].try_into()?)));
+                } else {
+                    rval.push(None);
+                }
+            }
+        }
+
+        self.readings = rval;
+
+        Ok(())
+    }
+
+    fn poll_tasks(&mut self, core: &mut dyn Core) -> Result<()> {
+        let hubris = self.hubris;
+        let (base, count) = hubris.task_table(core)?;
+        let task_t = hubris.lookup_struct_byname("Task")?;
+
+        //
+        // We read the entire task table at a go to get as consistent a
+        // snapshot as possible.
+        //
+        let mut taskblock = vec![0; task_t.size * count as usize];
+
+        core.op_start()?;
+        let rval = core.read_8(base, &mut taskblock);
+        core.op_done()?;
+        rval?;
+
+        self.tasks.resize_with(count as usize, Default::default);
+
+        for (i, counters) in self.tasks.iter_mut().enumerate() {
+            let value =
+                reflect::load(hubris, &taskblock, task_t, i * task_t.size)
+                    .with_context(|| {
+                        format!("loading task control block for task {}", i)
+                    })?;
+            let task = Task::from_value(&value)?;
+            let generation = u32::from(task.generation);
+
+            //
+            // Older kernels have an 8-bit generation that wraps; newer ones
+            // have a restart count.
+            //
+            if let Some(last) = counters.generation {
+                counters.restarts += match task.generation {
+                    GenOrRestartCount::Gen(_) => {
+                        generation.wrapping_sub(last) & 0xff
+                    }
+                    GenOrRestartCount::RestartCount(_) => {
+                        generation.wrapping_sub(last)
+                    }
+                } as u64;
+            }
+
+            let faulted = matches!(task.state, TaskState::Faulted { .. });
+
+            if faulted && !counters.faulted {
+                counters.faults += 1;
+            }
+
+            counters.generation = Some(generation);
+            counters.faulted = faulted;
+        }
+
+        Ok(())
+    }
+
+    fn render(&self) -> String {
+        let hubris = self.hubris;
+        let mut out = String::new();
+
+        let mut values = vec![];
+        let mut rails: HashMap<&str, Vec<(String, f64)>> = HashMap::new();
+
+        //
+        // Power rails are the devices that can measure voltage; any current
+        // and temperature sensors with the same name on the same device are
+        // measuring the same rail.
+        //
+        let voltage = self
+            .sensors
+            .iter()
+            .filter(|(_, s)| s.kind == HubrisSensorKind::Voltage)
+            .map(|(_, s)| (&s.name, s.device))
+            .collect::<Vec<_>>();
+
+        for ((i, s), val) in self.sensors.iter().zip(self.readings.iter()) {
+            let val = match val {
+                Some(val) => *val as f64,
+                None => continue,
+            };
+
+            let device = hubris.manifest.i2c_devices[s.device].device.as_str();
+
+            values.push((
+                labels(&[
+                    ("id", i.to_string().as_str()),
+                    ("kind", s.kind.to_string()),
+                    ("device", device),
+                    ("name", s.name.as_str()),
+                ]),
+                val,
+            ));
+
+            if !voltage.contains(&(&s.name, s.device)) {
+                continue;
+            }
+
+            let unit = match s.kind {
+                HubrisSensorKind::Voltage => "volts",
+                HubrisSensorKind::Current => "amperes",
+                HubrisSensorKind::Temperature => "celsius",
+                _ => continue,
+            };
+
+            rails.entry(unit).or_default().push((
+                labels(&[("rail", s.name.as_str()), ("device", device)]),
+                val,
+            ));
+        }
+
+        metric(
+            &mut out,
+            "humility_sensor_value",
+            "gauge",
+            "Most recent sensor reading",
+            &values,
+        );
+
+        for unit in ["volts", "amperes", "celsius"] {
+            if let Some(samples) = rails.get(unit) {
+                metric(
+                    &mut out,
+                    &format!("humility_power_rail_{}", unit),
+                    "gauge",
+                    &format!("Most recent power rail reading, in {}", unit),
+                    samples,
+                );
+            }
+        }
+
+        let task = |i: usize| {
+            labels(&[("task", hubris.task_name(i).unwrap_or("<unknown>"))])
+        };
+
+        let tasks = |f: &dyn Fn(&TaskCounters) -> Option<f64>| {
+            self.tasks
+                .iter()
+                .enumerate()
+                .filter_map(|(i, t)| Some((task(i), f(t)?)))
+                .collect::<Vec<_>>()
+        };
+
+        metric(
+            &mut out,
+            "humility_task_generation",
+            "gauge",
+            "Task generation (or restart count)",
+            &tasks(&|t| t.generation.map(|g| g as f64)),
+        );
+
+        metric(
+            &mut out,
+            "humility_task_faulted",
+            "gauge",
+            "Whether the task is currently faulted",
+            &tasks(&|t| Some(if t.faulted { 1.0 } else { 0.0 })),
+        );
+
+        metric(
+            &mut out,
+            "humility_task_faults_total",
+            "counter",
+            "Faults observed while exporting",
+            &tasks(&|t| Some(t.faults as f64)),
+        );
+
+        metric(
+            &mut out,
+            "humility_task_restarts_total",
+            "counter",
+            "Restarts observed while exporting",
+            &tasks(&|t| Some(t.restarts as f64)),
+        );
+
+        let none = String::new();
+
+        metric(
+            &mut out,
+            "humility_exporter_up",
+            "gauge",
+            "Whether the most recent poll succeeded",
+            &[(none.clone(), if self.up { 1.0 } else { 0.0 })],
+        );
+
+        metric(
+            &mut out,
+            "humility_exporter_polls_total",
+            "counter",
+            "Polls of the target",
+            &[(none.clone(), self.polls as f64)],
+        );
+
+        metric(
+            &mut out,
+            "humility_exporter_poll_errors_total",
+            "counter",
+            "Polls of the target that failed",
+            &[(none, self.errors as f64)],
+        );
+
+        out
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<String>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    //
+    // We don't care about any of the headers, but we consume them before
+    // responding.
+    //
+    loop {
+        let mut header = String::new();
+
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut fields = request.split_whitespace();
+
+    let (status, body) = match (fields.next(), fields.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", metrics.lock().unwrap().clone())
+        }
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+fn serve(listener: TcpListener, metrics: Arc<Mutex<String>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = respond(stream, &metrics) {
+                    log::debug!("failed to respond: {:?}", e);
+                }
+            }
+            Err(e) => {
+                log::debug!("failed to accept: {:?}", e);
+            }
+        }
+    }
+}
+
+fn exporter(context: &mut humility::ExecutionContext) -> Result<()> {
+    let core = &mut **context.core.as_mut().unwrap();
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let hubris = context.archive.as_ref().unwrap();
+
+    let subargs = ExporterArgs::try_parse_from(subargs)?;
+
+    if subargs.interval == 0 {
+        bail!("interval must be non-zero");
+    }
+
+    let listener = TcpListener::bind(&subargs.listen)
+        .with_context(|| format!("failed to listen on {}", subargs.listen))?;
+
+    let metrics = Arc::new(Mutex::new(String::new()));
+    let shared = metrics.clone();
+
+    humility::msg!("serving metrics on http://{}/metrics", subargs.listen);
+    thread::spawn(move || serve(listener, shared));
+
+    let mut hiffy = match HiffyContext::new(hubris, core, subargs.timeout) {
+        Ok(hiffy) => Some(hiffy),
+        Err(e) => {
+            humility::msg!("not exporting sensors: {:?}", e);
+            None
+        }
+    };
+
+    let mut exporter = Exporter::new(hubris, hiffy.as_mut());
+    let mut delay = subargs.interval;
+
+    loop {
+        let mut poll = || -> Result<()> {
+            exporter.poll_tasks(core)?;
+
+            if exporter.ops.is_empty() {
+                return Ok(());
+            }
+
+            //
+            // If a previous poll failed, our HIF context may be in an
+            // indeterminate state; we create a new one.
+            //
+            if hiffy.is_none() {
+                hiffy = Some(HiffyContext::new(hubris, core, subargs.timeout)?);
+            }
+
+            exporter.poll_sensors(core, hiffy.as_mut().unwrap())
+        };
+
+        let rval = poll();
+
+        exporter.polls += 1;
+
+        match rval {
+            Ok(()) => {
+                exporter.up = true;
+                delay = subargs.interval;
+            }
+            Err(e) => {
+                exporter.up = false;
+                exporter.errors += 1;
+                exporter.readings.clear();
+                hiffy = None;
+                delay = std::cmp::min(delay * 2, subargs.max_backoff);
+                humility::msg!("poll failed: {:?}; retrying in {}ms", e, delay);
+            }
+        }
+
+        *metrics.lock().unwrap() = exporter.render();
+
+        thread::sleep(Duration::from_millis(delay));
+    }
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "exporter",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: exporter,
+        },
+        ExporterArgs::command(),
+    )
+}
@@ -18,9 +18,82 @@
 //! executed without actually executing any commands, use the `-n`
 //! (`--dry-run`) flag.  Should use of OpenOCD need to be forced (that is,
 //! should probe-rs flashing fail), the `-O` (`--force-openocd`) flag can be
-//! used.  That said, OpenOCD should generally be discouraged; the disposition
-//! is to extend probe-rs to support any parts that must be flashed via
-//! OpenOCD.
+//! used; any `openocd` overrides of the target in the environment file (see
+//! `humility openocd`) apply.  That said, OpenOCD should generally be
+//! discouraged; the disposition is to extend probe-rs to support any parts
+//! that must be flashed via OpenOCD.
+//!
+//! RISC-V parts are flashed natively via probe-rs as well.  Because probe-rs
+//! generally lacks descriptions of these parts, the chip (which can't always
+//! be inferred from the archive's flash configuration) may need to be
+//! specified with `-c` (`--chip`), and a probe-rs target description (that
+//! is, a YAML file describing the part's memory map and flash algorithm)
+//! provided with `--chip-description` (or the `HUMILITY_CHIP_DESCRIPTION`
+//! environment variable):
+//!
+//! ```console
+//! $ humility -c my-rv-part flash --chip-description my-rv-part.yaml
+//! humility: attaching with chip set to "my-rv-part"
+//! humility: flashing done
+//! ```
+//!
+//! Once a RISC-V part has been natively flashed, the image is read back and
+//! verified against the archive; this can be skipped with `--skip-verify`.
+//! For other parts, the chip is always that of the archive (that is, `-c` is
+//! ignored), and the image is verified after flashing only if `--verify` is
+//! specified.
+//!
+//! For iterative development on large images, it can be much faster to erase
+//! and program only the flash sectors that have changed.  To perform such a
+//! differential flash, use the `--delta` (`-D`) option:  the image on the
+//! target is read back and compared against the archive, and only those
+//! sectors that differ are rewritten:
+//!
+//! ```console
+//! $ humility flash -F -D
+//! humility: attaching with chip set to "STM32H753ZITx"
+//! humility: attached via ST-Link V3
+//! humility: archive appears to be already flashed; forcing re-flash
+//! humility: 212 bytes differ, in 2 of 8 sectors
+//! humility: flashing done
+//! ```
+//!
+//! To verify the image on a target without flashing it, use `--verify-only`.
+//! This reads back every loadable segment of the archive's image, reporting
+//! the SHA-256 (abbreviated) of each as found in the archive and on the
+//! target -- and the exact address ranges that differ, if any:
+//!
+//! ```console
+//! $ humility flash --verify-only
+//! humility: attached via ST-Link V3
+//! ADDR             SIZE ARCHIVE          TARGET           STATUS
+//! 0x08000000        400 b2a1f0553d7ee0a1 b2a1f0553d7ee0a1 ok
+//! 0x08000190      94768 5e20d3f7c6c6cf5c 0c0d1d4a11b8e8a3 4 bytes differ
+//!
+//! START      END            NBYTES
+//! 0x0800f6c4 0x0800f6c8          4
+//! humility flash failed: 1 range differs from the archive
+//! ```
+//!
+//! This can be useful to diagnose partial flashes and bit rot; it will exit
+//! with a non-zero status if the image on the target does not match.
+//!
+//! If the target can't be attached to (e.g., because it is wedged or has
+//! disabled its debug port) and it is specified via an environment, `--recover`
+//! will take whichever of the target's `power-cycle` and
+//! `reset-via-debugmailbox` actions it defines (in that order), retrying the
+//! attach after each:
+//!
+//! ```console
+//! $ humility -t gimletlet flash --recover
+//! humility: attaching with chip set to "STM32H753ZITx"
+//! humility: failed to attach: ...; attempting recovery
+//! humility: gimletlet power-cycle: executing: 'power.sh --cycle gimletlet' ...
+//! humility: gimletlet power-cycle: done (status code 0)
+//! humility: attached via ST-Link V3
+//! humility: recovered target via power-cycle
+//! humility: flashing done
+//! ```
 //!
 //! If the specified archive includes auxiliary flash data and the new image
 //! includes a task with the `AuxFlash` API, two slots of auxiliary flash
@@ -31,10 +104,13 @@ use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::cli::{Cli, Subcommand};
-use humility::{core::Core, hubris::*};
+use humility::core::{Core, CORE_MAX_READSIZE};
+use humility::env::{Action, Environment};
+use humility::hubris::*;
 use humility_cmd::{Archive, Command};
-use path_slash::PathExt;
-use std::io::Write;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
 use std::process::ExitStatus;
 
 use serde::Deserialize;
@@ -66,6 +142,130 @@ struct FlashArgs {
         parse(try_from_str = parse_int::parse)
     )]
     reset_delay: u64,
+
+    /// probe-rs target description (YAML) for a chip not natively known
+    /// to probe-rs
+    #[clap(
+        long = "chip-description",
+        value_name = "yaml",
+        env = "HUMILITY_CHIP_DESCRIPTION"
+    )]
+    chip_description: Option<PathBuf>,
+
+    /// do not verify the image after flashing (RISC-V only)
+    #[clap(long = "skip-verify")]
+    skip_verify: bool,
+
+    /// verify the image after flashing (the default for RISC-V)
+    #[clap(long, conflicts_with = "skip_verify")]
+    verify: bool,
+
+    /// do not flash, but verify that the image on the target matches the
+    /// archive, reporting any differences
+    #[clap(
+        long = "verify-only",
+        conflicts_with_all = &[
+            "force", "dryrun", "retain", "force_openocd", "skip_verify",
+            "verify", "delta", "recover"
+        ]
+    )]
+    verify_only: bool,
+
+    /// compare the image on the target against the archive, and erase and
+    /// program only those flash sectors that differ
+    #[clap(long, short = 'D', conflicts_with = "force_openocd")]
+    delta: bool,
+
+    /// if the target cannot be attached to, recover it via the actions
+    /// defined by its environment (power cycling it or resetting it via the
+    /// debug mailbox) and retry
+    #[clap(long)]
+    recover: bool,
+}
+
+//
+// The actions that we take (if the target defines them) to recover a target
+// that we cannot attach to, in the order that we try them -- and the time
+// we allow the target to come back after each.
+//
+const RECOVERY_ACTIONS: &[Action] =
+    &[Action::PowerCycle, Action::ResetViaDebugmailbox];
+const RECOVERY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+type Recovery<'a> = Option<(&'a Environment, &'a str)>;
+
+//
+// Returns the environment and target to recover the target with, if recovery
+// has been requested.
+//
+fn recovery<'a>(
+    environment: &'a Option<Environment>,
+    args: &'a Cli,
+    subargs: &FlashArgs,
+) -> Result<Recovery<'a>> {
+    if !subargs.recover {
+        return Ok(None);
+    }
+
+    let (env, target) = match (environment, &args.target) {
+        (Some(env), Some(target)) => (env, target.as_str()),
+        _ => bail!("--recover requires a target within an environment"),
+    };
+
+    if !RECOVERY_ACTIONS.iter().any(|action| env.has_action(*action)) {
+        bail!(
+            "target {} defines no recovery action (one of: {})",
+            target,
+            RECOVERY_ACTIONS
+                .iter()
+                .map(|action| action.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(Some((env, target)))
+}
+
+//
+// Attaches via the specified function.  If this fails and recovery has been
+// requested, we take each recovery action that the target defines in turn,
+// retrying the attach after each.
+//
+fn attach(
+    recovery: Recovery,
+    mut attach: impl FnMut() -> Result<Box<dyn Core>>,
+) -> Result<Box<dyn Core>> {
+    let mut err = match attach() {
+        Ok(core) => return Ok(core),
+        Err(err) => err,
+    };
+
+    let (env, target) = match recovery {
+        Some(recovery) => recovery,
+        None => return Err(err),
+    };
+
+    humility::msg!("failed to attach: {}; attempting recovery", err);
+
+    for action in RECOVERY_ACTIONS {
+        if !env.has_action(*action) {
+            continue;
+        }
+
+        env.action(target, *action)?;
+        std::thread::sleep(RECOVERY_DELAY);
+
+        match attach() {
+            Ok(core) => {
+                humility::msg!("recovered target via {}", action);
+                return Ok(core);
+            }
+            Err(e) => err = e,
+        }
+    }
+
+    Err(err.context("failed to attach after recovery"))
 }
 
 //
@@ -99,12 +299,15 @@ struct FlashConfig {
 }
 
 fn force_openocd(
-    hubris: &mut HubrisArchive,
-    args: &Cli,
+    context: &humility::ExecutionContext,
     subargs: &FlashArgs,
     config: &FlashConfig,
     elf: &[u8],
+    recovery: Recovery,
 ) -> Result<()> {
+    let hubris = context.archive.as_ref().unwrap();
+    let args = &context.cli;
+
     // Images that include auxiliary flash data *must* be programmed through
     // probe-rs, because we use the resulting ProbeCore to program the
     // auxiliary flash (via hiffy)
@@ -122,7 +325,9 @@ fn force_openocd(
     };
 
     let serial = {
-        let mut c = humility::core::attach(probe, hubris)?;
+        let mut c = attach(recovery, || {
+            humility::core::attach_to_chip(probe, hubris, args.chip.as_deref())
+        })?;
         let core = c.as_mut();
 
         //
@@ -159,78 +364,80 @@ fn force_openocd(
         }
     };
 
-    let mut flash = std::process::Command::new("openocd");
+    let payload = match payload {
+        FlashProgramConfig::Payload(payload) => payload,
+        _ => bail!("unexpected OpenOCD payload: {:?}", payload),
+    };
 
-    //
-    // We need to create a temporary file to hold our OpenOCD
-    // configuration file and the SREC file that we're going to
-    // actually program.
-    //
-    let mut conf = tempfile::NamedTempFile::new()?;
-    let srec = tempfile::NamedTempFile::new()?;
-
-    if let Some(serial) = serial {
-        humility::msg!("specifying serial {}", serial);
-        //jlink adapter does not support hla
-        if let FlashProgramConfig::Payload(payload) = payload {
-            if payload.contains("jlink") {
-                //TODO find alternative to support specifying serial with jlink
-                // perhaps `writeln!(conf, "adapter serial {}", serial)?;`
-                //
-                humility::msg!("using jlink adapter, cannot specify serial");
-            }
-        } else {
-            //
-            // In OpenOCD 0.11 dev, hla_serial has been deprecated, and
-            // using it results in this warning:
-            //
-            //   DEPRECATED! use 'adapter serial' not 'hla_serial'
+    let serial = match serial {
+        //
+        // The jlink adapter does not support hla.
+        //
+        Some(_) if payload.contains("jlink") => {
             //
-            // Unfortunately, the newer variant ("adapter serial") does
-            // not exist prior to this interface being deprecated; in
-            // order to allow execution on older OpenOCD variants, we
-            // deliberately use the deprecated interface.  (And yes, it
-            // would probably be convenient if OpenOCD just made the old
-            // thing work instead of shouting about it and then doing it
-            // anyway.)
+            // TODO find alternative to support specifying serial with
+            // jlink -- perhaps `adapter serial`.
             //
-            writeln!(conf, "interface hla\nhla_serial {}", serial)?;
+            humility::msg!("using jlink adapter, cannot specify serial");
+            None
         }
-    }
-
-    if let FlashProgramConfig::Payload(ref payload) = payload {
-        write!(conf, "{}", payload)?;
-    } else {
-        bail!("unexpected OpenOCD payload: {:?}", payload);
-    }
-
-    std::fs::write(&srec, generate_srec_from_elf(elf)?)?;
+        Some(serial) => {
+            humility::msg!("specifying serial {}", serial);
+            Some(serial)
+        }
+        None => None,
+    };
 
     //
-    // OpenOCD only deals with slash paths, not native paths
-    // (regardless of platform), so we turn our paths into slash
-    // paths.
+    // We need a temporary directory to hold our OpenOCD configuration file
+    // and the SREC file that we're going to actually program.  We run
+    // OpenOCD from it, allowing us to refer to both by relative paths (and
+    // sparing us OpenOCD's insistence on slash paths).  The target may
+    // override the OpenOCD that we run, its configuration, and specify
+    // additional arguments.
     //
-    let conf_path = conf.path().to_slash_lossy();
-    let srec_path = srec.path().to_slash_lossy();
+    let dir = tempfile::tempdir()?;
+    let srec = "final.srec";
+
+    let mut flash = humility_cmd::openocd_command(
+        context.environment.as_ref(),
+        || Ok(payload.clone().into_bytes()),
+        None,
+        serial,
+        dir.path(),
+    )?;
+
+    std::fs::write(dir.path().join(srec), generate_srec_from_elf(elf)?)?;
 
     if subargs.retain || subargs.dryrun {
-        humility::msg!("retaining OpenOCD config as {:?}", conf.path());
-        humility::msg!("retaining srec as {}", srec_path);
-        conf.keep()?;
-        srec.keep()?;
+        let dir = dir.into_path();
+        humility::msg!("retaining OpenOCD config and srec in {:?}", dir);
     }
 
-    for arg in &config.args {
+    let mut flashargs = config.args.iter().peekable();
+
+    while let Some(arg) = flashargs.next() {
         match arg {
+            //
+            // The configuration has already been specified.
+            //
+            FlashArgument::Direct(val)
+                if val == "-f"
+                    && matches!(
+                        flashargs.peek(),
+                        Some(FlashArgument::Config)
+                    ) =>
+            {
+                flashargs.next();
+            }
             FlashArgument::Direct(ref val) => {
                 flash.arg(val);
             }
             FlashArgument::FormattedPayload(ref pre, ref post) => {
-                flash.arg(format!("{} {} {}", pre, srec_path, post));
+                flash.arg(format!("{} {} {}", pre, srec, post));
             }
             FlashArgument::Config => {
-                flash.arg(&conf_path);
+                flash.arg("openocd.cfg");
             }
             _ => {
                 anyhow::bail!("unexpected OpenOCD argument {:?}", arg);
@@ -259,20 +466,37 @@ fn flashcmd(context: &mut humility::ExecutionContext) -> Result<()> {
     let subargs = FlashArgs::try_parse_from(subargs)?;
 
     let config: FlashConfig = ron::from_str(&flash.metadata)?;
+    let recovery = recovery(&context.environment, &context.cli, &subargs)?;
+
+    if subargs.verify_only {
+        return verifycmd(hubris, &context.cli, &flash.elf);
+    }
 
     if subargs.force_openocd {
         humility::msg!("forcing flashing using OpenOCD");
-        return force_openocd(
-            hubris,
-            &context.cli,
-            &subargs,
-            &config,
-            &flash.elf,
-        );
+        return force_openocd(context, &subargs, &config, &flash.elf, recovery);
+    }
+
+    let riscv = hubris.arch.as_ref().unwrap().get_e_machine()
+        == goblin::elf::header::EM_RISCV;
+
+    if let Some(ref description) = subargs.chip_description {
+        humility::core::register_chip_description(description)?;
     }
 
+    //
+    // For RISC-V parts (which we often can't otherwise identify), a chip
+    // specified on the command line (or by the environment) trumps anything
+    // in the archive; for all other parts, the archive is authoritative.
+    //
+    let chip = if riscv {
+        context.cli.chip.clone().or_else(|| config.chip.clone())
+    } else {
+        config.chip.clone()
+    };
+
     // This is incredibly ugly! It also gives us backwards compatibility!
-    let chip = match config.chip {
+    let chip = match chip {
         Some(chip) => chip,
         None => match &config.program {
             FlashProgram::PyOcd(args) => {
@@ -304,11 +528,7 @@ fn flashcmd(context: &mut humility::ExecutionContext) -> Result<()> {
                         flashing using OpenOCD"
                     );
                     return force_openocd(
-                        hubris,
-                        &context.cli,
-                        &subargs,
-                        &config,
-                        &flash.elf,
+                        context, &subargs, &config, &flash.elf, recovery,
                     );
                 }
 
@@ -350,16 +570,27 @@ fn flashcmd(context: &mut humility::ExecutionContext) -> Result<()> {
                     }
 
                     if c.is_none() {
+                        //
+                        // Falling back to OpenOCD is what we have always done
+                        // for parts we can't identify -- but for RISC-V, we
+                        // want to flash natively, so we insist on the chip
+                        // being specified (or OpenOCD being forced).
+                        //
+                        if riscv {
+                            bail!(
+                                "could not get chip from OpenOCD config; \
+                                specify the chip with -c (and a description \
+                                with --chip-description), or use -O to \
+                                force flashing via OpenOCD"
+                            );
+                        }
+
                         humility::msg!(
                             "could not get chip from OpenOCD config; \
                         flashing using OpenOCD"
                         );
                         return force_openocd(
-                            hubris,
-                            &context.cli,
-                            &subargs,
-                            &config,
-                            &flash.elf,
+                            context, &subargs, &config, &flash.elf, recovery,
                         );
                     }
 
@@ -370,13 +601,23 @@ fn flashcmd(context: &mut humility::ExecutionContext) -> Result<()> {
         },
     };
 
+    if !humility::core::chip_is_known(&chip) {
+        bail!(
+            "probe-rs has no description for chip \"{}\"; specify one with \
+            --chip-description (or use -O to force flashing via OpenOCD)",
+            chip
+        );
+    }
+
     let probe = match &context.cli.probe {
         Some(p) => p,
         None => "auto",
     };
 
     humility::msg!("attaching with chip set to {:x?}", chip);
-    let mut c = humility::core::attach_for_flashing(probe, hubris, &chip)?;
+    let mut c = attach(recovery, || {
+        humility::core::attach_for_flashing(probe, hubris, &chip)
+    })?;
     let core = c.as_mut();
 
     core.halt()?;
@@ -400,16 +641,46 @@ fn flashcmd(context: &mut humility::ExecutionContext) -> Result<()> {
         }
     }
 
-    let ihex = tempfile::NamedTempFile::new()?;
-    std::fs::write(&ihex, generate_ihex_from_elf(&flash.elf)?)?;
-    let ihex_path = ihex.path();
+    let chunks = if subargs.delta {
+        match delta_chunks(core, &chip, &flash.elf) {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                core.run()?;
+                return Err(err);
+            }
+        }
+    } else {
+        elf_chunks(&flash.elf)?
+    };
+
+    if chunks.is_empty() {
+        humility::msg!("no delta; image on target already matches archive");
+    } else {
+        let ihex = tempfile::NamedTempFile::new()?;
+        std::fs::write(&ihex, generate_ihex(&chunks)?)?;
+        let ihex_path = ihex.path();
 
-    //
-    // Load the flash image, and reset the part if that works.
-    //
-    if let Err(err) = core.load(ihex_path) {
-        core.run()?;
-        return Err(err);
+        //
+        // Load the flash image, and reset the part if that works.
+        //
+        if let Err(err) = core.load(ihex_path) {
+            core.run()?;
+            return Err(err);
+        }
+
+        //
+        // Now read the image back to be sure that it actually took.  If it
+        // didn't, we don't want to reset into whatever is there.
+        //
+        let verify_image =
+            if riscv { !subargs.skip_verify } else { subargs.verify };
+
+        if verify_image {
+            if let Err(err) = verify(core, &flash.elf) {
+                core.run()?;
+                return Err(err);
+            }
+        }
     }
 
     //
@@ -545,17 +816,14 @@ pub fn init() -> (Command, ClapCommand<'static>) {
     )
 }
 
-/// While it may sound like the impetus for an OSHA investigation at the North
-/// Pole, this function is _actually_ designed to generate small (32-byte)
-/// chunks describing the data in the PHDRs of an ELF file. Unless the file is
-/// missing PHDRs, because objcopy sometimes does that for whatever reason, in
-/// which case we do the section headers.
-///
-/// This is an implementation factor of both SREC and IHEX generation.
-fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+/// Returns the loadable data of an ELF file as address/data pairs:  one
+/// for each PT_LOAD PHDR -- unless the file is missing PHDRs, because objcopy
+/// sometimes does that for whatever reason, in which case we do the
+/// PROGBITS section headers.
+fn elf_segments(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
     let elf = goblin::elf::Elf::parse(elf_data)?;
 
-    let mut addr_slices = vec![];
+    let mut segments = vec![];
 
     if elf.program_headers.is_empty() {
         for sh in &elf.section_headers {
@@ -567,11 +835,7 @@ fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
             let offset = usize::try_from(sh.sh_offset)?;
             let size = usize::try_from(sh.sh_size)?;
 
-            for (i, chunk) in
-                elf_data[offset..offset + size].chunks(32).enumerate()
-            {
-                addr_slices.push((addr + i as u32 * 32, chunk));
-            }
+            segments.push((addr, &elf_data[offset..offset + size]));
         }
     } else {
         for ph in &elf.program_headers {
@@ -583,10 +847,164 @@ fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
             let offset = usize::try_from(ph.p_offset)?;
             let size = usize::try_from(ph.p_filesz)?;
 
-            for (i, chunk) in
-                elf_data[offset..offset + size].chunks(32).enumerate()
+            segments.push((addr, &elf_data[offset..offset + size]));
+        }
+    }
+
+    Ok(segments)
+}
+
+/// While it may sound like the impetus for an OSHA investigation at the North
+/// Pole, this function is _actually_ designed to generate small (32-byte)
+/// chunks describing the loadable data of an ELF file (as determined by
+/// [`elf_segments`]).
+///
+/// This is an implementation factor of both SREC and IHEX generation.
+fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let mut addr_slices = vec![];
+
+    for (addr, data) in elf_segments(elf_data)? {
+        for (i, chunk) in data.chunks(32).enumerate() {
+            addr_slices.push((addr + i as u32 * 32, chunk));
+        }
+    }
+
+    Ok(addr_slices)
+}
+
+/// The result of reading back one loadable segment of the image.
+struct SegmentVerification {
+    /// Base address of the segment
+    addr: u32,
+
+    /// Length of the segment, in bytes
+    len: usize,
+
+    /// SHA-256 of the segment as found in the archive
+    expected: Vec<u8>,
+
+    /// SHA-256 of the segment as read back from the target
+    actual: Vec<u8>,
+
+    /// Ranges within the segment (as base and length) that differ
+    differs: Vec<(u32, u32)>,
+}
+
+impl SegmentVerification {
+    fn nbytes(&self) -> u32 {
+        self.differs.iter().map(|(_, len)| len).sum()
+    }
+}
+
+/// Reads back every loadable segment of the image through the core,
+/// hashing each and determining the exact ranges that differ.
+fn verify_segments(
+    core: &mut dyn Core,
+    elf: &[u8],
+) -> Result<Vec<SegmentVerification>> {
+    use indicatif::{ProgressBar, ProgressStyle};
+
+    let segments = elf_segments(elf)?;
+    let total: usize = segments.iter().map(|(_, data)| data.len()).sum();
+
+    let bar = ProgressBar::new(total as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: verifying [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    let mut buf = vec![0u8; CORE_MAX_READSIZE];
+    let mut rval = vec![];
+
+    for (addr, data) in segments {
+        let mut hasher = Sha256::new();
+        let mut differs: Vec<(u32, u32)> = vec![];
+
+        for (i, chunk) in data.chunks(CORE_MAX_READSIZE).enumerate() {
+            let base = addr + (i * CORE_MAX_READSIZE) as u32;
+            let buf = &mut buf[..chunk.len()];
+
+            core.read_8(base, buf)?;
+            hasher.update(&buf);
+
+            for (offs, (expected, actual)) in
+                chunk.iter().zip(buf.iter()).enumerate()
             {
-                addr_slices.push((addr + i as u32 * 32, chunk));
+                if expected == actual {
+                    continue;
+                }
+
+                let addr = base + offs as u32;
+
+                //
+                // Coalesce adjacent differing bytes into a single range.
+                //
+                match differs.last_mut() {
+                    Some((start, len)) if *start + *len == addr => *len += 1,
+                    _ => differs.push((addr, 1)),
+                }
+            }
+
+            bar.inc(chunk.len() as u64);
+        }
+
+        rval.push(SegmentVerification {
+            addr,
+            len: data.len(),
+            expected: Sha256::digest(data).to_vec(),
+            actual: hasher.finalize().to_vec(),
+            differs,
+        });
+    }
+
+    bar.finish_and_clear();
+
+    Ok(rval)
+}
+
+/// Reads back every loadable segment of the image through the core, failing
+/// if any byte differs from what is in the archive.
+fn verify(core: &mut dyn Core, elf: &[u8]) -> Result<()> {
+    let segments = verify_segments(core, elf)?;
+    let nbytes: u32 = segments.iter().map(|s| s.nbytes()).sum();
+
+    if let Some((first, _)) = segments.iter().flat_map(|s| &s.differs).next() {
+        bail!(
+            "verification failed: {} byte{} differ, starting at 0x{:x} \
+            (use --verify-only for details)",
+            nbytes,
+            if nbytes == 1 { "" } else { "s" },
+            first
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns chunks (as with [`elf_chunks`]) of only the loadable data of an
+/// ELF file that falls within the specified sectors.
+fn elf_chunks_within<'a>(
+    elf_data: &'a [u8],
+    sectors: &[(u32, u32)],
+) -> Result<Vec<(u32, &'a [u8])>> {
+    let mut addr_slices = vec![];
+
+    for (addr, data) in elf_segments(elf_data)? {
+        let end = addr as u64 + data.len() as u64;
+
+        for &(base, size) in sectors {
+            let start = std::cmp::max(addr as u64, base as u64);
+            let lim = std::cmp::min(end, base as u64 + size as u64);
+
+            if start >= lim {
+                continue;
+            }
+
+            let offs = (start - addr as u64) as usize;
+            let slice = &data[offs..offs + (lim - start) as usize];
+
+            for (i, chunk) in slice.chunks(32).enumerate() {
+                addr_slices.push((start as u32 + i as u32 * 32, chunk));
             }
         }
     }
@@ -594,6 +1012,138 @@ fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
     Ok(addr_slices)
 }
 
+/// Compares the image on the target against the archive, returning chunks
+/// (as with [`elf_chunks`]) that cover only the image data in flash sectors
+/// that differ.  Because probe-rs erases (and programs) only those sectors
+/// that contain data to be written, this results in only the differing
+/// sectors being rewritten.
+fn delta_chunks<'a>(
+    core: &mut dyn Core,
+    chip: &str,
+    elf: &'a [u8],
+) -> Result<Vec<(u32, &'a [u8])>> {
+    let sectors = humility::core::flash_sectors(chip)?;
+    let segments = verify_segments(core, elf)?;
+
+    let overlaps = |addr: u32, len: u32| {
+        sectors.iter().filter(move |&&(base, size)| {
+            (base as u64) < addr as u64 + len as u64
+                && (addr as u64) < base as u64 + size as u64
+        })
+    };
+
+    let mut dirty = BTreeSet::new();
+    let mut nbytes = 0;
+
+    for &(addr, len) in segments.iter().flat_map(|s| &s.differs) {
+        let mut covered = 0;
+
+        for &(base, size) in overlaps(addr, len) {
+            let start = std::cmp::max(addr as u64, base as u64);
+            let end = std::cmp::min(
+                addr as u64 + len as u64,
+                base as u64 + size as u64,
+            );
+            covered += end - start;
+            dirty.insert((base, size));
+        }
+
+        if covered != len as u64 {
+            bail!(
+                "image differs at 0x{:x}, which is not in a flash sector \
+                of {}; cannot perform a delta flash",
+                addr,
+                chip
+            );
+        }
+
+        nbytes += len;
+    }
+
+    let total = segments
+        .iter()
+        .flat_map(|s| overlaps(s.addr, s.len as u32))
+        .collect::<BTreeSet<_>>()
+        .len();
+
+    humility::msg!(
+        "{} bytes differ, in {} of {} sector{}",
+        nbytes,
+        dirty.len(),
+        total,
+        if total == 1 { "" } else { "s" }
+    );
+
+    elf_chunks_within(elf, &dirty.into_iter().collect::<Vec<_>>())
+}
+
+fn verifycmd(hubris: &HubrisArchive, args: &Cli, elf: &[u8]) -> Result<()> {
+    let probe = match &args.probe {
+        Some(p) => p,
+        None => "auto",
+    };
+
+    let mut c = humility::core::attach(probe, hubris)?;
+    let core = c.as_mut();
+
+    //
+    // The image ID check isn't sufficient to know that the image is intact,
+    // but it's useful context when it fails.
+    //
+    if let Err(err) = hubris.validate(core, HubrisValidate::ArchiveMatch) {
+        humility::msg!("archive does not match image ID: {}", err);
+    }
+
+    let hex = |sum: &[u8]| {
+        sum.iter().take(8).map(|b| format!("{:02x}", b)).collect::<String>()
+    };
+
+    core.halt()?;
+    let segments = verify_segments(core, elf);
+    core.run()?;
+    let segments = segments?;
+
+    println!(
+        "{:10} {:>10} {:16} {:16} STATUS",
+        "ADDR", "SIZE", "ARCHIVE", "TARGET"
+    );
+
+    for s in &segments {
+        println!(
+            "0x{:08x} {:>10} {:16} {:16} {}",
+            s.addr,
+            s.len,
+            hex(&s.expected),
+            hex(&s.actual),
+            if s.differs.is_empty() {
+                "ok".to_string()
+            } else {
+                format!("{} bytes differ", s.nbytes())
+            }
+        );
+    }
+
+    let differs = segments.iter().flat_map(|s| &s.differs).collect::<Vec<_>>();
+
+    if differs.is_empty() {
+        humility::msg!("image verified");
+        return Ok(());
+    }
+
+    println!("\n{:10} {:10} {:>10}", "START", "END", "NBYTES");
+
+    for (addr, len) in &differs {
+        println!("0x{:08x} 0x{:08x} {:>10}", addr, addr + len, len);
+    }
+
+    bail!(
+        "{} range{} differ{} from the archive",
+        differs.len(),
+        if differs.len() == 1 { "" } else { "s" },
+        if differs.len() == 1 { "s" } else { "" },
+    );
+}
+
 fn generate_srec_from_elf(data: &[u8]) -> Result<String> {
     let mut records = vec![srec::Record::S0("humility!".into())];
 
@@ -608,11 +1158,11 @@ fn generate_srec_from_elf(data: &[u8]) -> Result<String> {
     Ok(srec::writer::generate_srec_file(&records))
 }
 
-fn generate_ihex_from_elf(data: &[u8]) -> Result<String> {
+fn generate_ihex(chunks: &[(u32, &[u8])]) -> Result<String> {
     // Build up IHEX records from that information.
     let mut records = vec![];
 
-    for (addr, slice) in elf_chunks(data)? {
+    for &(addr, slice) in chunks {
         records.push(ihex::Record::ExtendedLinearAddress((addr >> 16) as u16));
         records.push(ihex::Record::Data {
             offset: addr as u16,
@@ -174,23 +174,12 @@ pub fn gdb(context: &mut humility::ExecutionContext) -> Result<()> {
     }
     //TODO feel like this should just call to humility openocd
     let _openocd = if subargs.run_openocd {
-        hubris
-            .extract_file_to(
-                "debug/openocd.cfg",
-                &work_dir.path().join("openocd.cfg"),
-            )
-            .context("openocd config missing. Is your Hubris build too old?")?;
-        let mut cmd = Command::new(
-            subargs.openocd.unwrap_or_else(|| "openocd".to_string()),
-        );
-        cmd.arg("-f").arg("openocd.cfg");
-        if let Some(serial) = serial {
-            cmd.arg("-c")
-                .arg("interface hla")
-                .arg("-c")
-                .arg(format!("hla_serial {}", serial));
-        }
-        cmd.current_dir(work_dir.path());
+        let mut cmd = humility_cmd::openocd(
+            context,
+            subargs.openocd,
+            serial,
+            work_dir.path(),
+        )?;
         cmd.stdin(Stdio::piped());
         Some(OpenOcdRunner(cmd.spawn().context("Could not start `openocd`")?))
     } else {
@@ -90,6 +90,96 @@
 //! Controller I2C3, device 0x48, register 0x4 = 0x1f
 //! ```
 //!
+//! ### Register maps
+//!
+//! If a device has a register map, its registers can be specified by name,
+//! in which case the register's full width is read by default and the
+//! result is decoded:
+//!
+//! ```console
+//! % humility i2c -b northeast0 -d tmp117 -r CONFIGURATION
+//! humility: attached via ST-Link
+//! Controller I2C2, device 0x48, register 0x1 (CONFIGURATION) = 0x02 0x20
+//!     HIGH_ALERT [15]          0x0
+//!     LOW_ALERT [14]           0x0
+//!     DATA_READY [13]          0x0
+//!     EEPROM_BUSY [12]         0x0
+//!     MOD [11:10]              0x0 (continuous)
+//!     CONV [9:7]               0x4
+//!     AVG [6:5]                0x1 (8 samples)
+//!     T_NA [4]                 0x0 (alert mode)
+//!     POL [3]                  0x0 (active low)
+//!     DR_ALERT [2]             0x0 (alert)
+//! ```
+//!
+//! A register specified by number is decoded only if the bytes read cover
+//! the entire register (e.g., with `--nbytes`).
+//!
+//! Register maps are built in for the ADT7420, TMP117 and TMP451, and are
+//! keyed by the driver name of the device in the Hubris manifest.  Note
+//! that this coverage is partial:  most devices that Hubris knows about
+//! don't (yet) have a built-in map.  A register map for any other device
+//! can be specified with `--regmap` (or the `HUMILITY_I2C_REGMAP`
+//! environment variable); it is a TOML file that names each register, its
+//! offset and width (in bits), and optionally its bitfields (as `msb:lsb`),
+//! names for their values, and a scale and units for the register as a
+//! whole:
+//!
+//! ```toml
+//! device = "tmp117"
+//! byteorder = "big"
+//!
+//! [[register]]
+//! name = "TEMP_RESULT"
+//! offset = 0x00
+//! width = 16
+//! signed = true
+//! scale = 0.0078125
+//! units = "C"
+//!
+//! [[register]]
+//! name = "CONFIGURATION"
+//! offset = 0x01
+//! width = 16
+//!
+//! [[register.field]]
+//! name = "MOD"
+//! bits = "11:10"
+//! values = { 0 = "continuous", 1 = "shutdown", 3 = "one-shot" }
+//! ```
+//!
+//! ### Tracing and replay
+//!
+//! If the `HUMILITY_I2C_TRACE` environment variable is set, every I2C read
+//! and write that Humility performs via HIF -- whether by `humility i2c` or
+//! by any other command that speaks I2C (e.g., `humility pmbus`) -- is
+//! appended to the named file, one JSON object per line, denoting the time
+//! (in seconds since the Unix epoch, so that successive sessions appended
+//! to the same file can be distinguished), controller, port, mux, address,
+//! register, bytes and result of each transaction.  (Only straight-line HIF
+//! programs can be traced; as scans loop, they are not recorded.)
+//!
+//! A trace can be replayed against a target with `--replay`:
+//!
+//! ```console
+//! % HUMILITY_I2C_TRACE=adt7420.trace humility i2c -c 3 -d 0x48 -r 0xb
+//! humility: attached via ST-Link
+//! Controller I2C3, device 0x48, register 0xb = 0xcb
+//! % humility i2c --replay adt7420.trace
+//! humility: attached via ST-Link
+//! I2C3, port H: dev 0x48, reg 0x0b, read 1 = 0xcb
+//! humility: replayed 1 transactions; 1 matched trace, 0 differed
+//! ```
+//!
+//! Any read whose result differs from that in the trace is flagged with the
+//! expected value.  `--replay` also accepts captures exported from a Saleae
+//! I2C analyzer (as CSV) or from an Aardvark (as a batch XML file); because
+//! these captures have no notion of controller or port, the bus on which to
+//! replay them must be specified (via `-b` or `-c`, and `-p` and `-m` as
+//! needed).  A bus specified this way overrides that in a Humility trace.
+//! Transactions are replayed in batches, so the timing of the original
+//! capture is not reproduced.
+//!
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
@@ -97,17 +187,21 @@ use clap::{CommandFactory, Parser};
 use hif::*;
 use humility::cli::Subcommand;
 use humility_cmd::hiffy::*;
+use humility_cmd::regmap::{ByteOrder, Register, RegisterMap};
 use humility_cmd::{Archive, Attach, Command, Dumper, Validate};
 
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 use std::time::Instant;
 
 use indicatif::{HumanBytes, HumanDuration};
 use indicatif::{ProgressBar, ProgressStyle};
 
+mod replay;
+
 #[derive(Parser, Debug, Default)]
 #[clap(name = "i2c", about = env!("CARGO_PKG_DESCRIPTION"))]
 pub struct I2cArgs {
@@ -153,11 +247,15 @@ pub struct I2cArgs {
     #[clap(long, short, value_name = "address")]
     device: Option<String>,
 
-    /// specifies register
-    #[clap(long, short, value_name = "register",
-        parse(try_from_str = parse_int::parse),
-    )]
-    register: Option<u8>,
+    /// specifies register, by number or (if the device has a register
+    /// map) by name
+    #[clap(long, short, value_name = "register")]
+    register: Option<String>,
+
+    /// specifies a register map for the device, overriding any built-in
+    /// map for its driver
+    #[clap(long, value_name = "filename", env = "HUMILITY_I2C_REGMAP")]
+    regmap: Option<String>,
 
     /// indicates a raw operation
     #[clap(long, short = 'R', conflicts_with = "register")]
@@ -197,11 +295,25 @@ pub struct I2cArgs {
         requires = "device",
     )]
     flash: Option<String>,
+
+    /// replay the I2C transactions in the specified trace, which may be
+    /// recorded by Humility, exported from a Saleae I2C analyzer, or an
+    /// Aardvark batch file
+    #[clap(long, value_name = "filename",
+        conflicts_with_all = &[
+            "scan", "scanreg", "register", "raw", "block", "write",
+            "writeraw", "nbytes", "flash", "device",
+        ],
+    )]
+    replay: Option<String>,
 }
 
 fn i2c_done(
     subargs: &I2cArgs,
     hargs: &humility_cmd::i2c::I2cArgs,
+    register: Option<u8>,
+    nbytes: Option<u8>,
+    regdef: Option<(&Register, ByteOrder)>,
     results: &[Result<Vec<u8>, u32>],
     func: &HiffyFunction,
 ) -> Result<()> {
@@ -351,7 +463,7 @@ fn i2c_done(
                 Err(err) => {
                     println!("Err({})", func.strerror(*err));
                 }
-                Ok(val) => match subargs.nbytes {
+                Ok(val) => match nbytes {
                     Some(n) if n > 2 => {
                         println!();
                         Dumper::new().dump(val, 0);
@@ -370,11 +482,15 @@ fn i2c_done(
         }
     } else {
         print!(
-            "Controller I2C{}, device 0x{:x}, {}register 0x{:x} = ",
+            "Controller I2C{}, device 0x{:x}, {}register 0x{:x}{} = ",
             hargs.controller,
             hargs.address.unwrap(),
             if subargs.writeraw { "raw write to " } else { "" },
-            subargs.register.unwrap()
+            register.unwrap(),
+            match regdef {
+                Some((reg, _)) => format!(" ({})", reg.name),
+                None => "".to_string(),
+            }
         );
 
         if results.is_empty() {
@@ -389,7 +505,7 @@ fn i2c_done(
                     Dumper::new().dump(val, 0);
                 }
 
-                Ok(val) => match subargs.nbytes {
+                Ok(val) => match nbytes {
                     Some(n) if n > 2 => {
                         println!();
                         Dumper::new().dump(val, 0);
@@ -405,6 +521,32 @@ fn i2c_done(
                     }
                 },
             }
+
+            //
+            // If we read a register that we have a definition for, decode
+            // it.  A register specified by number is only decoded if we
+            // read all of it (and silently not decoded otherwise).
+            //
+            if let (Some((reg, byteorder)), Ok(val)) = (regdef, &results[0]) {
+                if subargs.write.is_none()
+                    && !subargs.writeraw
+                    && (byname || val.len() == reg.nbytes())
+                {
+                    match reg.decode(byteorder, val) {
+                        Ok(decoded) => {
+                            for (name, val) in decoded {
+                                println!("    {:<24} {}", name, val);
+                            }
+                        }
+                        Err(err) => {
+                            println!(
+                                "    failed to decode {}: {}",
+                                reg.name, err
+                            );
+                        }
+                    }
+                }
+            }
         }
     }
 
@@ -433,15 +575,49 @@ fn i2c(context: &mut humility::ExecutionContext) -> Result<()> {
         && subargs.register.is_none()
         && !subargs.raw
         && subargs.flash.is_none()
+        && subargs.replay.is_none()
     {
         bail!(
             "must indicate a scan (-s/-S), specify a register (-r), \
-            indicate raw (-R) or flash (-f)"
+            indicate raw (-R), flash (-f) or replay (--replay)"
         );
     }
 
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
 
+    if let Some(filename) = &subargs.replay {
+        let transactions = replay::ingest(Path::new(filename))?;
+
+        //
+        // If we have been given a bus, all transactions are replayed on it;
+        // otherwise, the trace itself must specify where they belong.
+        //
+        let hargs = if subargs.bus.is_some()
+            || subargs.controller.is_some()
+            || subargs.port.is_some()
+            || subargs.mux.is_some()
+        {
+            Some(humility_cmd::i2c::I2cArgs::parse(
+                hubris,
+                &subargs.bus,
+                subargs.controller,
+                &subargs.port,
+                &subargs.mux,
+                &None,
+            )?)
+        } else {
+            None
+        };
+
+        return replay::replay(
+            hubris,
+            core,
+            &mut context,
+            &transactions,
+            hargs.as_ref(),
+        );
+    }
+
     let (fname, args) = if subargs.flash.is_some() {
         ("I2cBulkWrite", 8)
     } else {
@@ -463,6 +639,51 @@ fn i2c(context: &mut humility::ExecutionContext) -> Result<()> {
         &subargs.device,
     )?;
 
+    //
+    // If the device has a register map, registers may be specified by name
+    // -- in which case we read the register's full width by default.
+    //
+    let regmap = match (&subargs.regmap, &hargs.device) {
+        (Some(filename), _) => Some(RegisterMap::load(Path::new(filename))?),
+        (None, Some(device)) => RegisterMap::builtin(device)?,
+        (None, None) => None,
+    };
+
+    let (register, regdef, nbytes, byname) = match &subargs.register {
+        None => (None, None, subargs.nbytes, false),
+        Some(r) => match (parse_int::parse::<u8>(r), &regmap) {
+            (Ok(r), _) => (
+                Some(r),
+                regmap.as_ref().and_then(|m| m.register_at(r)),
+                subargs.nbytes,
+                false,
+            ),
+            (Err(_), Some(map)) => {
+                let reg = map.register(r)?;
+                let nbytes = match subargs.nbytes {
+                    Some(nbytes) => Some(nbytes),
+                    None if subargs.block || subargs.write.is_some() => None,
+                    None => Some(u8::try_from(reg.nbytes())?),
+                };
+
+                (Some(reg.offset), Some(reg), nbytes, true)
+            }
+            (Err(_), None) => {
+                bail!(
+                    "register \"{}\" is not a number, and there is no \
+                    register map for {}; specify one with --regmap",
+                    r,
+                    match &hargs.device {
+                        Some(device) => device.as_str(),
+                        None => "the device",
+                    }
+                );
+            }
+        },
+    };
+
+    let regdef = regdef.map(|r| (r, regmap.as_ref().unwrap().byteorder));
+
     let mut ops = vec![Op::Push(hargs.controller)];
 
     ops.push(Op::Push(hargs.port.index));
@@ -622,7 +843,7 @@ fn i2c(context: &mut humility::ExecutionContext) -> Result<()> {
         }
 
         if let Some(ref write) = subargs.write {
-            if let Some(register) = subargs.register {
+            if let Some(register) = register {
                 ops.push(Op::Push(register));
             } else {
                 ops.push(Op::PushNone);
@@ -650,16 +871,16 @@ fn i2c(context: &mut humility::ExecutionContext) -> Result<()> {
             // this as our 1-byte payload and set our register to None
             //
             ops.push(Op::PushNone);
-            ops.push(Op::Push(subargs.register.unwrap()));
+            ops.push(Op::Push(register.unwrap()));
             ops.push(Op::Push(1));
         } else {
-            if let Some(register) = subargs.register {
+            if let Some(register) = register {
                 ops.push(Op::Push(register));
             } else {
                 ops.push(Op::PushNone);
             }
 
-            if let Some(nbytes) = subargs.nbytes {
+            if let Some(nbytes) = nbytes {
                 ops.push(Op::Push(nbytes));
             } else if subargs.block {
                 ops.push(Op::PushNone);
@@ -705,7 +926,7 @@ fn i2c(context: &mut humility::ExecutionContext) -> Result<()> {
 
     let results = context.run(core, ops.as_slice(), None)?;
 
-    i2c_done(&subargs, &hargs, &results, func)?;
+    i2c_done(&subargs, &hargs, register, nbytes, regdef, &results, func)?;
 
     Ok(())
 }
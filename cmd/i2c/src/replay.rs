@@ -0,0 +1,559 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Ingestion and replay of I2C traces.  We accept traces recorded by
+// Humility (via HUMILITY_I2C_TRACE), CSV exported from the Saleae I2C
+// analyzer, and Aardvark batch XML.
+//
+
+use anyhow::{anyhow, bail, Context, Result};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::{parse_trace, I2cOperation, I2cTransaction};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+pub fn ingest(path: &Path) -> Result<Vec<I2cTransaction>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    let rval = if contents.trim_start().starts_with('{') {
+        parse_trace(contents.as_bytes())
+            .with_context(|| format!("failed to read {}", path.display()))?
+    } else if let Some(offset) = contents.find("<aardvark") {
+        ingest_aardvark(&contents[offset..])?
+    } else {
+        ingest_saleae(&contents).with_context(|| {
+            format!(
+                "{} is not a Humility trace, Saleae CSV or Aardvark XML",
+                path.display()
+            )
+        })?
+    };
+
+    if rval.is_empty() {
+        bail!("no I2C transactions found in {}", path.display());
+    }
+
+    Ok(rval)
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct SaleaeTraceRecord {
+    #[serde(rename = "Time [s]")]
+    time: f64,
+
+    #[serde(rename = "Packet ID")]
+    id: u32,
+
+    #[serde(rename = "Address")]
+    address: String,
+
+    #[serde(rename = "Data")]
+    data: String,
+
+    #[serde(rename = "Read/Write")]
+    rw: String,
+
+    #[serde(rename = "ACK/NAK")]
+    acknak: String,
+}
+
+//
+// A run of bytes in one direction to one address, as seen on the wire
+// between a (repeated) start and the next.
+//
+struct Segment {
+    time: f64,
+    address: u8,
+    read: bool,
+    bytes: Vec<u8>,
+}
+
+//
+// Folds segments into transactions:  a one-byte write followed by a read
+// from the same device is a register read; everything else stands alone.
+//
+fn transactions(segments: Vec<Segment>) -> Vec<I2cTransaction> {
+    let mut rval = vec![];
+    let mut segments = segments.into_iter().peekable();
+
+    while let Some(seg) = segments.next() {
+        let mut register = None;
+        let mut seg = seg;
+
+        if !seg.read && seg.bytes.len() == 1 {
+            if let Some(next) = segments.peek() {
+                if next.read && next.address == seg.address {
+                    register = Some(seg.bytes[0]);
+                    let time = seg.time;
+                    seg = segments.next().unwrap();
+                    seg.time = time;
+                }
+            }
+        }
+
+        let (op, result) = if seg.read {
+            let result = if seg.bytes.is_empty() {
+                None
+            } else {
+                Some(Ok(seg.bytes.clone()))
+            };
+
+            (I2cOperation::Read(Some(seg.bytes.len() as u8)), result)
+        } else {
+            (I2cOperation::Write(seg.bytes), None)
+        };
+
+        rval.push(I2cTransaction {
+            time: seg.time,
+            bus: None,
+            address: seg.address,
+            register,
+            op,
+            result,
+        });
+    }
+
+    rval
+}
+
+fn ingest_saleae(contents: &str) -> Result<Vec<I2cTransaction>> {
+    let mut rdr = csv::Reader::from_reader(contents.as_bytes());
+
+    match rdr.headers() {
+        Ok(hdr) if hdr.len() == 6 => {}
+        _ => bail!("unrecognized CSV header"),
+    }
+
+    let mut records = vec![];
+
+    for result in rdr.deserialize() {
+        let record: SaleaeTraceRecord = result?;
+        let address = parse_int::parse::<u8>(&record.address)
+            .with_context(|| format!("invalid address in {:?}", record))?;
+        let datum = parse_int::parse::<u8>(&record.data)
+            .with_context(|| format!("invalid data in {:?}", record))?;
+
+        records.push((record, address, datum));
+    }
+
+    //
+    // The analyzer can be configured to display addresses as 8-bit values
+    // (that is, including the R/W bit); if any address is too large to be a
+    // 7-bit address, we assume that this is what we're looking at.
+    //
+    let eightbit = records.iter().any(|(_, address, _)| *address > 0x7f);
+
+    if eightbit {
+        humility::msg!("addresses appear to be 8-bit; shifting to 7-bit");
+    }
+
+    let mut segments: Vec<Segment> = vec![];
+    let mut last = None;
+
+    for (record, address, datum) in records {
+        let address = if eightbit { address >> 1 } else { address };
+        let read = record.rw == "Read";
+
+        match segments.last_mut() {
+            Some(seg)
+                if last == Some(record.id)
+                    && seg.read == read
+                    && seg.address == address =>
+            {
+                seg.bytes.push(datum);
+            }
+            _ => {
+                segments.push(Segment {
+                    time: record.time,
+                    address,
+                    read,
+                    bytes: vec![datum],
+                });
+            }
+        }
+
+        last = Some(record.id);
+    }
+
+    Ok(transactions(segments))
+}
+
+fn ingest_aardvark(contents: &str) -> Result<Vec<I2cTransaction>> {
+    let doc = roxmltree::Document::parse(contents)?;
+    let root = doc.root_element();
+
+    if root.tag_name().name() != "aardvark" {
+        bail!("input is not an Aardvark file");
+    }
+
+    let attr = |node: &roxmltree::Node, name: &str| -> Result<u32> {
+        let val = node.attribute(name).ok_or_else(|| {
+            anyhow!("<{}> missing {}", node.tag_name().name(), name)
+        })?;
+
+        parse_int::parse::<u32>(val).with_context(|| {
+            format!("<{}>: invalid {}", node.tag_name().name(), name)
+        })
+    };
+
+    let mut segments = vec![];
+    let mut time = 0.0;
+
+    for node in root.children().filter(|n| n.is_element()) {
+        match node.tag_name().name() {
+            "i2c_write" => {
+                let radix = match node.attribute("radix") {
+                    Some(radix) => parse_int::parse::<u32>(radix)?,
+                    None => 16,
+                };
+
+                let mut bytes = vec![];
+
+                for v in node.text().unwrap_or("").split_whitespace() {
+                    let v = v.trim_start_matches("0x");
+
+                    match u8::from_str_radix(v, radix) {
+                        Ok(val) => bytes.push(val),
+                        Err(_) => bail!("bad i2c_write byte \"{}\"", v),
+                    }
+                }
+
+                segments.push(Segment {
+                    time,
+                    address: attr(&node, "addr")? as u8,
+                    read: false,
+                    bytes,
+                });
+            }
+
+            "i2c_read" => {
+                let count = attr(&node, "count")?;
+
+                if count > u8::MAX.into() {
+                    bail!("i2c_read of {} bytes is too large", count);
+                }
+
+                //
+                // A batch file says nothing about the data read; we use
+                // placeholder bytes to convey the count, and clear the
+                // (meaningless) result once we have our transactions.
+                //
+                segments.push(Segment {
+                    time,
+                    address: attr(&node, "addr")? as u8,
+                    read: true,
+                    bytes: vec![0; count as usize],
+                });
+            }
+
+            "sleep" => {
+                time += attr(&node, "ms")? as f64 / 1000.0;
+            }
+
+            _ => {}
+        }
+    }
+
+    let mut rval = transactions(segments);
+
+    for t in rval.iter_mut() {
+        t.result = None;
+    }
+
+    Ok(rval)
+}
+
+//
+// The HIF for a single transaction, along with a (conservative) estimate of
+// the space that its result will occupy on the return stack.
+//
+fn transaction_ops(
+    hubris: &HubrisArchive,
+    bus: Option<&humility_cmd::i2c::I2cArgs>,
+    t: &I2cTransaction,
+    read: &HiffyFunction,
+    write: &HiffyFunction,
+) -> Result<(Vec<Op>, usize)> {
+    let (controller, port, mux) = match (bus, &t.bus) {
+        (Some(bus), _) => (bus.controller, bus.port.index, bus.mux),
+        (None, Some(bus)) => (
+            bus.controller,
+            hubris.lookup_i2c_port(bus.controller, &bus.port)?.index,
+            bus.mux,
+        ),
+        (None, None) => {
+            bail!("trace does not specify a bus; specify one with -b or -c")
+        }
+    };
+
+    let mut ops = vec![Op::Push(controller), Op::Push(port)];
+
+    if let Some((mux, segment)) = mux {
+        ops.push(Op::Push(mux));
+        ops.push(Op::Push(segment));
+    } else {
+        ops.push(Op::PushNone);
+        ops.push(Op::PushNone);
+    }
+
+    ops.push(Op::Push(t.address));
+
+    match t.register {
+        Some(register) => ops.push(Op::Push(register)),
+        None => ops.push(Op::PushNone),
+    }
+
+    let rsize = match &t.op {
+        I2cOperation::Read(nbytes) => {
+            match nbytes {
+                Some(nbytes) => ops.push(Op::Push(*nbytes)),
+                None => ops.push(Op::PushNone),
+            }
+
+            ops.push(Op::Call(read.id));
+            ops.push(Op::DropN(7));
+
+            nbytes.unwrap_or(u8::MAX) as usize + 8
+        }
+        I2cOperation::Write(bytes) => {
+            for byte in bytes {
+                ops.push(Op::Push(*byte));
+            }
+
+            ops.push(Op::Push32(bytes.len() as u32));
+            ops.push(Op::Call(write.id));
+            ops.push(Op::DropN(u8::try_from(bytes.len() + 7)?));
+
+            8
+        }
+    };
+
+    Ok((ops, rsize))
+}
+
+///
+/// Replays the specified transactions, printing each with its result and
+/// noting any read that differs from the trace.
+///
+pub fn replay(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    transactions: &[I2cTransaction],
+    bus: Option<&humility_cmd::i2c::I2cArgs>,
+) -> Result<()> {
+    let funcs = context.functions()?;
+    let read = funcs.get("I2cRead", 7)?;
+    let write = funcs.get("I2cWrite", 8)?;
+
+    let mut batches: Vec<(Vec<Op>, std::ops::Range<usize>)> = vec![];
+    let mut ops = vec![];
+    let (mut tsize, mut rsize) = (0, 0);
+    let mut first = 0;
+
+    //
+    // We execute transactions in batches that fit in the target's program
+    // text and return stack.
+    //
+    for (ndx, t) in transactions.iter().enumerate() {
+        let (tops, r) = transaction_ops(hubris, bus, t, read, write)
+            .with_context(|| format!("transaction {}", ndx))?;
+        let size = context.ops_size(&tops)?;
+
+        if !ops.is_empty()
+            && (tsize + size + 1 > context.text_size()
+                || rsize + r > context.rstack_size())
+        {
+            ops.push(Op::Done);
+            batches.push((ops, first..ndx));
+            ops = vec![];
+            tsize = 0;
+            rsize = 0;
+            first = ndx;
+        }
+
+        ops.extend(tops);
+        tsize += size;
+        rsize += r;
+    }
+
+    ops.push(Op::Done);
+    batches.push((ops, first..transactions.len()));
+
+    let (mut matched, mut differed) = (0, 0);
+
+    for (ops, range) in batches {
+        let results = context.run(core, ops.as_slice(), None)?;
+
+        for (ndx, t) in transactions[range].iter().enumerate() {
+            let func = match t.op {
+                I2cOperation::Read(_) => read,
+                I2cOperation::Write(_) => write,
+            };
+
+            let mut actual = t.clone();
+
+            actual.result = results.get(ndx).map(|r| match r {
+                Ok(bytes) => Ok(bytes.clone()),
+                Err(code) => Err(func.strerror(*code)),
+            });
+
+            let bus = match (bus, &t.bus) {
+                (Some(bus), _) => {
+                    format!("I2C{}, port {}", bus.controller, bus.port.name)
+                }
+                (None, Some(bus)) => bus.to_string(),
+                (None, None) => unreachable!(),
+            };
+
+            print!("{}: {} = {}", bus, t, actual.result_str());
+
+            match (&t.result, &actual.result) {
+                (None, _) => println!(),
+                (Some(expected), Some(result)) if expected == result => {
+                    matched += 1;
+                    println!();
+                }
+                (Some(_), _) => {
+                    differed += 1;
+                    println!(" (expected {})", t.result_str());
+                }
+            }
+        }
+    }
+
+    humility::msg!(
+        "replayed {} transactions; {} matched trace, {} differed",
+        transactions.len(),
+        matched,
+        differed
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saleae() -> Result<()> {
+        let csv = "\
+Time [s],Packet ID,Address,Data,Read/Write,ACK/NAK
+0.100,0,0x48,0x0B,Write,ACK
+0.101,0,0x48,0xCB,Read,ACK
+0.102,0,0x48,0x01,Read,NAK
+0.200,1,0x48,0x01,Write,ACK
+0.201,1,0x48,0x80,Write,ACK
+0.300,2,0x49,0x00,Read,NAK
+";
+
+        let transactions = ingest_saleae(csv)?;
+        assert_eq!(transactions.len(), 3);
+
+        let t = &transactions[0];
+        assert_eq!(t.time, 0.100);
+        assert_eq!(t.bus, None);
+        assert_eq!(t.address, 0x48);
+        assert_eq!(t.register, Some(0x0b));
+        assert_eq!(t.op, I2cOperation::Read(Some(2)));
+        assert_eq!(t.result, Some(Ok(vec![0xcb, 0x01])));
+
+        let t = &transactions[1];
+        assert_eq!(t.time, 0.200);
+        assert_eq!(t.register, None);
+        assert_eq!(t.op, I2cOperation::Write(vec![0x01, 0x80]));
+        assert_eq!(t.result, None);
+
+        let t = &transactions[2];
+        assert_eq!(t.address, 0x49);
+        assert_eq!(t.register, None);
+        assert_eq!(t.op, I2cOperation::Read(Some(1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn saleae_eightbit() -> Result<()> {
+        let csv = "\
+Time [s],Packet ID,Address,Data,Read/Write,ACK/NAK
+0.100,0,0x90,0x0B,Write,ACK
+0.101,0,0x91,0xCB,Read,NAK
+";
+
+        let transactions = ingest_saleae(csv)?;
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].address, 0x48);
+        assert_eq!(transactions[0].register, Some(0x0b));
+
+        Ok(())
+    }
+
+    #[test]
+    fn saleae_bad() {
+        assert!(ingest_saleae("Time [s],Data\n0.1,0x0\n").is_err());
+
+        let csv = "\
+Time [s],Packet ID,Address,Data,Read/Write,ACK/NAK
+0.100,0,0x48,bogus,Write,ACK
+";
+        assert!(ingest_saleae(csv).is_err());
+    }
+
+    #[test]
+    fn aardvark() -> Result<()> {
+        let xml = r#"<aardvark>
+  <configure i2c="1" spi="1" gpio="0" tpower="1" pullups="0"/>
+  <i2c_bitrate khz="100"/>
+  <i2c_write addr="0x48" count="1" radix="16">0b</i2c_write>
+  <i2c_read addr="0x48" count="2"/>
+  <sleep ms="10"/>
+  <i2c_write addr="0x48" count="2" radix="16">01 80</i2c_write>
+  <i2c_read addr="0x49" count="1"/>
+</aardvark>
+"#;
+
+        let transactions = ingest_aardvark(xml)?;
+        assert_eq!(transactions.len(), 3);
+
+        let t = &transactions[0];
+        assert_eq!(t.time, 0.0);
+        assert_eq!(t.address, 0x48);
+        assert_eq!(t.register, Some(0x0b));
+        assert_eq!(t.op, I2cOperation::Read(Some(2)));
+        assert_eq!(t.result, None);
+
+        let t = &transactions[1];
+        assert_eq!(t.time, 0.010);
+        assert_eq!(t.register, None);
+        assert_eq!(t.op, I2cOperation::Write(vec![0x01, 0x80]));
+
+        let t = &transactions[2];
+        assert_eq!(t.address, 0x49);
+        assert_eq!(t.op, I2cOperation::Read(Some(1)));
+        assert_eq!(t.result, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn aardvark_bad() {
+        assert!(ingest_aardvark("<beagle/>").is_err());
+        assert!(ingest_aardvark(
+            r#"<aardvark><i2c_read addr="0x48" count="300"/></aardvark>"#
+        )
+        .is_err());
+        assert!(ingest_aardvark(
+            r#"<aardvark><i2c_write addr="0x48">zz</i2c_write></aardvark>"#
+        )
+        .is_err());
+    }
+}
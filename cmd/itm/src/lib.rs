@@ -31,6 +31,15 @@
 //! Task #7 Divide-by-zero
 //! ```
 //!
+//! ITM data can also be ingested from a capture via `--ingest` (`-i`).  The
+//! capture can be a file containing either the raw SWO byte stream or a CSV
+//! export of an asynchronous serial analyzer from Saleae Logic (with the
+//! format detected automatically, or specified with a `raw:` or `saleae:`
+//! prefix), or a TCP socket from an external SWO receiver, specified as
+//! `tcp:<host>:<port>`.  If the TPIU was bypassed when the data was captured,
+//! specify `--bypass` (`-b`).  The same sources can be ingested by `humility
+//! trace` and `humility test`.
+//!
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
@@ -44,10 +53,8 @@ use humility_cortex::debug::*;
 use humility_cortex::dwt::*;
 use humility_cortex::itm::*;
 use humility_cortex::scs::*;
+use humility_cortex::swv::*;
 use humility_cortex::tpiu::*;
-use std::fs::File;
-use std::io::Read;
-use std::time::Instant;
 
 const ITM_TRACEID_MAX: u8 = 0x7f;
 
@@ -75,9 +82,10 @@ struct ItmArgs {
     )]
     traceid: u8,
 
-    /// ingest ITM data as CSV
-    #[clap(long, short, value_name = "filename")]
-    ingest: Option<String>,
+    /// ingest ITM data from a capture: a file (either raw or a Saleae CSV
+    /// export), "raw:<file>", "saleae:<file>" or "tcp:<host>:<port>"
+    #[clap(long, short, value_name = "source")]
+    ingest: Option<SWVSource>,
 
     /// ingest directly from attached device
     #[clap(long, short, conflicts_with_all = &["disable", "ingest"])]
@@ -150,63 +158,24 @@ fn itmcmd_disable(core: &mut dyn Core) -> Result<()> {
     Ok(())
 }
 
-fn itmcmd_ingest(subargs: &ItmArgs, filename: &str) -> Result<()> {
-    let file = File::open(filename)?;
+fn itmcmd_ingest(subargs: &ItmArgs, source: &SWVSource) -> Result<()> {
+    let mut stream = SWVStream::open(source)?;
     let traceid = if subargs.bypass { None } else { Some(subargs.traceid) };
 
-    let process = |packet: &ITMPacket| -> Result<()> {
-        if let ITMPayload::Instrumentation { payload, .. } = &packet.payload {
-            for p in payload {
-                print!("{}", *p as char);
+    itm_ingest(
+        traceid,
+        || stream.next(None),
+        |packet| {
+            if let ITMPayload::Instrumentation { payload, .. } = &packet.payload
+            {
+                for p in payload {
+                    print!("{}", *p as char);
+                }
             }
-        }
-
-        Ok(())
-    };
 
-    let mut rdr = csv::Reader::from_reader(file);
-
-    match rdr.headers() {
-        Ok(_hdr) => {
-            type SaleaeTraceRecord = (f64, u8, Option<String>, Option<String>);
-            let mut iter = rdr.deserialize();
-
-            itm_ingest(
-                traceid,
-                || {
-                    if let Some(line) = iter.next() {
-                        let record: SaleaeTraceRecord = line?;
-                        Ok(Some((record.1, record.0)))
-                    } else {
-                        Ok(None)
-                    }
-                },
-                process,
-            )
-        }
-        Err(_) => {
-            humility::msg!("not a Saleae trace file; assuming raw input");
-
-            let mut file = File::open(filename)?;
-            let mut buffer = [0; 1];
-
-            itm_ingest(
-                traceid,
-                || {
-                    let nbytes = file.read(&mut buffer)?;
-
-                    match nbytes {
-                        1 => Ok(Some((buffer[0], 0.0))),
-                        0 => Ok(None),
-                        _ => {
-                            panic!("illegal read");
-                        }
-                    }
-                },
-                process,
-            )
-        }
-    }
+            Ok(())
+        },
+    )
 }
 
 fn itmcmd_ingest_attached(
@@ -214,8 +183,7 @@ fn itmcmd_ingest_attached(
     coreinfo: &CoreInfo,
     subargs: &ItmArgs,
 ) -> Result<()> {
-    let mut bytes: Vec<u8> = vec![];
-    let mut ndx = 0;
+    let mut stream = SWVStream::open(&SWVSource::Probe)?;
 
     let traceid = if coreinfo.address(CoreSightComponent::SWO).is_some() {
         None
@@ -223,18 +191,9 @@ fn itmcmd_ingest_attached(
         Some(subargs.traceid)
     };
 
-    let start = Instant::now();
-
     itm_ingest(
         traceid,
-        || {
-            while ndx == bytes.len() {
-                bytes = core.read_swv()?;
-                ndx = 0;
-            }
-            ndx += 1;
-            Ok(Some((bytes[ndx - 1], start.elapsed().as_secs_f64())))
-        },
+        || stream.next(Some(&mut *core)),
         |packet| {
             if let ITMPayload::Instrumentation { payload, port } =
                 &packet.payload
@@ -268,6 +227,10 @@ fn itmcmd(context: &mut humility::ExecutionContext) -> Result<()> {
     }
 
     if let Some(ingest) = &subargs.ingest {
+        if ingest.is_probe() {
+            bail!("to ingest from the attached device, use --attach");
+        }
+
         match itmcmd_ingest(subargs, ingest) {
             Err(e) => {
                 bail!("failed to ingest {}: {}", ingest, e);
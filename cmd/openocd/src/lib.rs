@@ -9,13 +9,15 @@
 //! If the intention is to only run GDB, note that `humility gdb --run-openocd`
 //! will both run OpenOCD and run a foreground GDB that is connected to it.
 //!
-
-use std::process::Command;
+//! If the target in the environment file has an `openocd` object, its
+//! `exec`, `config` and `args` members override the OpenOCD executable, the
+//! config file in the archive, and add arguments, respectively.
+//!
 
 use humility::cli::{Cli, Subcommand};
 use humility_cmd::{Archive, Command as HumilityCmd};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 use clap::{Command as ClapCommand, CommandFactory, Parser};
 
 #[derive(Parser, Debug)]
@@ -94,25 +96,9 @@ fn openocd(context: &mut humility::ExecutionContext) -> Result<()> {
     let subargs = OcdArgs::try_parse_from(subargs)?;
     let serial = get_probe_serial(&context.cli, subargs.serial.clone())?;
 
-    let hubris = context.archive.as_ref().unwrap();
-
     let work_dir = tempfile::tempdir()?;
-    hubris
-        .extract_file_to(
-            "debug/openocd.cfg",
-            &work_dir.path().join("openocd.cfg"),
-        )
-        .context("OpenOCD config missing. Is your Hubris build too old?")?;
     let mut cmd =
-        Command::new(subargs.exec.unwrap_or_else(|| "openocd".to_string()));
-    cmd.arg("-f").arg("openocd.cfg");
-    if let Some(serial) = serial {
-        cmd.arg("-c")
-            .arg("interface hla")
-            .arg("-c")
-            .arg(format!("hla_serial {}", serial));
-    }
-    cmd.current_dir(work_dir.path());
+        humility_cmd::openocd(context, subargs.exec, serial, work_dir.path())?;
 
     for opt in subargs.extra_options {
         cmd.arg(opt);
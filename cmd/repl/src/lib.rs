@@ -15,7 +15,7 @@
 //! ```console
 //! $ humility -a ../path/to/hubris/archive.zip repl
 //! humility: attached via ST-Link V2-1
-//! Welcome to the humility REPL! Try out some subcommands, or 'quit' to quit!
+//! Welcome to the humility REPL! Try out some subcommands, 'help' for help, or 'quit' to quit!
 //! humility> tasks
 //! system time = 7209837
 //! ID TASK                 GEN PRI STATE
@@ -49,7 +49,23 @@
 //! in the background; your code is still running in the background while you
 //! use the repl!
 //!
-//! Finally, as you can see, `quit` will quit the repl. There is also a `history`
-//! command, which will show you recent commands you've put into the prompt.
+//! Finally, as you can see, `quit` will quit the repl.  Other commands are
+//! also handled by the repl itself rather than by a subcommand:
 //!
-//! The repl is still very early days! We'll be adding more features in the future.
+//! - `help`: list these commands
+//! - `history`: show recent commands you've put into the prompt
+//! - `attach [probe]`: attach (or re-attach) to the target, optionally via a
+//!   different probe
+//! - `detach`: detach from the target, allowing another program to use the
+//!   probe; the next subcommand that needs the target will attach again
+//! - `archive <file>`: load a different archive, detaching from the target
+//!
+//! If a subcommand fails (or its arguments are invalid), the error is
+//! displayed and the repl continues, still attached.
+//!
+//! Tab completes subcommand names as the first word, a subcommand's flags
+//! for a word beginning with `-`, and otherwise task names, variable names
+//! and Idol operations (as `Interface.operation`) from the archive.
+//!
+//! History is kept across sessions in `~/.humility_history`, or in the file
+//! named by the `HUMILITY_HISTORY` environment variable.
@@ -50,6 +50,28 @@
 //! ...
 //! ```
 //!
+//! To follow ring buffers as they are written, use `-f` (`--follow`).  In
+//! this mode, the current contents of each ring buffer are displayed, and
+//! the ring buffers are then polled (every 100 milliseconds by default; see
+//! `-d` (`--delay`)), displaying only the entries that are new.  Each entry
+//! is prefixed with the time (in seconds since following began) at which it
+//! was seen, and with the ring buffer that contains it.  If an entry is
+//! repeated (which the ring buffer records by incrementing the count of the
+//! existing entry), it is displayed again with a count of the number of new
+//! repetitions.  If a ring buffer wraps between polls, entries may be lost,
+//! and a message is displayed indicating as much:
+//!
+//! ```console
+//! % humility ringbuf -f i2c
+//! humility: attached via ST-Link V3
+//!       TIME BUFFER                NDX LINE      GEN    COUNT PAYLOAD
+//!      0.000 I2C_DRIVER_RINGBUF     12  301       41        1 Write(0x48, 0x1)
+//!      0.000 I2C_DRIVER_RINGBUF     13  311       41        3 Read(0x48)
+//!      1.204 I2C_DRIVER_RINGBUF     13  311       41        2 Read(0x48)
+//!      1.204 I2C_DRIVER_RINGBUF     14  301       41        1 Write(0x4a, 0x1)
+//! ...
+//! ```
+//!
 //! See the [`ringbuf`
 //! documentation](https://github.com/oxidecomputer/hubris/blob/master/lib/ringbuf/src/lib.rs) for more details.
 
@@ -59,9 +81,12 @@ use clap::{CommandFactory, Parser};
 use humility::cli::Subcommand;
 use humility::core::Core;
 use humility::hubris::*;
-use humility::reflect::{self, Format, Load, Value};
-use humility_cmd::doppel::{Ringbuf, StaticCell};
+use humility::reflect::Format;
+use humility_cmd::doppel::{Ringbuf, RingbufEntry};
+use humility_cmd::ringbuf::RingbufVariable;
 use humility_cmd::{Archive, Attach, Command, Validate};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 #[clap(name = "ringbuf", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -69,56 +94,103 @@ struct RingbufArgs {
     /// list variables
     #[clap(long, short)]
     list: bool,
+    /// follow ring buffers, printing new entries as they appear
+    #[clap(long, short, conflicts_with = "list")]
+    follow: bool,
+    /// how long to delay in ms between polling ring buffers
+    #[clap(
+        long,
+        short,
+        default_value = "100",
+        requires = "follow",
+        conflicts_with = "list"
+    )]
+    delay: u64,
     /// print only a single ringbuffer by substring of name
     #[clap(conflicts_with = "list")]
     name: Option<String>,
 }
 
+///
+/// The contents of a ring buffer.
+///
+#[derive(Clone, Debug)]
+pub struct RingbufContents {
+    /// Name of the ring buffer
+    pub name: String,
+
+    /// Name of the task (or kernel) containing the ring buffer, if known
+    pub task: Option<String>,
+
+    /// True if the ring buffer has ever been written
+    pub written: bool,
+
+    /// The occupied entries of the ring buffer (along with their slot),
+    /// oldest first
+    pub entries: Vec<(usize, RingbufEntry)>,
+}
+
+///
+/// Returns the ring buffers in the archive, optionally restricted to those
+/// whose name (or containing task's name) contains the specified substring.
+///
+pub fn select<'a>(
+    hubris: &'a HubrisArchive,
+    name: Option<&str>,
+) -> Result<Vec<RingbufVariable<'a>>> {
+    let mut ringbufs = vec![];
+
+    for r in humility_cmd::ringbuf::ringbufs(hubris) {
+        if let Some(name) = name {
+            if r.name.contains(name) || r.taskname(hubris)?.contains(name) {
+                ringbufs.push(r);
+            }
+        } else {
+            ringbufs.push(r);
+        }
+    }
+
+    Ok(ringbufs)
+}
+
+///
+/// Reads the contents of a ring buffer.
+///
+pub fn read(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ringbuf: &RingbufVariable,
+) -> Result<RingbufContents> {
+    let contents = ringbuf.read(hubris, core)?;
+
+    Ok(RingbufContents {
+        name: ringbuf.name.to_string(),
+        task: ringbuf.taskname(hubris).ok().map(str::to_string),
+        written: contents.last.is_some(),
+        entries: contents
+            .entries()
+            .into_iter()
+            .map(|(slot, entry)| (slot, entry.clone()))
+            .collect(),
+    })
+}
+
 fn ringbuf_dump(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    definition: &HubrisStruct,
-    ringbuf_var: &HubrisVariable,
+    ringbuf: &RingbufVariable,
 ) -> Result<()> {
-    let mut buf: Vec<u8> = vec![];
-    buf.resize_with(ringbuf_var.size, Default::default);
-
-    core.op_start()?;
-    core.read_8(ringbuf_var.addr, buf.as_mut_slice())?;
-    core.op_done()?;
-
-    // There are two possible shapes of ringbufs, depending on the age of the
-    // firmware.
-    // - Raw Ringbuf that is not wrapped by anything.
-    // - Safe Ringbuf that is inside a StaticCell.
-    //
-    // Here we will attempt to handle them both -- first raw, then fallback.
-    let ringbuf_val: Value =
-        Value::Struct(reflect::load_struct(hubris, &buf, definition, 0)?);
-
-    let ringbuf: Ringbuf = Ringbuf::from_value(&ringbuf_val).or_else(|_e| {
-        let cell: StaticCell = StaticCell::from_value(&ringbuf_val)?;
-        Ringbuf::from_value(&cell.cell.value)
-    })?;
-
-    let ndx = if let Some(x) = ringbuf.last {
-        x as usize
-    } else {
+    let ringbuf = read(hubris, core, ringbuf)?;
+
+    if !ringbuf.written {
         return Ok(());
-    };
+    }
 
     let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
 
     println!("{:>4} {:>4} {:>8} {:>8} PAYLOAD", "NDX", "LINE", "GEN", "COUNT",);
 
-    for i in 0..ringbuf.buffer.len() {
-        let slot = (ndx + i + 1) % ringbuf.buffer.len();
-        let entry = &ringbuf.buffer[slot];
-
-        if entry.generation == 0 {
-            continue;
-        }
-
+    for (slot, entry) in ringbuf.entries {
         let mut dumped = vec![];
         entry.payload.format(hubris, fmt, &mut dumped)?;
         let dumped = String::from_utf8(dumped)?;
@@ -132,54 +204,164 @@ fn ringbuf_dump(
     Ok(())
 }
 
-fn taskname<'a>(
-    hubris: &'a HubrisArchive,
-    variable: &'a HubrisVariable,
-) -> Result<&'a str> {
-    Ok(&hubris.lookup_module(HubrisTask::from(variable.goff))?.name)
+//
+// The state of a ring buffer that we are following:  the line, generation
+// and count of each slot -- and the slot that was last written -- as of our
+// last read.
+//
+struct Followed<'a> {
+    ringbuf: RingbufVariable<'a>,
+    slots: Option<Vec<(u16, u16, u32)>>,
+    last: Option<usize>,
 }
 
-// this allow is meant for the header println! in the body but you cannot apply
-// an attribute to a macro invoction, so we have to put it here instead.
-#[allow(clippy::print_literal)]
-fn ringbuf(context: &mut humility::ExecutionContext) -> Result<()> {
-    let core = &mut **context.core.as_mut().unwrap();
-    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
-    let hubris = context.archive.as_ref().unwrap();
-
-    let subargs = RingbufArgs::try_parse_from(subargs)?;
+impl<'a> Followed<'a> {
+    ///
+    /// Determines the entries that are new since our last read, along with
+    /// the number of new occurrences of each, and a boolean indicating
+    /// whether entries may have been lost.
+    ///
+    fn update(&mut self, ringbuf: &Ringbuf) -> (Vec<(usize, u32)>, bool) {
+        let mut rval = vec![];
+        let mut lost = false;
 
-    let mut ringbufs = vec![];
+        let slots = ringbuf
+            .buffer
+            .iter()
+            .map(|e| (e.line, e.generation, e.count))
+            .collect::<Vec<_>>();
 
-    for v in hubris.qualified_variables() {
-        let def = match hubris.lookup_struct(v.1.goff) {
-            Ok(s) => {
-                // Skip variables whose type does not indicate they contain a
-                // ringbuf; this check is imprecise but probably good enough
-                if s.name.contains("Ringbuf") {
-                    Some(s)
-                } else {
-                    continue;
+        for (slot, entry) in ringbuf.entries() {
+            let count = match self.slots.as_ref().and_then(|s| s.get(slot)) {
+                Some(&(line, generation, count))
+                    if line == entry.line
+                        && generation == entry.generation
+                        && entry.count >= count =>
+                {
+                    //
+                    // This is the same entry that we saw before; if its
+                    // count has increased, it has been repeated.
+                    //
+                    entry.count - count
                 }
+
+                //
+                // Otherwise, the slot has been rewritten -- including the
+                // case where its count has gone backwards, as happens when
+                // the target is reset and records the same entry anew.
+                //
+                _ => entry.count,
+            };
+
+            if count != 0 {
+                rval.push((slot, count));
+            }
+        }
+
+        //
+        // If the slot that was last written when we last read has itself
+        // been overwritten, the ring buffer has wrapped and we may have
+        // missed entries.
+        //
+        if let (Some(previous), Some(last)) = (&self.slots, self.last) {
+            if let (Some(p), Some(c)) = (previous.get(last), slots.get(last)) {
+                lost = p.0 != c.0 || p.1 != c.1;
             }
-            Err(_) => {
-                // Type lookup failed, so fall back to the variable name
-                if v.0.ends_with("RINGBUF") {
-                    None
-                } else {
+        }
+
+        self.last = ringbuf.last.map(|last| last as usize);
+        self.slots = Some(slots);
+        (rval, lost)
+    }
+}
+
+fn ringbuf_follow(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ringbufs: Vec<RingbufVariable>,
+    subargs: &RingbufArgs,
+) -> Result<()> {
+    if core.is_dump() {
+        bail!("can only follow ring buffers on a live system");
+    }
+
+    let fmt = HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+    let start = Instant::now();
+    let delay = Duration::from_millis(subargs.delay);
+
+    let mut followed = ringbufs
+        .into_iter()
+        .map(|ringbuf| Followed { ringbuf, slots: None, last: None })
+        .collect::<Vec<_>>();
+
+    let width = followed.iter().map(|f| f.ringbuf.name.len()).max().unwrap();
+
+    println!(
+        "{:>10} {:width$} {:>4} {:>4} {:>8} {:>8} PAYLOAD",
+        "TIME",
+        "BUFFER",
+        "NDX",
+        "LINE",
+        "GEN",
+        "COUNT",
+        width = width
+    );
+
+    loop {
+        for f in followed.iter_mut() {
+            let ringbuf = match f.ringbuf.read(hubris, core) {
+                Ok(ringbuf) => ringbuf,
+                Err(e) => {
+                    humility::msg!("failed to read {}: {}", f.ringbuf.name, e);
                     continue;
                 }
+            };
+
+            let time = start.elapsed().as_secs_f64();
+            let (entries, lost) = f.update(&ringbuf);
+
+            if lost {
+                humility::msg!(
+                    "{} wrapped; entries may have been lost (try a shorter delay)",
+                    f.ringbuf.name
+                );
             }
-        };
 
-        if let Some(ref name) = subargs.name {
-            if v.0.contains(name) || taskname(hubris, v.1)?.contains(name) {
-                ringbufs.push((v, def));
+            for (slot, count) in entries {
+                let entry = &ringbuf.buffer[slot];
+                let mut dumped = vec![];
+                entry.payload.format(hubris, fmt, &mut dumped)?;
+                let dumped = String::from_utf8(dumped)?;
+
+                println!(
+                    "{:10.3} {:width$} {:4} {:4} {:8} {:8} {}",
+                    time,
+                    f.ringbuf.name,
+                    slot,
+                    entry.line,
+                    entry.generation,
+                    count,
+                    dumped,
+                    width = width
+                );
             }
-        } else {
-            ringbufs.push((v, def));
         }
+
+        thread::sleep(delay);
     }
+}
+
+// this allow is meant for the header println! in the body but you cannot apply
+// an attribute to a macro invoction, so we have to put it here instead.
+#[allow(clippy::print_literal)]
+fn ringbuf(context: &mut humility::ExecutionContext) -> Result<()> {
+    let core = &mut **context.core.as_mut().unwrap();
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let hubris = context.archive.as_ref().unwrap();
+
+    let subargs = RingbufArgs::try_parse_from(subargs)?;
+
+    let ringbufs = select(hubris, subargs.name.as_deref())?;
 
     if ringbufs.is_empty() {
         if let Some(name) = subargs.name {
@@ -189,34 +371,34 @@ fn ringbuf(context: &mut humility::ExecutionContext) -> Result<()> {
         }
     }
 
-    ringbufs.sort_by_key(|&(v, _def)| v);
-
     if subargs.list {
         println!("{:18} {:<30} {:<10} {}", "MODULE", "BUFFER", "ADDR", "SIZE");
 
-        for (v, _def) in ringbufs {
-            let t = taskname(hubris, v.1)?;
+        for r in ringbufs {
+            let t = r.taskname(hubris)?;
+            let v = r.variable;
 
-            println!("{:18} {:<30} 0x{:08x} {:<}", t, v.0, v.1.addr, v.1.size);
+            println!("{:18} {:<30} 0x{:08x} {:<}", t, r.name, v.addr, v.size);
         }
 
         return Ok(());
     }
 
-    for (v, def) in ringbufs {
+    if subargs.follow {
+        return ringbuf_follow(hubris, core, ringbufs, &subargs);
+    }
+
+    for r in ringbufs {
         // Try not to use `?` here, because it causes one bad ringbuf to make
         // them all unavailable.
         println!(
             "humility: ring buffer {} in {}:",
-            v.0,
-            taskname(hubris, v.1).unwrap_or("???")
+            r.name,
+            r.taskname(hubris).unwrap_or("???")
         );
-        if let Some(def) = def {
-            if let Err(e) = ringbuf_dump(hubris, core, def, v.1) {
-                humility::msg!("ringbuf dump failed: {}", e);
-            }
-        } else {
-            humility::msg!("could not look up type: {:?}", v.1.goff);
+
+        if let Err(e) = ringbuf_dump(hubris, core, &r) {
+            humility::msg!("ringbuf dump failed: {}", e);
         }
     }
 
@@ -235,3 +417,120 @@ pub fn init() -> (Command, ClapCommand<'static>) {
         RingbufArgs::command(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use humility::reflect::{Base, Value};
+
+    fn ringbuf(last: Option<u32>, slots: &[(u16, u16, u32)]) -> Ringbuf {
+        Ringbuf {
+            last,
+            buffer: slots
+                .iter()
+                .map(|&(line, generation, count)| RingbufEntry {
+                    line,
+                    generation,
+                    count,
+                    payload: Value::Base(Base::U0),
+                })
+                .collect(),
+        }
+    }
+
+    fn follow(
+        variable: &HubrisVariable,
+        reads: &[(Option<u32>, &[(u16, u16, u32)])],
+    ) -> Vec<(Vec<(usize, u32)>, bool)> {
+        let mut followed = Followed {
+            ringbuf: RingbufVariable {
+                name: "TEST_RINGBUF",
+                variable,
+                definition: None,
+            },
+            slots: None,
+            last: None,
+        };
+
+        reads
+            .iter()
+            .map(|(last, slots)| followed.update(&ringbuf(*last, slots)))
+            .collect()
+    }
+
+    fn variable() -> HubrisVariable {
+        HubrisVariable {
+            goff: HubrisGoff { object: 0, goff: 0 },
+            addr: 0,
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let updates = follow(
+            &variable(),
+            &[
+                (Some(0), &[(10, 1, 1), (0, 0, 0), (0, 0, 0)]),
+                (Some(0), &[(10, 1, 3), (0, 0, 0), (0, 0, 0)]),
+                (Some(0), &[(10, 1, 3), (0, 0, 0), (0, 0, 0)]),
+                (Some(1), &[(10, 1, 3), (20, 2, 1), (0, 0, 0)]),
+            ],
+        );
+
+        assert_eq!(
+            updates,
+            [
+                (vec![(0, 1)], false),
+                (vec![(0, 2)], false),
+                (vec![], false),
+                (vec![(1, 1)], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraparound() {
+        let updates = follow(
+            &variable(),
+            &[
+                (Some(1), &[(10, 1, 1), (20, 2, 1), (0, 0, 0)]),
+                (Some(0), &[(30, 4, 1), (20, 2, 1), (40, 3, 1)]),
+                (Some(1), &[(50, 7, 1), (60, 8, 1), (70, 6, 1)]),
+            ],
+        );
+
+        assert_eq!(
+            updates,
+            [
+                (vec![(0, 1), (1, 1)], false),
+                //
+                // Entries are returned oldest first; the slot that was last
+                // written hasn't been overwritten, so nothing was lost.
+                //
+                (vec![(2, 1), (0, 1)], false),
+                //
+                // Here the slot that was last written has been overwritten.
+                //
+                (vec![(2, 1), (0, 1), (1, 1)], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let updates = follow(
+            &variable(),
+            &[
+                (Some(0), &[(10, 1, 5), (0, 0, 0)]),
+                (Some(0), &[(10, 1, 2), (0, 0, 0)]),
+                (None, &[(0, 0, 0), (0, 0, 0)]),
+            ],
+        );
+
+        assert_eq!(
+            updates,
+            [(vec![(0, 5)], false), (vec![(0, 2)], false), (vec![], false)]
+        );
+    }
+}
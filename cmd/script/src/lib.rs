@@ -0,0 +1,63 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility script`
+//!
+//! `humility script` runs a [Rhai](https://rhai.rs) script against a single
+//! attached session, allowing multi-step procedures to be written without
+//! re-attaching to the target (or re-loading the archive) for each step.
+//! Any arguments following the script are available to it as the `ARGS`
+//! array (use `--` to pass arguments that begin with `-`).  In addition to
+//! the Rhai standard library, the following functions are available:
+//!
+//! - `read_word(addr)`: read a 32-bit word
+//! - `read_bytes(addr, len)`: read `len` bytes (at most 64 KiB) as a blob
+//! - `write_word(addr, val)`: write a 32-bit word
+//! - `write_bytes(addr, blob)`: write the bytes of a blob
+//! - `halt()`, `run()`, `step()`, `reset()`: control the target
+//! - `tasks()`: the names of the tasks in the archive, in task ID order
+//! - `task_id(name)`: the ID of the named task
+//! - `variable(name)`: the address and size of a variable, as a map
+//! - `readvar(name)`: the value of a variable (see below)
+//! - `idol(op)`, `idol(op, args)`: call the Idol operation named as
+//!   `Interface.operation` via HIF, with arguments specified as a map,
+//!   returning its result (see below)
+//! - `humility(cmd)`: run a Humility subcommand, specified as a string or
+//!   as an array of arguments
+//! - `sleep(ms)`: sleep for the specified number of milliseconds
+//!
+//! Values read from the target (via `readvar` or `idol`) are translated
+//! into Rhai values:  structures become maps, arrays and tuples become
+//! arrays, enum variants without contents become strings, and enum
+//! variants with contents become a map from the variant name to its
+//! contents.  For example:
+//!
+//! ```rhai
+//! // bring-up.rhai: cycle power until the sequencer reaches A0
+//! let state = idol("Sequencer.get_state");
+//!
+//! for attempt in 0..ARGS[0].parse_int() {
+//!     if state == "A0" {
+//!         break;
+//!     }
+//!
+//!     print(`attempt ${attempt}: state is ${state}`);
+//!     idol("Sequencer.set_state", #{ state: "A0" });
+//!     sleep(1000);
+//!     state = idol("Sequencer.get_state");
+//! }
+//!
+//! humility("tasks -sl");
+//! ```
+//!
+//! ```console
+//! % humility script bring-up.rhai 5
+//! humility: attached via ST-Link V3
+//! attempt 0: state is A2
+//! ...
+//! ```
+//!
+//! Errors (including the failure of a Humility subcommand) terminate the
+//! script unless caught with Rhai's `try`/`catch`.
+//!
@@ -20,6 +20,42 @@
 //! By default, `humility sensors` displays the value of each specified sensor
 //! and exits; to read values once per second, use the `-s` (`--sleep`)
 //! option. To print values as a table, use `--tabular`.
+//!
+//! To raise alarms when sensor values go out of bounds, use `-r` (`--rules`)
+//! to specify a TOML file containing rules.  Each rule matches sensors by any
+//! combination of `name`, `kind` and `device` (a rule with none of these
+//! matches every sensor), and specifies one or more conditions:  a `min`
+//! value, a `max` value, a maximum `rate` of change (in units per second),
+//! or a number of consecutive polls for which the sensor is `missing` a
+//! reading.  When a sensor enters alarm, the rule's `actions` are taken in
+//! order:
+//!
+//! - `log`: report the alarm (and its subsequent clearing); this is the
+//!   default if no actions are specified
+//! - `dump`: halt the target, take a dump, and resume it
+//! - `exec:<command>`: execute the named command from the environment
+//!   (see `humility exec`)
+//! - `exit`: exit with a non-zero status
+//!
+//! Alarms are edge-triggered:  actions are only taken when a sensor enters
+//! alarm, and the rule is re-armed once the condition clears.  Rules are
+//! typically used with `-s` (`--sleep`) to poll continuously, e.g.:
+//!
+//! ```toml
+//! [[rule]]
+//! kind = "temp"
+//! max = 85.0
+//! rate = 5.0
+//! actions = ["log", "dump"]
+//!
+//! [[rule]]
+//! name = "V12_SYS_A2"
+//! kind = "voltage"
+//! min = 11.4
+//! max = 12.6
+//! missing = 3
+//! actions = ["log", "exec:power.off", "exit"]
+//! ```
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
@@ -27,6 +63,7 @@ use clap::{CommandFactory, Parser};
 use hif::*;
 use humility::cli::Subcommand;
 use humility::core::Core;
+use humility::env::Environment;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::idol;
@@ -35,6 +72,9 @@ use std::collections::HashSet;
 use std::thread;
 use std::time::Duration;
 
+mod rules;
+use rules::{Action, Alarm, Rules, Transition};
+
 #[derive(Parser, Debug)]
 #[clap(name = "sensors", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct SensorsArgs {
@@ -78,30 +118,56 @@ struct SensorsArgs {
         use_value_delimiter = true
     )]
     named: Option<Vec<String>>,
+
+    /// evaluate sensor values against rules in the specified TOML file
+    #[clap(long, short, value_name = "file", conflicts_with = "list")]
+    rules: Option<String>,
 }
 
-fn list(
-    hubris: &HubrisArchive,
+///
+/// A reading of a single sensor.
+///
+#[derive(Clone, Debug)]
+pub struct SensorReading {
+    /// Sensor ID
+    pub id: usize,
+
+    /// Name of the sensor
+    pub name: String,
+
+    /// Kind of the sensor
+    pub kind: HubrisSensorKind,
+
+    /// Name of the device containing the sensor
+    pub device: String,
+
+    /// The value read, if the sensor could be read
+    pub value: Option<f32>,
+}
+
+///
+/// Returns the sensors (along with their IDs) matching the specified types,
+/// devices and names; each of these that is `None` matches every sensor.
+///
+pub fn select<'a>(
+    hubris: &'a HubrisArchive,
     types: &Option<HashSet<HubrisSensorKind>>,
     devices: &Option<HashSet<&String>>,
     named: &Option<HashSet<&String>>,
-) -> Result<()> {
-    println!(
-        "{:2} {:<7} {:2} {:2} {:3} {:4} {:13} {:4}",
-        "ID", "KIND", "C", "P", "MUX", "ADDR", "DEVICE", "NAME"
-    );
+) -> Vec<(usize, &'a HubrisSensor)> {
+    let mut sensors = vec![];
 
-    for (ndx, s) in hubris.manifest.sensors.iter().enumerate() {
+    for (i, s) in hubris.manifest.sensors.iter().enumerate() {
         if let Some(types) = types {
             if types.get(&s.kind).is_none() {
                 continue;
             }
         }
 
-        let device = &hubris.manifest.i2c_devices[s.device];
-
         if let Some(devices) = devices {
-            if devices.get(&device.device).is_none() {
+            let d = &hubris.manifest.i2c_devices[s.device];
+
+            if devices.get(&d.device).is_none() {
                 continue;
             }
         }
@@ -112,6 +178,136 @@ fn list(
             }
         }
 
+        sensors.push((i, s));
+    }
+
+    sensors
+}
+
+///
+/// Reads a set of sensors via the `sensor` task.  The HIF operations to do
+/// this are assembled once, allowing the sensors to be read repeatedly.
+///
+pub struct SensorReader<'a> {
+    sensors: Vec<(usize, &'a HubrisSensor)>,
+    ops: Vec<Vec<Op>>,
+}
+
+impl<'a> SensorReader<'a> {
+    pub fn new(
+        hubris: &'a HubrisArchive,
+        context: &mut HiffyContext,
+        sensors: Vec<(usize, &'a HubrisSensor)>,
+    ) -> Result<Self> {
+        let mut all_ops = vec![];
+        let funcs = context.functions()?;
+        let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)
+            .context("is the 'sensor' task present?")?;
+
+        let ok = hubris.lookup_basetype(op.ok)?;
+
+        if ok.encoding != HubrisEncoding::Float {
+            bail!("expected return value of read_sensors() to be a float");
+        }
+
+        if ok.size != 4 {
+            bail!("expected return value of read_sensors() to be an f32");
+        }
+
+        if hubris.manifest.sensors.is_empty() {
+            bail!("no sensors found");
+        }
+
+        for s in sensors.chunks(100) {
+            let mut ops = vec![];
+
+            for (i, _) in s {
+                let payload = op.payload(&[(
+                    "id",
+                    idol::IdolArgument::Scalar(*i as u64),
+                )])?;
+                context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+            }
+
+            ops.push(Op::Done);
+            all_ops.push(ops);
+        }
+
+        Ok(Self { sensors, ops: all_ops })
+    }
+
+    /// Returns the sensors to be read (along with their IDs)
+    pub fn sensors(&self) -> &[(usize, &'a HubrisSensor)] {
+        &self.sensors
+    }
+
+    ///
+    /// Reads the sensors, returning their values in the same order as
+    /// [`SensorReader::sensors`]; a sensor that could not be read has a
+    /// value of `None`.
+    ///
+    pub fn read(
+        &self,
+        core: &mut dyn Core,
+        context: &mut HiffyContext,
+    ) -> Result<Vec<Option<f32>>> {
+        let mut rval = vec![];
+
+        for ops in &self.ops {
+            let results = context.run(core, ops.as_slice(), None)?;
+
+            for r in results {
+                if let Ok(val) = r {
+                    rval.push(Some(f32::from_le_bytes(val[0..4].try_into()?)));
+                } else {
+                    rval.push(None);
+                }
+            }
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Reads the sensors, returning a reading for each.
+    ///
+    pub fn readings(
+        &self,
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+        context: &mut HiffyContext,
+    ) -> Result<Vec<SensorReading>> {
+        let values = self.read(core, context)?;
+
+        Ok(self
+            .sensors
+            .iter()
+            .zip(values)
+            .map(|((id, s), value)| SensorReading {
+                id: *id,
+                name: s.name.clone(),
+                kind: s.kind,
+                device: hubris.manifest.i2c_devices[s.device].device.clone(),
+                value,
+            })
+            .collect())
+    }
+}
+
+fn list(
+    hubris: &HubrisArchive,
+    types: &Option<HashSet<HubrisSensorKind>>,
+    devices: &Option<HashSet<&String>>,
+    named: &Option<HashSet<&String>>,
+) -> Result<()> {
+    println!(
+        "{:2} {:<7} {:2} {:2} {:3} {:4} {:13} {:4}",
+        "ID", "KIND", "C", "P", "MUX", "ADDR", "DEVICE", "NAME"
+    );
+
+    for (ndx, s) in select(hubris, types, devices, named) {
+        let device = &hubris.manifest.i2c_devices[s.device];
+
         let mux = match (device.mux, device.segment) {
             (Some(m), Some(s)) => format!("{}:{}", m, s),
             (None, None) => "-".to_string(),
@@ -134,81 +330,90 @@ fn list(
     Ok(())
 }
 
-fn print(
+fn alarm(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    subargs: &SensorsArgs,
-    context: &mut HiffyContext,
-    types: &Option<HashSet<HubrisSensorKind>>,
-    devices: &Option<HashSet<&String>>,
-    named: &Option<HashSet<&String>>,
+    environment: Option<(&Environment, &str)>,
+    transition: &Transition,
 ) -> Result<()> {
-    let mut all_ops = vec![];
-    let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)
-        .context("is the 'sensor' task present?")?;
-
-    let ok = hubris.lookup_basetype(op.ok)?;
+    let (alarm, raised) = match transition {
+        Transition::Raised(alarm) => (alarm, true),
+        Transition::Cleared(alarm) => (alarm, false),
+    };
 
-    if ok.encoding != HubrisEncoding::Float {
-        bail!("expected return value of read_sensors() to be a float");
-    }
+    let Alarm { sensor, description, actions } = alarm;
+    let what = format!("{} ({})", sensor.name, sensor.kind.to_string());
 
-    if ok.size != 4 {
-        bail!("expected return value of read_sensors() to be an f32");
-    }
+    if !raised {
+        if actions.contains(&Action::Log) {
+            humility::msg!("{}: alarm {}", what, description);
+        }
 
-    if hubris.manifest.sensors.is_empty() {
-        bail!("no sensors found");
+        return Ok(());
     }
 
-    let mut sensors = vec![];
-
-    for (i, s) in hubris.manifest.sensors.iter().enumerate() {
-        if let Some(types) = types {
-            if types.get(&s.kind).is_none() {
-                continue;
+    for action in actions.iter() {
+        match action {
+            Action::Log => {
+                humility::msg!("{}: alarm: {}", what, description);
             }
-        }
-
-        if let Some(devices) = devices {
-            let d = &hubris.manifest.i2c_devices[s.device];
-
-            if devices.get(&d.device).is_none() {
-                continue;
+            Action::Dump => {
+                core.halt()?;
+                humility::msg!("{}: core halted for dump", what);
+                let rval = hubris.dump(core, None);
+                core.run()?;
+                humility::msg!("core resumed");
+                rval?;
             }
-        }
-
-        if let Some(named) = named {
-            if named.get(&s.name).is_none() {
-                continue;
+            Action::Exec(cmd) => {
+                //
+                // We have validated the presence of the environment (and
+                // the command within it) before we started polling.
+                //
+                let (env, target) = environment.unwrap();
+                let status = env.exec(target, cmd)?;
+
+                if !status.success() {
+                    humility::msg!("{}: command \"{}\" failed", what, cmd);
+                }
+            }
+            Action::Exit => {
+                bail!("{}: alarm: {}", what, description);
             }
         }
-
-        sensors.push((i, s));
     }
 
-    for s in sensors.chunks(100) {
-        let mut ops = vec![];
+    Ok(())
+}
 
-        for (i, _) in s {
-            let payload =
-                op.payload(&[("id", idol::IdolArgument::Scalar(*i as u64))])?;
-            context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
-        }
+#[allow(clippy::too_many_arguments)]
+fn print(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &SensorsArgs,
+    context: &mut HiffyContext,
+    types: &Option<HashSet<HubrisSensorKind>>,
+    devices: &Option<HashSet<&String>>,
+    named: &Option<HashSet<&String>>,
+    mut rules: Option<(Rules, Option<(&Environment, &str)>)>,
+) -> Result<()> {
+    let sensors = select(hubris, types, devices, named);
 
-        ops.push(Op::Done);
-        all_ops.push(ops);
+    if let Some((ref rules, _)) = rules {
+        rules.validate(hubris, &sensors)?;
     }
 
+    let reader = SensorReader::new(hubris, context, sensors)?;
+    let sensors = reader.sensors();
+
     if subargs.tabular {
-        for (_, s) in &sensors {
+        for (_, s) in sensors {
             print!(" {:>12}", s.name.to_uppercase());
         }
 
         println!();
 
-        for (_, s) in &sensors {
+        for (_, s) in sensors {
             print!(" {:>12}", s.kind.to_string().to_uppercase());
         }
 
@@ -216,22 +421,10 @@ fn print(
     }
 
     loop {
-        let mut rval = vec![];
-
-        for ops in &all_ops {
-            let results = context.run(core, ops.as_slice(), None)?;
-
-            for r in results {
-                if let Ok(val) = r {
-                    rval.push(Some(f32::from_le_bytes(val[0..4].try_into()?)));
-                } else {
-                    rval.push(None);
-                }
-            }
-        }
+        let rval = reader.read(core, context)?;
 
         if subargs.tabular {
-            for val in rval {
+            for val in &rval {
                 if let Some(val) = val {
                     print!(" {:>12.2}", val);
                 } else {
@@ -252,6 +445,12 @@ fn print(
             }
         }
 
+        if let Some((ref mut rules, environment)) = rules {
+            for transition in rules.evaluate(hubris, sensors, &rval) {
+                alarm(hubris, core, environment, &transition)?;
+            }
+        }
+
         if !subargs.sleep {
             break;
         }
@@ -341,9 +540,57 @@ fn sensors(context: &mut humility::ExecutionContext) -> Result<()> {
         return Ok(());
     }
 
+    let rules = match subargs.rules {
+        Some(ref filename) => {
+            let rules = Rules::load(filename)?;
+
+            let environment = match (&context.environment, &context.cli.target)
+            {
+                (Some(env), Some(target)) => Some((env, target.as_str())),
+                _ => None,
+            };
+
+            for action in rules.actions() {
+                if let Action::Exec(cmd) = action {
+                    match environment {
+                        Some((env, target)) => {
+                            if !env.commands(target)?.contains_key(cmd) {
+                                bail!(
+                                    "rules specify command \"{}\", which is \
+                                    not defined for target {}",
+                                    cmd,
+                                    target
+                                );
+                            }
+                        }
+                        None => {
+                            bail!(
+                                "rules specify command \"{}\", but no \
+                                environment and target have been specified",
+                                cmd
+                            );
+                        }
+                    }
+                }
+            }
+
+            Some((rules, environment))
+        }
+        None => None,
+    };
+
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
 
-    print(hubris, core, &subargs, &mut context, &types, &devices, &named)?;
+    print(
+        hubris,
+        core,
+        &subargs,
+        &mut context,
+        &types,
+        &devices,
+        &named,
+        rules,
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,502 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Rules for sensor values, as loaded from a TOML file.  Each rule matches
+// sensors by any combination of name, kind and device, and specifies the
+// conditions under which a matching sensor is in alarm (a minimum, a
+// maximum, a maximum rate of change in units per second, and a number of
+// consecutive polls without a reading), along with the actions to take when
+// a sensor enters alarm.
+//
+
+use anyhow::{anyhow, bail, Result};
+use humility::hubris::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Instant;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Action {
+    /// Report the alarm (and its clearing)
+    Log,
+    /// Take a dump of the target
+    Dump,
+    /// Execute the named command from the environment
+    Exec(String),
+    /// Exit with a non-zero status
+    Exit,
+}
+
+impl TryFrom<String> for Action {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        Ok(match s.as_str() {
+            "log" => Action::Log,
+            "dump" => Action::Dump,
+            "exit" => Action::Exit,
+            _ => match s.strip_prefix("exec:") {
+                Some(cmd) => Action::Exec(cmd.to_string()),
+                None => bail!(
+                    "unrecognized action \"{}\" (expected \"log\", \"dump\", \
+                    \"exit\" or \"exec:<command>\")",
+                    s
+                ),
+            },
+        })
+    }
+}
+
+fn default_actions() -> Vec<Action> {
+    vec![Action::Log]
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: Option<String>,
+    pub kind: Option<String>,
+    pub device: Option<String>,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub rate: Option<f32>,
+    pub missing: Option<u32>,
+    #[serde(default = "default_actions")]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Default)]
+struct RuleState {
+    last: Option<(f32, Instant)>,
+    missing: u32,
+    alarmed: bool,
+}
+
+pub struct Alarm<'a> {
+    pub sensor: &'a HubrisSensor,
+    pub description: String,
+    pub actions: &'a [Action],
+}
+
+pub enum Transition<'a> {
+    Raised(Alarm<'a>),
+    Cleared(Alarm<'a>),
+}
+
+pub struct Rules {
+    rules: Vec<(Rule, Option<HubrisSensorKind>)>,
+    state: HashMap<(usize, usize), RuleState>,
+}
+
+impl Rules {
+    pub fn load(filename: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(filename)
+            .map_err(|e| anyhow!("failed to read {}: {}", filename, e))?;
+
+        Self::parse(&contents)
+            .map_err(|e| anyhow!("failed to parse {}: {}", filename, e))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let file: RulesFile = toml::from_str(contents)?;
+        let mut rules = vec![];
+
+        for (ndx, rule) in file.rule.into_iter().enumerate() {
+            let kind = match &rule.kind {
+                Some(kind) => match HubrisSensorKind::from_string(kind) {
+                    Some(kind) => Some(kind),
+                    None => {
+                        bail!("rule {}: unrecognized kind \"{}\"", ndx, kind)
+                    }
+                },
+                None => None,
+            };
+
+            if rule.min.is_none()
+                && rule.max.is_none()
+                && rule.rate.is_none()
+                && rule.missing.is_none()
+            {
+                bail!("rule {}: must specify min, max, rate or missing", ndx);
+            }
+
+            rules.push((rule, kind));
+        }
+
+        Ok(Self { rules, state: HashMap::new() })
+    }
+
+    fn matches(
+        hubris: &HubrisArchive,
+        (rule, kind): &(Rule, Option<HubrisSensorKind>),
+        s: &HubrisSensor,
+    ) -> bool {
+        let device = &hubris.manifest.i2c_devices[s.device].device;
+
+        rule.name.as_ref().map_or(true, |n| *n == s.name)
+            && kind.map_or(true, |k| k == s.kind)
+            && rule.device.as_ref().map_or(true, |d| d == device)
+    }
+
+    ///
+    /// Returns an error if any rule fails to match any of the specified
+    /// sensors (which likely indicates a misspelled name or device).
+    ///
+    pub fn validate(
+        &self,
+        hubris: &HubrisArchive,
+        sensors: &[(usize, &HubrisSensor)],
+    ) -> Result<()> {
+        for (ndx, rule) in self.rules.iter().enumerate() {
+            if !sensors.iter().any(|(_, s)| Self::matches(hubris, rule, s)) {
+                bail!("rule {} does not match any sensor", ndx);
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Returns the actions that may be taken by any rule.
+    ///
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.rules.iter().flat_map(|(rule, _)| rule.actions.iter())
+    }
+
+    ///
+    /// Evaluates the rules against one poll of the specified sensors,
+    /// returning any alarms that have been raised or cleared.
+    ///
+    pub fn evaluate<'a>(
+        &'a mut self,
+        hubris: &HubrisArchive,
+        sensors: &[(usize, &'a HubrisSensor)],
+        values: &[Option<f32>],
+    ) -> Vec<Transition<'a>> {
+        self.evaluate_at(hubris, sensors, values, Instant::now())
+    }
+
+    fn evaluate_at<'a>(
+        &'a mut self,
+        hubris: &HubrisArchive,
+        sensors: &[(usize, &'a HubrisSensor)],
+        values: &[Option<f32>],
+        now: Instant,
+    ) -> Vec<Transition<'a>> {
+        let mut rval = vec![];
+
+        for (r, rule) in self.rules.iter().enumerate() {
+            for ((i, s), value) in sensors.iter().zip(values.iter()) {
+                if !Self::matches(hubris, rule, s) {
+                    continue;
+                }
+
+                let state = self.state.entry((r, *i)).or_default();
+                let (rule, _) = rule;
+                let mut violations = vec![];
+
+                match value {
+                    Some(val) => {
+                        state.missing = 0;
+
+                        if let Some(min) = rule.min {
+                            if *val < min {
+                                violations.push(format!(
+                                    "{:.2} below minimum of {:.2}",
+                                    val, min
+                                ));
+                            }
+                        }
+
+                        if let Some(max) = rule.max {
+                            if *val > max {
+                                violations.push(format!(
+                                    "{:.2} above maximum of {:.2}",
+                                    val, max
+                                ));
+                            }
+                        }
+
+                        if let (Some(rate), Some((last, when))) =
+                            (rule.rate, state.last)
+                        {
+                            let elapsed = (now - when).as_secs_f32();
+                            let change = (*val - last).abs() / elapsed;
+
+                            if elapsed > 0.0 && change > rate {
+                                violations.push(format!(
+                                    "changed from {:.2} to {:.2} \
+                                    ({:.2}/s; maximum is {:.2}/s)",
+                                    last, val, change, rate
+                                ));
+                            }
+                        }
+
+                        state.last = Some((*val, now));
+                    }
+                    None => {
+                        state.missing += 1;
+
+                        if let Some(missing) = rule.missing {
+                            if state.missing >= missing {
+                                violations.push(format!(
+                                    "no reading for {} consecutive polls",
+                                    state.missing
+                                ));
+                            }
+                        }
+                    }
+                }
+
+                let alarmed = !violations.is_empty();
+
+                let alarm = |description| Alarm {
+                    sensor: *s,
+                    description,
+                    actions: &rule.actions,
+                };
+
+                if alarmed && !state.alarmed {
+                    rval.push(Transition::Raised(alarm(violations.join("; "))));
+                } else if !alarmed && state.alarmed {
+                    rval.push(Transition::Cleared(alarm(
+                        "cleared".to_string(),
+                    )));
+                }
+
+                state.alarmed = alarmed;
+            }
+        }
+
+        rval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn archive() -> Result<HubrisArchive> {
+        let mut hubris = HubrisArchive::new()?;
+
+        for device in ["tmp117", "max31790"] {
+            hubris.manifest.i2c_devices.push(HubrisI2cDevice {
+                device: device.to_string(),
+                name: None,
+                controller: 1,
+                port: HubrisI2cPort { name: "B".to_string(), index: 1 },
+                mux: None,
+                segment: None,
+                address: 0x48,
+                description: device.to_string(),
+                class: HubrisI2cDeviceClass::Unspecified,
+                removable: false,
+            });
+        }
+
+        Ok(hubris)
+    }
+
+    fn sensors() -> Vec<HubrisSensor> {
+        vec![
+            HubrisSensor {
+                name: "T1".to_string(),
+                kind: HubrisSensorKind::Temperature,
+                device: 0,
+            },
+            HubrisSensor {
+                name: "FAN0".to_string(),
+                kind: HubrisSensorKind::Speed,
+                device: 1,
+            },
+        ]
+    }
+
+    //
+    // Evaluates one poll, summarizing each transition as the sensor, whether
+    // the alarm was raised, and its description.
+    //
+    fn poll(
+        rules: &mut Rules,
+        hubris: &HubrisArchive,
+        sensors: &[HubrisSensor],
+        values: &[Option<f32>],
+        now: Instant,
+    ) -> Vec<(String, bool, String)> {
+        let sensors = sensors.iter().enumerate().collect::<Vec<_>>();
+
+        rules
+            .evaluate_at(hubris, &sensors, values, now)
+            .into_iter()
+            .map(|t| match t {
+                Transition::Raised(a) => {
+                    (a.sensor.name.clone(), true, a.description)
+                }
+                Transition::Cleared(a) => {
+                    (a.sensor.name.clone(), false, a.description)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edges() -> Result<()> {
+        let hubris = archive()?;
+        let sensors = sensors();
+        let mut rules = Rules::parse(
+            r#"
+            [[rule]]
+            kind = "temp"
+            max = 50.0
+            "#,
+        )?;
+
+        let now = Instant::now();
+        let mut check = |v| poll(&mut rules, &hubris, &sensors, &[v, v], now);
+
+        assert!(check(Some(40.0)).is_empty());
+
+        //
+        // An alarm is raised only on the transition into alarm, and only
+        // for sensors that the rule matches.
+        //
+        assert_eq!(
+            check(Some(60.0)),
+            [(
+                "T1".to_string(),
+                true,
+                "60.00 above maximum of 50.00".to_string()
+            )]
+        );
+        assert!(check(Some(70.0)).is_empty());
+
+        assert_eq!(
+            check(Some(45.0)),
+            [("T1".to_string(), false, "cleared".to_string())]
+        );
+        assert!(check(Some(45.0)).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rate() -> Result<()> {
+        let hubris = archive()?;
+        let sensors = sensors();
+        let mut rules = Rules::parse(
+            r#"
+            [[rule]]
+            name = "FAN0"
+            rate = 1.0
+            actions = ["log", "exec:reset"]
+            "#,
+        )?;
+
+        let start = Instant::now();
+        let mut check = |v, secs| {
+            let now = start + Duration::from_secs(secs);
+            poll(&mut rules, &hubris, &sensors, &[Some(0.0), Some(v)], now)
+        };
+
+        //
+        // With no previous reading -- or no elapsed time since it -- there
+        // is no rate to exceed.
+        //
+        assert!(check(10.0, 0).is_empty());
+        assert!(check(20.0, 0).is_empty());
+
+        assert!(check(21.0, 2).is_empty());
+        assert_eq!(
+            check(25.0, 3),
+            [(
+                "FAN0".to_string(),
+                true,
+                "changed from 21.00 to 25.00 (4.00/s; maximum is 1.00/s)"
+                    .to_string()
+            )]
+        );
+        assert_eq!(
+            check(25.5, 4),
+            [("FAN0".to_string(), false, "cleared".to_string())]
+        );
+
+        assert_eq!(
+            rules.actions().collect::<Vec<_>>(),
+            [&Action::Log, &Action::Exec("reset".to_string())]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing() -> Result<()> {
+        let hubris = archive()?;
+        let sensors = sensors();
+        let mut rules = Rules::parse(
+            r#"
+            [[rule]]
+            device = "tmp117"
+            missing = 2
+            "#,
+        )?;
+
+        let now = Instant::now();
+        let mut check =
+            |v| poll(&mut rules, &hubris, &sensors, &[v, None], now);
+
+        assert!(check(None).is_empty());
+        assert_eq!(
+            check(None),
+            [(
+                "T1".to_string(),
+                true,
+                "no reading for 2 consecutive polls".to_string()
+            )]
+        );
+        assert!(check(None).is_empty());
+        assert_eq!(
+            check(Some(30.0)),
+            [("T1".to_string(), false, "cleared".to_string())]
+        );
+
+        //
+        // A reading resets the count of missed polls.
+        //
+        assert!(check(None).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate() -> Result<()> {
+        let hubris = archive()?;
+        let sensors = sensors();
+        let sensors = sensors.iter().enumerate().collect::<Vec<_>>();
+
+        let rules = Rules::parse("[[rule]]\nname = \"T1\"\nmax = 1.0\n")?;
+        rules.validate(&hubris, &sensors)?;
+
+        let rules = Rules::parse("[[rule]]\nname = \"T2\"\nmax = 1.0\n")?;
+        assert!(rules.validate(&hubris, &sensors).is_err());
+
+        assert!(Rules::parse("[[rule]]\nname = \"T1\"\n").is_err());
+        assert!(Rules::parse("[[rule]]\nkind = \"bogus\"\nmax = 1.0").is_err());
+        assert!(
+            Rules::parse("[[rule]]\nmax = 1.0\nactions = [\"bogus\"]").is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility serve`
+//!
+//! `humility serve` attaches to a target and remains attached, serving the
+//! session to local clients via a Unix domain socket.  This allows several
+//! engineers (or CI jobs) to share one probe, which would otherwise be
+//! available only to whoever attached to it first.  By default, the socket
+//! is `/tmp/humility.sock`; use `-s` (`--socket`) or the `HUMILITY_SOCKET`
+//! environment variable to specify a different path.
+//!
+//! Any client that can connect to the socket has full control of the
+//! target (it can write memory, reset the target, run commands, etc.), so
+//! the socket is created accessible only to the user running `humility
+//! serve` (that is, with mode 0600).  To share the session with other
+//! users, grant them access to the socket explicitly once it has been
+//! created (e.g., by changing its group and mode).
+//!
+//! ```console
+//! % humility serve -s /tmp/grimey.sock
+//! humility: attached via ST-Link V3
+//! humility: serving on /tmp/grimey.sock
+//! humility: ci-job-1312 (client 0) connected
+//! ...
+//! ```
+//!
+//! Clients send [JSON-RPC 2.0](https://www.jsonrpc.org/specification)
+//! requests, one per line, and receive a response to each, one per line.
+//! Requests are serialized:  each is run to completion before the next is
+//! started.  The following methods are available:
+//!
+//! | Method       | Parameters                | Result                         |
+//! |--------------|---------------------------|--------------------------------|
+//! | `hello`      | `name`                    | client ID, archive, probe      |
+//! | `lock`       |                           | `null`                         |
+//! | `unlock`     |                           | `null`                         |
+//! | `halt`       |                           | `null`                         |
+//! | `run`        |                           | `null`                         |
+//! | `step`       |                           | `null`                         |
+//! | `reset`      |                           | `null`                         |
+//! | `read`       | `addr`, `len` (max 65536) | array of bytes                 |
+//! | `read_word`  | `addr`                    | 32-bit word                    |
+//! | `write`      | `addr`, `data` (bytes)    | `null`                         |
+//! | `write_word` | `addr`, `value`           | `null`                         |
+//! | `readvar`    | `name`                    | value of the variable          |
+//! | `call`       | `op`, `args`              | result of the Idol call        |
+//! | `command`    | `args`                    | output of the subcommand       |
+//!
+//! Clients should identify themselves with `hello`; the name they provide
+//! is used to identify them in the server's messages.  A client that needs
+//! to perform several operations without interference (e.g., halting the
+//! target, reading memory and resuming it) can take the lock with `lock`:
+//! requests from other clients are held until the lock is released with
+//! `unlock` (or the client disconnects).
+//!
+//! The `call` method calls the Idol operation named by `op` (as
+//! `Interface.operation`) via HIF, with `args` being an object mapping
+//! argument names to integers or strings.  The `command` method runs a
+//! Humility subcommand, specified as an array of arguments; its output is
+//! returned as `output` (and is also included as the `data` of the error
+//! if the subcommand fails).  Values read from the target (via `readvar` or
+//! `call`) are translated into JSON:  structures become objects, arrays and
+//! tuples become arrays, enum variants without contents become strings, and
+//! enum variants with contents become an object mapping the variant name to
+//! its contents.  For example:
+//!
+//! ```console
+//! % echo '{"jsonrpc":"2.0","id":1,"method":"command","params":{"args":["tasks"]}}' | nc -U /tmp/grimey.sock
+//! {"id":1,"jsonrpc":"2.0","result":{"output":"system time = 1764993\n..."}}
+//! % echo '{"jsonrpc":"2.0","id":2,"method":"call","params":{"op":"Sensor.get","args":{"id":3}}}' | nc -U /tmp/grimey.sock
+//! {"id":2,"jsonrpc":"2.0","result":38.4375}
+//! ```
+//!
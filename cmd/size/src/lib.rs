@@ -0,0 +1,384 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility size`
+//!
+//! `humility size` breaks down the size of the kernel and each task in a
+//! Hubris archive.  It does not connect to a Hubris target to operate.  By
+//! default, sizes are broken down by (allocated) section, with the bytes of
+//! flash and RAM consumed by each:
+//!
+//! ```console
+//! % humility size --task net
+//! TASK               FLASH      RAM NAME
+//! net                60788        - .text
+//! net                 8176        - .rodata
+//! net                   12       12 .data
+//! net                    -    53000 .bss
+//! ```
+//!
+//! To break down sizes by crate, use `--crates` (`-c`); to break them down
+//! by symbol, use `--symbols` (`-s`).  Code is attributed to the crate of
+//! the DWARF compilation unit that contains it (which, for generic code, is
+//! the crate that instantiated it); data and any code not in a known
+//! compilation unit are attributed to the crate named in the path of the
+//! symbol.  Any bytes of a section that aren't covered by a symbol are
+//! shown as unattributed (e.g., `<unattributed .text>`).  Results are
+//! sorted by flash size, but can be sorted by RAM size or by name via
+//! `--sort`.  To see only the largest entries, use `--top` (`-n`):
+//!
+//! ```console
+//! % humility size --task net --crates -n 5
+//! TASK               FLASH      RAM NAME
+//! net                22608        - smoltcp
+//! net                17372    53000 task_net
+//! net                 9826        - drv_stm32h7_eth
+//! net                 4208        - core
+//! net                 3354       12 userlib
+//! ```
+//!
+//! Finally, sizes can be compared against another archive with `--diff`
+//! (`-d`), in which case only entries that differ are shown (including
+//! those of tasks present in only one archive), along with the change from
+//! the other archive.  When comparing archives, results are sorted by the
+//! magnitude of that change:
+//!
+//! ```console
+//! % humility -a build-b.zip size --task net --symbols --diff build-a.zip -n 3
+//! TASK               FLASH    DELTA      RAM    DELTA NAME
+//! net                 2960     +624        -       +0 task_net::main
+//! net                  388     -212        -       +0 smoltcp::iface::interface::InterfaceInner::process_ipv6
+//! net                    -     -104        -       +0 task_net::bsp::preinit
+//! ```
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::cli::Subcommand;
+use humility::hubris::*;
+use humility_cmd::{load_archive, Archive, Command};
+use std::collections::BTreeMap;
+
+#[derive(Parser, Debug)]
+#[clap(name = "size", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct SizeArgs {
+    /// restrict to the specified task (or "kernel")
+    #[clap(long, short, value_name = "task")]
+    task: Option<String>,
+
+    /// break down sizes by crate
+    #[clap(long, short, conflicts_with = "symbols")]
+    crates: bool,
+
+    /// break down sizes by symbol
+    #[clap(long, short)]
+    symbols: bool,
+
+    /// sort by flash size, RAM size, or name
+    #[clap(
+        long, value_name = "key", default_value = "flash",
+        possible_values = &["flash", "ram", "name"],
+    )]
+    sort: String,
+
+    /// display only the top N entries
+    #[clap(long, short = 'n', value_name = "N")]
+    top: Option<usize>,
+
+    /// compare against the specified archive
+    #[clap(long, short, value_name = "archive")]
+    diff: Option<String>,
+}
+
+//
+// Flash and RAM sizes, in bytes.
+//
+type Sizes = (u32, u32);
+
+struct Section {
+    name: String,
+    addr: u32,
+    size: u32,
+    flash: bool,
+    ram: bool,
+}
+
+//
+// Returns the allocated sections of the specified module.
+//
+fn sections(
+    hubris: &HubrisArchive,
+    module: &HubrisModule,
+) -> Result<Vec<Section>> {
+    use goblin::elf::section_header::{SHF_ALLOC, SHF_WRITE, SHT_NOBITS};
+
+    let buffer = hubris.module_elf(module)?;
+    let elf = goblin::elf::Elf::parse(&buffer).with_context(|| {
+        format!("failed to parse ELF object for {}", module.name)
+    })?;
+
+    let mut rval = vec![];
+
+    for sh in elf.section_headers.iter() {
+        if sh.sh_flags & SHF_ALLOC as u64 == 0 || sh.sh_size == 0 {
+            continue;
+        }
+
+        let name = match elf.shdr_strtab.get(sh.sh_name) {
+            Some(Ok(name)) => name.to_string(),
+            _ => bail!("bad section name in {}", module.name),
+        };
+
+        rval.push(Section {
+            name,
+            addr: sh.sh_addr as u32,
+            size: sh.sh_size as u32,
+            flash: sh.sh_type != SHT_NOBITS,
+            ram: sh.sh_flags & SHF_WRITE as u64 != 0,
+        });
+    }
+
+    Ok(rval)
+}
+
+//
+// Returns the crate named in the path of a (demangled) symbol, if any --
+// accommodating trait implementations (e.g., "<heapless::Vec<T, N> as
+// core::fmt::Debug>::fmt").
+//
+fn path_crate(name: &str) -> Option<&str> {
+    let (krate, _) = name.trim_start_matches('<').split_once("::")?;
+
+    if krate.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Some(krate)
+    } else {
+        None
+    }
+}
+
+fn breakdown(
+    hubris: &HubrisArchive,
+    module: &HubrisModule,
+    subargs: &SizeArgs,
+) -> Result<BTreeMap<String, Sizes>> {
+    let mut rval: BTreeMap<String, Sizes> = BTreeMap::new();
+
+    let mut add = |name: &str, section: &Section, size: u32| {
+        let entry = rval.entry(name.to_string()).or_default();
+
+        if section.flash {
+            entry.0 += size;
+        }
+
+        if section.ram {
+            entry.1 += size;
+        }
+    };
+
+    for section in sections(hubris, module)? {
+        if !subargs.crates && !subargs.symbols {
+            add(&section.name, &section, section.size);
+            continue;
+        }
+
+        //
+        // As we go, we track the bytes in the section that are covered by
+        // symbols (accommodating symbols that overlap) to attribute any
+        // that aren't.
+        //
+        let end = section.addr.saturating_add(section.size);
+        let (mut covered, mut cursor) = (0, section.addr);
+
+        for (addr, name, size) in
+            hubris.lookup_symbols(section.addr, section.size)
+        {
+            let limit = addr.saturating_add(size).min(end);
+
+            if limit > cursor {
+                covered += limit - addr.max(cursor);
+                cursor = limit;
+            }
+
+            if subargs.symbols {
+                add(name, &section, size);
+                continue;
+            }
+
+            let krate = hubris
+                .lookup_crate(addr)
+                .or_else(|| path_crate(name))
+                .unwrap_or("<unknown>");
+
+            add(krate, &section, size);
+        }
+
+        if covered < section.size {
+            let name = format!("<unattributed {}>", section.name);
+            add(&name, &section, section.size - covered);
+        }
+    }
+
+    Ok(rval)
+}
+
+fn bytes(s: u32) -> String {
+    if s == 0 {
+        "-".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+fn size(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let subargs = SizeArgs::try_parse_from(subargs)?;
+    let hubris = context.archive.as_ref().unwrap();
+
+    let other = match &subargs.diff {
+        Some(archive) => Some(load_archive(archive)?),
+        None => None,
+    };
+
+    let exists = |name: &str| {
+        hubris.lookup_module_byname(name).is_some()
+            || other
+                .as_ref()
+                .map_or(false, |o| o.lookup_module_byname(name).is_some())
+    };
+
+    //
+    // When comparing archives, we include any tasks that exist only in the
+    // archive being compared against.
+    //
+    let names: Vec<&str> = match &subargs.task {
+        Some(task) if exists(task.as_str()) => vec![task.as_str()],
+        Some(task) => bail!("no such task: {}", task),
+        None => {
+            let mut names = vec![];
+            let others = other.iter().flat_map(|o| o.modules());
+
+            for module in hubris.modules().chain(others) {
+                if !names.contains(&module.name.as_str()) {
+                    names.push(module.name.as_str());
+                }
+            }
+
+            names
+        }
+    };
+
+    //
+    // Each row consists of the task, the name of the entry, its sizes and
+    // the sizes in the archive being compared against (if any).
+    //
+    let mut rows: Vec<(&str, String, Sizes, Sizes)> = vec![];
+
+    for task in names {
+        let mut entries: BTreeMap<String, (Sizes, Sizes)> = BTreeMap::new();
+
+        if let Some(m) = hubris.lookup_module_byname(task) {
+            for (name, s) in breakdown(hubris, m, &subargs)? {
+                entries.entry(name).or_default().0 = s;
+            }
+        }
+
+        if let Some(other) = &other {
+            if let Some(m) = other.lookup_module_byname(task) {
+                for (name, s) in breakdown(other, m, &subargs)? {
+                    entries.entry(name).or_default().1 = s;
+                }
+            }
+        }
+
+        for (name, (s, o)) in entries {
+            if other.is_none() || s != o {
+                rows.push((task, name, s, o));
+            }
+        }
+    }
+
+    let delta = |s: u32, o: u32| s as i64 - o as i64;
+
+    match (subargs.sort.as_str(), other.is_some()) {
+        ("name", _) => rows.sort_by(|a, b| (&a.1, a.0).cmp(&(&b.1, b.0))),
+        ("ram", false) => rows.sort_by_key(|r| std::cmp::Reverse(r.2 .1)),
+        ("ram", true) => {
+            rows.sort_by_key(|r| std::cmp::Reverse(delta(r.2 .1, r.3 .1).abs()))
+        }
+        (_, false) => rows.sort_by_key(|r| std::cmp::Reverse(r.2 .0)),
+        (_, true) => {
+            rows.sort_by_key(|r| std::cmp::Reverse(delta(r.2 .0, r.3 .0).abs()))
+        }
+    }
+
+    if let Some(top) = subargs.top {
+        rows.truncate(top);
+    }
+
+    if other.is_some() {
+        println!(
+            "{:15} {:>8} {:>8} {:>8} {:>8} NAME",
+            "TASK", "FLASH", "DELTA", "RAM", "DELTA"
+        );
+    } else {
+        println!("{:15} {:>8} {:>8} NAME", "TASK", "FLASH", "RAM");
+    }
+
+    for (task, name, s, o) in rows {
+        if other.is_some() {
+            println!(
+                "{:15} {:>8} {:>+8} {:>8} {:>+8} {}",
+                task,
+                bytes(s.0),
+                delta(s.0, o.0),
+                bytes(s.1),
+                delta(s.1, o.1),
+                name
+            );
+        } else {
+            println!(
+                "{:15} {:>8} {:>8} {}",
+                task,
+                bytes(s.0),
+                bytes(s.1),
+                name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "size",
+            archive: Archive::Required,
+            run: size,
+        },
+        SizeArgs::command(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crates() {
+        assert_eq!(
+            path_crate("smoltcp::iface::Interface::poll"),
+            Some("smoltcp")
+        );
+        assert_eq!(path_crate("task_net::main"), Some("task_net"));
+        assert_eq!(
+            path_crate("<heapless::Vec<T, N> as core::fmt::Debug>::fmt"),
+            Some("heapless")
+        );
+        assert_eq!(path_crate("<&T as core::fmt::Debug>::fmt"), None);
+        assert_eq!(path_crate("memcpy"), None);
+        assert_eq!(path_crate(""), None);
+    }
+}
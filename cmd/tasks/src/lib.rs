@@ -122,6 +122,7 @@ use humility::regs::Register;
 use humility_cmd::doppel::{self, Task, TaskDesc, TaskId, TaskState};
 use humility_cmd::{Archive, Attach, Command, Validate};
 use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
 
 #[derive(Parser, Debug)]
 #[clap(name = "tasks", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -170,14 +171,63 @@ fn print_regs(regs: &BTreeMap<Register, u32>, additional: bool) {
     println!();
 }
 
-#[rustfmt::skip::macros(println)]
-fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
-    let core = &mut **context.core.as_mut().unwrap();
-    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
-    let hubris = context.archive.as_ref().unwrap();
+///
+/// A task, as read from the task table.
+///
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    /// Task index
+    pub id: u32,
 
-    let subargs = TasksArgs::try_parse_from(subargs)?;
+    /// Name of the task's module
+    pub name: String,
+
+    /// Address of the task's control block
+    pub addr: u32,
+
+    /// True if this is the currently running task
+    pub current: bool,
+
+    /// The task control block
+    pub task: Task,
+
+    /// The task's descriptor
+    pub desc: TaskDesc,
 
+    /// The task control block, as a reflected value
+    pub value: reflect::Value,
+
+    /// A human-readable explanation of the task's state
+    pub state: String,
+}
+
+///
+/// A snapshot of the tasks in a system.
+///
+#[derive(Clone, Debug)]
+pub struct Tasks {
+    /// System time, in ticks
+    pub ticks: u64,
+
+    /// True if the system has been left halted, in which case it is up to
+    /// the caller to resume it (via `Core::run`)
+    pub halted: bool,
+
+    /// The tasks, in task index order
+    pub tasks: Vec<TaskInfo>,
+}
+
+///
+/// Reads the task table as consistently as possible by halting the system
+/// while it is read.  The system is resumed unless `keep_halted` is set or
+/// a task has panicked (in which case the system is left halted to allow
+/// for further inspection).
+///
+pub fn read_tasks(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    keep_halted: bool,
+) -> Result<Tasks> {
     let (base, task_count) = hubris.task_table(core)?;
     log::debug!("task table: {:#x?}, count: {}", base, task_count);
     let ticks = core.read_word_64(hubris.lookup_variable("TICKS")?.addr)?;
@@ -194,85 +244,141 @@ fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
 
     let syscall_arg = save + state.lookup_member(&syscall_arg)?.offset;
 
-    let mut found = false;
+    core.halt()?;
 
-    let printer = humility_cmd::stack::StackPrinter {
-        indent: 3,
-        line: subargs.line,
-        additional: subargs.registers || subargs.verbose,
-    };
+    let cur =
+        hubris.arch.as_ref().unwrap().get_current_task_ptr(hubris, core)?
+            as u32;
 
-    loop {
-        core.halt()?;
+    //
+    // We read the entire task table at a go to get as consistent a
+    // snapshot as possible.
+    //
+    let mut taskblock = vec![0; task_t.size * task_count as usize];
+    core.read_8(base, &mut taskblock)?;
+
+    let mut tasks = vec![];
+    let mut panicked = false;
+    let mut regs = HashMap::new();
 
-        let cur =
-            hubris.arch.as_ref().unwrap().get_current_task_ptr(hubris, core)?
-                as u32;
+    for i in 0..task_count {
+        let addr = base + i * task_t.size as u32;
+        let offs = i as usize * task_t.size;
+
+        let task_value: reflect::Value =
+            reflect::load(hubris, &taskblock, task_t, offs).with_context(
+                || format!("loading task control block for task {}", i),
+            )?;
+        let task: Task = Task::from_value(&task_value)?;
 
         //
-        // We read the entire task table at a go to get as consistent a
-        // snapshot as possible.
+        // Always load the first 3 syscall registers
+        // They are in the saved state in our
+        // task structure (and are needed to explain state).
         //
-        let mut taskblock = vec![0; task_t.size * task_count as usize];
-        core.read_8(base, &mut taskblock)?;
-
-        let mut tasks = vec![];
-        let mut panicked = false;
-        let mut regs = HashMap::new();
-
-        for i in 0..task_count {
-            let addr = base + i * task_t.size as u32;
-            let offs = i as usize * task_t.size;
-
-            let task_value: reflect::Value =
-                reflect::load(hubris, &taskblock, task_t, offs).with_context(
-                    || format!("loading task control block for task {}", i),
-                )?;
-            let task: Task = Task::from_value(&task_value)?;
-
-            //
-            // Always load the first 3 syscall registers
-            // They are in the saved state in our
-            // task structure (and are needed to print state).
-            //
-            for r in 0..=2 {
-                let reg = hubris
-                    .arch
-                    .as_ref()
-                    .unwrap()
-                    .get_syscall_register(r.try_into().unwrap())
-                    .unwrap();
-                let o = offs + syscall_arg + r * 4;
-
-                let v =
-                    u32::from_le_bytes(taskblock[o..o + 4].try_into().unwrap());
-                regs.insert((i, reg), v);
-            }
+        for r in 0..=2 {
+            let reg = hubris
+                .arch
+                .as_ref()
+                .unwrap()
+                .get_syscall_register(r.try_into().unwrap())
+                .unwrap();
+            let o = offs + syscall_arg + r * 4;
 
-            tasks.push((addr, task_value, task));
+            let v = u32::from_le_bytes(taskblock[o..o + 4].try_into().unwrap());
+            regs.insert((i, reg), v);
+        }
 
-            if let TaskState::Faulted { fault, .. } = task.state {
-                if fault == doppel::FaultInfo::Panic {
-                    panicked = true;
-                }
+        tasks.push((addr, task_value, task));
+
+        if let TaskState::Faulted { fault, .. } = task.state {
+            if fault == doppel::FaultInfo::Panic {
+                panicked = true;
             }
         }
+    }
 
-        if let Ok(pc) = core.read_reg(hubris.arch.as_ref().unwrap().get_pc()) {
-            if hubris.instr_mod(pc as u32).is_none() {
-                humility::warn!(
-                    "PC 0x{:x} is unknown; \
-                    system may be executing in ROM!",
-                    pc
-                );
-            }
+    if let Ok(pc) = core.read_reg(hubris.arch.as_ref().unwrap().get_pc()) {
+        if hubris.instr_mod(pc as u32).is_none() {
+            humility::warn!(
+                "PC 0x{:x} is unknown; \
+                system may be executing in ROM!",
+                pc
+            );
         }
+    }
 
-        let keep_halted = subargs.stack || subargs.registers || panicked;
+    let halted = keep_halted || panicked;
 
-        if !keep_halted {
-            core.run()?;
-        }
+    if !halted {
+        core.run()?;
+    }
+
+    let mut rval = vec![];
+
+    for (i, (addr, value, task)) in tasks.into_iter().enumerate() {
+        let i = i as u32;
+        let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
+
+        let name = match hubris.lookup_module(HubrisTask::Task(i)) {
+            Ok(m) => &m.name,
+            _ => "<unknown>",
+        };
+
+        let irqs = hubris.manifest.task_irqs.get(name);
+
+        let timer = task.timer.deadline.map(|deadline| {
+            (deadline.0 as i64 - ticks as i64, task.timer.to_post.0)
+        });
+
+        let mut state = String::new();
+
+        explain_state(
+            &mut state,
+            hubris,
+            core,
+            i,
+            &regs,
+            task.state,
+            addr == cur,
+            irqs,
+            timer,
+        )?;
+
+        rval.push(TaskInfo {
+            id: i,
+            name: name.to_string(),
+            addr,
+            current: addr == cur,
+            task,
+            desc,
+            value,
+            state,
+        });
+    }
+
+    Ok(Tasks { ticks, halted, tasks: rval })
+}
+
+#[rustfmt::skip::macros(println)]
+fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
+    let core = &mut **context.core.as_mut().unwrap();
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let hubris = context.archive.as_ref().unwrap();
+
+    let subargs = TasksArgs::try_parse_from(subargs)?;
+
+    let mut found = false;
+
+    let printer = humility_cmd::stack::StackPrinter {
+        indent: 3,
+        line: subargs.line,
+        additional: subargs.registers || subargs.verbose,
+    };
+
+    loop {
+        let Tasks { ticks, halted, tasks } =
+            read_tasks(hubris, core, subargs.stack || subargs.registers)?;
 
         println!("system time = {}", ticks);
 
@@ -281,55 +387,34 @@ fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
 
         let mut any_names_truncated = false;
 
-        for (i, (addr, task_value, task)) in tasks.iter().enumerate() {
-            let i = i as u32;
-            let desc: TaskDesc = task.descriptor.load_from(hubris, core)?;
-
-            let module = match hubris.lookup_module(HubrisTask::Task(i)) {
-                Ok(m) => &m.name,
-                _ => "<unknown>",
-            };
-
-            let irqs = hubris.manifest.task_irqs.get(module);
+        for info in &tasks {
+            let i = info.id;
+            let task = &info.task;
 
             if let Some(ref task) = subargs.task {
-                if *task != module {
+                if *task != info.name {
                     continue;
                 }
 
                 found = true;
             }
 
-            let timer = task.timer.deadline.map(|deadline| {
-                (deadline.0 as i64 - ticks as i64, task.timer.to_post.0)
-            });
-
             {
-                let mut modname = module.to_string();
+                let mut modname = info.name.clone();
                 if modname.len() > 20 {
                     modname.truncate(20);
                     modname.push('…');
                     any_names_truncated = true;
                 }
-                print!(
-                    "{:2} {:21} {:>8} {:3} ",
+                println!(
+                    "{:2} {:21} {:>8} {:3} {}",
                     i,
                     modname,
                     u32::from(task.generation),
-                    task.priority.0
+                    task.priority.0,
+                    info.state,
                 );
             }
-            explain_state(
-                hubris,
-                core,
-                i,
-                &regs,
-                task.state,
-                *addr == cur,
-                irqs,
-                timer,
-            )?;
-            println!();
 
             if subargs.stack || subargs.registers {
                 let t = HubrisTask::Task(i);
@@ -340,7 +425,7 @@ fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
                     match hubris.stack(
                         core,
                         t,
-                        desc.initial_stack as u32,
+                        info.desc.initial_stack as u32,
                         &regs,
                     ) {
                         Ok(stack) => printer.print(hubris, &stack),
@@ -364,7 +449,7 @@ fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
                 };
 
                 print!("   |\n   +-----------> ");
-                task_value.format(hubris, fmt, &mut std::io::stdout())?;
+                info.value.format(hubris, fmt, &mut std::io::stdout())?;
                 println!("\n");
             }
 
@@ -378,7 +463,7 @@ fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
                 humility manifest to see them.");
         }
 
-        if keep_halted {
+        if halted {
             core.op_done()?;
         }
 
@@ -396,6 +481,7 @@ fn tasks(context: &mut humility::ExecutionContext) -> Result<()> {
 
 #[allow(clippy::too_many_arguments)]
 fn explain_state(
+    out: &mut String,
     hubris: &HubrisArchive,
     core: &mut dyn Core,
     task_index: u32,
@@ -408,13 +494,14 @@ fn explain_state(
     match ts {
         TaskState::Healthy(ss) => {
             explain_sched_state(
-                hubris, task_index, regs, current, irqs, timer, ss,
+                out, hubris, task_index, regs, current, irqs, timer, ss,
             )?;
         }
         TaskState::Faulted { fault, original_state } => {
-            explain_fault_info(hubris, core, task_index, regs, fault)?;
-            print!(" (was: ");
+            explain_fault_info(out, hubris, core, task_index, regs, fault)?;
+            write!(out, " (was: ")?;
             explain_sched_state(
+                out,
                 hubris,
                 task_index,
                 regs,
@@ -423,13 +510,15 @@ fn explain_state(
                 timer,
                 original_state,
             )?;
-            print!(")");
+            write!(out, ")")?;
         }
     }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn explain_sched_state(
+    out: &mut String,
     hubris: &HubrisArchive,
     task_index: u32,
     regs: &HashMap<(u32, Register), u32>,
@@ -441,46 +530,53 @@ fn explain_sched_state(
     use doppel::SchedState;
 
     match e {
-        SchedState::Stopped => print!("not started"),
-        SchedState::Exited => print!("EXITED"),
+        SchedState::Stopped => write!(out, "not started")?,
+        SchedState::Exited => write!(out, "EXITED")?,
         SchedState::Runnable => {
             if current {
-                print!("RUNNING")
+                write!(out, "RUNNING")?
             } else {
-                print!("ready")
+                write!(out, "ready")?
             }
         }
         SchedState::InSend(tid) => {
             if tid == TaskId::KERNEL {
-                print!("HALT: send to kernel");
+                write!(out, "HALT: send to kernel")?;
             } else {
-                print!("wait: send to ");
-                print_task_id(hubris, tid);
+                write!(out, "wait: send to ")?;
+                write_task_id(out, hubris, tid)?;
             }
         }
         SchedState::InReply(tid) => {
-            print!("wait: reply from ");
-            print_task_id(hubris, tid);
+            write!(out, "wait: reply from ")?;
+            write_task_id(out, hubris, tid)?;
         }
         SchedState::InRecv(tid) => {
             let syscall_2 =
                 hubris.arch.as_ref().unwrap().get_syscall_register(2).unwrap();
             let notmask = *regs.get(&(task_index, syscall_2)).unwrap();
-            explain_recv(hubris, tid, notmask, irqs, timer);
+            explain_recv(out, hubris, tid, notmask, irqs, timer)?;
         }
     }
     Ok(())
 }
 
-fn print_task_id(hubris: &HubrisArchive, task_id: TaskId) {
+fn write_task_id(
+    out: &mut String,
+    hubris: &HubrisArchive,
+    task_id: TaskId,
+) -> Result<()> {
     if let Some(n) = hubris.task_name(task_id.index()) {
-        print!("{}/gen{}", n, task_id.generation());
+        write!(out, "{}/gen{}", n, task_id.generation())?;
     } else {
-        print!("unknown#{}/gen{}", task_id.index(), task_id.generation());
+        write!(out, "unknown#{}/gen{}", task_id.index(), task_id.generation())?;
     }
+
+    Ok(())
 }
 
 fn explain_fault_info(
+    out: &mut String,
     hubris: &HubrisArchive,
     core: &mut dyn Core,
     task_index: u32,
@@ -489,46 +585,46 @@ fn explain_fault_info(
 ) -> Result<()> {
     use doppel::FaultInfo;
 
-    print!("FAULT: ");
+    write!(out, "FAULT: ")?;
     match fi {
-        FaultInfo::DivideByZero => print!("divide by zero"),
-        FaultInfo::IllegalText => print!("jump to non-executable mem"),
-        FaultInfo::IllegalInstruction => print!("illegal instruction"),
+        FaultInfo::DivideByZero => write!(out, "divide by zero")?,
+        FaultInfo::IllegalText => write!(out, "jump to non-executable mem")?,
+        FaultInfo::IllegalInstruction => write!(out, "illegal instruction")?,
         FaultInfo::InvalidOperation(bits) => {
-            print!("general fault, cfsr=0x{:x}", bits);
+            write!(out, "general fault, cfsr=0x{:x}", bits)?;
         }
         FaultInfo::StackOverflow { address } => {
-            print!("stack overflow; sp=0x{:x}", address);
+            write!(out, "stack overflow; sp=0x{:x}", address)?;
         }
         FaultInfo::Injected(task) => {
-            print!("killed by ");
-            print_task_id(hubris, task);
+            write!(out, "killed by ")?;
+            write_task_id(out, hubris, task)?;
         }
         FaultInfo::MemoryAccess { address, source } => {
-            print!("mem fault (");
+            write!(out, "mem fault (")?;
             if let Some(addr) = address {
-                print!("precise: 0x{:x}", addr);
+                write!(out, "precise: 0x{:x}", addr)?;
             } else {
-                print!("imprecise");
+                write!(out, "imprecise")?;
             }
-            print!(")");
+            write!(out, ")")?;
 
-            explain_fault_source(source);
+            explain_fault_source(out, source)?;
         }
         FaultInfo::BusError { address, source } => {
-            print!("bus fault (");
+            write!(out, "bus fault (")?;
             if let Some(addr) = address {
-                print!("precise: 0x{:x}", addr);
+                write!(out, "precise: 0x{:x}", addr)?;
             } else {
-                print!("imprecise");
+                write!(out, "imprecise")?;
             }
-            print!(")");
+            write!(out, ")")?;
 
-            explain_fault_source(source);
+            explain_fault_source(out, source)?;
         }
         FaultInfo::SyscallUsage(ue) => {
-            print!("in syscall: ");
-            explain_usage_error(ue);
+            write!(out, "in syscall: ")?;
+            explain_usage_error(out, ue)?;
         }
         FaultInfo::Panic => {
             let msg_base = *regs
@@ -557,36 +653,47 @@ fn explain_fault_info(
             let mut buf = vec![0; msg_len];
             core.read_8(msg_base, &mut buf)?;
             match std::str::from_utf8(&buf) {
-                Ok(msg) => print!("{}", msg),
-                Err(_) => print!("panic with invalid message"),
+                Ok(msg) => write!(out, "{}", msg)?,
+                Err(_) => write!(out, "panic with invalid message")?,
             }
         }
         FaultInfo::FromServer(task_id, reason) => {
-            print!("reply fault: task id {}, reason {:?}", task_id, reason);
+            write!(
+                out,
+                "reply fault: task id {}, reason {:?}",
+                task_id, reason
+            )?;
         }
     }
     Ok(())
 }
 
-fn explain_usage_error(e: doppel::UsageError) {
+fn explain_usage_error(out: &mut String, e: doppel::UsageError) -> Result<()> {
     use doppel::UsageError::*;
     match e {
-        BadSyscallNumber => print!("undefined syscall number"),
-        InvalidSlice => print!("sent malformed slice to kernel"),
-        TaskOutOfRange => print!("used bogus task index"),
-        IllegalTask => print!("illegal task operation"),
-        LeaseOutOfRange => print!("bad caller lease index"),
-        OffsetOutOfRange => print!("bad caller lease offset"),
-        NoIrq => print!("referred to undefined interrupt"),
-        BadKernelMessage => print!("sent nonsense IPC to kernel"),
+        BadSyscallNumber => write!(out, "undefined syscall number")?,
+        InvalidSlice => write!(out, "sent malformed slice to kernel")?,
+        TaskOutOfRange => write!(out, "used bogus task index")?,
+        IllegalTask => write!(out, "illegal task operation")?,
+        LeaseOutOfRange => write!(out, "bad caller lease index")?,
+        OffsetOutOfRange => write!(out, "bad caller lease offset")?,
+        NoIrq => write!(out, "referred to undefined interrupt")?,
+        BadKernelMessage => write!(out, "sent nonsense IPC to kernel")?,
     }
+
+    Ok(())
 }
 
-fn explain_fault_source(e: doppel::FaultSource) {
+fn explain_fault_source(
+    out: &mut String,
+    e: doppel::FaultSource,
+) -> Result<()> {
     match e {
-        doppel::FaultSource::User => print!(" in task code"),
-        doppel::FaultSource::Kernel => print!(" in syscall"),
+        doppel::FaultSource::User => write!(out, " in task code")?,
+        doppel::FaultSource::Kernel => write!(out, " in syscall")?,
     }
+
+    Ok(())
 }
 
 /// Heuristic recognition of receive states used by normal programs.
@@ -602,12 +709,13 @@ fn explain_fault_source(e: doppel::FaultSource) {
 ///
 /// - Make common cases unobtrusive and easy to scan.
 fn explain_recv(
+    out: &mut String,
     hubris: &HubrisArchive,
     src: Option<TaskId>,
     notmask: u32,
     irqs: Option<&Vec<(u32, u32)>>,
     timer: Option<(i64, u32)>,
-) {
+) -> Result<()> {
     // Come up with a description for each notification bit.
     struct NoteInfo {
         irqs: Vec<u32>,
@@ -646,40 +754,42 @@ fn explain_recv(
             outer_first = true;
         }
         Some(other) => {
-            print!("recv(");
-            print_task_id(hubris, other);
-            print!(" only)");
+            write!(out, "recv(")?;
+            write_task_id(out, hubris, other)?;
+            write!(out, " only)")?;
         }
         None => {
-            print!("recv");
+            write!(out, "recv")?;
         }
     }
 
     // Display notification bits, along with meaning where we can.
     if notmask != 0 {
-        print!("{}notif:", if outer_first { "" } else { ", " });
+        write!(out, "{}notif:", if outer_first { "" } else { ", " })?;
         for nt in note_types {
-            print!(" bit{}", nt.bit);
+            write!(out, " bit{}", nt.bit)?;
             if !nt.irqs.is_empty() || nt.timer.is_some() {
-                print!("(");
+                write!(out, "(")?;
                 let mut first = true;
                 if let Some(ts) = nt.timer {
-                    print!("T{:+}", ts);
+                    write!(out, "T{:+}", ts)?;
                     first = false;
                 }
                 for irq in &nt.irqs {
-                    print!("{}irq{}", if !first { "/" } else { "" }, irq);
+                    write!(out, "{}irq{}", if !first { "/" } else { "" }, irq)?;
                     first = false;
                 }
-                print!(")");
+                write!(out, ")")?;
             }
         }
     }
 
     // Flag things that are probably bugs
     if src == Some(TaskId::KERNEL) && notmask == 0 {
-        print!("(DEAD)");
+        write!(out, "(DEAD)")?;
     }
+
+    Ok(())
 }
 
 pub fn init() -> (Command, ClapCommand<'static>) {
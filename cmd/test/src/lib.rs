@@ -106,19 +106,53 @@
 //! All received packet data will be dumped to the resulting output file,
 //! allowing these transient failures to be differentiated from deeper issues.
 //!
+//! To run the test suite more than once (e.g., to shake out intermittent
+//! failures), use `--runs` (`-r`).  The target is reset between runs -- or,
+//! with `--power-cycle` (`-P`), power cycled via the `power-cycle` action of
+//! its target in the environment:
+//!
+//! ```console
+//! $ humility -t gimletlet test --runs 2 --power-cycle
+//! humility: attached via ST-Link V3
+//! humility: run 1 of 2
+//! ...
+//! humility: tests completed: pass
+//! humility: gimletlet power-cycle: executing: 'power.sh --cycle gimletlet' ...
+//! humility: gimletlet power-cycle: done (status code 0)
+//! humility: attached via ST-Link V3
+//! humility: run 2 of 2
+//! ...
+//! humility: tests completed: pass
+//! humility: 2 of 2 runs passed
+//! ```
+//!
+//! Rather than running the test suite on the attached device, `humility test`
+//! can also parse the results of a previous run from captured ITM data via
+//! `--ingest` (`-i`), e.g. from a capture taken in CI; see `humility itm`
+//! for the supported sources.  If the capture ends before the test run has
+//! completed, the test fails.
+//!
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::cli::Subcommand;
 use humility::core::Core;
+use humility::env::Action;
 use humility::hubris::*;
 use humility_cmd::test::*;
 use humility_cmd::{Archive, Attach, Command, Validate};
 use humility_cortex::itm::*;
-use std::cell::RefCell;
+use humility_cortex::swv::*;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+//
+// The time we allow the target to boot after it has been reset or power
+// cycled between runs.
+//
+const BOOT_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Debug)]
 #[clap(name = "test", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -129,17 +163,37 @@ struct TestArgs {
     /// sets the output file
     #[clap(long, short, value_name = "filename")]
     output: Option<String>,
+    /// parse results from captured ITM data rather than running the suite
+    /// on the attached device: a file (either raw or a Saleae CSV export),
+    /// "raw:<file>", "saleae:<file>" or "tcp:<host>:<port>"
+    #[clap(long, short, value_name = "source")]
+    ingest: Option<SWVSource>,
+    /// assume bypassed TPIU in ingested data
+    #[clap(long, short, requires = "ingest")]
+    bypass: bool,
+    /// run the test suite the specified number of times, resetting the
+    /// target between runs
+    #[clap(
+        long, short, default_value = "1", conflicts_with = "ingest",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    runs: u32,
+    /// power cycle the target between runs rather than resetting it
+    #[clap(long, short = 'P', conflicts_with = "ingest")]
+    power_cycle: bool,
 }
 
 fn test_ingest(
-    core: &mut dyn Core,
+    core: Option<&mut dyn Core>,
     subargs: &TestArgs,
     hubris: &HubrisArchive,
+    stream: &mut SWVStream,
     traceid: Option<u8>,
-) -> Result<()> {
-    let mut bufs: VecDeque<(Vec<u8>, f64)> = VecDeque::new();
+) -> Result<bool> {
+    let mut bufs: VecDeque<Vec<(u8, f64)>> = VecDeque::new();
     let mut ndx = 0;
-    let mut current = None;
+    let mut current: Option<Vec<(u8, f64)>> = None;
+    let mut exhausted = false;
 
     let v = hubris
         .lookup_variable("TEST_KICK")
@@ -152,7 +206,16 @@ fn test_ingest(
     let start = Instant::now();
 
     let mut testrun = TestRun::new(hubris);
-    let mut kicked = false;
+
+    //
+    // If we are ingesting a capture, the test suite has already been kicked.
+    //
+    let mut kicked = core.is_none();
+
+    //
+    // Set to whether the run passed once it has completed.
+    //
+    let outcome = Cell::new(None);
 
     let shared = RefCell::new(core);
 
@@ -165,20 +228,29 @@ fn test_ingest(
     let rval = itm_ingest(
         traceid,
         || {
+            if outcome.get().is_some() {
+                return Ok(None);
+            }
+
             loop {
                 if start.elapsed().as_secs() > timeout {
                     bail!("timed out after {} seconds", timeout);
                 }
 
                 //
-                // We will keep reading until we have a zero byte read, at
-                // which time we will kick out and process one byte.
+                // We will keep reading until we have a zero byte read (or
+                // have exhausted our source), at which time we will kick out
+                // and process one byte.
                 //
-                let buf = shared.borrow_mut().read_swv()?;
-
-                if !buf.is_empty() {
-                    bufs.push_back((buf, start.elapsed().as_secs_f64()));
-                    continue;
+                if !exhausted {
+                    match stream.read(shared.borrow_mut().as_deref_mut())? {
+                        Some(buf) if !buf.is_empty() => {
+                            bufs.push_back(buf);
+                            continue;
+                        }
+                        Some(_) => {}
+                        None => exhausted = true,
+                    }
                 }
 
                 match current {
@@ -187,7 +259,7 @@ fn test_ingest(
                         ndx = 0;
                     }
 
-                    Some((ref buf, _)) => {
+                    Some(ref buf) => {
                         if ndx == buf.len() {
                             current = bufs.pop_front();
                             ndx = 0;
@@ -196,17 +268,22 @@ fn test_ingest(
                 }
 
                 if current.is_none() {
+                    if exhausted {
+                        return Ok(None);
+                    }
+
                     continue;
                 }
 
                 break;
             }
 
-            let (buf, pulled) = current.as_ref().unwrap();
+            let buf = current.as_ref().unwrap();
             ndx += 1;
 
-            let datum = (buf[ndx - 1], start.elapsed().as_secs_f64());
-            wire.borrow_mut().push((datum.0, *pulled, datum.1));
+            let (datum, pulled) = buf[ndx - 1];
+            let datum = (datum, start.elapsed().as_secs_f64());
+            wire.borrow_mut().push((datum.0, pulled, datum.1));
 
             Ok(Some(datum))
         },
@@ -235,16 +312,14 @@ fn test_ingest(
                     }
 
                     if testrun.completed() {
-                        if testrun.failed() {
-                            testrun.report(output, &wire.borrow(), None)?;
-                            std::process::exit(1);
-                        }
+                        let failed = testrun.failed();
 
-                        if subargs.dumpalways {
+                        if failed || subargs.dumpalways {
                             testrun.report(output, &wire.borrow(), None)?;
                         }
 
-                        std::process::exit(0);
+                        outcome.set(Some(!failed));
+                        return Ok(());
                     }
                 }
 
@@ -254,9 +329,11 @@ fn test_ingest(
                 match packet.header {
                     ITMHeader::Sync => {
                         if !kicked {
-                            shared.borrow_mut().halt()?;
-                            shared.borrow_mut().write_word_32(v.addr, 1)?;
-                            shared.borrow_mut().run()?;
+                            let mut core = shared.borrow_mut();
+                            let core = core.as_deref_mut().unwrap();
+                            core.halt()?;
+                            core.write_word_32(v.addr, 1)?;
+                            core.run()?;
                             kicked = true;
                         }
                     }
@@ -274,38 +351,142 @@ fn test_ingest(
         },
     );
 
-    match rval {
-        Ok(_) => rval,
-        Err(err) => {
-            testrun.report(output, &wire.borrow(), Some(&err))?;
-            Err(err)
-        }
+    if let (Ok(_), Some(passed)) = (&rval, outcome.get()) {
+        return Ok(passed);
     }
+
+    //
+    // If we're here without error, we have run out of data before the test
+    // run completed.
+    //
+    let err = match rval {
+        Ok(_) => anyhow::anyhow!("ITM data ended before tests completed"),
+        Err(err) => err,
+    };
+
+    testrun.report(output, &wire.borrow(), Some(&err))?;
+    Err(err)
 }
 
-fn test(context: &mut humility::ExecutionContext) -> Result<()> {
+fn test_attached(
+    context: &mut humility::ExecutionContext,
+    subargs: &TestArgs,
+) -> Result<bool> {
     let core = &mut **context.core.as_mut().unwrap();
-    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
     let hubris = context.archive.as_ref().unwrap();
 
+    let stim = 0x0000_ffff;
+    let traceid = itm_enable_ingest(core, hubris, stim)?;
+    let mut stream = SWVStream::open(&SWVSource::Probe)?;
+    test_ingest(Some(core), subargs, hubris, &mut stream, traceid)
+}
+
+fn test_runs(
+    context: &mut humility::ExecutionContext,
+    subargs: &TestArgs,
+) -> Result<bool> {
+    let power = match (&context.environment, &context.cli.target) {
+        _ if !subargs.power_cycle => None,
+        (Some(env), Some(target)) if env.has_action(Action::PowerCycle) => {
+            Some((env.clone(), target.clone()))
+        }
+        (Some(_), Some(target)) => {
+            bail!("target {} has no {} action", target, Action::PowerCycle);
+        }
+        _ => bail!("--power-cycle requires a target within an environment"),
+    };
+
+    let mut passed = 0;
+
+    for run in 0..subargs.runs {
+        if run > 0 {
+            match &power {
+                Some((env, target)) => {
+                    //
+                    // Once power cycled, we will need to attach anew.
+                    //
+                    context.core = None;
+                    env.action(target, Action::PowerCycle)?;
+                }
+                None => {
+                    //
+                    // If we failed to attach on the previous run, there is
+                    // nothing to reset; we will attempt to attach anew.
+                    //
+                    if let Some(core) = context.core.as_mut() {
+                        core.reset()?;
+                    }
+                }
+            }
+
+            std::thread::sleep(BOOT_DELAY);
+        }
+
+        let mut pass = false;
+
+        let rval = humility_cmd::attach(
+            context,
+            Attach::LiveOnly,
+            Validate::Booted,
+            |context| {
+                if subargs.runs > 1 {
+                    humility::msg!("run {} of {}", run + 1, subargs.runs);
+                }
+
+                pass = test_attached(context, subargs)?;
+                Ok(())
+            },
+        );
+
+        //
+        // A run that fails to attach (or otherwise fails to complete) counts
+        // as a failed run; we continue with any remaining runs.
+        //
+        match rval {
+            Ok(()) if pass => passed += 1,
+            Ok(()) => {}
+            Err(err) => {
+                humility::msg!("run {} failed: {:#}", run + 1, err);
+            }
+        }
+    }
+
+    if subargs.runs > 1 {
+        humility::msg!("{} of {} runs passed", passed, subargs.runs);
+    }
+
+    Ok(passed == subargs.runs)
+}
+
+fn test(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
     let subargs = TestArgs::try_parse_from(subargs)?;
 
-    hubris.validate(core, HubrisValidate::Booted)?;
+    let passed = match &subargs.ingest {
+        Some(source) if !source.is_probe() => {
+            let hubris = context.archive.as_ref().unwrap();
+            let mut stream = SWVStream::open(source)?;
 
-    let stim = 0x0000_ffff;
-    let traceid = itm_enable_ingest(core, hubris, stim)?;
-    test_ingest(core, &subargs, hubris, traceid)?;
+            let traceid =
+                if subargs.bypass { None } else { Some(ITM_TRACEID_DEFAULT) };
+
+            test_ingest(None, &subargs, hubris, &mut stream, traceid)?
+        }
+        _ => test_runs(context, &subargs)?,
+    };
+
+    if !passed {
+        bail!("tests failed");
+    }
 
     Ok(())
 }
 
 pub fn init() -> (Command, ClapCommand<'static>) {
     (
-        Command::Attached {
+        Command::Unattached {
             name: "test",
             archive: Archive::Required,
-            attach: Attach::LiveOnly,
-            validate: Validate::Booted,
             run: test,
         },
         TestArgs::command(),
@@ -2,17 +2,58 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, Result};
+//! ## `humility trace`
+//!
+//! `humility trace` traces the scheduling of Hubris tasks, displaying each
+//! change in a task's scheduling state.  With `--statemap` (`-s`), output is
+//! instead in the form expected by
+//! [statemap](https://github.com/oxidecomputer/statemap), allowing task
+//! activity to be visualized.
+//!
+//! On ARM, trace is sourced from ITM packets that the kernel emits on each
+//! context switch and scheduling state change.  These are read from the
+//! attached target by default, but can also be ingested from a capture via
+//! `--ingest` (`-i`); see `humility itm` for the supported sources.  When
+//! ingesting a capture, task names are taken from the archive.
+//!
+//! On RISC-V (or with `--ringbuf` on ARM), trace is instead sourced from a
+//! ring buffer in the kernel that records context switch events, and can be
+//! taken from either a live target or a dump.  The ring buffer's entries are
+//! expected to have members named `task` and `time` (the task switched to,
+//! and the timer value at the switch) and optionally `state` (the new
+//! scheduling state of the task, in which case the entry records a state
+//! change rather than a switch); these are resolved from the DWARF
+//! definition of the ring buffer's payload.  If the payload is an enum, each
+//! variant with these members is an event and other variants are ignored.
+//! If the kernel has more than one ring buffer, the one to use can be
+//! specified as an argument to `--ringbuf`; the frequency of the timer (in
+//! Hz) is specified with `--frequency`.
+//!
+//! ```console
+//! % humility -d ./hubris.core.0 trace
+//! humility: attached to dump
+//! humility: tracing from kernel ring buffer kern::SCHED_RINGBUF
+//! 0.001025000 3 (ping): Running
+//! 0.001034000 3 (ping): InReply((2))
+//! 0.001037000 2 (pong): Running
+//! ...
+//! ```
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::cli::Subcommand;
 use humility::core::Core;
 use humility::hubris::*;
+use humility::reflect::{Base, Format, Value};
+use humility_cmd::doppel::RingbufEntry;
+use humility_cmd::ringbuf::{ringbufs, RingbufVariable};
 use humility_cmd::{Archive, Attach, Command, Validate};
 use humility_cortex::itm::*;
+use humility_cortex::swv::*;
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::time::Instant;
 use std::time::SystemTime;
 
 #[derive(Parser, Debug)]
@@ -21,73 +62,112 @@ struct TraceArgs {
     /// provide statemap-ready output
     #[clap(long, short)]
     statemap: bool,
+
+    /// trace from a kernel ring buffer (optionally specified by substring
+    /// of its name) rather than from ITM
+    #[clap(long, value_name = "name", min_values = 0)]
+    ringbuf: Option<Option<String>>,
+
+    /// frequency of ring buffer timestamps, in Hz
+    #[clap(
+        long, value_name = "hz", default_value = "1000000",
+        parse(try_from_str = parse_int::parse)
+    )]
+    frequency: u64,
+
+    /// trace from captured ITM data rather than the attached device: a file
+    /// (either raw or a Saleae CSV export), "raw:<file>", "saleae:<file>" or
+    /// "tcp:<host>:<port>"
+    #[clap(long, short, value_name = "source", conflicts_with = "ringbuf")]
+    ingest: Option<SWVSource>,
+
+    /// assume bypassed TPIU in ingested data
+    #[clap(long, short, requires = "ingest")]
+    bypass: bool,
 }
 
+//
+// The index of a task within a Hubris TaskId (the remaining bits being the
+// task's generation).
+//
+const HUBRIS_TASKID_INDEX_MASK: u64 = 0x3ff;
+
+//
+// Emits the statemap header (describing the states and the entities), and
+// returns a map from a scheduling state (as displayed) to its state value.
+//
 #[rustfmt::skip::macros(println)]
+fn tracecmd_statemap_header(
+    tasks: &HashMap<u32, String>,
+) -> Result<HashMap<String, i32>> {
+    let mut states: HashMap<String, i32> = HashMap::new();
+
+    let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+
+    let colors = [
+        "#ed441f", "#ef5b3b", "#f27357", "#f48a73", "#f6a28f", "#f8b9ab",
+        "#fad0c7", "#fde8e3",
+    ];
+
+    println!("{{");
+    println!("\t\"start\": [ {}, {} ],", t.as_secs(), t.subsec_nanos());
+    println!("\t\"title\": \"Hubris tasks\",");
+    println!("\t\"entityKind\": \"Task\",");
+
+    println!("\t\"states\": {{");
+    println!("\t\t\"Running\": \
+        {{ \"value\": 0, \"color\": \"#DAF7A6\" }},");
+    println!("\t\t\"Runnable\": \
+        {{ \"value\": 1, \"color\": \"#9BC362\" }},");
+    println!("\t\t\"InRecv\": \
+        {{ \"value\": 2, \"color\": \"#e0e0e0\" }},");
+
+    states.insert("Runnable".to_string(), 1);
+    states.insert("InRecv(None)".to_string(), 2);
+
+    //
+    // Images generally have more tasks than we have colors; we cycle
+    // through them.
+    //
+    for i in 0..tasks.len() {
+        let name = tasks.get(&(i as u32)).unwrap();
+        let s = format!("InReply(({}))", i);
+        let state = 3 + i;
+
+        states.insert(s, state as i32);
+        println!("\t\t\"InReply({})\": {{ \"value\": {}, \
+            \"color\": \"{}\" }}{}", name, state, colors[i % colors.len()],
+            if i < tasks.len() - 1 { "," } else { "" });
+    }
+
+    println!("\t}}");
+    println!("}}");
+
+    for i in 0..tasks.len() {
+        let name = tasks.get(&(i as u32)).unwrap();
+        println!("{{ \"entity\": \"{}\", \"description\": \"{}\" }}",
+            i, name);
+    }
+
+    Ok(states)
+}
 
+#[rustfmt::skip::macros(println)]
 fn tracecmd_ingest(
-    core: &mut dyn Core,
+    mut core: Option<&mut dyn Core>,
     subargs: &TraceArgs,
     hubris: &HubrisArchive,
     tasks: &HashMap<u32, String>,
+    stream: &mut SWVStream,
     traceid: Option<u8>,
 ) -> Result<()> {
-    let mut bytes: Vec<u8> = vec![];
-    let mut ndx = 0;
-
-    let start = Instant::now();
-    let mut ts: f64 = 0.0;
     let mut time = 0;
 
-    let mut states: HashMap<String, i32> = HashMap::new();
-
-    if subargs.statemap {
-        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-
-        let colors = [
-            "#ed441f", "#ef5b3b", "#f27357", "#f48a73", "#f6a28f", "#f8b9ab",
-            "#fad0c7", "#fde8e3",
-        ];
-
-        println!("{{");
-        println!("\t\"start\": [ {}, {} ],", t.as_secs(), t.subsec_nanos());
-        println!("\t\"title\": \"Hubris tasks\",");
-        println!("\t\"entityKind\": \"Task\",");
-
-        println!("\t\"states\": {{");
-        println!("\t\t\"Running\": \
-            {{ \"value\": 0, \"color\": \"#DAF7A6\" }},");
-        println!("\t\t\"Runnable\": \
-            {{ \"value\": 1, \"color\": \"#9BC362\" }},");
-        println!("\t\t\"InRecv\": \
-            {{ \"value\": 2, \"color\": \"#e0e0e0\" }},");
-
-        states.insert("Runnable".to_string(), 1);
-        states.insert("InRecv(None)".to_string(), 2);
-
-        // TODO: this algorithm is broken for images with more than eight tasks,
-        // which makes using iterators hard.
-        #[allow(clippy::needless_range_loop)]
-        for i in 0..tasks.len() {
-            let name = tasks.get(&(i as u32)).unwrap();
-            let s = format!("InReply(({}))", i);
-            let state = 3 + i;
-
-            states.insert(s, state as i32);
-            println!("\t\t\"InReply({})\": {{ \"value\": {}, \
-                \"color\": \"{}\" }}{}", name, state, colors[i],
-                if i < tasks.len() - 1 { "," } else { "" });
-        }
-
-        println!("\t}}");
-        println!("}}");
-
-        for i in 0..tasks.len() {
-            let name = tasks.get(&(i as u32)).unwrap();
-            println!("{{ \"entity\": \"{}\", \"description\": \"{}\" }}",
-                i, name);
-        }
-    }
+    let states = if subargs.statemap {
+        tracecmd_statemap_header(tasks)?
+    } else {
+        HashMap::new()
+    };
 
     let tstruct = hubris.lookup_struct_byname("Task")?;
     let state = tstruct.lookup_member("state")?;
@@ -104,15 +184,7 @@ fn tracecmd_ingest(
 
     itm_ingest(
         traceid,
-        || {
-            while ndx == bytes.len() {
-                bytes = core.read_swv().unwrap();
-                ts = start.elapsed().as_secs_f64();
-                ndx = 0;
-            }
-            ndx += 1;
-            Ok(Some((bytes[ndx - 1], ts)))
-        },
+        || stream.next(core.as_deref_mut()),
         |packet| {
             match &packet.payload {
                 ITMPayload::Instrumentation { payload, port } => {
@@ -201,12 +273,302 @@ fn tracecmd_ingest(
     )
 }
 
-fn tracecmd(context: &mut humility::ExecutionContext) -> Result<()> {
+//
+// The scheduling event members of a kernel ring buffer entry's payload (or
+// of one variant of it), as resolved from its DWARF definition.
+//
+#[derive(Copy, Clone, Debug)]
+struct TraceEventMembers {
+    /// the members are within a 1-tuple (e.g., a newtype variant)
+    newtype: bool,
+    /// there is a `state` member
+    state: bool,
+}
+
+#[derive(Debug)]
+enum TraceEventLayout {
+    Struct(TraceEventMembers),
+    Enum(HashMap<String, TraceEventMembers>),
+}
+
+//
+// Returns true if the specified type is an integer, or a newtype around
+// one (e.g., a TaskId).
+//
+fn tracecmd_is_integer(hubris: &HubrisArchive, goff: HubrisGoff) -> bool {
+    match hubris.lookup_type(goff) {
+        Ok(HubrisType::Base(b)) => matches!(
+            b.encoding,
+            HubrisEncoding::Signed | HubrisEncoding::Unsigned
+        ),
+        Ok(HubrisType::Struct(s)) => match s.newtype() {
+            Some(inner) => tracecmd_is_integer(hubris, inner),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+//
+// Resolves the members of a scheduling event from the specified struct:
+// an integer `task` and `time` and, optionally, an enum `state`.
+//
+fn tracecmd_event_members(
+    hubris: &HubrisArchive,
+    goff: HubrisGoff,
+) -> Result<TraceEventMembers> {
+    let mut s = hubris.lookup_struct(goff)?;
+    let mut newtype = false;
+
+    if let Some(inner) = s.newtype() {
+        s = hubris.lookup_struct(inner)?;
+        newtype = true;
+    }
+
+    for name in ["task", "time"] {
+        let member = s.lookup_member(name)?;
+
+        if !tracecmd_is_integer(hubris, member.goff) {
+            bail!("{}.{} is not an integer", s.name, name);
+        }
+    }
+
+    let state = match s.lookup_member("state") {
+        Ok(member) => {
+            if hubris.lookup_enum(member.goff).is_err() {
+                bail!("{}.state is not an enum", s.name);
+            }
+
+            true
+        }
+        Err(_) => false,
+    };
+
+    Ok(TraceEventMembers { newtype, state })
+}
+
+fn tracecmd_event_layout(
+    hubris: &HubrisArchive,
+    goff: HubrisGoff,
+) -> Result<TraceEventLayout> {
+    let e = match hubris.lookup_enum(goff) {
+        Ok(e) => e,
+        Err(_) => {
+            return Ok(TraceEventLayout::Struct(tracecmd_event_members(
+                hubris, goff,
+            )?));
+        }
+    };
+
+    //
+    // For an enum, each variant that has the members of a scheduling event
+    // is an event; any others are ignored.
+    //
+    let variants = e
+        .variants
+        .iter()
+        .filter_map(|v| {
+            let members = tracecmd_event_members(hubris, v.goff?).ok()?;
+            Some((v.name.clone(), members))
+        })
+        .collect::<HashMap<_, _>>();
+
+    if variants.is_empty() {
+        bail!("no variant of {} has task and time members", e.name);
+    }
+
+    Ok(TraceEventLayout::Enum(variants))
+}
+
+//
+// Returns the task, time and (if present) state of a ring buffer entry,
+// as laid out by the specified layout.
+//
+fn tracecmd_event<'a>(
+    layout: &TraceEventLayout,
+    entry: &'a RingbufEntry,
+) -> Option<(&'a Value, &'a Value, Option<&'a Value>)> {
+    let payload = &entry.payload;
+
+    let (members, value) = match (layout, payload) {
+        (TraceEventLayout::Struct(members), _) => (members, payload),
+        (TraceEventLayout::Enum(variants), Value::Enum(e)) => {
+            (variants.get(e.disc())?, e.contents()?)
+        }
+        _ => return None,
+    };
+
+    let value = match (members.newtype, value) {
+        (false, _) => value,
+        (true, Value::Tuple(t)) if t.len() == 1 => &t[0],
+        _ => return None,
+    };
+
+    let s = match value {
+        Value::Struct(s) => s,
+        _ => return None,
+    };
+
+    let member = |name| s.iter().find(|(n, _)| *n == name).map(|(_, v)| v);
+
+    Some((
+        member("task")?,
+        member("time")?,
+        if members.state { Some(member("state")?) } else { None },
+    ))
+}
+
+//
+// Extracts an integer from the specified value, looking through newtypes
+// (e.g., a TaskId) and enum variants to find it.
+//
+fn tracecmd_integer(value: &Value) -> Option<u64> {
+    match value {
+        Value::Base(b) => match *b {
+            Base::U8(v) => Some(v as u64),
+            Base::U16(v) => Some(v as u64),
+            Base::U32(v) => Some(v as u64),
+            Base::U64(v) => Some(v),
+            Base::I8(v) => Some(v as u64),
+            Base::I16(v) => Some(v as u64),
+            Base::I32(v) => Some(v as u64),
+            Base::I64(v) => Some(v as u64),
+            _ => None,
+        },
+        Value::Struct(s) => s.iter().find_map(|(_, v)| tracecmd_integer(v)),
+        Value::Tuple(t) => t.iter().find_map(tracecmd_integer),
+        Value::Enum(e) => tracecmd_integer(e.contents()?),
+        _ => None,
+    }
+}
+
+//
+// Determines the statemap key for a scheduling state, matching the
+// representation of the state when sourced from ITM.
+//
+fn tracecmd_state_key(state: &Value) -> Option<String> {
+    let e = match state {
+        Value::Enum(e) => e,
+        _ => return None,
+    };
+
+    match (e.disc(), e.contents()) {
+        ("InRecv", Some(Value::Tuple(t))) if t.len() == 1 => match &t[0] {
+            Value::Enum(inner) if inner.disc() == "None" => {
+                Some("InRecv(None)".to_string())
+            }
+            _ => None,
+        },
+        ("InReply", Some(contents)) => {
+            let task = tracecmd_integer(contents)? & HUBRIS_TASKID_INDEX_MASK;
+            Some(format!("InReply(({}))", task))
+        }
+        (disc, None) => Some(disc.to_string()),
+        _ => None,
+    }
+}
+
+#[rustfmt::skip::macros(println)]
+fn tracecmd_ringbuf(
+    core: &mut dyn Core,
+    subargs: &TraceArgs,
+    hubris: &HubrisArchive,
+    tasks: &HashMap<u32, String>,
+    name: Option<&str>,
+) -> Result<()> {
+    let candidates = ringbufs(hubris)
+        .into_iter()
+        .filter(|r| matches!(r.taskname(hubris), Ok("kernel")))
+        .filter(|r| name.map_or(true, |n| r.name.contains(n)))
+        .collect::<Vec<RingbufVariable>>();
+
+    let ringbuf = match candidates.len() {
+        0 => match name {
+            Some(n) => bail!("no kernel ring buffer matches \"{}\"", n),
+            None => bail!("kernel has no ring buffer from which to trace"),
+        },
+        1 => &candidates[0],
+        _ => {
+            let names = candidates.iter().map(|r| r.name).collect::<Vec<_>>();
+            bail!(
+                "multiple kernel ring buffers found ({}); specify one \
+                with --ringbuf",
+                names.join(", ")
+            );
+        }
+    };
+
+    humility::msg!("tracing from kernel ring buffer {}", ringbuf.name);
+
+    if subargs.frequency == 0 {
+        bail!("frequency must be non-zero");
+    }
+
+    let states = if subargs.statemap {
+        tracecmd_statemap_header(tasks)?
+    } else {
+        HashMap::new()
+    };
+
+    let frequency = subargs.frequency as f64;
+    let fmt = HubrisPrintFormat::default();
+    let invalid = "<invalid>".to_string();
+
+    let layout = tracecmd_event_layout(hubris, ringbuf.payload(hubris)?)
+        .with_context(|| {
+            format!("{} does not contain scheduling events", ringbuf.name)
+        })?;
+
+    for (_, entry) in ringbuf.read(hubris, core)?.entries() {
+        let (task, time, state) = match tracecmd_event(&layout, entry) {
+            Some(event) => event,
+            None => continue,
+        };
+
+        let (time, task) =
+            match (tracecmd_integer(time), tracecmd_integer(task)) {
+                (Some(time), Some(task)) => (
+                    time as f64 / frequency,
+                    (task & HUBRIS_TASKID_INDEX_MASK) as u32,
+                ),
+                _ => continue,
+            };
+
+        if subargs.statemap {
+            let value = match state {
+                None => 0,
+                Some(state) => tracecmd_state_key(state)
+                    .and_then(|key| states.get(&key).copied())
+                    .unwrap_or(-1),
+            };
+
+            println!("{{ \"time\": \"{}\", \"entity\": \"{}\", \
+                \"state\": {} }}",
+                (time * 1_000_000_000_f64) as u64, task, value);
+        } else {
+            let state = match state {
+                None => "Running".to_string(),
+                Some(state) => {
+                    let mut out = vec![];
+                    state.format(hubris, fmt, &mut out)?;
+                    String::from_utf8(out)?
+                }
+            };
+
+            println!("{:.9} {} ({}): {}",
+                time, task, tasks.get(&task).unwrap_or(&invalid), state);
+        }
+    }
+
+    Ok(())
+}
+
+fn tracecmd_attached(
+    context: &mut humility::ExecutionContext,
+    subargs: &TraceArgs,
+) -> Result<()> {
     let core = &mut **context.core.as_mut().unwrap();
-    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
     let hubris = context.archive.as_ref().unwrap();
-
-    let subargs = &TraceArgs::try_parse_from(subargs)?;
     let mut tasks: HashMap<u32, String> = HashMap::new();
 
     //
@@ -236,24 +598,99 @@ fn tracecmd(context: &mut humility::ExecutionContext) -> Result<()> {
         tasks.insert(i, module.to_string());
     }
 
+    //
+    // RISC-V has no ITM, so there we always trace from the kernel's ring
+    // buffer; elsewhere, we do so only if asked.
+    //
+    let riscv = hubris
+        .manifest
+        .target
+        .as_ref()
+        .map_or(false, |t| t.starts_with("riscv"));
+
+    if riscv || subargs.ringbuf.is_some() {
+        let name = subargs.ringbuf.as_ref().and_then(|r| r.as_deref());
+        return tracecmd_ringbuf(core, subargs, hubris, &tasks, name);
+    }
+
+    if core.is_dump() {
+        bail!("tracing from ITM requires a live target; try --ringbuf");
+    }
+
     //
     // Now enable ITM and ingest.
     //
     let traceid = itm_enable_ingest(core, hubris, 0xf000_0000)?;
-    tracecmd_ingest(core, subargs, hubris, &tasks, traceid)?;
+    let mut stream = SWVStream::open(&SWVSource::Probe)?;
+    tracecmd_ingest(Some(core), subargs, hubris, &tasks, &mut stream, traceid)
+}
 
-    Ok(())
+fn tracecmd(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let subargs = TraceArgs::try_parse_from(subargs)?;
+
+    match &subargs.ingest {
+        Some(source) if !source.is_probe() => {
+            //
+            // We are ingesting a capture, so we have no target from which to
+            // read the task table; take our task names from the archive.
+            //
+            let hubris = context.archive.as_ref().unwrap();
+            let mut stream = SWVStream::open(source)?;
+
+            let tasks = (0..hubris.ntasks())
+                .map(|i| {
+                    let name = hubris.task_name(i).unwrap_or("<unknown>");
+                    (i as u32, name.to_string())
+                })
+                .collect::<HashMap<u32, String>>();
+
+            let traceid =
+                if subargs.bypass { None } else { Some(ITM_TRACEID_DEFAULT) };
+
+            tracecmd_ingest(
+                None,
+                &subargs,
+                hubris,
+                &tasks,
+                &mut stream,
+                traceid,
+            )
+        }
+        _ => humility_cmd::attach(
+            context,
+            Attach::Any,
+            Validate::Match,
+            |context| tracecmd_attached(context, &subargs),
+        ),
+    }
 }
 
 pub fn init() -> (Command, ClapCommand<'static>) {
     (
-        Command::Attached {
+        Command::Unattached {
             name: "trace",
             archive: Archive::Required,
-            attach: Attach::LiveOnly,
-            validate: Validate::Match,
             run: tracecmd,
         },
         TraceArgs::command(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn many_tasks() -> Result<()> {
+        let tasks: HashMap<u32, String> =
+            (0..12).map(|i| (i, format!("task{}", i))).collect();
+
+        let states = tracecmd_statemap_header(&tasks)?;
+
+        assert_eq!(states.len(), 2 + tasks.len());
+        assert_eq!(states["InReply((11))"], 14);
+
+        Ok(())
+    }
+}
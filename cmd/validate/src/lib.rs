@@ -42,12 +42,13 @@
 //! ```
 //!
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use colored::Colorize;
 use hif::*;
 use humility::cli::Subcommand;
+use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::i2c::I2cArgs;
@@ -132,6 +133,134 @@ fn list(hubris: &HubrisArchive, hargs: &Option<I2cArgs>) -> Result<()> {
 
     Ok(())
 }
+
+///
+/// The result of validating a device.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceValidation {
+    /// The device is present, but could not be validated
+    Present,
+    /// The device is present and has been validated
+    Validated,
+    /// The device is absent, but is removable
+    Removed,
+    /// The device is absent
+    Absent,
+    /// The device is present, but failed validation
+    Failed,
+    /// The device timed out
+    Timeout,
+    /// The device returned an error
+    Error,
+    /// A successful result of an unrecognized variant (named here)
+    Unrecognized(String),
+    /// A successful result that could not be decoded (formatted here)
+    Undecoded(String),
+    /// An unrecognized failure
+    Unknown(String),
+}
+
+impl std::fmt::Display for DeviceValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceValidation::Present => write!(f, "present"),
+            DeviceValidation::Validated => write!(f, "validated"),
+            DeviceValidation::Removed => write!(f, "removed"),
+            DeviceValidation::Absent => write!(f, "absent"),
+            DeviceValidation::Failed => write!(f, "failed"),
+            DeviceValidation::Timeout => write!(f, "timeout"),
+            DeviceValidation::Error => write!(f, "error"),
+            DeviceValidation::Unrecognized(s) => write!(f, "<{}>", s),
+            DeviceValidation::Undecoded(s) => write!(f, "{}", s),
+            DeviceValidation::Unknown(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+///
+/// Validates the specified devices (identified by their index in the
+/// manifest) via the `validate` task, returning a result for each.
+///
+pub fn validate_devices<'a>(
+    hubris: &'a HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    devices: &[usize],
+) -> Result<Vec<(usize, &'a HubrisI2cDevice, DeviceValidation)>> {
+    let funcs = context.functions()?;
+    let op = idol::IdolOperation::new(hubris, "Validate", "validate_i2c", None)
+        .context("is the 'validate' task present?")?;
+    let mut ops = vec![];
+
+    for ndx in devices {
+        if *ndx >= hubris.manifest.i2c_devices.len() {
+            bail!("invalid device identifier {}", ndx);
+        }
+
+        let payload =
+            op.payload(&[("index", idol::IdolArgument::Scalar(*ndx as u64))])?;
+        context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+    }
+
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    let fmt = HubrisPrintFormat {
+        newline: false,
+        hex: true,
+        ..HubrisPrintFormat::default()
+    };
+
+    let ok = hubris.lookup_enum(op.ok)?;
+    let mut rval = vec![];
+
+    for (rndx, ndx) in devices.iter().enumerate() {
+        let device = &hubris.manifest.i2c_devices[*ndx];
+
+        let result = match &results[rndx] {
+            Ok(val) => {
+                if let Some(variant) = ok.lookup_variant(val[0].into()) {
+                    match variant.name.as_str() {
+                        "Present" => DeviceValidation::Present,
+                        "Validated" => DeviceValidation::Validated,
+                        _ => {
+                            DeviceValidation::Unrecognized(variant.name.clone())
+                        }
+                    }
+                } else {
+                    DeviceValidation::Undecoded(
+                        hubris.printfmt(val, op.ok, fmt)?,
+                    )
+                }
+            }
+            Err(e) => match op.error.unwrap().lookup_variant(*e as u64) {
+                Some(variant) => match variant.name.as_str() {
+                    "NotPresent" => {
+                        if device.removable {
+                            DeviceValidation::Removed
+                        } else {
+                            DeviceValidation::Absent
+                        }
+                    }
+                    "BadValidation" => DeviceValidation::Failed,
+                    "DeviceTimeout" => DeviceValidation::Timeout,
+                    "DeviceError" => DeviceValidation::Error,
+                    _ => {
+                        DeviceValidation::Unknown(format!("<{}>", variant.name))
+                    }
+                },
+                None => DeviceValidation::Unknown(format!("Err(0x{:x?})", e)),
+            },
+        };
+
+        rval.push((*ndx, device, result));
+    }
+
+    Ok(rval)
+}
+
 fn validate(context: &mut humility::ExecutionContext) -> Result<()> {
     let core = &mut **context.core.as_mut().unwrap();
     let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
@@ -157,11 +286,6 @@ fn validate(context: &mut humility::ExecutionContext) -> Result<()> {
     }
 
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
-    let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Validate", "validate_i2c", None)
-        .context("is the 'validate' task present?")?;
-    let mut ops = vec![];
-
     let mut devices = vec![];
 
     for (ndx, device) in hubris.manifest.i2c_devices.iter().enumerate() {
@@ -181,59 +305,24 @@ fn validate(context: &mut humility::ExecutionContext) -> Result<()> {
             }
         }
 
-        devices.push((ndx, device));
-
-        let payload =
-            op.payload(&[("index", idol::IdolArgument::Scalar(ndx as u64))])?;
-        context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+        devices.push(ndx);
     }
 
-    ops.push(Op::Done);
-
-    let results = context.run(core, ops.as_slice(), None)?;
-
-    let fmt = HubrisPrintFormat {
-        newline: false,
-        hex: true,
-        ..HubrisPrintFormat::default()
-    };
+    let results = validate_devices(hubris, core, &mut context, &devices)?;
 
     println!(
         "{:2} {:11} {:>2} {:2} {:3} {:4} {:13} DESCRIPTION",
         "ID", "VALIDATION", "C", "P", "MUX", "ADDR", "DEVICE"
     );
 
-    let ok = hubris.lookup_enum(op.ok)?;
-
-    for (rndx, (ndx, device)) in devices.iter().enumerate() {
-        let result = match &results[rndx] {
-            Ok(val) => {
-                if let Some(variant) = ok.lookup_variant(val[0].into()) {
-                    match variant.name.as_str() {
-                        "Present" => "present".yellow(),
-                        "Validated" => "validated".green(),
-                        _ => format!("<{}>", variant.name).cyan(),
-                    }
-                } else {
-                    hubris.printfmt(val, op.ok, fmt)?.white()
-                }
-            }
-            Err(e) => match op.error.unwrap().lookup_variant(*e as u64) {
-                Some(variant) => match variant.name.as_str() {
-                    "NotPresent" => {
-                        if device.removable {
-                            "removed".blue()
-                        } else {
-                            "absent".red()
-                        }
-                    }
-                    "BadValidation" => "failed".red(),
-                    "DeviceTimeout" => "timeout".red(),
-                    "DeviceError" => "error".red(),
-                    _ => format!("<{}>", variant.name).red(),
-                },
-                None => format!("Err(0x{:x?})", e).red(),
-            },
+    for (ndx, device, result) in results {
+        let result = match result {
+            DeviceValidation::Present => result.to_string().yellow(),
+            DeviceValidation::Validated => result.to_string().green(),
+            DeviceValidation::Removed => result.to_string().blue(),
+            DeviceValidation::Unrecognized(_) => result.to_string().cyan(),
+            DeviceValidation::Undecoded(_) => result.to_string().white(),
+            _ => result.to_string().red(),
         };
 
         let mux = match (device.mux, device.segment) {
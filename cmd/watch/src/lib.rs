@@ -0,0 +1,385 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility watch`
+//!
+//! `humility watch` periodically reads one or more variables, displaying
+//! the fields that have changed since the previous sample.  The first
+//! sample displays every field of each variable; subsequent samples display
+//! only the fields that have changed, highlighted and along with their
+//! previous values (times are in UTC):
+//!
+//! ```console
+//! % humility watch TIMER_STATE
+//! humility: attached via ST-Link V3
+//! humility: watching 1 variable every 1000ms
+//! 12:02:11.043 TIMER_STATE.ticks = 0x2e1c5
+//! 12:02:11.043 TIMER_STATE.deadline = 0x2e1c8
+//! 12:02:11.043 TIMER_STATE.state = Armed
+//! 12:02:12.045 TIMER_STATE.ticks = 0x2e5b0 (was 0x2e1c5)
+//! 12:02:12.045 TIMER_STATE.deadline = 0x2e5b3 (was 0x2e1c8)
+//! ...
+//! ```
+//!
+//! Fields are named by their path within the variable:  members of
+//! structures are separated by `.`, elements of arrays and tuples are
+//! indexed (e.g., `[3]`), and the contents of an enum variant are named by
+//! the variant (e.g., `state.Armed.deadline`).  To display every field on
+//! every sample (with changed fields highlighted), use `-a` (`--all`).
+//! Values are displayed in hex unless `-d` (`--decimal`) is specified.
+//!
+//! The interval between samples can be set with `-i` (`--interval`), and
+//! the number of samples with `-n` (`--count`); by default, `humility watch`
+//! runs until interrupted.  All variables are read together, minimizing the
+//! time that a target that must be halted to be read is stopped.
+//!
+//! To record the samples, use `-o` (`--output`) to specify a file to which
+//! one JSON object is appended per variable per sample, containing the
+//! time (in seconds since the epoch), the variable, all of its fields, and
+//! the paths of the fields that changed:
+//!
+//! ```json
+//! {"time":1666108931.043,"variable":"TIMER_STATE","fields":{...},"changed":["ticks","deadline"]}
+//! ```
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use colored::Colorize;
+use humility::cli::Subcommand;
+use humility::hubris::*;
+use humility::reflect::{self, Value};
+use humility_cmd::json::base_json;
+use humility_cmd::{Archive, Attach, Command, Validate};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Parser, Debug)]
+#[clap(name = "watch", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct WatchArgs {
+    /// list variables
+    #[clap(long, short)]
+    list: bool,
+
+    /// values in decimal instead of hex
+    #[clap(long, short)]
+    decimal: bool,
+
+    /// display all fields on every sample, not just those that changed
+    #[clap(long, short)]
+    all: bool,
+
+    /// interval between samples
+    #[clap(
+        long, short, default_value = "1000", value_name = "ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// number of samples to take
+    #[clap(
+        long, short = 'n', value_name = "samples",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    count: Option<u64>,
+
+    /// file to which to append samples as JSON lines
+    #[clap(long, short, value_name = "filename")]
+    output: Option<String>,
+
+    /// variables to watch
+    #[clap(required_unless_present = "list", conflicts_with = "list")]
+    variables: Vec<String>,
+}
+
+///
+/// A field within a variable, as identified by its path.
+///
+struct Field {
+    path: String,
+    display: String,
+    json: serde_json::Value,
+}
+
+struct Watched<'a> {
+    name: String,
+    variable: &'a HubrisVariable,
+    last: Option<BTreeMap<String, String>>,
+}
+
+///
+/// Flattens a value into its constituent fields.
+///
+fn flatten(value: &Value, path: &str, hex: bool, fields: &mut Vec<Field>) {
+    let member = |name: &str| {
+        if path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", path, name)
+        }
+    };
+
+    match value {
+        Value::Base(base) => {
+            let display = if hex && base.supports_hex() {
+                format!("0x{:x}", base)
+            } else {
+                base.to_string()
+            };
+
+            fields.push(Field {
+                path: path.to_string(),
+                display,
+                json: base_json(base),
+            });
+        }
+
+        Value::Struct(s) => {
+            for (name, v) in s.iter() {
+                flatten(v, &member(name), hex, fields);
+            }
+        }
+
+        Value::Tuple(t) => {
+            for (i, v) in t.iter().enumerate() {
+                flatten(v, &format!("{}[{}]", path, i), hex, fields);
+            }
+        }
+
+        Value::Array(a) => {
+            for (i, v) in a.iter().enumerate() {
+                flatten(v, &format!("{}[{}]", path, i), hex, fields);
+            }
+        }
+
+        Value::Enum(e) => {
+            fields.push(Field {
+                path: path.to_string(),
+                display: e.disc().to_string(),
+                json: serde_json::Value::String(e.disc().to_string()),
+            });
+
+            if let Some(contents) = e.contents() {
+                flatten(contents, &member(e.disc()), hex, fields);
+            }
+        }
+
+        Value::Ptr(p) => {
+            let display = format!("0x{:08x}", p.addr());
+
+            fields.push(Field {
+                path: path.to_string(),
+                json: serde_json::Value::String(display.clone()),
+                display,
+            });
+        }
+    }
+}
+
+fn timestamp(now: SystemTime) -> (String, f64) {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+
+    (
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            (secs / 3600) % 24,
+            (secs / 60) % 60,
+            secs % 60,
+            since.subsec_millis()
+        ),
+        since.as_secs_f64(),
+    )
+}
+
+fn watch_sample(
+    hubris: &HubrisArchive,
+    watched: &mut Watched,
+    buf: &[u8],
+    subargs: &WatchArgs,
+    output: &mut Option<File>,
+    now: SystemTime,
+) -> Result<()> {
+    let ty = hubris.lookup_type(watched.variable.goff)?;
+    let value = reflect::load_value(hubris, buf, ty, 0)?;
+
+    let mut fields = vec![];
+    flatten(&value, "", !subargs.decimal, &mut fields);
+
+    let (when, secs) = timestamp(now);
+    let mut changed = vec![];
+
+    for field in &fields {
+        let name = if field.path.is_empty() {
+            watched.name.clone()
+        } else if field.path.starts_with('[') {
+            format!("{}{}", watched.name, field.path)
+        } else {
+            format!("{}.{}", watched.name, field.path)
+        };
+
+        let previous = match &watched.last {
+            Some(last) => last.get(&field.path),
+            None => None,
+        };
+
+        match (&watched.last, previous) {
+            (None, _) => {
+                println!("{} {} = {}", when, name, field.display);
+            }
+            (Some(_), Some(previous)) if *previous == field.display => {
+                if subargs.all {
+                    println!("{} {} = {}", when, name, field.display);
+                }
+            }
+            (Some(_), previous) => {
+                changed.push(field.path.as_str());
+
+                let was = match previous {
+                    Some(previous) => format!(" (was {})", previous),
+                    None => "".to_string(),
+                };
+
+                println!(
+                    "{} {} = {}{}",
+                    when,
+                    name.bold(),
+                    field.display.yellow().bold(),
+                    was
+                );
+            }
+        }
+    }
+
+    if let Some(output) = output {
+        let json = serde_json::json!({
+            "time": secs,
+            "variable": watched.name,
+            "fields": fields
+                .iter()
+                .map(|f| (f.path.clone(), f.json.clone()))
+                .collect::<serde_json::Map<_, _>>(),
+            "changed": changed,
+        });
+
+        writeln!(output, "{}", json)?;
+    }
+
+    watched.last =
+        Some(fields.into_iter().map(|f| (f.path, f.display)).collect());
+
+    Ok(())
+}
+
+fn watch(context: &mut humility::ExecutionContext) -> Result<()> {
+    let core = &mut **context.core.as_mut().unwrap();
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let hubris = context.archive.as_ref().unwrap();
+
+    let subargs = WatchArgs::try_parse_from(subargs)?;
+
+    if subargs.list {
+        return hubris.list_variables();
+    }
+
+    let mut watched = vec![];
+
+    for name in &subargs.variables {
+        let variables = hubris.lookup_variables(name)?;
+
+        for variable in variables {
+            //
+            // If the same name refers to multiple variables (e.g., a static
+            // of the same name in different tasks), we differentiate them
+            // by address.
+            //
+            let name = if variables.len() > 1 {
+                format!("{}@0x{:08x}", name, variable.addr)
+            } else {
+                name.clone()
+            };
+
+            watched.push(Watched { name, variable, last: None });
+        }
+    }
+
+    if watched.is_empty() {
+        bail!("no variables to watch");
+    }
+
+    let mut output = match &subargs.output {
+        Some(filename) => {
+            Some(OpenOptions::new().create(true).append(true).open(filename)?)
+        }
+        None => None,
+    };
+
+    let mut bufs =
+        watched.iter().map(|w| vec![0u8; w.variable.size]).collect::<Vec<_>>();
+
+    humility::msg!(
+        "watching {} variable{} every {}ms",
+        watched.len(),
+        if watched.len() == 1 { "" } else { "s" },
+        subargs.interval
+    );
+
+    let interval = Duration::from_millis(subargs.interval);
+    let mut samples = 0;
+
+    loop {
+        let start = Instant::now();
+
+        //
+        // Read all of our variables in one operation, so a target that is
+        // halted to be read is only halted once per sample.
+        //
+        core.op_start()?;
+
+        let rval = watched
+            .iter()
+            .zip(bufs.iter_mut())
+            .try_for_each(|(w, buf)| core.read_8(w.variable.addr, buf));
+
+        core.op_done()?;
+        rval?;
+
+        let now = SystemTime::now();
+
+        for (w, buf) in watched.iter_mut().zip(bufs.iter()) {
+            watch_sample(hubris, w, buf, &subargs, &mut output, now)?;
+        }
+
+        samples += 1;
+
+        if let Some(count) = subargs.count {
+            if samples >= count {
+                break;
+            }
+        }
+
+        if let Some(remaining) = interval.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "watch",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Match,
+            run: watch,
+        },
+        WatchArgs::command(),
+    )
+}
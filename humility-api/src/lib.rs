@@ -0,0 +1,585 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! A library interface to Humility, allowing programs (e.g., test harnesses)
+//! to attach to a Hubris system and retrieve the same information as the
+//! `humility` commands -- but as typed data rather than as output to be
+//! parsed.  This crate is the interface that should be used by consumers
+//! outside of this repository:  its types and functions will remain stable,
+//! even as the internals of the crates it is built upon change.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use humility_api::Session;
+//!
+//! let mut session = Session::attach("build-gimlet.zip", None)?;
+//!
+//! for task in session.tasks()?.tasks {
+//!     println!("{} {}", task.name, task.state);
+//! }
+//!
+//! for reading in session.sensors()? {
+//!     println!("{} {:?}", reading.name, reading.value);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::env::Environment;
+use humility::hubris::*;
+use humility::reflect::Format;
+use humility_cmd::doppel::TaskState;
+use humility_cmd::hiffy::HiffyContext;
+
+///
+/// A task, as read from the task table.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+    /// Task index
+    pub id: u32,
+
+    /// Name of the task
+    pub name: String,
+
+    /// Generation (or restart count) of the task
+    pub generation: u32,
+
+    /// Priority of the task
+    pub priority: u8,
+
+    /// True if this is the currently running task
+    pub current: bool,
+
+    /// True if the task has faulted
+    pub faulted: bool,
+
+    /// A human-readable explanation of the task's state
+    pub state: String,
+}
+
+///
+/// A snapshot of the tasks in a system.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tasks {
+    /// System time, in ticks
+    pub ticks: u64,
+
+    /// The tasks, in task index order
+    pub tasks: Vec<Task>,
+}
+
+impl From<cmd_tasks::Tasks> for Tasks {
+    fn from(tasks: cmd_tasks::Tasks) -> Self {
+        Self {
+            ticks: tasks.ticks,
+            tasks: tasks
+                .tasks
+                .into_iter()
+                .map(|info| Task {
+                    id: info.id,
+                    name: info.name,
+                    generation: u32::from(info.task.generation),
+                    priority: info.task.priority.0,
+                    current: info.current,
+                    faulted: matches!(
+                        info.task.state,
+                        TaskState::Faulted { .. }
+                    ),
+                    state: info.state,
+                })
+                .collect(),
+        }
+    }
+}
+
+///
+/// An entry in a ring buffer.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RingbufEntry {
+    /// Slot in the ring buffer
+    pub slot: usize,
+
+    /// Line number at which the entry was recorded
+    pub line: u16,
+
+    /// Generation of the entry
+    pub generation: u16,
+
+    /// Number of times that the entry was repeated
+    pub count: u32,
+
+    /// The payload of the entry, formatted as `humility ringbuf` does
+    pub payload: String,
+}
+
+///
+/// The contents of a ring buffer.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ringbuf {
+    /// Name of the ring buffer
+    pub name: String,
+
+    /// Name of the task (or kernel) containing the ring buffer, if known
+    pub task: Option<String>,
+
+    /// True if the ring buffer has ever been written
+    pub written: bool,
+
+    /// The occupied entries of the ring buffer, oldest first
+    pub entries: Vec<RingbufEntry>,
+}
+
+impl Ringbuf {
+    fn new(
+        hubris: &HubrisArchive,
+        contents: cmd_ringbuf::RingbufContents,
+    ) -> Result<Self> {
+        let fmt =
+            HubrisPrintFormat { hex: true, ..HubrisPrintFormat::default() };
+        let mut entries = vec![];
+
+        for (slot, entry) in contents.entries {
+            let mut payload = vec![];
+            entry.payload.format(hubris, fmt, &mut payload)?;
+
+            entries.push(RingbufEntry {
+                slot,
+                line: entry.line,
+                generation: entry.generation,
+                count: entry.count,
+                payload: String::from_utf8(payload)?,
+            });
+        }
+
+        Ok(Self {
+            name: contents.name,
+            task: contents.task,
+            written: contents.written,
+            entries,
+        })
+    }
+}
+
+///
+/// The kind of a sensor.
+///
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SensorKind {
+    Temperature,
+    Power,
+    Current,
+    Voltage,
+    Speed,
+}
+
+impl From<HubrisSensorKind> for SensorKind {
+    fn from(kind: HubrisSensorKind) -> Self {
+        match kind {
+            HubrisSensorKind::Temperature => SensorKind::Temperature,
+            HubrisSensorKind::Power => SensorKind::Power,
+            HubrisSensorKind::Current => SensorKind::Current,
+            HubrisSensorKind::Voltage => SensorKind::Voltage,
+            HubrisSensorKind::Speed => SensorKind::Speed,
+        }
+    }
+}
+
+///
+/// A reading of a single sensor.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorReading {
+    /// Sensor ID
+    pub id: usize,
+
+    /// Name of the sensor
+    pub name: String,
+
+    /// Kind of the sensor
+    pub kind: SensorKind,
+
+    /// Name of the device containing the sensor
+    pub device: String,
+
+    /// The value read, if the sensor could be read
+    pub value: Option<f32>,
+}
+
+impl From<cmd_sensors::SensorReading> for SensorReading {
+    fn from(reading: cmd_sensors::SensorReading) -> Self {
+        Self {
+            id: reading.id,
+            name: reading.name,
+            kind: reading.kind.into(),
+            device: reading.device,
+            value: reading.value,
+        }
+    }
+}
+
+///
+/// The result of validating a device.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceValidation {
+    /// The device is present, but could not be validated
+    Present,
+    /// The device is present and has been validated
+    Validated,
+    /// The device is absent, but is removable
+    Removed,
+    /// The device is absent
+    Absent,
+    /// The device is present, but failed validation
+    Failed,
+    /// The device timed out
+    Timeout,
+    /// The device returned an error
+    Error,
+    /// A result that Humility does not recognize, as it would display it
+    Other(String),
+}
+
+impl From<cmd_validate::DeviceValidation> for DeviceValidation {
+    fn from(result: cmd_validate::DeviceValidation) -> Self {
+        use cmd_validate::DeviceValidation as Validation;
+
+        match result {
+            Validation::Present => DeviceValidation::Present,
+            Validation::Validated => DeviceValidation::Validated,
+            Validation::Removed => DeviceValidation::Removed,
+            Validation::Absent => DeviceValidation::Absent,
+            Validation::Failed => DeviceValidation::Failed,
+            Validation::Timeout => DeviceValidation::Timeout,
+            Validation::Error => DeviceValidation::Error,
+            other => DeviceValidation::Other(other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceValidation::Present => write!(f, "present"),
+            DeviceValidation::Validated => write!(f, "validated"),
+            DeviceValidation::Removed => write!(f, "removed"),
+            DeviceValidation::Absent => write!(f, "absent"),
+            DeviceValidation::Failed => write!(f, "failed"),
+            DeviceValidation::Timeout => write!(f, "timeout"),
+            DeviceValidation::Error => write!(f, "error"),
+            DeviceValidation::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+///
+/// The default timeout (in milliseconds) for operations performed via the
+/// `hiffy` task.
+///
+pub const DEFAULT_TIMEOUT: u32 = 5000;
+
+///
+/// A Hubris archive and the system (live or dump) that it describes.
+///
+pub struct Session {
+    hubris: HubrisArchive,
+    core: Box<dyn Core>,
+    timeout: u32,
+}
+
+impl Session {
+    fn new(hubris: HubrisArchive, mut core: Box<dyn Core>) -> Result<Self> {
+        hubris.validate(&mut *core, HubrisValidate::ArchiveMatch)?;
+        Ok(Self { hubris, core, timeout: DEFAULT_TIMEOUT })
+    }
+
+    ///
+    /// Attaches to a live system via the specified probe (or, if `None`, the
+    /// first probe found), validating it against the specified archive.
+    ///
+    pub fn attach(archive: &str, probe: Option<&str>) -> Result<Self> {
+        let mut hubris = HubrisArchive::new()?;
+        hubris.load(archive, HubrisArchiveDoneness::Cook)?;
+
+        let core = humility::core::attach(probe.unwrap_or("auto"), &hubris)?;
+        Self::new(hubris, core)
+    }
+
+    ///
+    /// Attaches to a dump.
+    ///
+    pub fn attach_dump(dump: &str) -> Result<Self> {
+        let mut hubris = HubrisArchive::new()?;
+        hubris.load_dump(dump, HubrisArchiveDoneness::Cook)?;
+
+        let core = humility::core::attach_dump(dump, &hubris)?;
+        Self::new(hubris, core)
+    }
+
+    ///
+    /// Attaches to a target as described in an environment file (including
+    /// its chip and hart, if specified), using the archive of the specified
+    /// name (if the target has more than one).
+    ///
+    pub fn attach_target(
+        environment: &str,
+        target: &str,
+        archive_name: Option<&str>,
+    ) -> Result<Self> {
+        let env = Environment::from_file(environment, target)?;
+        let archive = env.archive(&archive_name.map(str::to_string))?;
+
+        let mut hubris = HubrisArchive::new()?;
+        hubris.load(&archive, HubrisArchiveDoneness::Cook)?;
+
+        let mut core = humility::core::attach_to_chip(
+            &env.probe,
+            &hubris,
+            env.chip.as_deref(),
+        )?;
+
+        if let Some(hart) = env.hart {
+            core.select_hart(hart)?;
+        }
+
+        Self::new(hubris, core)
+    }
+
+    ///
+    /// Sets the timeout (in milliseconds) for operations performed via the
+    /// `hiffy` task, e.g. reading sensors and validating devices.
+    ///
+    pub fn set_timeout(&mut self, timeout: u32) {
+        self.timeout = timeout;
+    }
+
+    ///
+    /// Returns the Hubris archive.  Its type is that of Humility's internals,
+    /// and as such is not covered by the stability of this interface.
+    ///
+    pub fn hubris(&self) -> &HubrisArchive {
+        &self.hubris
+    }
+
+    ///
+    /// Returns the core, for operations not otherwise provided.  As with
+    /// [`Session::hubris`], this is not covered by the stability of this
+    /// interface.
+    ///
+    pub fn core(&mut self) -> &mut dyn Core {
+        &mut *self.core
+    }
+
+    fn booted(&mut self) -> Result<()> {
+        self.hubris.validate(&mut *self.core, HubrisValidate::Booted)
+    }
+
+    fn live(&mut self) -> Result<()> {
+        if self.core.is_dump() {
+            bail!("must be run against a live system");
+        }
+
+        self.booted()
+    }
+
+    ///
+    /// Reads the tasks, as `humility tasks` does.
+    ///
+    pub fn tasks(&mut self) -> Result<Tasks> {
+        self.booted()?;
+
+        let tasks =
+            cmd_tasks::read_tasks(&self.hubris, &mut *self.core, false)?;
+
+        //
+        // If a task has panicked, the system has been left halted; we
+        // resume it, as we have nothing further to do with it.
+        //
+        if tasks.halted {
+            self.core.run()?;
+        }
+
+        Ok(tasks.into())
+    }
+
+    ///
+    /// Reads ring buffers, as `humility ringbuf` does, optionally restricted
+    /// to those whose name (or containing task's name) contains `name`.
+    ///
+    pub fn ringbufs(&mut self, name: Option<&str>) -> Result<Vec<Ringbuf>> {
+        let hubris = &self.hubris;
+
+        cmd_ringbuf::select(hubris, name)?
+            .iter()
+            .map(|r| {
+                let contents = cmd_ringbuf::read(hubris, &mut *self.core, r)?;
+                Ringbuf::new(hubris, contents)
+            })
+            .collect()
+    }
+
+    ///
+    /// Reads every sensor, as `humility sensors` does.
+    ///
+    pub fn sensors(&mut self) -> Result<Vec<SensorReading>> {
+        self.live()?;
+
+        let hubris = &self.hubris;
+        let core = &mut *self.core;
+
+        let sensors = cmd_sensors::select(hubris, &None, &None, &None);
+        let mut context = HiffyContext::new(hubris, core, self.timeout)?;
+        let reader =
+            cmd_sensors::SensorReader::new(hubris, &mut context, sensors)?;
+
+        Ok(reader
+            .readings(hubris, core, &mut context)?
+            .into_iter()
+            .map(SensorReading::from)
+            .collect())
+    }
+
+    ///
+    /// Validates every device, as `humility validate` does, returning the
+    /// result of validating each device along with its index in the
+    /// archive's manifest (i.e., its ID in `humility validate --list`).
+    ///
+    pub fn validate(&mut self) -> Result<Vec<(usize, DeviceValidation)>> {
+        self.live()?;
+
+        let hubris = &self.hubris;
+        let core = &mut *self.core;
+
+        let devices =
+            (0..hubris.manifest.i2c_devices.len()).collect::<Vec<_>>();
+        let mut context = HiffyContext::new(hubris, core, self.timeout)?;
+
+        Ok(cmd_validate::validate_devices(
+            hubris,
+            core,
+            &mut context,
+            &devices,
+        )?
+        .into_iter()
+        .map(|(ndx, _, result)| (ndx, result.into()))
+        .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use humility::reflect::{Base, Value};
+
+    #[test]
+    fn validation() {
+        use cmd_validate::DeviceValidation as Validation;
+
+        let cases = [
+            (Validation::Present, DeviceValidation::Present),
+            (Validation::Validated, DeviceValidation::Validated),
+            (Validation::Removed, DeviceValidation::Removed),
+            (Validation::Absent, DeviceValidation::Absent),
+            (Validation::Failed, DeviceValidation::Failed),
+            (Validation::Timeout, DeviceValidation::Timeout),
+            (Validation::Error, DeviceValidation::Error),
+            (
+                Validation::Unrecognized("Degraded".to_string()),
+                DeviceValidation::Other("<Degraded>".to_string()),
+            ),
+            (
+                Validation::Undecoded("0x7".to_string()),
+                DeviceValidation::Other("0x7".to_string()),
+            ),
+            (
+                Validation::Unknown("Err(0x9)".to_string()),
+                DeviceValidation::Other("Err(0x9)".to_string()),
+            ),
+        ];
+
+        for (result, expected) in cases {
+            let display = result.to_string();
+            let converted = DeviceValidation::from(result);
+
+            assert_eq!(converted, expected);
+            assert_eq!(converted.to_string(), display);
+        }
+    }
+
+    #[test]
+    fn sensors() {
+        let reading = cmd_sensors::SensorReading {
+            id: 3,
+            name: "vdd_core".to_string(),
+            kind: HubrisSensorKind::Voltage,
+            device: "tps546b24a".to_string(),
+            value: Some(0.95),
+        };
+
+        assert_eq!(
+            SensorReading::from(reading),
+            SensorReading {
+                id: 3,
+                name: "vdd_core".to_string(),
+                kind: SensorKind::Voltage,
+                device: "tps546b24a".to_string(),
+                value: Some(0.95),
+            }
+        );
+    }
+
+    #[test]
+    fn ringbufs() -> Result<()> {
+        let hubris = HubrisArchive::new()?;
+
+        let entry = |line, count, val| humility_cmd::doppel::RingbufEntry {
+            line,
+            generation: 1,
+            count,
+            payload: Value::Base(Base::U32(val)),
+        };
+
+        let contents = cmd_ringbuf::RingbufContents {
+            name: "RINGBUF".to_string(),
+            task: Some("net".to_string()),
+            written: true,
+            entries: vec![(3, entry(120, 1, 0x1de)), (0, entry(87, 4, 0))],
+        };
+
+        let ringbuf = Ringbuf::new(&hubris, contents)?;
+
+        assert_eq!(ringbuf.name, "RINGBUF");
+        assert_eq!(ringbuf.task.as_deref(), Some("net"));
+        assert!(ringbuf.written);
+        assert_eq!(
+            ringbuf.entries,
+            vec![
+                RingbufEntry {
+                    slot: 3,
+                    line: 120,
+                    generation: 1,
+                    count: 1,
+                    payload: "0x1de".to_string(),
+                },
+                RingbufEntry {
+                    slot: 0,
+                    line: 87,
+                    generation: 1,
+                    count: 4,
+                    payload: "0x0".to_string(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
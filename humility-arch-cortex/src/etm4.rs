@@ -0,0 +1,1140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// ETMv4, as found on ARMv8-M parts (e.g., the Cortex-M33).  The ETMv4 trace
+// unit lives at the same address as the ETMv3 macrocell on ARMv7-M parts,
+// but its registers and its trace protocol are entirely different.  We
+// only support instruction trace:  the trace consists of atoms (one per
+// executed branch, indicating whether or not it was taken) and addresses
+// (for the targets of indirect branches and for exceptions) -- from which
+// the instruction stream can be reconstructed given the program image.
+//
+
+use crate::debug::Register;
+use crate::register;
+use crate::tpiu::*;
+use anyhow::{bail, Result};
+use bitfield::bitfield;
+use humility::core::Core;
+
+macro_rules! etm4_register {
+    ($reg:ty, $offs:expr, $($arg:tt)*) => (
+        register!($reg, 0xe004_1000 + ($offs * 4), $($arg)*);
+    )
+}
+
+//
+// Programming Control Register
+//
+etm4_register!(TRCPRGCTLR, 0x001,
+    #[derive(Copy, Clone)]
+    pub struct TRCPRGCTLR(u32);
+    impl Debug;
+    pub enable, set_enable: 0;
+);
+
+//
+// Status Register
+//
+etm4_register!(TRCSTATR, 0x003,
+    #[derive(Copy, Clone)]
+    pub struct TRCSTATR(u32);
+    impl Debug;
+    pub pmstable, _: 1;
+    pub idle, _: 0;
+);
+
+//
+// Trace Configuration Register
+//
+etm4_register!(TRCCONFIGR, 0x004,
+    #[derive(Copy, Clone)]
+    pub struct TRCCONFIGR(u32);
+    impl Debug;
+    pub data_value, set_data_value: 17;
+    pub data_address, set_data_address: 16;
+    pub vmid_option, set_vmid_option: 15;
+    pub q_elements, set_q_elements: 14, 13;
+    pub return_stack, set_return_stack: 12;
+    pub timestamp, set_timestamp: 11;
+    pub conditional, set_conditional: 10, 8;
+    pub vmid, set_vmid: 7;
+    pub context_id, set_context_id: 6;
+    pub cycle_counting, set_cycle_counting: 4;
+    pub branch_broadcast, set_branch_broadcast: 3;
+    pub instp0, set_instp0: 2, 1;
+);
+
+//
+// Event Control 0 Register
+//
+etm4_register!(TRCEVENTCTL0R, 0x008,
+    #[derive(Copy, Clone)]
+    pub struct TRCEVENTCTL0R(u32);
+    impl Debug;
+    pub event3, set_event3: 31, 24;
+    pub event2, set_event2: 23, 16;
+    pub event1, set_event1: 15, 8;
+    pub event0, set_event0: 7, 0;
+);
+
+//
+// Event Control 1 Register
+//
+etm4_register!(TRCEVENTCTL1R, 0x009,
+    #[derive(Copy, Clone)]
+    pub struct TRCEVENTCTL1R(u32);
+    impl Debug;
+    pub lpoverride, set_lpoverride: 12;
+    pub atb, set_atb: 11;
+    pub data_sync_marker, set_data_sync_marker: 10;
+    pub instruction_enable, set_instruction_enable: 3, 0;
+);
+
+//
+// Stall Control Register
+//
+etm4_register!(TRCSTALLCTLR, 0x00b,
+    #[derive(Copy, Clone)]
+    pub struct TRCSTALLCTLR(u32);
+    impl Debug;
+    pub instpriority, set_instpriority: 10;
+    pub istall, set_istall: 8;
+    pub level, set_level: 3, 0;
+);
+
+//
+// Global Timestamp Control Register
+//
+etm4_register!(TRCTSCTLR, 0x00c,
+    #[derive(Copy, Clone)]
+    pub struct TRCTSCTLR(u32);
+    impl Debug;
+    pub event, set_event: 7, 0;
+);
+
+//
+// Synchronization Period Register
+//
+etm4_register!(TRCSYNCPR, 0x00d,
+    #[derive(Copy, Clone)]
+    pub struct TRCSYNCPR(u32);
+    impl Debug;
+    pub period, set_period: 4, 0;
+);
+
+//
+// Cycle Count Control Register
+//
+etm4_register!(TRCCCCTLR, 0x00e,
+    #[derive(Copy, Clone)]
+    pub struct TRCCCCTLR(u32);
+    impl Debug;
+    pub threshold, set_threshold: 11, 0;
+);
+
+//
+// Branch Broadcast Control Register
+//
+etm4_register!(TRCBBCTLR, 0x00f,
+    #[derive(Copy, Clone)]
+    pub struct TRCBBCTLR(u32);
+    impl Debug;
+    pub mode, set_mode: 8;
+    pub range, set_range: 7, 0;
+);
+
+//
+// Trace ID Register
+//
+etm4_register!(TRCTRACEIDR, 0x010,
+    #[derive(Copy, Clone)]
+    pub struct TRCTRACEIDR(u32);
+    impl Debug;
+    pub traceid, set_traceid: 6, 0;
+);
+
+//
+// ViewInst Main Control Register
+//
+etm4_register!(TRCVICTLR, 0x020,
+    #[derive(Copy, Clone)]
+    pub struct TRCVICTLR(u32);
+    impl Debug;
+    pub exlevel_ns, set_exlevel_ns: 23, 20;
+    pub exlevel_s, set_exlevel_s: 19, 16;
+    pub trace_errors, set_trace_errors: 11;
+    pub trace_reset, set_trace_reset: 10;
+    pub start_stop_status, set_start_stop_status: 9;
+    pub event, set_event: 7, 0;
+);
+
+//
+// ViewInst Include/Exclude Control Register
+//
+etm4_register!(TRCVIIECTLR, 0x021,
+    #[derive(Copy, Clone)]
+    pub struct TRCVIIECTLR(u32);
+    impl Debug;
+    pub exclude, set_exclude: 23, 16;
+    pub include, set_include: 7, 0;
+);
+
+//
+// ViewInst Start/Stop Control Register
+//
+etm4_register!(TRCVISSCTLR, 0x022,
+    #[derive(Copy, Clone)]
+    pub struct TRCVISSCTLR(u32);
+    impl Debug;
+    pub stop, set_stop: 31, 16;
+    pub start, set_start: 15, 0;
+);
+
+//
+// ID Register 0
+//
+etm4_register!(TRCIDR0, 0x078,
+    #[derive(Copy, Clone)]
+    pub struct TRCIDR0(u32);
+    impl Debug;
+    pub commopt, _: 29;
+    pub tssize, _: 28, 24;
+    pub trcexdata, _: 17;
+    pub qsupp, _: 16, 15;
+    pub qfilt, _: 14;
+    pub condtype, _: 13, 12;
+    pub numevent, _: 11, 10;
+    pub retstack, _: 9;
+    pub trccci, _: 7;
+    pub trccond, _: 6;
+    pub trcbb, _: 5;
+    pub trcdata, _: 4, 3;
+    pub instp0, _: 2, 1;
+);
+
+//
+// ID Register 1.  Note that this is at the same offset as the ETMv3 ETMIDR,
+// which allows the trace architecture to be determined.
+//
+etm4_register!(TRCIDR1, 0x079,
+    #[derive(Copy, Clone)]
+    pub struct TRCIDR1(u32);
+    impl Debug;
+    pub designer, _: 31, 24;
+    pub trcarchmaj, _: 11, 8;
+    pub trcarchmin, _: 7, 4;
+    pub revision, _: 3, 0;
+);
+
+//
+// ID Register 2
+//
+etm4_register!(TRCIDR2, 0x07a,
+    #[derive(Copy, Clone)]
+    pub struct TRCIDR2(u32);
+    impl Debug;
+    pub ccsize, _: 28, 25;
+    pub dvsize, _: 24, 20;
+    pub dasize, _: 19, 15;
+    pub vmidsize, _: 14, 10;
+    pub cidsize, _: 9, 5;
+    pub iasize, _: 4, 0;
+);
+
+//
+// ID Register 3
+//
+etm4_register!(TRCIDR3, 0x07b,
+    #[derive(Copy, Clone)]
+    pub struct TRCIDR3(u32);
+    impl Debug;
+    pub nooverflow, _: 31;
+    pub numproc, _: 30, 28;
+    pub sysstall, _: 27;
+    pub stallctl, _: 26;
+    pub syncpr, _: 25;
+    pub trcerr, _: 24;
+    pub exlevel_ns, _: 23, 20;
+    pub exlevel_s, _: 19, 16;
+    pub ccitmin, _: 11, 0;
+);
+
+//
+// ID Register 4
+//
+etm4_register!(TRCIDR4, 0x07c,
+    #[derive(Copy, Clone)]
+    pub struct TRCIDR4(u32);
+    impl Debug;
+    pub numvmidc, _: 31, 28;
+    pub numcidc, _: 27, 24;
+    pub numssscc, _: 23, 20;
+    pub numrspair, _: 19, 16;
+    pub numpc, _: 15, 12;
+    pub suppdac, _: 8;
+    pub numdvc, _: 7, 4;
+    pub numacpairs, _: 3, 0;
+);
+
+//
+// ID Register 5
+//
+etm4_register!(TRCIDR5, 0x07d,
+    #[derive(Copy, Clone)]
+    pub struct TRCIDR5(u32);
+    impl Debug;
+    pub oe, _: 31;
+    pub numcntr, _: 30, 28;
+    pub numseqstate, _: 27, 25;
+    pub lpoverride, _: 23;
+    pub atbtrig, _: 22;
+    pub traceidsize, _: 21, 16;
+    pub numextinsel, _: 11, 9;
+    pub numextin, _: 8, 0;
+);
+
+//
+// OS Lock Access Register
+//
+etm4_register!(TRCOSLAR, 0x0c0,
+    #[derive(Copy, Clone)]
+    pub struct TRCOSLAR(u32);
+    impl Debug;
+    pub oslk, set_oslk: 0;
+);
+
+//
+// PowerDown Status Register
+//
+etm4_register!(TRCPDSR, 0x0c5,
+    #[derive(Copy, Clone)]
+    pub struct TRCPDSR(u32);
+    impl Debug;
+    pub oslk, _: 5;
+    pub stickypd, _: 1;
+    pub power, _: 0;
+);
+
+//
+// Software Lock Access Register
+//
+etm4_register!(TRCLAR, 0x3ec,
+    #[derive(Copy, Clone)]
+    pub struct TRCLAR(u32);
+    impl Debug;
+    pub key, _: 31, 0;
+);
+
+impl TRCLAR {
+    pub fn unlock(core: &mut dyn humility::core::Core) -> Result<()> {
+        core.write_word_32(TRCLAR::ADDRESS, 0xc5ac_ce55)?;
+        Ok(())
+    }
+
+    pub fn lock(core: &mut dyn humility::core::Core) -> Result<()> {
+        core.write_word_32(TRCLAR::ADDRESS, 0x1de_c0de)?;
+        Ok(())
+    }
+}
+
+//
+// Software Lock Status Register
+//
+etm4_register!(TRCLSR, 0x3ed,
+    #[derive(Copy, Clone)]
+    pub struct TRCLSR(u32);
+    impl Debug;
+    pub locked, _: 1;
+    pub implemented, _: 0;
+);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ETM4Header {
+    Extension,
+    TraceInfo,
+    Timestamp {
+        cc: bool,
+    },
+    TraceOn,
+    FunctionReturn,
+    Exception,
+    ExceptionReturn,
+    CycleCountF1 {
+        u: bool,
+    },
+    CycleCountF2,
+    CycleCountF3 {
+        count: u8,
+    },
+    Commit,
+    Ignore,
+    Event {
+        event: u8,
+    },
+    Context {
+        payload: bool,
+    },
+    AddressWithContext {
+        is: u8,
+        wide: bool,
+    },
+    ExactMatch {
+        qe: u8,
+    },
+    ShortAddress {
+        is: u8,
+    },
+    LongAddress {
+        is: u8,
+        wide: bool,
+    },
+
+    /// A sequence of `n` atoms, the oldest in the least significant bit of
+    /// `pattern`; a set bit denotes an E atom (a taken branch), a clear bit
+    /// an N atom (a branch not taken).
+    Atoms {
+        n: u8,
+        pattern: u32,
+    },
+}
+
+//
+// An exception, as reported by an ETMv4 exception packet.  On M-profile
+// parts, the packet carries the exception number itself (unlike the ETMv3
+// encoding, which has its own numbering).
+//
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ETM4Exception {
+    Reset,
+    NMI,
+    HardFault,
+    MemManage,
+    BusFault,
+    UsageFault,
+    SecureFault,
+    SVC,
+    DebugMonitor,
+    PendSV,
+    SysTick,
+    IRQ { irq: u16 },
+    Reserved { exception: u16 },
+}
+
+impl ETM4Exception {
+    pub fn from_number(xcp: u16) -> Self {
+        match xcp {
+            1 => ETM4Exception::Reset,
+            2 => ETM4Exception::NMI,
+            3 => ETM4Exception::HardFault,
+            4 => ETM4Exception::MemManage,
+            5 => ETM4Exception::BusFault,
+            6 => ETM4Exception::UsageFault,
+            7 => ETM4Exception::SecureFault,
+            11 => ETM4Exception::SVC,
+            12 => ETM4Exception::DebugMonitor,
+            14 => ETM4Exception::PendSV,
+            15 => ETM4Exception::SysTick,
+            16..=0x1ff => ETM4Exception::IRQ { irq: xcp - 16 },
+            _ => ETM4Exception::Reserved { exception: xcp },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ETM4Payload {
+    None,
+    ASync,
+    Discard,
+    Overflow,
+    TraceInfo { info: Option<u64>, key: Option<u64> },
+    Timestamp { timestamp: u64, cycles: Option<u64> },
+    Exception { exception: ETM4Exception, e0: bool, e1: bool },
+    CycleCount { count: Option<u64> },
+    Commit { count: u64 },
+    Address { addr: u32, is: u8 },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ETM4Packet {
+    pub header: ETM4Header,
+    pub payload: ETM4Payload,
+    pub offset: usize,
+    pub time: f64,
+}
+
+pub struct ETM4Config {
+    pub traceid: u8,
+    /// TRCIDR0.COMMOPT: commit elements are implied by cycle counts
+    pub commopt: bool,
+    /// size of the context ID in address-with-context packets, in bytes
+    pub context_id: u8,
+    /// size of the VMID in address-with-context packets, in bytes
+    pub vmid: u8,
+}
+
+pub fn etm4_header(hdr: u8) -> Option<ETM4Header> {
+    let atoms = |n: u8, pattern: u32| ETM4Header::Atoms { n, pattern };
+
+    Some(match hdr {
+        0x00 => ETM4Header::Extension,
+        0x01 => ETM4Header::TraceInfo,
+        0x02 | 0x03 => ETM4Header::Timestamp { cc: hdr & 1 != 0 },
+        0x04 => ETM4Header::TraceOn,
+        0x05 => ETM4Header::FunctionReturn,
+        0x06 => ETM4Header::Exception,
+        0x07 => ETM4Header::ExceptionReturn,
+        0x0c | 0x0d => ETM4Header::CycleCountF2,
+        0x0e | 0x0f => ETM4Header::CycleCountF1 { u: hdr & 1 != 0 },
+        0x10..=0x1f => ETM4Header::CycleCountF3 { count: hdr & 0b11 },
+        0x2d => ETM4Header::Commit,
+        0x70 => ETM4Header::Ignore,
+        0x71..=0x7f => ETM4Header::Event { event: hdr & 0b1111 },
+        0x80 | 0x81 => ETM4Header::Context { payload: hdr & 1 != 0 },
+        0x82 | 0x83 => {
+            ETM4Header::AddressWithContext { is: hdr & 1, wide: false }
+        }
+        0x85 | 0x86 => {
+            ETM4Header::AddressWithContext { is: hdr - 0x85, wide: true }
+        }
+        0x90..=0x92 => ETM4Header::ExactMatch { qe: hdr & 0b11 },
+        0x95 | 0x96 => ETM4Header::ShortAddress { is: hdr - 0x95 },
+        0x9a | 0x9b => ETM4Header::LongAddress { is: hdr & 1, wide: false },
+        0x9d | 0x9e => ETM4Header::LongAddress { is: hdr - 0x9d, wide: true },
+
+        //
+        // Format 6:  COUNT + 3 E atoms, followed by an E atom (if A is
+        // clear) or an N atom (if A is set).
+        //
+        0xc0..=0xd4 | 0xe0..=0xf4 => {
+            let n = (hdr & 0b1_1111) + 4;
+            let mut pattern = (1 << n) - 1;
+
+            if hdr & 0b10_0000 != 0 {
+                pattern &= !(1 << (n - 1));
+            }
+
+            atoms(n, pattern)
+        }
+
+        //
+        // Format 5:  five atoms in one of four patterns, as selected by
+        // bits 5, 1 and 0.
+        //
+        0xd5..=0xd7 | 0xf5 => {
+            let abc = ((hdr >> 3) & 0b100) | (hdr & 0b11);
+
+            atoms(
+                5,
+                match abc {
+                    0b101 => 0b11110,
+                    0b001 => 0b00000,
+                    0b010 => 0b01010,
+                    _ => 0b10101,
+                },
+            )
+        }
+
+        // Format 2:  two atoms
+        0xd8..=0xdb => atoms(2, (hdr & 0b11) as u32),
+
+        // Format 4:  four atoms in one of four patterns
+        0xdc..=0xdf => atoms(
+            4,
+            match hdr & 0b11 {
+                0b00 => 0b1110,
+                0b01 => 0b0000,
+                0b10 => 0b1010,
+                _ => 0b0101,
+            },
+        ),
+
+        // Format 1:  one atom
+        0xf6 | 0xf7 => atoms(1, (hdr & 0b1) as u32),
+
+        // Format 3:  three atoms
+        0xf8..=0xff => atoms(3, (hdr & 0b111) as u32),
+
+        _ => return None,
+    })
+}
+
+//
+// Returns the length of the field starting at `start` that is encoded with
+// continuation bits (that is, with bit 7 set in every byte but the last),
+// and has at most `max` bytes (the last of which, if present, uses all
+// eight bits) -- or None if the field is not yet complete.
+//
+fn cfield_len(payload: &[u8], start: usize, max: usize) -> Option<usize> {
+    for i in 0..max {
+        let b = *payload.get(start + i)?;
+
+        if i == max - 1 || b & 0x80 == 0 {
+            return Some(i + 1);
+        }
+    }
+
+    None
+}
+
+fn cfield_value(payload: &[u8], start: usize, max: usize) -> u64 {
+    let mut val = 0u64;
+
+    for i in 0..max {
+        let b = payload[start + i];
+
+        if i == max - 1 {
+            val |= (b as u64) << (7 * i);
+            break;
+        }
+
+        val |= ((b & 0x7f) as u64) << (7 * i);
+
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+
+    val
+}
+
+//
+// Returns true if we have the entire payload for the specified header.
+//
+fn etm4_packet_complete(
+    hdr: ETM4Header,
+    payload: &[u8],
+    config: &ETM4Config,
+) -> bool {
+    //
+    // The length of a context information field (and the VMID and context
+    // ID that follow it, as indicated) at the specified offset.
+    //
+    let context = |o: usize| -> Option<usize> {
+        let info = *payload.get(o)?;
+        let mut len = o + 1;
+
+        if info & 0b0100_0000 != 0 {
+            len += config.vmid as usize;
+        }
+
+        if info & 0b1000_0000 != 0 {
+            len += config.context_id as usize;
+        }
+
+        Some(len)
+    };
+
+    let len = match hdr {
+        ETM4Header::Extension => match payload.first() {
+            //
+            // An A-sync packet is a run of zeroes terminated by 0x80; if
+            // we see anything else, we'll fail to decode it.
+            //
+            Some(0x00) => match payload.last() {
+                Some(0x00) if payload.len() < 11 => None,
+                _ => Some(payload.len()),
+            },
+            Some(_) => Some(1),
+            None => None,
+        },
+
+        ETM4Header::TraceInfo => (|| {
+            let mut o = cfield_len(payload, 0, 5)?;
+            let plctl = payload[0];
+
+            for section in 0..4 {
+                if plctl & (1 << section) != 0 {
+                    o += cfield_len(payload, o, 5)?;
+                }
+            }
+
+            Some(o)
+        })(),
+
+        ETM4Header::Timestamp { cc } => (|| {
+            let mut o = cfield_len(payload, 0, 9)?;
+
+            if cc {
+                o += cfield_len(payload, o, 3)?;
+            }
+
+            Some(o)
+        })(),
+
+        ETM4Header::Exception | ETM4Header::ShortAddress { .. } => {
+            match payload.first() {
+                Some(b) if b & 0x80 != 0 => Some(2),
+                Some(_) => Some(1),
+                None => None,
+            }
+        }
+
+        ETM4Header::CycleCountF1 { u } => (|| {
+            let mut o = 0;
+
+            if !config.commopt {
+                o += cfield_len(payload, o, 5)?;
+            }
+
+            if !u {
+                o += cfield_len(payload, o, 3)?;
+            }
+
+            Some(o)
+        })(),
+
+        ETM4Header::CycleCountF2 => Some(1),
+        ETM4Header::Commit => cfield_len(payload, 0, 5),
+        ETM4Header::Context { payload: true } => context(0),
+        ETM4Header::AddressWithContext { wide, .. } => {
+            context(if wide { 8 } else { 4 })
+        }
+        ETM4Header::LongAddress { wide, .. } => Some(if wide { 8 } else { 4 }),
+        _ => Some(0),
+    };
+
+    match len {
+        Some(len) => payload.len() >= len,
+        None => false,
+    }
+}
+
+//
+// Decodes the payload of a complete packet.  Addresses are resolved against
+// our address history (the most recent address first), which is updated
+// accordingly.
+//
+fn etm4_payload_decode(
+    hdr: ETM4Header,
+    payload: &[u8],
+    config: &ETM4Config,
+    history: &mut [(u32, u8); 3],
+) -> Result<ETM4Payload> {
+    //
+    // A long address:  the low byte has seven bits of address (starting at
+    // bit 2 for IS0, bit 1 for IS1); the next byte has seven (IS0) or eight
+    // (IS1) bits, and the remaining two bytes eight bits each.  (For 64-bit
+    // addresses, we only concern ourselves with the low 32 bits.)
+    //
+    let long = |is: u8| -> u32 {
+        let mut addr = if is == 0 {
+            ((payload[0] & 0x7f) as u32) << 2
+                | ((payload[1] & 0x7f) as u32) << 9
+        } else {
+            ((payload[0] & 0x7f) as u32) << 1 | (payload[1] as u32) << 8
+        };
+
+        addr |= (payload[2] as u32) << 16 | (payload[3] as u32) << 24;
+        addr
+    };
+
+    let push = |history: &mut [(u32, u8); 3], addr: u32, is: u8| {
+        history.rotate_right(1);
+        history[0] = (addr, is);
+        ETM4Payload::Address { addr, is }
+    };
+
+    Ok(match hdr {
+        ETM4Header::Extension => match payload {
+            [0x00, .., 0x80] => ETM4Payload::ASync,
+            [0x03] => ETM4Payload::Discard,
+            [0x05] => ETM4Payload::Overflow,
+            _ => bail!("bad extension packet: {:x?}", payload),
+        },
+
+        ETM4Header::TraceInfo => {
+            let plctl = payload[0];
+            let mut o = cfield_len(payload, 0, 5).unwrap();
+            let mut sections = [None; 4];
+
+            for (section, val) in sections.iter_mut().enumerate() {
+                if plctl & (1 << section) != 0 {
+                    *val = Some(cfield_value(payload, o, 5));
+                    o += cfield_len(payload, o, 5).unwrap();
+                }
+            }
+
+            ETM4Payload::TraceInfo { info: sections[0], key: sections[1] }
+        }
+
+        ETM4Header::Timestamp { cc } => {
+            let timestamp = cfield_value(payload, 0, 9);
+            let o = cfield_len(payload, 0, 9).unwrap();
+
+            ETM4Payload::Timestamp {
+                timestamp,
+                cycles: if cc {
+                    Some(cfield_value(payload, o, 3))
+                } else {
+                    None
+                },
+            }
+        }
+
+        ETM4Header::Exception => {
+            let mut xcp = ((payload[0] >> 1) & 0b1_1111) as u16;
+
+            if payload[0] & 0x80 != 0 {
+                xcp |= ((payload[1] & 0b1_1111) as u16) << 5;
+            }
+
+            ETM4Payload::Exception {
+                exception: ETM4Exception::from_number(xcp),
+                e0: payload[0] & 0b1 != 0,
+                e1: payload[0] & 0b0100_0000 != 0,
+            }
+        }
+
+        ETM4Header::CycleCountF1 { u } => {
+            let o = if config.commopt {
+                0
+            } else {
+                cfield_len(payload, 0, 5).unwrap()
+            };
+
+            ETM4Payload::CycleCount {
+                count: if u { None } else { Some(cfield_value(payload, o, 3)) },
+            }
+        }
+
+        ETM4Header::CycleCountF2 => ETM4Payload::CycleCount {
+            count: Some((payload[0] & 0b1111) as u64),
+        },
+
+        ETM4Header::CycleCountF3 { count } => {
+            ETM4Payload::CycleCount { count: Some(count as u64) }
+        }
+
+        ETM4Header::Commit => {
+            ETM4Payload::Commit { count: cfield_value(payload, 0, 5) }
+        }
+
+        ETM4Header::ShortAddress { is } => {
+            let shift = if is == 0 { 2 } else { 1 };
+            let mut addr = ((payload[0] & 0x7f) as u32) << shift;
+            let mut bits = 7 + shift;
+
+            if payload[0] & 0x80 != 0 {
+                addr |= (payload[1] as u32) << bits;
+                bits += 8;
+            }
+
+            let mask = (1u32 << bits) - 1;
+            let addr = (history[0].0 & !mask) | addr;
+            push(history, addr, is)
+        }
+
+        ETM4Header::LongAddress { is, .. }
+        | ETM4Header::AddressWithContext { is, .. } => {
+            push(history, long(is), is)
+        }
+
+        ETM4Header::ExactMatch { qe } => {
+            let (addr, is) = history[qe as usize];
+            push(history, addr, is)
+        }
+
+        _ => ETM4Payload::None,
+    })
+}
+
+//
+// Our state in decoding the ETMv4 byte stream (once it has been extracted
+// from its TPIU frames).
+//
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ETM4IngestState {
+    ASyncSearching,
+    TraceInfoSearching,
+    Ingesting,
+}
+
+struct ETM4Decoder {
+    state: ETM4IngestState,
+    hdr: Option<ETM4Header>,
+    payload: Vec<u8>,
+    history: [(u32, u8); 3],
+    runlen: usize,
+}
+
+impl ETM4Decoder {
+    fn new() -> Self {
+        Self {
+            state: ETM4IngestState::ASyncSearching,
+            hdr: None,
+            payload: Vec::with_capacity(16),
+            history: [(0, 0); 3],
+            runlen: 0,
+        }
+    }
+
+    fn ingest(
+        &mut self,
+        config: &ETM4Config,
+        datum: u8,
+        offset: usize,
+        time: f64,
+        callback: &mut impl FnMut(&ETM4Packet) -> Result<()>,
+    ) -> Result<()> {
+        if self.state == ETM4IngestState::ASyncSearching {
+            match datum {
+                0 => self.runlen += 1,
+                0x80 if self.runlen >= 11 => {
+                    humility::msg!(
+                        "A-sync alignment synchronization \
+                        packet found at offset {}",
+                        offset
+                    );
+                    self.state = ETM4IngestState::TraceInfoSearching;
+                }
+                _ => self.runlen = 0,
+            }
+
+            return Ok(());
+        }
+
+        let h = match self.hdr {
+            Some(h) => {
+                self.payload.push(datum);
+                h
+            }
+            None => {
+                let h = match etm4_header(datum) {
+                    Some(h) => h,
+                    None => {
+                        bail!(
+                            "unrecognized ETMv4 header 0x{:x} at offset {}",
+                            datum,
+                            offset
+                        );
+                    }
+                };
+
+                self.payload.truncate(0);
+                self.hdr = Some(h);
+                h
+            }
+        };
+
+        if !etm4_packet_complete(h, &self.payload, config) {
+            return Ok(());
+        }
+
+        self.hdr = None;
+
+        if let (ETM4IngestState::TraceInfoSearching, ETM4Header::TraceInfo) =
+            (self.state, h)
+        {
+            //
+            // A trace info packet resets our decompression state; we can
+            // now ingest everything (starting with this packet).
+            //
+            self.history = [(0, 0); 3];
+            self.state = ETM4IngestState::Ingesting;
+        }
+
+        if self.state == ETM4IngestState::Ingesting {
+            callback(&ETM4Packet {
+                header: h,
+                payload: etm4_payload_decode(
+                    h,
+                    &self.payload,
+                    config,
+                    &mut self.history,
+                )?,
+                offset,
+                time,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn etm4_ingest(
+    config: &ETM4Config,
+    mut readnext: impl FnMut() -> Result<Option<(u8, f64)>>,
+    mut callback: impl FnMut(&ETM4Packet) -> Result<()>,
+) -> Result<()> {
+    let mut decoder = ETM4Decoder::new();
+
+    let mut valid = vec![false; 256];
+    valid[config.traceid as usize] = true;
+
+    tpiu_ingest(&valid, &mut readnext, |packet| {
+        decoder.ingest(
+            config,
+            packet.datum,
+            packet.offset,
+            packet.time,
+            &mut callback,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ETM4Config {
+        ETM4Config { traceid: 1, commopt: false, context_id: 0, vmid: 0 }
+    }
+
+    //
+    // Decodes a stream of ETMv4 trace bytes (that is, as they emerge from
+    // the TPIU formatter), returning the packets that result.
+    //
+    fn decode(config: &ETM4Config, bytes: &[u8]) -> Result<Vec<ETM4Packet>> {
+        let mut decoder = ETM4Decoder::new();
+        let mut rval = vec![];
+
+        for (offset, datum) in bytes.iter().enumerate() {
+            decoder.ingest(config, *datum, offset, 0.0, &mut |packet| {
+                rval.push(*packet);
+                Ok(())
+            })?;
+        }
+
+        Ok(rval)
+    }
+
+    const ASYNC: [u8; 12] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80];
+
+    #[test]
+    fn exception_numbers() {
+        assert_eq!(ETM4Exception::from_number(1), ETM4Exception::Reset);
+        assert_eq!(ETM4Exception::from_number(3), ETM4Exception::HardFault);
+        assert_eq!(ETM4Exception::from_number(7), ETM4Exception::SecureFault);
+        assert_eq!(ETM4Exception::from_number(11), ETM4Exception::SVC);
+        assert_eq!(ETM4Exception::from_number(15), ETM4Exception::SysTick);
+        assert_eq!(
+            ETM4Exception::from_number(16),
+            ETM4Exception::IRQ { irq: 0 }
+        );
+        assert_eq!(
+            ETM4Exception::from_number(8),
+            ETM4Exception::Reserved { exception: 8 }
+        );
+    }
+
+    #[test]
+    fn atom_headers() {
+        let atoms = |hdr| match etm4_header(hdr) {
+            Some(ETM4Header::Atoms { n, pattern }) => (n, pattern),
+            h => panic!("0x{:x}: unexpected header {:?}", hdr, h),
+        };
+
+        assert_eq!(atoms(0xf6), (1, 0b0));
+        assert_eq!(atoms(0xf7), (1, 0b1));
+        assert_eq!(atoms(0xda), (2, 0b10));
+        assert_eq!(atoms(0xfd), (3, 0b101));
+        assert_eq!(atoms(0xdc), (4, 0b1110));
+        assert_eq!(atoms(0xd7), (5, 0b10101));
+        assert_eq!(atoms(0xf5), (5, 0b11110));
+        assert_eq!(atoms(0xc0), (4, 0b1111));
+        assert_eq!(atoms(0xe0), (4, 0b0111));
+        assert_eq!(atoms(0xc2), (6, 0b111111));
+
+        assert_eq!(etm4_header(0x08), None);
+    }
+
+    #[test]
+    fn trace() -> Result<()> {
+        let mut bytes = vec![];
+
+        //
+        // Garbage and packets before synchronization are ignored, as are
+        // packets after A-sync but before a trace info packet.
+        //
+        bytes.extend([0x12, 0x34]);
+        bytes.extend(ASYNC);
+        bytes.extend([0xf7]);
+
+        bytes.extend([
+            0x01, 0x00, // trace info, no sections
+            0x9a, 0x0d, 0x09, 0x00,
+            0x08, // long address (IS0), 0x0800_1234
+            0xdb, // two E atoms
+            0x95, 0x10, // short address (IS0), 0x0800_1240
+            0x06, 0x5e, // exception 15 (SysTick), E1 set
+            0x9a, 0x0d, 0x09, 0x00, 0x08, // preferred return address
+            0x06, 0xe6, 0x01, // exception 51 (IRQ 35), E1 set
+            0x90, // exact match on the most recent address
+            0x02, 0x85, 0x01, // timestamp 0x85
+            0x00, 0x05, // overflow
+        ]);
+
+        let packets = decode(&config(), &bytes)?;
+        let headers = packets.iter().map(|p| p.header).collect::<Vec<_>>();
+
+        assert_eq!(
+            headers,
+            [
+                ETM4Header::TraceInfo,
+                ETM4Header::LongAddress { is: 0, wide: false },
+                ETM4Header::Atoms { n: 2, pattern: 0b11 },
+                ETM4Header::ShortAddress { is: 0 },
+                ETM4Header::Exception,
+                ETM4Header::LongAddress { is: 0, wide: false },
+                ETM4Header::Exception,
+                ETM4Header::ExactMatch { qe: 0 },
+                ETM4Header::Timestamp { cc: false },
+                ETM4Header::Extension,
+            ]
+        );
+
+        let addr = |p: &ETM4Packet| match p.payload {
+            ETM4Payload::Address { addr, is } => (addr, is),
+            payload => panic!("expected address, found {:?}", payload),
+        };
+
+        assert_eq!(packets[0].offset, 16);
+        assert_eq!(addr(&packets[1]), (0x0800_1234, 0));
+        assert_eq!(addr(&packets[3]), (0x0800_1240, 0));
+        assert_eq!(addr(&packets[5]), (0x0800_1234, 0));
+        assert_eq!(addr(&packets[7]), (0x0800_1234, 0));
+
+        match packets[4].payload {
+            ETM4Payload::Exception { exception, e0, e1 } => {
+                assert_eq!(exception, ETM4Exception::SysTick);
+                assert!(!e0);
+                assert!(e1);
+            }
+            payload => panic!("expected exception, found {:?}", payload),
+        }
+
+        match packets[6].payload {
+            ETM4Payload::Exception { exception, .. } => {
+                assert_eq!(exception, ETM4Exception::IRQ { irq: 35 });
+            }
+            payload => panic!("expected exception, found {:?}", payload),
+        }
+
+        match packets[8].payload {
+            ETM4Payload::Timestamp { timestamp, cycles } => {
+                assert_eq!(timestamp, 0x85);
+                assert_eq!(cycles, None);
+            }
+            payload => panic!("expected timestamp, found {:?}", payload),
+        }
+
+        assert!(matches!(packets[9].payload, ETM4Payload::Overflow));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut bytes = ASYNC.to_vec();
+        bytes.extend([0x01, 0x00, 0x08]);
+
+        let err = decode(&config(), &bytes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unrecognized ETMv4 header 0x8 at offset 14"
+        );
+    }
+}
@@ -573,6 +573,12 @@ pub fn itm_enable_explicit(
     Ok(())
 }
 
+///
+/// The trace identifier used by [`itm_enable_ingest`]; captures of ITM data
+/// enabled that way (and without a bypassed TPIU) will have this identifier.
+///
+pub const ITM_TRACEID_DEFAULT: u8 = 0x3a;
+
 ///
 /// Enables ITM by pulling clock scaler values from the specified Hubris
 /// archive.
@@ -591,7 +597,7 @@ pub fn itm_enable_ingest(
     // to be a recognizable value.
     //
     let clockscaler = swoscaler(hubris, core)?;
-    let traceid = 0x3a;
+    let traceid = ITM_TRACEID_DEFAULT;
 
     itm_enable_explicit(core, &coreinfo, clockscaler, traceid, stim)?;
 
@@ -5,9 +5,11 @@
 pub mod debug;
 pub mod dwt;
 pub mod etm;
+pub mod etm4;
 pub mod itm;
 pub mod scs;
 pub mod swo;
+pub mod swv;
 pub mod tpiu;
 
 #[macro_use]
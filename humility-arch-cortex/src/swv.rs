@@ -0,0 +1,368 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Sources of SWV (Serial Wire Viewer) data.  SWV data can be read live from
+// the attached probe, but it can also come from a saved capture of the raw
+// SWO byte stream, from a CSV export of an asynchronous serial analyzer in
+// Saleae Logic, or from an external SWO receiver that makes the byte stream
+// available on a TCP socket.  A source is specified as a string:
+//
+//   probe                 the attached probe
+//   raw:<filename>        raw capture of the SWO byte stream
+//   saleae:<filename>     Saleae Logic CSV export
+//   tcp:<host>:<port>     raw SWO byte stream from a TCP socket
+//   <filename>            Saleae Logic CSV export if the file has a CSV
+//                         header, raw capture otherwise
+//
+
+use anyhow::{anyhow, bail, Result};
+use humility::core::Core;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SWVSource {
+    Probe,
+    Raw(String),
+    Saleae(String),
+    Tcp(String),
+}
+
+impl FromStr for SWVSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "probe" {
+            return Ok(SWVSource::Probe);
+        }
+
+        if let Some(filename) = s.strip_prefix("raw:") {
+            return Ok(SWVSource::Raw(filename.to_string()));
+        }
+
+        if let Some(filename) = s.strip_prefix("saleae:") {
+            return Ok(SWVSource::Saleae(filename.to_string()));
+        }
+
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            if !addr.contains(':') {
+                bail!("TCP source must be specified as tcp:<host>:<port>");
+            }
+
+            return Ok(SWVSource::Tcp(addr.to_string()));
+        }
+
+        //
+        // A bare filename:  if the file has a valid CSV header, we assume
+        // that it's a Saleae export; otherwise, we take it to be raw.
+        //
+        let file = File::open(s)
+            .map_err(|e| anyhow!("failed to open {}: {}", s, e))?;
+
+        Ok(match csv::Reader::from_reader(file).headers() {
+            Ok(_) => SWVSource::Saleae(s.to_string()),
+            Err(_) => {
+                humility::msg!("not a Saleae trace file; assuming raw input");
+                SWVSource::Raw(s.to_string())
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for SWVSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SWVSource::Probe => write!(f, "probe"),
+            SWVSource::Raw(filename) => write!(f, "raw:{}", filename),
+            SWVSource::Saleae(filename) => write!(f, "saleae:{}", filename),
+            SWVSource::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+impl SWVSource {
+    /// Returns true if this source requires an attached probe
+    pub fn is_probe(&self) -> bool {
+        *self == SWVSource::Probe
+    }
+}
+
+type SaleaeTraceRecord = (f64, u8, Option<String>, Option<String>);
+
+enum SWVInput {
+    Probe,
+    Raw(File),
+    Saleae(csv::DeserializeRecordsIntoIter<File, SaleaeTraceRecord>),
+    Tcp(TcpStream),
+}
+
+pub struct SWVStream {
+    input: SWVInput,
+    start: Instant,
+    pending: VecDeque<(u8, f64)>,
+}
+
+//
+// The amount of time we will block on a TCP source before returning to our
+// caller (allowing it to, e.g., check for timeouts).
+//
+const SWV_TCP_TIMEOUT: Duration = Duration::from_millis(100);
+
+const SWV_READ_SIZE: usize = 4096;
+
+impl SWVStream {
+    pub fn open(source: &SWVSource) -> Result<Self> {
+        let input = match source {
+            SWVSource::Probe => SWVInput::Probe,
+            SWVSource::Raw(filename) => {
+                SWVInput::Raw(File::open(filename).map_err(|e| {
+                    anyhow!("failed to open {}: {}", filename, e)
+                })?)
+            }
+            SWVSource::Saleae(filename) => {
+                let file = File::open(filename).map_err(|e| {
+                    anyhow!("failed to open {}: {}", filename, e)
+                })?;
+
+                SWVInput::Saleae(
+                    csv::Reader::from_reader(file).into_deserialize(),
+                )
+            }
+            SWVSource::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).map_err(|e| {
+                    anyhow!("failed to connect to {}: {}", addr, e)
+                })?;
+                stream.set_read_timeout(Some(SWV_TCP_TIMEOUT))?;
+                humility::msg!("reading SWV from {}", addr);
+                SWVInput::Tcp(stream)
+            }
+        };
+
+        Ok(Self { input, start: Instant::now(), pending: VecDeque::new() })
+    }
+
+    ///
+    /// Reads whatever SWV data is available, along with the time (in
+    /// seconds) at which each byte was received.  For live sources, the
+    /// returned data may be empty if nothing is available; `None` is
+    /// returned when the source has been exhausted.  A core must be
+    /// provided if the source is the probe.
+    ///
+    pub fn read(
+        &mut self,
+        core: Option<&mut dyn Core>,
+    ) -> Result<Option<Vec<(u8, f64)>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.drain(..).collect()));
+        }
+
+        let now = self.start.elapsed().as_secs_f64();
+        let mut buf = [0u8; SWV_READ_SIZE];
+
+        match &mut self.input {
+            SWVInput::Probe => {
+                let core = core.ok_or_else(|| {
+                    anyhow!("reading SWV from the probe requires a core")
+                })?;
+
+                Ok(Some(core.read_swv()?.iter().map(|b| (*b, now)).collect()))
+            }
+
+            SWVInput::Raw(file) => match file.read(&mut buf)? {
+                0 => Ok(None),
+                n => Ok(Some(buf[..n].iter().map(|b| (*b, 0.0)).collect())),
+            },
+
+            SWVInput::Saleae(iter) => {
+                let mut rval = vec![];
+
+                for line in iter.take(SWV_READ_SIZE) {
+                    let record = line?;
+                    rval.push((record.1, record.0));
+                }
+
+                Ok(if rval.is_empty() { None } else { Some(rval) })
+            }
+
+            SWVInput::Tcp(stream) => match stream.read(&mut buf) {
+                Ok(0) => Ok(None),
+                Ok(n) => Ok(Some(buf[..n].iter().map(|b| (*b, now)).collect())),
+                Err(e)
+                    if e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut =>
+                {
+                    Ok(Some(vec![]))
+                }
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    ///
+    /// Returns the next byte of SWV data (and the time at which it was
+    /// received), blocking until one is available; `None` is returned when
+    /// the source has been exhausted.  This is suitable for use as the
+    /// `readnext` argument to `itm_ingest`.
+    ///
+    pub fn next(
+        &mut self,
+        mut core: Option<&mut dyn Core>,
+    ) -> Result<Option<(u8, f64)>> {
+        while self.pending.is_empty() {
+            match self.read(core.as_deref_mut())? {
+                Some(data) => self.pending.extend(data),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(self.pending.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    //
+    // A file in the temporary directory that is removed when dropped.
+    //
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "humility-swv-{}-{}",
+                std::process::id(),
+                name
+            ));
+
+            File::create(&path).unwrap().write_all(contents).unwrap();
+            Self(path)
+        }
+
+        fn name(&self) -> String {
+            self.0.to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn drain(source: &SWVSource) -> Result<Vec<(u8, f64)>> {
+        let mut stream = SWVStream::open(source)?;
+        let mut rval = vec![];
+
+        while let Some(datum) = stream.next(None)? {
+            rval.push(datum);
+        }
+
+        Ok(rval)
+    }
+
+    #[test]
+    fn sources() -> Result<()> {
+        assert_eq!("probe".parse::<SWVSource>()?, SWVSource::Probe);
+        assert_eq!(
+            "raw:foo.bin".parse::<SWVSource>()?,
+            SWVSource::Raw("foo.bin".to_string())
+        );
+        assert_eq!(
+            "saleae:foo.csv".parse::<SWVSource>()?,
+            SWVSource::Saleae("foo.csv".to_string())
+        );
+        assert_eq!(
+            "tcp:localhost:3443".parse::<SWVSource>()?,
+            SWVSource::Tcp("localhost:3443".to_string())
+        );
+
+        assert!("tcp:localhost".parse::<SWVSource>().is_err());
+        assert!("/nonexistent/swv.bin".parse::<SWVSource>().is_err());
+
+        for s in ["probe", "raw:a", "saleae:b", "tcp:c:1"] {
+            assert_eq!(s.parse::<SWVSource>()?.to_string(), s);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw() -> Result<()> {
+        let bytes = [0x01, 0x80, 0xff, 0x00, 0x03];
+        let file = TempFile::new("raw", &bytes);
+
+        //
+        // A file that isn't valid CSV is taken to be raw.
+        //
+        let source = file.name().parse::<SWVSource>()?;
+        assert_eq!(source, SWVSource::Raw(file.name()));
+
+        let data = drain(&source)?;
+        assert_eq!(data.iter().map(|(b, _)| *b).collect::<Vec<_>>(), bytes);
+        assert!(data.iter().all(|(_, t)| *t == 0.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn saleae() -> Result<()> {
+        let csv = "\
+Time [s],Value,Parity Error,Framing Error
+0.000100,1,,
+0.000150,128,,
+0.000200,3,,Error
+";
+        let file = TempFile::new("saleae", csv.as_bytes());
+
+        //
+        // A file with a CSV header is taken to be a Saleae export.
+        //
+        let source = file.name().parse::<SWVSource>()?;
+        assert_eq!(source, SWVSource::Saleae(file.name()));
+
+        let data = drain(&source)?;
+        assert_eq!(data, [(1, 0.000100), (128, 0.000150), (3, 0.000200)]);
+
+        let bad = TempFile::new("saleae-bad", b"Time [s],Value\n0.1,bogus\n");
+        assert!(drain(&SWVSource::Saleae(bad.name())).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+
+        let sender = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0x01, 0x02, 0x03]).unwrap();
+        });
+
+        let data = drain(&SWVSource::Tcp(addr))?;
+        sender.join().unwrap();
+
+        assert_eq!(data.iter().map(|(b, _)| *b).collect::<Vec<_>>(), [1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn probe_requires_core() -> Result<()> {
+        let mut stream = SWVStream::open(&SWVSource::Probe)?;
+        assert!(stream.read(None).is_err());
+
+        Ok(())
+    }
+}
@@ -370,6 +370,27 @@ pub struct Ringbuf {
     pub buffer: Vec<RingbufEntry>,
 }
 
+impl Ringbuf {
+    ///
+    /// Returns the occupied entries of the ring buffer (along with their
+    /// slot), oldest first.
+    ///
+    pub fn entries(&self) -> Vec<(usize, &RingbufEntry)> {
+        let ndx = match self.last {
+            Some(ndx) => ndx as usize,
+            None => return vec![],
+        };
+
+        let len = self.buffer.len();
+
+        (0..len)
+            .map(|i| (ndx + i + 1) % len)
+            .map(|slot| (slot, &self.buffer[slot]))
+            .filter(|(_, entry)| entry.generation != 0)
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, Load)]
 pub struct StaticCell {
     pub cell: UnsafeCell,
@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::i2c::I2cTracer;
 use crate::{doppel::StaticCell, idol};
 use anyhow::{anyhow, bail, Context, Result};
 use hif::*;
@@ -39,6 +40,7 @@ pub struct HiffyContext<'a> {
     kicked: Option<Instant>,
     timeout: u32,
     state: State,
+    tracer: Option<I2cTracer>,
 }
 
 #[derive(Debug)]
@@ -258,6 +260,7 @@ impl<'a> HiffyContext<'a> {
             kicked: None,
             timeout,
             state: State::Initialized,
+            tracer: I2cTracer::from_env(hubris)?,
         })
     }
 
@@ -383,7 +386,13 @@ impl<'a> HiffyContext<'a> {
             rval.insert(func.name.clone(), func);
         }
 
-        Ok(HiffyFunctions(rval))
+        let rval = HiffyFunctions(rval);
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.functions(&rval);
+        }
+
+        Ok(rval)
     }
 
     /// Convenience routine to translate an Idol call into HIF operations,
@@ -633,6 +642,10 @@ impl<'a> HiffyContext<'a> {
 
         self.kicked = Some(Instant::now());
 
+        if let Some(tracer) = &mut self.tracer {
+            tracer.start(ops);
+        }
+
         self.state = State::Kicked;
 
         core.op_done()?;
@@ -789,6 +802,10 @@ impl<'a> HiffyContext<'a> {
 
         self.state = State::ResultsConsumed;
 
+        if let Some(tracer) = &mut self.tracer {
+            tracer.results(&rvec)?;
+        }
+
         Ok(rvec)
     }
 
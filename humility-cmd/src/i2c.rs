@@ -2,9 +2,23 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::hiffy::HiffyFunctions;
 use anyhow::{bail, Context, Result};
+use hif::{Op, TargetFunction};
 use humility::hubris::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///
+/// Environment variable that, if set, names a file to which every I2C
+/// transaction performed via HIF is recorded.
+///
+pub const I2C_TRACE_ENV: &str = "HUMILITY_I2C_TRACE";
 
 pub struct I2cArgs<'a> {
     pub controller: u8,
@@ -288,3 +302,409 @@ impl<'a> I2cArgs<'a> {
         Ok(Self { controller, port, mux, device, address, class })
     }
 }
+
+///
+/// The bus on which an I2C transaction was performed.  (This may be absent
+/// for transactions ingested from a logic analyzer or host adapter capture,
+/// which have no notion of controller or port.)
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct I2cTraceBus {
+    pub controller: u8,
+    pub port: String,
+    pub mux: Option<(u8, u8)>,
+}
+
+impl fmt::Display for I2cTraceBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "I2C{}, port {}", self.controller, self.port)?;
+
+        if let Some((mux, segment)) = self.mux {
+            write!(f, ", seg {}:{}", mux, segment)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum I2cOperation {
+    /// A read of the specified number of bytes (or a block read if `None`)
+    Read(Option<u8>),
+    /// A write of the specified bytes
+    Write(Vec<u8>),
+}
+
+///
+/// A single I2C transaction, as recorded in a trace.  A trace file consists
+/// of one such transaction per line, serialized as JSON.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct I2cTransaction {
+    /// Time of the transaction, in seconds.  For transactions recorded by
+    /// Humility, this is an absolute time (in seconds since the Unix epoch),
+    /// allowing the sessions appended to a single trace to be told apart;
+    /// for those ingested from other captures, it is relative to the start
+    /// of the capture.
+    pub time: f64,
+    pub bus: Option<I2cTraceBus>,
+    pub address: u8,
+    pub register: Option<u8>,
+    pub op: I2cOperation,
+    /// The bytes read (empty for a write) or the name of the error
+    pub result: Option<Result<Vec<u8>, String>>,
+}
+
+impl I2cTransaction {
+    pub fn result_str(&self) -> String {
+        match &self.result {
+            None => "-".to_string(),
+            Some(Ok(bytes)) if bytes.is_empty() => "Success".to_string(),
+            Some(Ok(bytes)) => hexbytes(bytes),
+            Some(Err(err)) => format!("Err({})", err),
+        }
+    }
+}
+
+impl fmt::Display for I2cTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dev 0x{:02x}", self.address)?;
+
+        if let Some(register) = self.register {
+            write!(f, ", reg 0x{:02x}", register)?;
+        }
+
+        match &self.op {
+            I2cOperation::Read(Some(nbytes)) => write!(f, ", read {}", nbytes),
+            I2cOperation::Read(None) => write!(f, ", block read"),
+            I2cOperation::Write(bytes) => {
+                write!(f, ", write {}", hexbytes(bytes))
+            }
+        }
+    }
+}
+
+fn hexbytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("0x{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+///
+/// Reads a trace file as written by [`I2cTracer`].
+///
+pub fn read_trace(path: &Path) -> Result<Vec<I2cTransaction>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+
+    parse_trace(BufReader::new(file))
+        .with_context(|| format!("failed to read {}", path.display()))
+}
+
+///
+/// Parses a trace (as written by [`I2cTracer`]) from the specified reader.
+///
+pub fn parse_trace<R: BufRead>(rdr: R) -> Result<Vec<I2cTransaction>> {
+    let mut rval = vec![];
+
+    for (lineno, line) in rdr.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        rval.push(serde_json::from_str(&line).with_context(|| {
+            format!("line {}: bad trace record", lineno + 1)
+        })?);
+    }
+
+    Ok(rval)
+}
+
+//
+// A call to an I2C function within a HIF program, as determined by
+// [`I2cTracer::start`], waiting for its result.
+//
+#[derive(Debug)]
+struct PendingTransaction {
+    transaction: I2cTransaction,
+    errmap: HashMap<u32, String>,
+}
+
+///
+/// Records the I2C transactions performed by HIF programs to a trace file.
+/// Because the arguments to HIF functions are only known by executing the
+/// program, we can only trace programs that are straight-line (that is,
+/// those that do not branch); I2C calls in other programs are not recorded.
+///
+#[derive(Debug)]
+pub struct I2cTracer {
+    out: BufWriter<File>,
+    buses: HashMap<(u8, u8), String>,
+    functions: HashMap<u8, (String, HashMap<u32, String>)>,
+    pending: Option<Vec<Option<PendingTransaction>>>,
+    warned: bool,
+}
+
+impl I2cTracer {
+    pub fn new(hubris: &HubrisArchive, path: &Path) -> Result<Self> {
+        let file =
+            File::options().create(true).append(true).open(path).with_context(
+                || format!("failed to open {}", path.display()),
+            )?;
+
+        let buses = hubris
+            .manifest
+            .i2c_buses
+            .iter()
+            .map(|b| ((b.controller, b.port.index), b.port.name.clone()))
+            .collect();
+
+        Ok(Self {
+            out: BufWriter::new(file),
+            buses,
+            functions: HashMap::new(),
+            pending: None,
+            warned: false,
+        })
+    }
+
+    ///
+    /// Returns a tracer if one has been requested via [`I2C_TRACE_ENV`].
+    ///
+    pub fn from_env(hubris: &HubrisArchive) -> Result<Option<Self>> {
+        match std::env::var_os(I2C_TRACE_ENV) {
+            Some(path) if !path.is_empty() => {
+                Ok(Some(Self::new(hubris, Path::new(&path))?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    ///
+    /// Notes the I2C functions that the target supports.
+    ///
+    pub fn functions(&mut self, funcs: &HiffyFunctions) {
+        for (name, func) in &funcs.0 {
+            if name == "I2cRead" || name == "I2cWrite" {
+                self.functions
+                    .insert(func.id.0, (name.clone(), func.errmap.clone()));
+            }
+        }
+    }
+
+    ///
+    /// Determines the I2C transactions that the specified program will
+    /// perform, to be recorded when its results are known.
+    ///
+    pub fn start(&mut self, ops: &[Op]) {
+        self.pending = None;
+
+        if self.functions.is_empty() {
+            return;
+        }
+
+        let is_i2c = |op: &Op| match op {
+            Op::Call(TargetFunction(id)) => self.functions.contains_key(id),
+            _ => false,
+        };
+
+        if !ops.iter().any(is_i2c) {
+            return;
+        }
+
+        match self.decode(ops) {
+            Some(pending) => self.pending = Some(pending),
+            None => {
+                if !self.warned {
+                    humility::warn!(
+                        "HIF program branches; its I2C transactions \
+                        will not be traced"
+                    );
+                    self.warned = true;
+                }
+            }
+        }
+    }
+
+    //
+    // Simulates the HIF stack to determine the arguments of each call,
+    // returning an entry for each call (in the order that results will be
+    // returned) -- or `None` if the program can't be simulated.
+    //
+    fn decode(&self, ops: &[Op]) -> Option<Vec<Option<PendingTransaction>>> {
+        let mut stack: Vec<Option<u32>> = vec![];
+        let mut calls = vec![];
+
+        for op in ops {
+            match op {
+                Op::Push(val) => stack.push(Some(*val as u32)),
+                Op::Push16(val) => stack.push(Some(*val as u32)),
+                Op::Push32(val) => stack.push(Some(*val)),
+                Op::PushNone => stack.push(None),
+                Op::Drop => {
+                    stack.pop()?;
+                }
+                Op::DropN(n) => {
+                    let n = *n as usize;
+                    stack.truncate(stack.len().checked_sub(n)?);
+                }
+                Op::Swap => {
+                    let len = stack.len();
+
+                    if len < 2 {
+                        return None;
+                    }
+
+                    stack.swap(len - 1, len - 2);
+                }
+                Op::Call(TargetFunction(id)) => {
+                    calls.push(match self.functions.get(id) {
+                        Some((name, errmap)) => Some(PendingTransaction {
+                            transaction: self.transaction(name, &stack)?,
+                            errmap: errmap.clone(),
+                        }),
+                        None => None,
+                    });
+                }
+                Op::Done => break,
+                _ => return None,
+            }
+        }
+
+        Some(calls)
+    }
+
+    //
+    // Constructs a transaction from the stack at the time of a call to
+    // I2cRead or I2cWrite.
+    //
+    fn transaction(
+        &self,
+        name: &str,
+        stack: &[Option<u32>],
+    ) -> Option<I2cTransaction> {
+        let (args, op) = if name == "I2cRead" {
+            let args = stack.len().checked_sub(7)?;
+            let nbytes = stack[args + 6].map(|n| n as u8);
+            (&stack[args..], I2cOperation::Read(nbytes))
+        } else {
+            let len = (*stack.last()?)? as usize;
+            let args = stack.len().checked_sub(len + 7)?;
+            let bytes = stack[args + 6..args + 6 + len]
+                .iter()
+                .map(|b| b.map(|b| b as u8))
+                .collect::<Option<Vec<_>>>()?;
+            (&stack[args..], I2cOperation::Write(bytes))
+        };
+
+        let controller = args[0]? as u8;
+        let port = args[1]? as u8;
+
+        let mux = match (args[2], args[3]) {
+            (Some(m), Some(s)) => Some((m as u8, s as u8)),
+            _ => None,
+        };
+
+        Some(I2cTransaction {
+            time: 0.0,
+            bus: Some(I2cTraceBus {
+                controller,
+                port: match self.buses.get(&(controller, port)) {
+                    Some(name) => name.clone(),
+                    None => port.to_string(),
+                },
+                mux,
+            }),
+            address: args[4]? as u8,
+            register: args[5].map(|r| r as u8),
+            op,
+            result: None,
+        })
+    }
+
+    ///
+    /// Records the transactions of the most recently started program, given
+    /// its results.
+    ///
+    pub fn results(&mut self, results: &[Result<Vec<u8>, u32>]) -> Result<()> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        for (p, result) in pending.into_iter().zip(results.iter()) {
+            if let Some(PendingTransaction { mut transaction, errmap }) = p {
+                transaction.time = time;
+                transaction.result = Some(match result {
+                    Ok(bytes) => Ok(bytes.clone()),
+                    Err(code) => Err(match errmap.get(code) {
+                        Some(name) => name.clone(),
+                        None => format!("<Unknown error: {}>", code),
+                    }),
+                });
+
+                serde_json::to_writer(&mut self.out, &transaction)?;
+                writeln!(self.out)?;
+            }
+        }
+
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace() -> Result<()> {
+        let trace = r#"{"time":1700000000.5,"bus":{"controller":3,"port":"H","mux":null},"address":72,"register":11,"op":{"read":1},"result":{"Ok":[203]}}
+
+{"time":1700000100.25,"bus":{"controller":3,"port":"H","mux":[1,2]},"address":72,"register":null,"op":{"write":[1,128]},"result":{"Err":"NoDevice"}}
+"#;
+
+        let transactions = parse_trace(trace.as_bytes())?;
+        assert_eq!(transactions.len(), 2);
+
+        let t = &transactions[0];
+        assert_eq!(t.time, 1700000000.5);
+        assert_eq!(t.bus.as_ref().unwrap().to_string(), "I2C3, port H");
+        assert_eq!(t.to_string(), "dev 0x48, reg 0x0b, read 1");
+        assert_eq!(t.result_str(), "0xcb");
+
+        let t = &transactions[1];
+        assert_eq!(t.bus.as_ref().unwrap().mux, Some((1, 2)));
+        assert_eq!(t.op, I2cOperation::Write(vec![0x01, 0x80]));
+        assert_eq!(t.to_string(), "dev 0x48, write 0x01 0x80");
+        assert_eq!(t.result_str(), "Err(NoDevice)");
+
+        //
+        // What we write, we must be able to read back.
+        //
+        let mut out = vec![];
+
+        for t in &transactions {
+            serde_json::to_writer(&mut out, t)?;
+            writeln!(out)?;
+        }
+
+        assert_eq!(parse_trace(out.as_slice())?, transactions);
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace_bad_record() {
+        let trace = "\n{\"time\":0.0}\n";
+        let err = parse_trace(trace.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: bad trace record");
+    }
+}
@@ -2,9 +2,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::hiffy::HiffyContext;
 use ::idol::syntax::{AttributedTy, Operation, RecvStrategy, Reply};
 use anyhow::{anyhow, bail, Context, Result};
+use humility::core::Core;
 use humility::hubris::*;
+use humility::reflect::Value;
 use indexmap::IndexMap;
 
 #[derive(Debug)]
@@ -580,3 +583,40 @@ pub fn lookup_reply<'a>(
         Reply::Simple(ok) => Ok((lookup_ok(&ok.ty.0)?, None)),
     }
 }
+
+///
+/// Calls the Idol operation named as `Interface.operation` via HIF, with
+/// the specified arguments, returning its result.
+///
+pub fn call(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    timeout: u32,
+    name: &str,
+    args: &[(&str, IdolArgument)],
+) -> Result<Value> {
+    let (interface, operation) = match name.split_once('.') {
+        Some(split) => split,
+        None => {
+            bail!("Idol operation must be specified as Interface.operation")
+        }
+    };
+
+    let op = IdolOperation::new(hubris, interface, operation, None)?;
+    let payload = op.payload(args)?;
+
+    let mut context = HiffyContext::new(hubris, core, timeout)?;
+    let funcs = context.functions()?;
+    let mut ops = vec![];
+
+    context.idol_call_ops(&funcs, &op, &payload, &mut ops)?;
+    ops.push(hif::Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    if results.len() != 1 {
+        bail!("unexpected results from {}: {:?}", name, results);
+    }
+
+    context.idol_result(&op, &results[0])
+}
@@ -0,0 +1,80 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use humility::reflect::{Base, Value};
+use serde_json::json;
+
+///
+/// Translates a base type into JSON.
+///
+pub fn base_json(base: &Base) -> serde_json::Value {
+    match *base {
+        Base::I8(v) => json!(v),
+        Base::I16(v) => json!(v),
+        Base::I32(v) => json!(v),
+        Base::I64(v) => json!(v),
+        Base::U8(v) => json!(v),
+        Base::U16(v) => json!(v),
+        Base::U32(v) => json!(v),
+        Base::U64(v) => json!(v),
+        Base::Bool(v) => json!(v),
+        Base::F32(v) => json!(v),
+        Base::F64(v) => json!(v),
+        Base::U0 => serde_json::Value::Null,
+
+        //
+        // JSON numbers can't reliably represent 128-bit values.
+        //
+        Base::I128(_) | Base::U128(_) => json!(base.to_string()),
+    }
+}
+
+///
+/// Translates a value read from the target into JSON:  structures become
+/// objects, arrays and tuples become arrays, enum variants without contents
+/// become strings, and enum variants with contents become an object mapping
+/// the variant name to its contents.
+///
+pub fn value_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Base(base) => base_json(base),
+        Value::Struct(s) => serde_json::Value::Object(
+            s.iter().map(|(n, v)| (n.to_string(), value_json(v))).collect(),
+        ),
+        Value::Tuple(t) => {
+            serde_json::Value::Array(t.iter().map(value_json).collect())
+        }
+        Value::Array(a) => {
+            serde_json::Value::Array(a.iter().map(value_json).collect())
+        }
+        Value::Enum(e) => match e.contents() {
+            Some(contents) => {
+                let mut map = serde_json::Map::new();
+                map.insert(e.disc().to_string(), value_json(contents));
+                serde_json::Value::Object(map)
+            }
+            None => json!(e.disc()),
+        },
+        Value::Ptr(p) => json!(p.addr()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bases() {
+        assert_eq!(base_json(&Base::U8(7)), json!(7));
+        assert_eq!(base_json(&Base::I32(-3)), json!(-3));
+        assert_eq!(base_json(&Base::U64(u64::MAX)), json!(u64::MAX));
+        assert_eq!(base_json(&Base::Bool(true)), json!(true));
+        assert_eq!(base_json(&Base::F64(0.5)), json!(0.5));
+        assert_eq!(base_json(&Base::U0), serde_json::Value::Null);
+        assert_eq!(
+            base_json(&Base::U128(u128::MAX)),
+            json!(u128::MAX.to_string())
+        );
+    }
+}
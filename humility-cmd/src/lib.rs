@@ -7,10 +7,13 @@ pub mod hiffy;
 pub mod i2c;
 pub mod idol;
 pub mod jefe;
+pub mod json;
+pub mod regmap;
+pub mod ringbuf;
 pub mod stack;
 pub mod test;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use humility::cli::Cli;
 use humility::core::Core;
 use humility::hubris::*;
@@ -79,7 +82,13 @@ pub fn attach_live(
             None => "auto",
         };
 
-        humility::core::attach(probe, hubris)
+        let mut core = humility::core::attach(probe, hubris)?;
+
+        if let Some(hart) = args.hart {
+            core.select_hart(hart)?;
+        }
+
+        Ok(core)
     }
 }
 
@@ -132,6 +141,94 @@ pub fn attach(
     (run)(context)
 }
 
+///
+/// Loads and cooks the specified archive, apart from the archive (if any)
+/// of the execution context -- e.g., to compare against it.
+///
+pub fn load_archive(archive: &str) -> Result<HubrisArchive> {
+    let mut hubris = HubrisArchive::new().context("failed to initialize")?;
+
+    hubris
+        .load(archive, HubrisArchiveDoneness::Cook)
+        .with_context(|| format!("failed to load archive \"{}\"", archive))?;
+
+    Ok(hubris)
+}
+
+///
+/// Returns a command to run OpenOCD in the specified directory, placing its
+/// configuration there as `openocd.cfg`.  The target in the environment
+/// file may override the OpenOCD that is run (if `exec` is not specified)
+/// and its configuration, and may specify additional arguments.
+///
+pub fn openocd(
+    context: &humility::ExecutionContext,
+    exec: Option<String>,
+    serial: Option<String>,
+    dir: &std::path::Path,
+) -> Result<std::process::Command> {
+    let hubris = context.archive.as_ref().unwrap();
+
+    openocd_command(
+        context.environment.as_ref(),
+        || {
+            hubris.extract_file("debug/openocd.cfg").context(
+                "OpenOCD config missing. Is your Hubris build too old?",
+            )
+        },
+        exec,
+        serial,
+        dir,
+    )
+}
+
+///
+/// Like [`openocd`], but with the configuration (absent an override from
+/// the environment) supplied by the caller rather than taken from the
+/// archive -- e.g., for flashing, which uses the flash configuration.
+///
+pub fn openocd_command(
+    environment: Option<&humility::env::Environment>,
+    config: impl FnOnce() -> Result<Vec<u8>>,
+    exec: Option<String>,
+    serial: Option<String>,
+    dir: &std::path::Path,
+) -> Result<std::process::Command> {
+    let overrides =
+        environment.and_then(|env| env.openocd.clone()).unwrap_or_default();
+
+    let path = dir.join("openocd.cfg");
+
+    match &overrides.config {
+        Some(override_path) => {
+            std::fs::copy(override_path, &path).with_context(|| {
+                format!("failed to copy OpenOCD config {}", override_path)
+            })?;
+        }
+        None => {
+            std::fs::write(&path, config()?)?;
+        }
+    }
+
+    let mut cmd = std::process::Command::new(
+        exec.or(overrides.exec).unwrap_or_else(|| "openocd".to_string()),
+    );
+
+    cmd.arg("-f").arg("openocd.cfg");
+
+    if let Some(serial) = serial {
+        cmd.arg("-c")
+            .arg("interface hla")
+            .arg("-c")
+            .arg(format!("hla_serial {}", serial));
+    }
+
+    cmd.args(&overrides.args);
+    cmd.current_dir(dir);
+
+    Ok(cmd)
+}
+
 pub struct Dumper {
     /// Word size, in bytes
     pub size: usize,
@@ -0,0 +1,295 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Declarative register maps for I2C devices.  A register map is a TOML
+//! file that names a device's registers (with their offset and width in
+//! bits) and, optionally, the bitfields within them (with names for their
+//! values) and a scale and units with which to interpret the register as a
+//! whole.  Maps are keyed by the driver name of the device (that is, the
+//! `device` field of an I2C device in the Hubris manifest).  Maps for a
+//! few devices are built in, but this coverage is partial:  most devices
+//! that Hubris knows about don't have one, and need a map to be supplied.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("adt7420", include_str!("../regmaps/adt7420.toml")),
+    ("tmp117", include_str!("../regmaps/tmp117.toml")),
+    ("tmp451", include_str!("../regmaps/tmp451.toml")),
+];
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    #[default]
+    Big,
+    Little,
+}
+
+fn default_width() -> u8 {
+    8
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterMap {
+    pub device: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub byteorder: ByteOrder,
+    #[serde(rename = "register")]
+    pub registers: Vec<Register>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Register {
+    pub name: String,
+    pub offset: u8,
+    #[serde(default = "default_width")]
+    pub width: u8,
+    #[serde(default)]
+    pub signed: bool,
+    pub scale: Option<f64>,
+    pub units: Option<String>,
+    #[serde(default, rename = "field")]
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    pub name: String,
+    bits: String,
+    #[serde(default)]
+    values: BTreeMap<String, String>,
+}
+
+impl Field {
+    /// Returns the most and least significant bits of the field
+    pub fn bits(&self) -> Result<(u8, u8)> {
+        let parse = |s: &str| {
+            s.trim().parse::<u8>().with_context(|| {
+                format!("field {}: bad bits \"{}\"", self.name, self.bits)
+            })
+        };
+
+        let (msb, lsb) = match self.bits.split_once(':') {
+            Some((msb, lsb)) => (parse(msb)?, parse(lsb)?),
+            None => {
+                let bit = parse(&self.bits)?;
+                (bit, bit)
+            }
+        };
+
+        if msb < lsb {
+            bail!("field {}: bits must be given as msb:lsb", self.name);
+        }
+
+        Ok((msb, lsb))
+    }
+
+    /// Returns the name of the specified field value, if it has one
+    pub fn value_name(&self, val: u64) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| parse_int::parse::<u64>(k).ok() == Some(val))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Register {
+    pub fn nbytes(&self) -> usize {
+        self.width as usize / 8
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.width % 8 != 0 || self.width > 64 {
+            bail!("register {}: invalid width {}", self.name, self.width);
+        }
+
+        for field in &self.fields {
+            let (msb, _) = field.bits()?;
+
+            if msb >= self.width {
+                bail!(
+                    "register {}: field {} exceeds register width",
+                    self.name,
+                    field.name
+                );
+            }
+
+            for k in field.values.keys() {
+                parse_int::parse::<u64>(k).map_err(|_| {
+                    anyhow!("field {}: bad value \"{}\"", field.name, k)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of the register, given the bytes read from it
+    pub fn value(&self, byteorder: ByteOrder, bytes: &[u8]) -> u64 {
+        let fold = |v: u64, b: &u8| (v << 8) | *b as u64;
+
+        match byteorder {
+            ByteOrder::Big => bytes.iter().fold(0, fold),
+            ByteOrder::Little => bytes.iter().rev().fold(0, fold),
+        }
+    }
+
+    ///
+    /// Decodes the bytes read from the register into a list of name/value
+    /// pairs:  the register as a whole (if it has a scale or units),
+    /// followed by each of its fields.
+    ///
+    pub fn decode(
+        &self,
+        byteorder: ByteOrder,
+        bytes: &[u8],
+    ) -> Result<Vec<(String, String)>> {
+        if bytes.len() != self.nbytes() {
+            bail!(
+                "register {} is {} bytes; found {}",
+                self.name,
+                self.nbytes(),
+                bytes.len()
+            );
+        }
+
+        let raw = self.value(byteorder, bytes);
+        let mut rval = vec![];
+
+        if self.scale.is_some() || self.units.is_some() {
+            let val = if self.signed && self.width < 64 {
+                let shift = 64 - self.width as u32;
+                (((raw << shift) as i64) >> shift) as f64
+            } else if self.signed {
+                raw as i64 as f64
+            } else {
+                raw as f64
+            };
+
+            let val = val * self.scale.unwrap_or(1.0);
+            let units = self.units.as_deref().unwrap_or("");
+
+            rval.push((self.name.clone(), format!("{} {}", val, units)));
+        }
+
+        for field in &self.fields {
+            let (msb, lsb) = field.bits()?;
+            let mask = u64::MAX >> (63 - (msb - lsb) as u32);
+            let val = (raw >> lsb) & mask;
+
+            let label = if msb == lsb {
+                format!("{} [{}]", field.name, msb)
+            } else {
+                format!("{} [{}:{}]", field.name, msb, lsb)
+            };
+
+            let val = match field.value_name(val) {
+                Some(name) => format!("0x{:x} ({})", val, name),
+                None => format!("0x{:x}", val),
+            };
+
+            rval.push((label, val));
+        }
+
+        Ok(rval)
+    }
+}
+
+impl RegisterMap {
+    pub fn parse(contents: &str) -> Result<Self> {
+        let map: RegisterMap = toml::from_str(contents)?;
+
+        for register in &map.registers {
+            register
+                .validate()
+                .with_context(|| format!("register map {}", map.device))?;
+        }
+
+        Ok(map)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+
+        Self::parse(&contents)
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    ///
+    /// Returns the built-in register map for the specified driver, if any.
+    ///
+    pub fn builtin(device: &str) -> Result<Option<Self>> {
+        match BUILTIN.iter().find(|(name, _)| *name == device) {
+            Some((_, contents)) => Ok(Some(Self::parse(contents)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Looks up a register by name (without regard to case)
+    pub fn register(&self, name: &str) -> Result<&Register> {
+        self.registers
+            .iter()
+            .find(|r| r.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let names: Vec<_> =
+                    self.registers.iter().map(|r| r.name.as_str()).collect();
+
+                anyhow!(
+                    "no register {} for {}; expected one of: {}",
+                    name,
+                    self.device,
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// Looks up a register by offset
+    pub fn register_at(&self, offset: u8) -> Option<&Register> {
+        self.registers.iter().find(|r| r.offset == offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_maps() -> Result<()> {
+        for (name, _) in BUILTIN {
+            let map = RegisterMap::builtin(name)?.unwrap();
+            assert_eq!(map.device, *name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn decode() -> Result<()> {
+        let map = RegisterMap::builtin("tmp117")?.unwrap();
+
+        let temp = map.register("temp_result")?;
+        let decoded = temp.decode(map.byteorder, &[0xfe, 0x00])?;
+        assert_eq!(decoded[0].1, "-4 C");
+
+        let config = map.register_at(0x01).unwrap();
+        let decoded = config.decode(map.byteorder, &[0x0c, 0x20])?;
+        let (label, val) = &decoded[4];
+        assert_eq!(label, "MOD [11:10]");
+        assert_eq!(val, "0x3 (one-shot)");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!
+//! Discovery and reading of Hubris ring buffers (as created via the
+//! `ringbuf!` macro in the Hubris `ringbuf` crate).
+//!
+
+use crate::doppel::{Ringbuf, StaticCell};
+use anyhow::{anyhow, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility::reflect::{self, Load, Value};
+
+///
+/// A ring buffer variable, along with its type (if it could be found).
+///
+pub struct RingbufVariable<'a> {
+    pub name: &'a str,
+    pub variable: &'a HubrisVariable,
+    pub definition: Option<&'a HubrisStruct>,
+}
+
+impl<'a> RingbufVariable<'a> {
+    /// Returns the name of the task (or kernel) containing the ring buffer
+    pub fn taskname(&self, hubris: &'a HubrisArchive) -> Result<&'a str> {
+        Ok(&hubris.lookup_module(HubrisTask::from(self.variable.goff))?.name)
+    }
+
+    ///
+    /// Returns the type of the payload of the ring buffer's entries, as
+    /// determined from the DWARF definition of the ring buffer.
+    ///
+    pub fn payload(&self, hubris: &HubrisArchive) -> Result<HubrisGoff> {
+        let definition = self.definition.ok_or_else(|| {
+            anyhow!("could not look up type: {:?}", self.variable.goff)
+        })?;
+
+        //
+        // As in `read`, the ring buffer may be inside a StaticCell.
+        //
+        let ringbuf = match definition.lookup_member("buffer") {
+            Ok(_) => definition,
+            Err(_) => {
+                let cell = definition.lookup_member("cell")?;
+                let cell = hubris.lookup_struct(cell.goff)?;
+                hubris.lookup_struct(cell.lookup_member("value")?.goff)?
+            }
+        };
+
+        let buffer = ringbuf.lookup_member("buffer")?;
+        let entry = hubris.lookup_array(buffer.goff)?;
+        let entry = hubris.lookup_struct(entry.goff)?;
+
+        Ok(entry.lookup_member("payload")?.goff)
+    }
+
+    ///
+    /// Reads the ring buffer from the specified core.
+    ///
+    pub fn read(
+        &self,
+        hubris: &HubrisArchive,
+        core: &mut dyn Core,
+    ) -> Result<Ringbuf> {
+        let definition = self.definition.ok_or_else(|| {
+            anyhow!("could not look up type: {:?}", self.variable.goff)
+        })?;
+
+        let mut buf: Vec<u8> = vec![];
+        buf.resize_with(self.variable.size, Default::default);
+
+        core.op_start()?;
+        let rval = core.read_8(self.variable.addr, buf.as_mut_slice());
+        core.op_done()?;
+        rval?;
+
+        // There are two possible shapes of ringbufs, depending on the age of
+        // the firmware.
+        // - Raw Ringbuf that is not wrapped by anything.
+        // - Safe Ringbuf that is inside a StaticCell.
+        //
+        // Here we will attempt to handle them both -- first raw, then
+        // fallback.
+        let ringbuf_val: Value =
+            Value::Struct(reflect::load_struct(hubris, &buf, definition, 0)?);
+
+        Ringbuf::from_value(&ringbuf_val).or_else(|_e| {
+            let cell: StaticCell = StaticCell::from_value(&ringbuf_val)?;
+            Ringbuf::from_value(&cell.cell.value)
+        })
+    }
+}
+
+///
+/// Returns all ring buffers in the archive, sorted by name.
+///
+pub fn ringbufs(hubris: &HubrisArchive) -> Vec<RingbufVariable> {
+    let mut rval = vec![];
+
+    for (name, variable) in hubris.qualified_variables() {
+        let definition = match hubris.lookup_struct(variable.goff) {
+            Ok(s) => {
+                // Skip variables whose type does not indicate they contain a
+                // ringbuf; this check is imprecise but probably good enough
+                if s.name.contains("Ringbuf") {
+                    Some(s)
+                } else {
+                    continue;
+                }
+            }
+            Err(_) => {
+                // Type lookup failed, so fall back to the variable name
+                if name.ends_with("RINGBUF") {
+                    None
+                } else {
+                    continue;
+                }
+            }
+        };
+
+        rval.push(RingbufVariable { name, variable, definition });
+    }
+
+    rval.sort_by_key(|r| (r.name, r.variable));
+    rval
+}
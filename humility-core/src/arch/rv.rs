@@ -27,6 +27,18 @@ impl RVArch {
     }
 }
 
+///
+/// Looks up the control transfer type of the previously-disassembled
+/// instruction at `addr`, returning `None` if the instruction does not
+/// transfer control.
+///
+pub fn rv_instr_target(
+    hubris: &HubrisArchive,
+    addr: u32,
+) -> Option<HubrisTarget> {
+    hubris.instrs.get(&addr).and_then(|&(_, target)| target)
+}
+
 impl Arch for RVArch {
     fn get_e_machine(&self) -> u16 {
         goblin::elf::header::EM_RISCV
@@ -157,12 +169,144 @@ impl Arch for RVArch {
         "riscv".to_string()
     }
 
+    //
+    // Capstone's RISC-V support doesn't reliably group control transfer
+    // instructions (or resolve their targets), so we decode the handful of
+    // instructions that can transfer control ourselves.  These targets are
+    // used to reconstruct control flow from E-Trace.
+    //
     fn instr_branch_target(
         &self,
         _cs: &Capstone,
-        _isntr: &capstone::Insn,
+        instr: &capstone::Insn,
     ) -> Option<HubrisTarget> {
-        None
+        let addr = instr.address() as u32;
+        let link = |rd: u32| rd == 1 || rd == 5;
+        let sext = |val: u32, bits: u32| {
+            (((val << (32 - bits)) as i32) >> (32 - bits)) as u32
+        };
+
+        match *instr.bytes() {
+            [b0, b1, b2, b3] => {
+                let i = u32::from_le_bytes([b0, b1, b2, b3]);
+                let rd = (i >> 7) & 0x1f;
+                let rs1 = (i >> 15) & 0x1f;
+
+                match i & 0x7f {
+                    // BEQ, BNE, BLT, BGE, BLTU, BGEU
+                    0x63 => {
+                        let imm = ((i >> 31) & 0x1) << 12
+                            | ((i >> 7) & 0x1) << 11
+                            | ((i >> 25) & 0x3f) << 5
+                            | ((i >> 8) & 0xf) << 1;
+
+                        Some(HubrisTarget::Conditional(
+                            addr.wrapping_add(sext(imm, 13)),
+                        ))
+                    }
+
+                    // JAL
+                    0x6f => {
+                        let imm = ((i >> 31) & 0x1) << 20
+                            | ((i >> 12) & 0xff) << 12
+                            | ((i >> 20) & 0x1) << 11
+                            | ((i >> 21) & 0x3ff) << 1;
+                        let target = addr.wrapping_add(sext(imm, 21));
+
+                        if rd == 0 {
+                            Some(HubrisTarget::Direct(target))
+                        } else {
+                            Some(HubrisTarget::Call(target))
+                        }
+                    }
+
+                    // JALR
+                    0x67 => {
+                        if rd != 0 {
+                            Some(HubrisTarget::IndirectCall)
+                        } else if link(rs1) {
+                            Some(HubrisTarget::Return)
+                        } else {
+                            Some(HubrisTarget::Indirect)
+                        }
+                    }
+
+                    // MRET, SRET
+                    0x73 if i == 0x3020_0073 || i == 0x1020_0073 => {
+                        Some(HubrisTarget::Indirect)
+                    }
+
+                    _ => None,
+                }
+            }
+
+            [b0, b1] => {
+                let h = u16::from_le_bytes([b0, b1]) as u32;
+                let funct3 = h >> 13;
+
+                //
+                // The CJ-format offset is imm[11|4|9:8|10|6|7|3:1|5]; the
+                // CB-format offset is imm[8|4:3] and imm[7:6|2:1|5].
+                //
+                let cj = || {
+                    let imm = ((h >> 12) & 0x1) << 11
+                        | ((h >> 11) & 0x1) << 4
+                        | ((h >> 9) & 0x3) << 8
+                        | ((h >> 8) & 0x1) << 10
+                        | ((h >> 7) & 0x1) << 6
+                        | ((h >> 6) & 0x1) << 7
+                        | ((h >> 3) & 0x7) << 1
+                        | ((h >> 2) & 0x1) << 5;
+
+                    addr.wrapping_add(sext(imm, 12))
+                };
+
+                let cb = || {
+                    let imm = ((h >> 12) & 0x1) << 8
+                        | ((h >> 10) & 0x3) << 3
+                        | ((h >> 5) & 0x3) << 6
+                        | ((h >> 3) & 0x3) << 1
+                        | ((h >> 2) & 0x1) << 5;
+
+                    addr.wrapping_add(sext(imm, 9))
+                };
+
+                match (h & 0x3, funct3) {
+                    // C.J
+                    (0b01, 0b101) => Some(HubrisTarget::Direct(cj())),
+
+                    // C.JAL (RV32 only; this is C.ADDIW on RV64)
+                    (0b01, 0b001) if self.get_abi_size() == 32 => {
+                        Some(HubrisTarget::Call(cj()))
+                    }
+
+                    // C.BEQZ, C.BNEZ
+                    (0b01, 0b110) | (0b01, 0b111) => {
+                        Some(HubrisTarget::Conditional(cb()))
+                    }
+
+                    // C.JR, C.JALR (but not C.MV, C.ADD or C.EBREAK)
+                    (0b10, 0b100) => {
+                        let rs1 = (h >> 7) & 0x1f;
+                        let rs2 = (h >> 2) & 0x1f;
+
+                        match (rs1, rs2, (h >> 12) & 0x1) {
+                            (0, _, _) => None,
+                            (rs1, 0, 0) if link(rs1) => {
+                                Some(HubrisTarget::Return)
+                            }
+                            (_, 0, 0) => Some(HubrisTarget::Indirect),
+                            (_, 0, _) => Some(HubrisTarget::IndirectCall),
+                            _ => None,
+                        }
+                    }
+
+                    _ => None,
+                }
+            }
+
+            _ => None,
+        }
     }
 
     //
@@ -2,14 +2,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use clap::{AppSettings, Parser};
+use clap::{AppSettings, ArgGroup, Parser};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(name = "humility", max_term_width = 80)]
 #[clap(global_setting(AppSettings::NoAutoVersion))]
+#[clap(group(ArgGroup::new("multiple").args(&["all_targets", "targets"])))]
 pub struct Cli {
     /// verbose messages
-    #[clap(long, short)]
+    #[clap(long, short, env = "HUMILITY_VERBOSE")]
     pub verbose: bool,
 
     /// terse output
@@ -48,24 +49,51 @@ pub struct Cli {
 
     //
     // probe-rs requires the chip to be specified when creating a session,
-    // even though it is only used for flashing (which we don't use probe-rs
-    // to do).  Historically, we had a `-c` option to specify this, but its
-    // presence was causing confusion and it has been deprecated.  However,
-    // Hubris uses Humility to flash, and specifies this option.  Because we
-    // may want to use this properly in the future (namely, if/when we use
-    // probe-rs to flash), we continue to accept this option (and test for its
-    // presence), thereby eliminating two potential Hubris flag days.  (Until
-    // it once again means something, we hide the option from the help
-    // output.)
+    // even though it is only used for flashing.  Historically, we had a `-c`
+    // option to specify this, but its presence was causing confusion and it
+    // has been deprecated.  However, Hubris uses Humility to flash, and
+    // specifies this option, so we continue to accept it.  The only
+    // consumer is `humility flash`, which uses it (for RISC-V parts only) to
+    // override the chip inferred from the archive's flash configuration --
+    // which is needed for parts that we cannot otherwise infer.
+    // Because it has no meaning for any other subcommand, we continue to
+    // hide the option from the help output.
     //
     #[clap(long, short, env = "HUMILITY_CHIP", hide = true)]
     pub chip: Option<String>,
 
+    //
+    // On parts with more than one hart (or core), the index of the one to
+    // attach to.  This is generally specified via the environment file
+    // rather than on the command line, and is hidden for the same reason as
+    // the chip.
+    //
+    #[clap(long, env = "HUMILITY_HART", hide = true)]
+    pub hart: Option<usize>,
+
     /// list targets within an environment
     #[clap(long = "list-targets", requires = "environment",
         conflicts_with_all = &["dump", "probe", "target"])]
     pub list_targets: bool,
 
+    /// run the subcommand against every target within an environment
+    #[clap(long = "all-targets", requires = "environment",
+        conflicts_with_all = &["dump", "probe", "target", "list_targets"])]
+    pub all_targets: bool,
+
+    /// run the subcommand against each of the specified (comma-separated)
+    /// targets within an environment
+    #[clap(long, requires = "environment", use_value_delimiter = true,
+        value_name = "targets",
+        conflicts_with_all = &["dump", "probe", "target", "list_targets",
+            "all_targets"])]
+    pub targets: Option<Vec<String>>,
+
+    /// when running against multiple targets, run the subcommand in
+    /// parallel against targets that have different probes
+    #[clap(long, requires = "multiple")]
+    pub parallel: bool,
+
     #[clap(subcommand)]
     pub cmd: Option<Subcommand>,
 }
@@ -8,6 +8,7 @@ use anyhow::{anyhow, bail, Result};
 
 use crate::hubris::*;
 use crate::regs::Register;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::path::Path;
 use std::str;
@@ -70,6 +71,16 @@ pub trait Core {
     fn op_done(&mut self) -> Result<()> {
         Ok(())
     }
+
+    /// Selects the hart (or core) upon which subsequent operations act, for
+    /// parts that have more than one.
+    fn select_hart(&mut self, hart: usize) -> Result<()> {
+        if hart != 0 {
+            bail!("cannot select hart {} via this probe", hart);
+        }
+
+        Ok(())
+    }
 }
 
 fn parse_probe(probe: &str) -> (&str, Option<usize>) {
@@ -324,6 +335,66 @@ pub fn attach_to_chip(
     }
 }
 
+///
+/// Returns true if probe-rs has a description for the named chip -- either
+/// built in or registered via [`register_chip_description`].
+///
+pub fn chip_is_known(chip: &str) -> bool {
+    probe_rs::config::get_target_by_name(chip).is_ok()
+}
+
+///
+/// Registers a probe-rs chip description (a target YAML file, including any
+/// flash algorithms) so that a part that probe-rs doesn't know about natively
+/// (as is the case for most RISC-V parts) can be attached to and flashed.
+///
+pub fn register_chip_description(path: &Path) -> Result<()> {
+    probe_rs::config::add_target_from_yaml(path).map_err(|e| {
+        anyhow!("failed to load chip description {}: {}", path.display(), e)
+    })
+}
+
+///
+/// Returns the flash sectors (as base address and size) of the named chip,
+/// as described by its probe-rs flash algorithms.
+///
+pub fn flash_sectors(chip: &str) -> Result<Vec<(u32, u32)>> {
+    let target = probe_rs::config::get_target_by_name(chip)
+        .map_err(|e| anyhow!("no description for chip {}: {}", chip, e))?;
+
+    let mut sectors = BTreeMap::new();
+
+    for algo in &target.flash_algorithms {
+        let props = &algo.flash_properties;
+        let range = &props.address_range;
+
+        //
+        // Each sector description gives the size of the sectors from its
+        // address until the address of the next description (or the end
+        // of the algorithm's range).
+        //
+        for (i, desc) in props.sectors.iter().enumerate() {
+            let end = match props.sectors.get(i + 1) {
+                Some(next) => range.start + next.address,
+                None => range.end,
+            };
+
+            let mut addr = range.start + desc.address;
+
+            while addr < end && desc.size != 0 {
+                sectors.entry(addr as u32).or_insert(desc.size as u32);
+                addr += desc.size;
+            }
+        }
+    }
+
+    if sectors.is_empty() {
+        bail!("chip {} has no flash sectors", chip);
+    }
+
+    Ok(sectors.into_iter().collect())
+}
+
 pub fn attach_for_flashing(
     probe: &str,
     hubris: &HubrisArchive,
@@ -25,6 +25,7 @@ pub struct ProbeCore {
     halted: u32,
     unhalted_read: BTreeMap<u32, u32>,
     can_flash: bool,
+    hart: usize,
 }
 
 impl ProbeCore {
@@ -48,6 +49,7 @@ impl ProbeCore {
             //TODO probably a way to abstract this out
             unhalted_read: crate::arch::arm::unhalted_read_regions(),
             can_flash,
+            hart: 0,
         }
     }
 
@@ -55,7 +57,7 @@ impl ProbeCore {
         &mut self,
         mut func: impl FnMut(&mut probe_rs::Core) -> Result<()>,
     ) -> Result<()> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
 
         if self.unhalted_reads {
             func(&mut core)
@@ -95,7 +97,7 @@ impl Core for ProbeCore {
 
         if let Some(range) = self.unhalted_read.range(..=addr).next_back() {
             if addr + 4 < range.0 + range.1 {
-                let mut core = self.session.core(0)?;
+                let mut core = self.session.core(self.hart)?;
                 return Ok(core.read_word_32(addr)?);
             }
         }
@@ -116,7 +118,7 @@ impl Core for ProbeCore {
 
         if let Some(range) = self.unhalted_read.range(..=addr).next_back() {
             if addr + (data.len() as u32) < range.0 + range.1 {
-                let mut core = self.session.core(0)?;
+                let mut core = self.session.core(self.hart)?;
                 return Ok(core.read_8(addr, data)?);
             }
         }
@@ -127,7 +129,7 @@ impl Core for ProbeCore {
     // TODO need to bump probe-rs version to support 64bit values
     // for now just upcast everything to match the interface
     fn read_reg(&mut self, reg: Register) -> Result<u64> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
         let reg_id = Register::to_u16(&reg).unwrap();
 
         use num_traits::ToPrimitive;
@@ -140,7 +142,7 @@ impl Core for ProbeCore {
     // TODO need to bump probe-rs version to support 64bit values
     // for now just upcast everything to match the interface
     fn write_reg(&mut self, reg: Register, value: u64) -> Result<()> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
         let reg_id = Register::to_u16(&reg).unwrap();
 
         use num_traits::ToPrimitive;
@@ -154,20 +156,20 @@ impl Core for ProbeCore {
     }
 
     fn write_word_32(&mut self, addr: u32, data: u32) -> Result<()> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
         core.write_word_32(addr, data)?;
         Ok(())
     }
 
     fn write_8(&mut self, addr: u32, data: &[u8]) -> Result<()> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
         core.write_8(addr, data)?;
         Ok(())
     }
 
     fn halt(&mut self) -> Result<()> {
         if self.halted == 0 {
-            let mut core = self.session.core(0)?;
+            let mut core = self.session.core(self.hart)?;
             core.halt(std::time::Duration::from_millis(1000))?;
         }
 
@@ -179,7 +181,7 @@ impl Core for ProbeCore {
         self.halted -= 1;
 
         if self.halted == 0 {
-            let mut core = self.session.core(0)?;
+            let mut core = self.session.core(self.hart)?;
             core.run()?;
         }
 
@@ -187,7 +189,7 @@ impl Core for ProbeCore {
     }
 
     fn step(&mut self) -> Result<()> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
         core.step()?;
         Ok(())
     }
@@ -301,7 +303,7 @@ impl Core for ProbeCore {
     }
 
     fn reset(&mut self) -> Result<()> {
-        let mut core = self.session.core(0)?;
+        let mut core = self.session.core(self.hart)?;
         core.reset()?;
         Ok(())
     }
@@ -321,4 +323,15 @@ impl Core for ProbeCore {
 
         Ok(())
     }
+
+    fn select_hart(&mut self, hart: usize) -> Result<()> {
+        let ncores = self.session.list_cores().len();
+
+        if hart >= ncores {
+            bail!("hart {} does not exist (part has {})", hart, ncores);
+        }
+
+        self.hart = hart;
+        Ok(())
+    }
 }
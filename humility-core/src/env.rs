@@ -2,19 +2,118 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{anyhow, bail, Result};
+use crate::msg;
+use anyhow::{anyhow, bail, Context, Result};
 use indexmap::IndexMap;
 use serde::Deserialize;
 use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
 use std::{fs, path::PathBuf};
 
+//
+// When power cycling a target that has no power-cycle action of its own, the
+// time that we leave it powered off.
+//
+const POWER_CYCLE_DELAY: Duration = Duration::from_secs(1);
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Environment {
+    #[serde(default)]
     pub probe: String,
+    #[serde(default)]
     pub archive: serde_json::Value,
     pub description: Option<String>,
     pub cmds: Option<serde_json::Value>,
+
+    /// Target whose definition this target inherits and overrides
+    pub inherits: Option<String>,
+
+    /// If set, this target exists only to be inherited by other targets
+    #[serde(default, rename = "abstract")]
+    pub is_abstract: bool,
+
+    /// Chip name, as used by probe-rs (and as otherwise specified by `-c`)
+    pub chip: Option<String>,
+
+    /// Index of the hart (or core) to attach to
+    pub hart: Option<usize>,
+
+    /// Overrides for running OpenOCD against this target
+    pub openocd: Option<OpenOcd>,
+
+    /// Default arguments for subcommands, keyed by subcommand name
+    pub args: Option<BTreeMap<String, String>>,
+
+    /// Commands to control power to the target
+    pub power: Option<Power>,
+
+    /// Commands for the standard actions that Humility itself may take
+    pub actions: Option<BTreeMap<Action, String>>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct OpenOcd {
+    /// OpenOCD executable to run
+    pub exec: Option<String>,
+
+    /// OpenOCD configuration file, overriding the one in the archive
+    pub config: Option<String>,
+
+    /// Additional arguments to OpenOCD
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Power {
+    pub on: Option<String>,
+    pub off: Option<String>,
+    pub cycle: Option<String>,
+}
+
+///
+/// The standard actions that Humility can take upon a target (e.g., to
+/// recover it), and which a target defines in its `actions` object -- or,
+/// for the power actions, its `power` object.
+///
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    PowerOn,
+    PowerOff,
+    PowerCycle,
+    EnterIsp,
+    ResetViaDebugmailbox,
+}
+
+impl Action {
+    pub const ALL: [Action; 5] = [
+        Action::PowerOn,
+        Action::PowerOff,
+        Action::PowerCycle,
+        Action::EnterIsp,
+        Action::ResetViaDebugmailbox,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::PowerOn => "power-on",
+            Action::PowerOff => "power-off",
+            Action::PowerCycle => "power-cycle",
+            Action::EnterIsp => "enter-isp",
+            Action::ResetViaDebugmailbox => "reset-via-debugmailbox",
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 impl Environment {
@@ -43,10 +142,337 @@ impl Environment {
         }
     }
 
+    fn load_cmds<'a>(
+        target: &str,
+        cmds: &'a Value,
+        stack: &mut Vec<&'a str>,
+        rval: &mut BTreeMap<String, String>,
+    ) -> Result<()> {
+        match cmds {
+            Value::Object(obj) => {
+                for (key, value) in obj.iter() {
+                    stack.push(key);
+                    Self::load_cmds(target, value, stack, rval)?;
+                    stack.pop();
+                }
+            }
+            Value::String(cmd) => {
+                rval.insert(stack.join("."), cmd.to_string());
+            }
+            _ => {
+                bail!(
+                    "illegal command for target {} at {}: {:?}",
+                    target,
+                    stack.join("."),
+                    cmds
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Returns the commands defined for the specified target, keyed by their
+    /// (dot-separated) names.
+    ///
+    pub fn commands(&self, target: &str) -> Result<BTreeMap<String, String>> {
+        let mut rval = BTreeMap::new();
+
+        if let Some(ref cmds) = self.cmds {
+            let mut stack = Vec::new();
+            Self::load_cmds(target, cmds, &mut stack, &mut rval)?;
+        }
+
+        //
+        // Power commands are also available as commands, named (e.g.)
+        // "power.on".
+        //
+        if let Some(ref power) = self.power {
+            for (name, cmd) in [
+                ("on", &power.on),
+                ("off", &power.off),
+                ("cycle", &power.cycle),
+            ] {
+                if let Some(cmd) = cmd {
+                    let name = format!("power.{}", name);
+
+                    if rval.contains_key(&name) {
+                        bail!(
+                            "target {}: {} is defined in both \"cmds\" \
+                            and \"power\"",
+                            target,
+                            name
+                        );
+                    }
+
+                    rval.insert(name, cmd.to_string());
+                }
+            }
+        }
+
+        //
+        // As are the standard actions, under their own names.
+        //
+        if let Some(ref actions) = self.actions {
+            for (action, cmd) in actions {
+                rval.insert(action.name().to_string(), cmd.to_string());
+            }
+        }
+
+        if rval.is_empty() {
+            bail!("target {} has no defined commands", target);
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Returns the default arguments for the specified subcommand, if any.
+    ///
+    pub fn args(&self, cmd: &str) -> Vec<String> {
+        match self.args.as_ref().and_then(|args| args.get(cmd)) {
+            Some(args) => splitty::split_unquoted_char(args, ' ')
+                .unwrap_quotes(true)
+                .filter(|arg| !arg.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    ///
+    /// Executes the named command for the specified target, returning its
+    /// exit status.
+    ///
+    pub fn exec(
+        &self,
+        target: &str,
+        cmd: &str,
+    ) -> Result<std::process::ExitStatus> {
+        let avail = self.commands(target)?;
+
+        let cmdline = match avail.get(cmd) {
+            Some(cmdline) => cmdline,
+            None => {
+                bail!("unknown command \"{}\"; --list for commands", cmd);
+            }
+        };
+
+        Self::run(target, cmd, cmdline)
+    }
+
+    fn run(
+        target: &str,
+        cmd: &str,
+        cmdline: &str,
+    ) -> Result<std::process::ExitStatus> {
+        let args = splitty::split_unquoted_char(cmdline, ' ')
+            .unwrap_quotes(true)
+            .collect::<Vec<_>>();
+
+        if args.is_empty() {
+            bail!("command \"{}\" for target {} is empty", cmd, target);
+        }
+
+        msg!("{} {}: executing: '{}' ...", target, cmd, cmdline);
+
+        let status =
+            std::process::Command::new(args[0]).args(&args[1..]).status()?;
+
+        msg!(
+            "{} {}: done ({})",
+            target,
+            cmd,
+            match status.code() {
+                Some(code) => format!("status code {code}"),
+                None => "terminated by signal".to_string(),
+            }
+        );
+
+        Ok(status)
+    }
+
+    fn power_command(&self, action: Action) -> Option<&str> {
+        let power = self.power.as_ref()?;
+
+        match action {
+            Action::PowerOn => power.on.as_deref(),
+            Action::PowerOff => power.off.as_deref(),
+            Action::PowerCycle => power.cycle.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn action_command(&self, action: Action) -> Option<&str> {
+        self.actions
+            .as_ref()
+            .and_then(|a| a.get(&action))
+            .map(String::as_str)
+            .or_else(|| self.power_command(action))
+    }
+
+    ///
+    /// Returns true if the target can take the specified action.  (A target
+    /// that can be powered on and off can be power cycled, even if it
+    /// defines no power-cycle action of its own.)
+    ///
+    pub fn has_action(&self, action: Action) -> bool {
+        match action {
+            Action::PowerCycle => {
+                self.action_command(action).is_some()
+                    || (self.has_action(Action::PowerOff)
+                        && self.has_action(Action::PowerOn))
+            }
+            _ => self.action_command(action).is_some(),
+        }
+    }
+
+    ///
+    /// Takes the specified action on the target, failing if the target does
+    /// not define it or if its command fails.
+    ///
+    pub fn action(&self, target: &str, action: Action) -> Result<()> {
+        let cmdline = match self.action_command(action) {
+            Some(cmdline) => cmdline,
+            None if action == Action::PowerCycle
+                && self.has_action(Action::PowerCycle) =>
+            {
+                self.action(target, Action::PowerOff)?;
+                std::thread::sleep(POWER_CYCLE_DELAY);
+                return self.action(target, Action::PowerOn);
+            }
+            None => bail!("target {} has no {} action", target, action),
+        };
+
+        let status = Self::run(target, action.name(), cmdline)?;
+
+        if !status.success() {
+            bail!("{} failed for target {}", action, target);
+        }
+
+        Ok(())
+    }
+
     fn read(filename: &str) -> Result<IndexMap<String, Environment>> {
         let path = PathBuf::from(filename);
         let input = fs::read_to_string(&path)?;
-        Ok(serde_json::from_str(&input)?)
+        let raw: IndexMap<String, Value> = serde_json::from_str(&input)?;
+        Self::resolve(&raw)
+    }
+
+    ///
+    /// Resolves each target's inheritance, and then validates the result.
+    /// Only targets that are not abstract must have a probe and an archive.
+    ///
+    fn resolve(
+        raw: &IndexMap<String, Value>,
+    ) -> Result<IndexMap<String, Environment>> {
+        let mut rval = IndexMap::new();
+
+        for target in raw.keys() {
+            let value = Self::inherit(raw, target, &mut vec![])?;
+            let env: Environment = serde_json::from_value(value)
+                .with_context(|| format!("invalid target \"{}\"", target))?;
+
+            if !env.is_abstract {
+                if env.probe.is_empty() {
+                    bail!("target \"{}\" has no probe", target);
+                }
+
+                if env.archive.is_null() {
+                    bail!("target \"{}\" has no archive", target);
+                }
+            }
+
+            for action in Action::ALL {
+                let actions = env.actions.as_ref();
+
+                if actions.map_or(false, |a| a.contains_key(&action))
+                    && env.power_command(action).is_some()
+                {
+                    bail!(
+                        "target \"{}\" defines {} in both \"actions\" and \
+                        \"power\"",
+                        target,
+                        action
+                    );
+                }
+            }
+
+            rval.insert(target.clone(), env);
+        }
+
+        Ok(rval)
+    }
+
+    fn inherit<'a>(
+        raw: &'a IndexMap<String, Value>,
+        target: &'a str,
+        stack: &mut Vec<&'a str>,
+    ) -> Result<Value> {
+        if stack.contains(&target) {
+            stack.push(target);
+            bail!("targets inherit from one another: {}", stack.join(" -> "));
+        }
+
+        let obj = match raw.get(target) {
+            Some(Value::Object(obj)) => obj,
+            Some(_) => bail!("target \"{}\" must be an object", target),
+            None => bail!(
+                "target \"{}\" inherits from unknown target \"{}\"",
+                stack.last().unwrap(),
+                target
+            ),
+        };
+
+        let base = match obj.get("inherits") {
+            None => return Ok(Value::Object(obj.clone())),
+            Some(Value::String(base)) => base,
+            Some(_) => {
+                bail!("target \"{}\": \"inherits\" must be a target", target)
+            }
+        };
+
+        stack.push(target);
+        let mut rval = Self::inherit(raw, base, stack)?;
+        stack.pop();
+
+        let map = rval.as_object_mut().unwrap();
+
+        //
+        // A target that inherits from an abstract target is not itself
+        // abstract (unless it says so).  Objects are merged, with the
+        // exception of the archive, which is taken in its entirety.
+        //
+        map.remove("abstract");
+
+        for (key, value) in obj {
+            match map.get_mut(key) {
+                Some(base) if key != "archive" => Self::merge(base, value),
+                _ => {
+                    map.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        Ok(rval)
+    }
+
+    fn merge(base: &mut Value, value: &Value) {
+        match (base, value) {
+            (Value::Object(base), Value::Object(obj)) => {
+                for (key, value) in obj {
+                    match base.get_mut(key) {
+                        Some(base) => Self::merge(base, value),
+                        None => {
+                            base.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+            (base, value) => *base = value.clone(),
+        }
     }
 
     pub fn validate(filename: &str) -> Result<()> {
@@ -58,7 +484,7 @@ impl Environment {
         let env = Self::read(filename)?;
         let mut rval = vec![];
 
-        for (target, e) in &env {
+        for (target, e) in env.iter().filter(|(_, e)| !e.is_abstract) {
             rval.push((target.clone(), e.description.clone()))
         }
 
@@ -68,11 +494,27 @@ impl Environment {
     pub fn from_file(filename: &str, target: &str) -> Result<Self> {
         let env = Self::read(filename)?;
 
-        if let Some(e) = env.get(target) {
-            Ok(e.clone())
-        } else {
-            let keys = env.keys().map(|n| &**n).collect::<Vec<_>>().join(", ");
-            bail!("invalid target \"{}\" (expected one of: {})", target, keys);
+        match env.get(target) {
+            Some(e) if e.is_abstract => {
+                bail!(
+                    "target \"{}\" is abstract; it can only be inherited",
+                    target
+                );
+            }
+            Some(e) => Ok(e.clone()),
+            None => {
+                let keys = env
+                    .iter()
+                    .filter(|(_, e)| !e.is_abstract)
+                    .map(|(n, _)| &**n)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                bail!(
+                    "invalid target \"{}\" (expected one of: {})",
+                    target,
+                    keys
+                );
+            }
         }
     }
 }
@@ -114,3 +556,105 @@ fn validate_multi_archive() {
     let _b = v.get("board1").unwrap().archive(&Some("name1".to_string()));
     let _b = v.get("board1").unwrap().archive(&Some("name2".to_string()));
 }
+
+#[test]
+fn validate_inherits() {
+    let data = r#"
+    {
+        "sidecar": {
+            "abstract": true,
+            "chip": "STM32H753ZITx",
+            "args": { "sensors": "-T 10000", "i2c": "-b front" },
+            "power": { "on": "power.sh --on", "off": "power.sh --off" }
+        },
+        "sidecar-3": {
+            "inherits": "sidecar",
+            "probe" : "1234:5678:ABCDEFG",
+            "archive" : "/some/valid/path",
+            "args": { "i2c": "-b rear" },
+            "power": { "off": "power.sh --off sidecar-3" }
+        }
+    }
+    "#;
+
+    let raw: IndexMap<String, Value> = serde_json::from_str(data).unwrap();
+    let v = Environment::resolve(&raw).unwrap();
+
+    assert!(v.get("sidecar").unwrap().is_abstract);
+
+    let e = v.get("sidecar-3").unwrap();
+    assert!(!e.is_abstract);
+    assert_eq!(e.chip.as_deref(), Some("STM32H753ZITx"));
+    assert_eq!(e.args("sensors"), vec!["-T", "10000"]);
+    assert_eq!(e.args("i2c"), vec!["-b", "rear"]);
+    assert!(e.args("tasks").is_empty());
+
+    let cmds = e.commands("sidecar-3").unwrap();
+    assert_eq!(cmds.get("power.on").unwrap(), "power.sh --on");
+    assert_eq!(cmds.get("power.off").unwrap(), "power.sh --off sidecar-3");
+}
+
+#[test]
+fn validate_inherits_errors() {
+    let resolve = |data: &str| {
+        let raw: IndexMap<String, Value> = serde_json::from_str(data).unwrap();
+        Environment::resolve(&raw).unwrap_err().to_string()
+    };
+
+    let err =
+        resolve(r#"{ "a": { "inherits": "b" }, "b": { "inherits": "a" } }"#);
+    assert_eq!(err, "targets inherit from one another: a -> b -> a");
+
+    let err = resolve(r#"{ "a": { "inherits": "c", "probe": "usb" } }"#);
+    assert_eq!(err, "target \"a\" inherits from unknown target \"c\"");
+
+    let err = resolve(
+        r#"{ "a": { "abstract": true, "chip": "x" },
+        "b": { "inherits": "a", "archive": "/some/path" } }"#,
+    );
+    assert_eq!(err, "target \"b\" has no probe");
+
+    let err = resolve(
+        r#"{ "a": { "probe": "usb", "archive": "/a",
+        "hart": "one" } }"#,
+    );
+    assert_eq!(err, "invalid target \"a\"");
+}
+
+#[test]
+fn validate_actions() {
+    let data = r#"
+    {
+        "gimletlet": {
+            "probe" : "1234:5678:ABCDEFG",
+            "archive" : "/some/valid/path",
+            "power": { "on": "power.sh --on", "off": "power.sh --off" },
+            "actions": { "enter-isp": "isp.sh" }
+        }
+    }
+    "#;
+
+    let raw: IndexMap<String, Value> = serde_json::from_str(data).unwrap();
+    let v = Environment::resolve(&raw).unwrap();
+    let e = v.get("gimletlet").unwrap();
+
+    assert!(e.has_action(Action::PowerCycle));
+    assert!(e.has_action(Action::EnterIsp));
+    assert!(!e.has_action(Action::ResetViaDebugmailbox));
+
+    let cmds = e.commands("gimletlet").unwrap();
+    assert_eq!(cmds.get("enter-isp").unwrap(), "isp.sh");
+
+    let bad = r#"{ "a": { "probe": "usb", "archive": "/a",
+        "actions": { "power-down": "off.sh" } } }"#;
+    let raw: IndexMap<String, Value> = serde_json::from_str(bad).unwrap();
+    assert!(Environment::resolve(&raw).is_err());
+
+    let both = r#"{ "a": { "probe": "usb", "archive": "/a",
+        "power": { "on": "on.sh" }, "actions": { "power-on": "on.sh" } } }"#;
+    let raw: IndexMap<String, Value> = serde_json::from_str(both).unwrap();
+    assert_eq!(
+        Environment::resolve(&raw).unwrap_err().to_string(),
+        "target \"a\" defines power-on in both \"actions\" and \"power\""
+    );
+}
@@ -59,6 +59,28 @@ pub struct HubrisManifest {
     pub auxflash: Option<HubrisConfigAuxflash>,
 }
 
+impl HubrisManifest {
+    /// Returns the version of the archive, if known.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Returns the git revision from which the archive was built, if known.
+    pub fn gitrev(&self) -> Option<&str> {
+        self.gitrev.as_deref()
+    }
+
+    /// Returns the board for which the archive was built, if known.
+    pub fn board(&self) -> Option<&str> {
+        self.board.as_deref()
+    }
+
+    /// Returns the features enabled for the specified task, if any.
+    pub fn task_features(&self, name: &str) -> Option<&[String]> {
+        self.task_features.get(name).map(|f| f.as_slice())
+    }
+}
+
 //
 // This structure (and the structures that it refers to) contain everything
 // that we might want to pull out of the config TOML -- which will be a subset
@@ -676,6 +698,9 @@ pub struct HubrisArchive {
     // ELF symbols: name to value/length
     esyms_byname: MultiMap<String, (u32, u32)>,
 
+    // DWARF compilation units: base address to length/crate tuple
+    units: BTreeMap<u32, (u32, String)>,
+
     // Inlined: address/nesting tuple to length/goff/origin tuple
     inlined: BTreeMap<(u32, isize), (u32, HubrisGoff, HubrisGoff)>,
 
@@ -744,6 +769,7 @@ impl HubrisArchive {
             dsyms: BTreeMap::new(),
             esyms: BTreeMap::new(),
             esyms_byname: MultiMap::new(),
+            units: BTreeMap::new(),
             inlined: BTreeMap::new(),
             subprograms: HashMap::new(),
             basetypes: HashMap::new(),
@@ -800,6 +826,28 @@ impl HubrisArchive {
         })
     }
 
+    /// Returns the ELF symbols (that is, the address, demangled name and
+    /// size of each) within the specified address range.
+    pub fn lookup_symbols(
+        &self,
+        base: u32,
+        size: u32,
+    ) -> impl Iterator<Item = (u32, &str, u32)> {
+        self.esyms
+            .range(base..base.saturating_add(size))
+            .map(|(&addr, (name, len))| (addr, name.as_str(), *len))
+    }
+
+    /// Returns the name of the crate whose compilation unit contains the
+    /// specified address, if any.
+    pub fn lookup_crate(&self, addr: u32) -> Option<&str> {
+        self.units
+            .range(..=addr)
+            .next_back()
+            .filter(|&(&base, &(len, _))| addr >= base && addr - base < len)
+            .map(|(_, (_, krate))| krate.as_str())
+    }
+
     pub fn instr_inlined(&self, pc: u32, base: u32) -> Vec<HubrisInlined> {
         let mut inlined: Vec<HubrisInlined> = vec![];
 
@@ -1045,6 +1093,42 @@ impl HubrisArchive {
         Err(anyhow!("missing address range for {}", goff))
     }
 
+    fn dwarf_unit<R: gimli::Reader<Offset = usize>>(
+        &mut self,
+        dwarf: &gimli::Dwarf<R>,
+        unit: &gimli::Unit<R>,
+    ) -> Result<()> {
+        //
+        // Rust names its compilation units after the codegen unit (e.g.,
+        // "src/lib.rs/@/heapless.4c2d9b0e-cgu.0"); the crate name is what
+        // precedes the hash.  Compilation units from other sources don't
+        // have this form, and we don't record a crate for them.
+        //
+        let name = match &unit.name {
+            Some(name) => name.to_string_lossy()?,
+            None => return Ok(()),
+        };
+
+        let krate = match name.split_once("/@/") {
+            Some((_, cgu)) => cgu.split('.').next().unwrap().to_string(),
+            None => return Ok(()),
+        };
+
+        let mut ranges = dwarf.unit_ranges(unit)?;
+
+        while let Some(range) = ranges.next()? {
+            //
+            // Ranges that begin at 0 are for code that has been discarded.
+            //
+            if range.begin != 0 && range.end > range.begin {
+                let len = (range.end - range.begin) as u32;
+                self.units.insert(range.begin as u32, (len, krate.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
     fn dwarf_subprogram<'a, R: gimli::Reader<Offset = usize>>(
         &mut self,
         dwarf: &'a gimli::Dwarf<gimli::EndianSlice<gimli::LittleEndian>>,
@@ -1897,6 +1981,15 @@ impl HubrisArchive {
         let mut iter = dwarf.units();
         while let Some(header) = iter.next()? {
             let unit = dwarf.unit(header)?;
+
+            //
+            // Failing to determine the crate of a unit isn't fatal:  its code
+            // will just be attributed to a crate some other way.
+            //
+            if let Err(err) = self.dwarf_unit(&dwarf, &unit) {
+                log::debug!("skipping crate for compilation unit: {}", err);
+            }
+
             let mut entries = unit.entries();
             let mut depth = 0;
             let mut stack: Vec<HubrisGoff> = vec![];
@@ -2136,8 +2229,8 @@ impl HubrisArchive {
 
             last = (addr, b.len());
 
-            // this data is used only by arm for etm tracing
-            // No need to port for riscv
+            // this data is used for instruction trace: ETM on arm, E-Trace
+            // on riscv
             let target = self
                 .arch
                 .as_ref()
@@ -3158,6 +3251,14 @@ impl HubrisArchive {
         }
     }
 
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &HubrisVariable)> {
+        self.variables.iter_all().flat_map(|(n, v)| {
+            v.iter()
+                .map(|e| (n.as_str(), e))
+                .collect::<Vec<(&str, &HubrisVariable)>>()
+        })
+    }
+
     pub fn qualified_variables(
         &self,
     ) -> impl Iterator<Item = (&str, &HubrisVariable)> {
@@ -3175,6 +3276,29 @@ impl HubrisArchive {
         }
     }
 
+    /// Returns the module with the specified name (that is, "kernel" or the
+    /// name of a task), if any.
+    pub fn lookup_module_byname(&self, name: &str) -> Option<&HubrisModule> {
+        self.modules.values().find(|m| m.name == name)
+    }
+
+    /// Returns the modules (that is, the kernel and each task) in the
+    /// archive.
+    pub fn modules(&self) -> impl Iterator<Item = &HubrisModule> {
+        self.modules.values()
+    }
+
+    /// Returns the regions loaded from the archive's ELF objects, in address
+    /// order.
+    pub fn loaded_regions(&self) -> impl Iterator<Item = &HubrisRegion> {
+        self.loaded.values()
+    }
+
+    /// Returns every structure in the archive's DWARF information.
+    pub fn structs(&self) -> impl Iterator<Item = &HubrisStruct> {
+        self.structs.values()
+    }
+
     pub fn lookup_task(&self, name: &str) -> Option<&HubrisTask> {
         self.tasks.get(name)
     }
@@ -4261,7 +4385,7 @@ impl HubrisArchive {
         Ok(())
     }
 
-    pub fn extract_file_to(&self, filename: &str, target: &Path) -> Result<()> {
+    pub fn extract_file(&self, filename: &str) -> Result<Vec<u8>> {
         let cursor = Cursor::new(self.archive.as_slice());
         let mut archive = zip::ZipArchive::new(cursor)?;
         let mut file = archive
@@ -4270,9 +4394,24 @@ impl HubrisArchive {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
+        Ok(buffer)
+    }
+
+    pub fn extract_file_to(&self, filename: &str, target: &Path) -> Result<()> {
+        let buffer = self.extract_file(filename)?;
         std::fs::write(target, &buffer).map_err(Into::into)
     }
 
+    /// Returns the contents of the ELF object for the specified module.
+    pub fn module_elf(&self, module: &HubrisModule) -> Result<Vec<u8>> {
+        match module.task {
+            HubrisTask::Kernel => self.extract_file("elf/kernel"),
+            HubrisTask::Task(_) => {
+                self.extract_file(&format!("elf/task/{}", module.name))
+            }
+        }
+    }
+
     /// Copies the kernel and every task ELF file to the given directory.
     pub fn extract_elfs_to(&self, p: &Path) -> Result<()> {
         self.extract_file_to("elf/kernel", &p.join("kernel"))?;
@@ -4292,13 +4431,10 @@ impl HubrisArchive {
 
     #[allow(clippy::print_literal)]
     pub fn list_variables(&self) -> Result<()> {
-        let mut variables = vec![];
-
-        for (name, variable) in &self.variables {
-            for v in variable {
-                variables.push((HubrisTask::from(v.goff), name, v));
-            }
-        }
+        let mut variables = self
+            .variables()
+            .map(|(name, v)| (HubrisTask::from(v.goff), name, v))
+            .collect::<Vec<_>>();
 
         variables.sort();
 
@@ -4976,6 +5112,9 @@ impl<'a> std::fmt::Display for HubrisType<'a> {
 #[derive(Copy, Clone, Debug)]
 pub enum HubrisTarget {
     Direct(u32),
+    /// A conditional branch to the specified target.  (This is only used on
+    /// RISC-V; on ARM, conditional branches are reported as `Direct`.)
+    Conditional(u32),
     Indirect,
     Call(u32),
     IndirectCall,
@@ -5209,3 +5348,29 @@ fn demangle_name(name: &str) -> String {
     // values on functions.
     format!("{:#}", rustc_demangle::demangle(name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crates() -> Result<()> {
+        let mut hubris = HubrisArchive::new()?;
+        hubris.units.insert(0x1000, (0x100, "heapless".to_string()));
+        hubris.units.insert(0xffff_ff00, (0x100, "task_top".to_string()));
+
+        assert_eq!(hubris.lookup_crate(0xfff), None);
+        assert_eq!(hubris.lookup_crate(0x1000), Some("heapless"));
+        assert_eq!(hubris.lookup_crate(0x10ff), Some("heapless"));
+        assert_eq!(hubris.lookup_crate(0x1100), None);
+
+        //
+        // A unit that extends to the top of the address space must not
+        // overflow.
+        //
+        assert_eq!(hubris.lookup_crate(0xffff_ff00), Some("task_top"));
+        assert_eq!(hubris.lookup_crate(0xffff_ffff), Some("task_top"));
+
+        Ok(())
+    }
+}
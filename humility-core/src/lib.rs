@@ -83,6 +83,18 @@ impl ExecutionContext {
 
                 cli.probe = Some(env.probe.clone());
 
+                //
+                // A chip or hart specified on the command line (or in an
+                // environment variable) wins over the environment file.
+                //
+                if cli.chip.is_none() {
+                    cli.chip = env.chip.clone();
+                }
+
+                if cli.hart.is_none() {
+                    cli.hart = env.hart;
+                }
+
                 //
                 // If we have an archive on the command-line or in an environment
                 // variable, we want ot prefer that over whatever is in the
@@ -8,6 +8,7 @@ use humility::cli::Subcommand;
 use humility::hubris::*;
 use humility_cmd::{Archive, Command};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 //
 // Our build.rs creates cmds.rs, which looks at our workspace to assemble
@@ -17,6 +18,8 @@ use std::collections::HashMap;
 include!(concat!(env!("OUT_DIR"), "/cmds.rs"));
 
 use crate::cmd_repl;
+use crate::cmd_script;
+use crate::cmd_serve;
 
 pub fn init(
     command: ClapCommand<'static>,
@@ -42,18 +45,101 @@ pub fn init(
     (cmds, rval)
 }
 
+//
+// Returns the ways that the specified option of a subcommand can be spelled:
+// its long and short forms, if the subcommand defines it (and just as given,
+// if it does not).
+//
+fn spellings(subcmd: Option<&ClapCommand>, opt: &str) -> Vec<String> {
+    let opt = opt.split('=').next().unwrap();
+    let long = |arg: &clap::Arg| arg.get_long().map(|l| format!("--{}", l));
+    let short = |arg: &clap::Arg| arg.get_short().map(|s| format!("-{}", s));
+
+    let arg = subcmd.and_then(|subcmd| {
+        subcmd.get_arguments().find(|arg| {
+            long(arg).as_deref() == Some(opt)
+                || short(arg).as_deref() == Some(opt)
+        })
+    });
+
+    match arg {
+        Some(arg) => long(arg).into_iter().chain(short(arg)).collect(),
+        None => vec![opt.to_string()],
+    }
+}
+
+///
+/// Inserts the default arguments for the subcommand as specified by the
+/// target in the environment file (if any).  Defaults are options (each
+/// with any values that follow it); an option that has been given
+/// explicitly on the command line is not defaulted.
+///
+fn defaults(context: &mut humility::ExecutionContext, clap: &ClapCommand) {
+    let env = match &context.environment {
+        Some(env) => env,
+        None => return,
+    };
+
+    let Subcommand::Other(subargs) = context.cli.cmd.as_mut().unwrap();
+    let defaults = env.args(&subargs[0]);
+
+    if defaults.is_empty() {
+        return;
+    }
+
+    let subcmd = clap.find_subcommand(&subargs[0]);
+
+    let given = |spelling: &String| {
+        subargs[1..].iter().take_while(|arg| *arg != "--").any(|arg| {
+            arg == spelling
+                || arg.starts_with(&format!("{}=", spelling))
+                || (!spelling.starts_with("--")
+                    && !arg.starts_with("--")
+                    && arg.starts_with(spelling.as_str()))
+        })
+    };
+
+    let mut insert = vec![];
+    let mut skip = false;
+
+    for arg in defaults {
+        if arg.starts_with('-') {
+            skip = spellings(subcmd, &arg).iter().any(given);
+        }
+
+        if !skip {
+            insert.push(arg);
+        }
+    }
+
+    if context.cli.verbose && !insert.is_empty() {
+        humility::msg!(
+            "default arguments for {}: {}",
+            subargs[0],
+            insert.join(" ")
+        );
+    }
+
+    subargs.splice(1..1, insert);
+}
+
 pub fn subcommand(
     context: &mut humility::ExecutionContext,
     commands: &HashMap<&'static str, Command>,
+    clap: &ClapCommand,
 ) -> Result<()> {
+    defaults(context, clap);
+
     let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
     let cmd = subargs[0].as_str();
 
-    let command = commands
-        .get(cmd)
-        .with_context(|| format!("command {} not found", cmd))?;
-
-    let mut hubris = HubrisArchive::new().context("failed to initialize")?;
+    let command = match commands.get(cmd) {
+        Some(command) => command,
+        None => match plugin(cmd) {
+            Some(path) => return run_plugin(context, &path, subargs),
+            None => bail!("command {} not found", cmd),
+        },
+    };
 
     let (archive, doneness) = match command {
         Command::Attached { archive, .. } => {
@@ -65,7 +151,51 @@ pub fn subcommand(
         Command::Raw { .. } => (Archive::Required, HubrisArchiveDoneness::Raw),
     };
 
-    if archive != Archive::Ignored {
+    //
+    // If we are running in the context of a session (e.g., from the REPL or
+    // a script), we may already have a loaded archive; if so, we use it
+    // rather than loading it again.  A command that ignores the archive or
+    // needs it raw gets a fresh one, with the session's archive restored
+    // when the command completes (whether or not it succeeds).
+    //
+    let cooked =
+        archive != Archive::Ignored && doneness == HubrisArchiveDoneness::Cook;
+    let loaded = |context: &humility::ExecutionContext| {
+        context.archive.as_ref().map_or(false, |h| h.loaded())
+    };
+    let reuse = cooked && loaded(context);
+
+    let saved = if reuse {
+        None
+    } else {
+        let fresh = HubrisArchive::new().context("failed to initialize")?;
+        context.archive.replace(fresh)
+    };
+
+    let rval = execute(context, command, archive, doneness);
+
+    //
+    // If the session didn't have a loaded archive, we keep the one that the
+    // command successfully loaded and cooked for use by subsequent commands.
+    //
+    if let Some(saved) = saved {
+        if saved.loaded() || !cooked || !loaded(context) || rval.is_err() {
+            context.archive = Some(saved);
+        }
+    }
+
+    rval
+}
+
+fn execute(
+    context: &mut humility::ExecutionContext,
+    command: &Command,
+    archive: Archive,
+    doneness: HubrisArchiveDoneness,
+) -> Result<()> {
+    let hubris = context.archive.as_mut().unwrap();
+
+    if archive != Archive::Ignored && !hubris.loaded() {
         if let Some(archive) = &context.cli.archive {
             hubris.load(archive, doneness).with_context(|| {
                 format!("failed to load archive \"{}\"", archive)
@@ -88,8 +218,6 @@ pub fn subcommand(
         bail!("must provide a Hubris archive or dump");
     }
 
-    context.archive = Some(hubris);
-
     match command {
         Command::Attached { run, attach, validate, .. } => {
             humility_cmd::attach(context, *attach, *validate, |context| {
@@ -100,3 +228,105 @@ pub fn subcommand(
         Command::Raw { run, .. } => (run)(context),
     }
 }
+
+//
+// The version of the interface presented to plugins, as conveyed to them in
+// the HUMILITY_PLUGIN_VERSION environment variable.  This should be bumped
+// whenever the environment given to a plugin changes incompatibly.
+//
+const PLUGIN_VERSION: u32 = 1;
+
+///
+/// Looks for a plugin -- an executable named `humility-<name>` -- in the
+/// directories of `PATH`.  Plugins can only add commands:  a plugin named
+/// the same as a built-in command is never found.
+///
+fn plugin(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    let filename = format!("humility-{}{}", name, std::env::consts::EXE_SUFFIX);
+
+    std::env::split_paths(&path).map(|dir| dir.join(&filename)).find(|p| {
+        match std::fs::metadata(p) {
+            #[cfg(unix)]
+            Ok(md) => {
+                use std::os::unix::fs::PermissionsExt;
+                md.is_file() && md.permissions().mode() & 0o111 != 0
+            }
+            #[cfg(not(unix))]
+            Ok(md) => md.is_file(),
+            Err(_) => false,
+        }
+    })
+}
+
+///
+/// Runs a plugin, passing it its arguments and (via the environment) the
+/// archive, dump and probe as resolved from our own arguments, environment
+/// file and target (along with our verbosity).  These are conveyed via the
+/// same environment variables that Humility itself consumes, so a plugin
+/// that in turn runs `humility` will operate on the same archive and target
+/// with the same verbosity.
+///
+fn run_plugin(
+    context: &humility::ExecutionContext,
+    path: &Path,
+    subargs: &[String],
+) -> Result<()> {
+    let cli = &context.cli;
+    let mut cmd = std::process::Command::new(path);
+
+    cmd.args(&subargs[1..]);
+    cmd.env("HUMILITY_PLUGIN_VERSION", PLUGIN_VERSION.to_string());
+
+    if let Ok(exe) = std::env::current_exe() {
+        cmd.env("HUMILITY", exe);
+    }
+
+    //
+    // The environment and target have already been resolved into an archive
+    // and a probe; we remove them from the plugin's environment (they would
+    // otherwise conflict with the probe), instead passing them under their
+    // own names for plugins that need to know them (e.g., to run `humility
+    // exec`).
+    //
+    cmd.env_remove("HUMILITY_ENVIRONMENT");
+    cmd.env_remove("HUMILITY_TARGET");
+
+    let vars = [
+        ("HUMILITY_ARCHIVE", &cli.archive),
+        ("HUMILITY_DUMP", &cli.dump),
+        ("HUMILITY_PROBE", &cli.probe),
+        ("HUMILITY_CHIP", &cli.chip),
+        ("HUMILITY_HART", &cli.hart.map(|hart| hart.to_string())),
+        ("HUMILITY_PLUGIN_ENVIRONMENT", &cli.environment),
+        ("HUMILITY_PLUGIN_TARGET", &cli.target),
+    ];
+
+    for (var, val) in vars {
+        match val {
+            Some(val) => cmd.env(var, val),
+            None => cmd.env_remove(var),
+        };
+    }
+
+    if cli.verbose {
+        cmd.env("HUMILITY_VERBOSE", "1");
+    } else {
+        cmd.env_remove("HUMILITY_VERBOSE");
+    }
+
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to run {}", path.display()))?;
+
+    if !status.success() {
+        match status.code() {
+            Some(code) => {
+                bail!("{} exited with status {}", path.display(), code)
+            }
+            None => bail!("{} was terminated by a signal", path.display()),
+        }
+    }
+
+    Ok(())
+}
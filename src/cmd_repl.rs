@@ -7,17 +7,40 @@
 //! read, eval, print, loop
 
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
+use clap::CommandFactory;
+use humility::cli::Cli;
+use humility::hubris::*;
 use humility_cmd::{Archive, Attach, Command, Validate};
 
 use reedline::{
-    FileBackedHistory, PromptHistorySearchStatus, Reedline, Signal,
+    CircularCompletionHandler, FileBackedHistory, PromptHistorySearchStatus,
+    Reedline, Signal, Span,
 };
 
 use crate::{cmd, version};
 
+//
+// The number of commands retained in our history.
+//
+const HISTORY_SIZE: usize = 1000;
+
+//
+// Commands that are handled by the REPL itself rather than by a subcommand.
+//
+const META_COMMANDS: &[(&str, &str)] = &[
+    ("archive", "<file>: load a different archive (and detach)"),
+    ("attach", "[probe]: attach (or re-attach) to the target"),
+    ("detach", "detach from the target"),
+    ("help", "display this message"),
+    ("history", "display command history"),
+    ("quit", "leave the REPL"),
+];
+
 struct Prompt;
 
 impl reedline::Prompt for Prompt {
@@ -57,35 +80,196 @@ impl reedline::Prompt for Prompt {
     }
 }
 
-fn repl(context: &mut humility::ExecutionContext) -> Result<()> {
-    // hardcoding to 100 entries for now. Even though this is called
-    // FileBackedHistory, this constructor only uses one in-memory.
-    let history = Box::new(FileBackedHistory::new(100));
+///
+/// Completes subcommands (and meta-commands) as the first word, the flags
+/// of the subcommand for a word that begins with `-`, and otherwise task
+/// names, variable names and Idol operations from the archive.
+///
+struct ReplCompleter {
+    commands: BTreeMap<String, Vec<String>>,
+    words: Vec<String>,
+}
+
+impl ReplCompleter {
+    fn new(hubris: Option<&HubrisArchive>) -> Self {
+        let mut commands = BTreeMap::new();
+        let (_, clap) = cmd::init(Cli::command());
+
+        for subcmd in clap.get_subcommands() {
+            let mut flags = vec!["--help".to_string()];
+
+            for arg in subcmd.get_arguments() {
+                if let Some(long) = arg.get_long() {
+                    flags.push(format!("--{}", long));
+                }
+
+                if let Some(short) = arg.get_short() {
+                    flags.push(format!("-{}", short));
+                }
+            }
+
+            commands.insert(subcmd.get_name().to_string(), flags);
+        }
+
+        for (name, _) in META_COMMANDS {
+            commands.insert(name.to_string(), vec![]);
+        }
+
+        let mut words = BTreeSet::new();
+
+        if let Some(hubris) = hubris {
+            for i in 0..hubris.ntasks() {
+                let task = HubrisTask::Task(i as u32);
+
+                if let Ok(module) = hubris.lookup_module(task) {
+                    words.insert(module.name.clone());
+
+                    if let Some(iface) = &module.iface {
+                        for (op, _) in iface.ops.iter() {
+                            words.insert(format!("{}.{}", iface.name, op));
+                        }
+                    }
+                }
+            }
+
+            for (name, _) in hubris.variables() {
+                words.insert(name.to_string());
+            }
+        }
 
-    let mut line_editor = Reedline::create()?.with_history(history)?;
+        Self { commands, words: words.into_iter().collect() }
+    }
+}
+
+impl reedline::Completer for ReplCompleter {
+    fn complete(&self, line: &str, pos: usize) -> Vec<(Span, String)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..];
+
+        let candidates: Box<dyn Iterator<Item = &String>> =
+            match line[..start].split_whitespace().next() {
+                None => Box::new(self.commands.keys()),
+                Some(cmd) if word.starts_with('-') => {
+                    match self.commands.get(cmd) {
+                        Some(flags) => Box::new(flags.iter()),
+                        None => Box::new(std::iter::empty()),
+                    }
+                }
+                Some(_) => Box::new(self.words.iter()),
+            };
+
+        candidates
+            .filter(|c| c.starts_with(word))
+            .map(|c| (Span::new(start, pos), c.clone()))
+            .collect()
+    }
+}
+
+//
+// Our history is kept in the file named by HUMILITY_HISTORY, or in
+// ~/.humility_history if that isn't set.
+//
+fn history_file() -> Option<PathBuf> {
+    match std::env::var_os("HUMILITY_HISTORY") {
+        Some(file) => Some(PathBuf::from(file)),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".humility_history")),
+    }
+}
+
+fn history() -> Box<FileBackedHistory> {
+    if let Some(file) = history_file() {
+        match FileBackedHistory::with_file(HISTORY_SIZE, file.clone()) {
+            Ok(history) => return Box::new(history),
+            Err(e) => {
+                humility::msg!(
+                    "failed to open history file {}: {}",
+                    file.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    Box::new(FileBackedHistory::new(HISTORY_SIZE))
+}
+
+fn completion(
+    context: &humility::ExecutionContext,
+) -> Box<CircularCompletionHandler> {
+    let completer = ReplCompleter::new(context.archive.as_ref());
+    Box::new(
+        CircularCompletionHandler::default()
+            .with_completer(Box::new(completer)),
+    )
+}
+
+fn repl(context: &mut humility::ExecutionContext) -> Result<()> {
+    let mut line_editor = Reedline::create()?
+        .with_history(history())?
+        .with_completion_action_handler(completion(context));
 
     let prompt = Prompt;
 
-    println!("Welcome to the humility REPL! Try out some subcommands, or 'quit' to quit!");
+    println!("Welcome to the humility REPL! Try out some subcommands, 'help' for help, or 'quit' to quit!");
 
     loop {
         let sig = line_editor.read_line(&prompt)?;
 
         match sig {
             Signal::Success(buffer) => {
-                let buffer = buffer.trim();
-                match buffer {
-                    "quit" => {
+                let mut words = buffer.split_whitespace();
+
+                let rval = match (words.next(), words.next(), words.next()) {
+                    (None, ..) => Ok(()),
+                    (Some("quit" | "exit"), None, _) => {
                         println!("Quitting!");
-                        std::process::exit(0);
+                        break Ok(());
+                    }
+                    (Some("help"), None, _) => {
+                        help();
+                        Ok(())
                     }
-                    "history" => {
+                    (Some("history"), None, _) => {
                         line_editor.print_history()?;
+                        Ok(())
+                    }
+                    (Some("attach"), probe, None) => attach(context, probe),
+                    (Some("detach"), None, _) => {
+                        detach(context);
+                        Ok(())
                     }
-                    user_input => {
-                        let result = eval(context, user_input)?;
-                        println!("{result}");
+                    (Some("archive"), Some(archive), None) => {
+                        let rval = load(context, archive);
+
+                        //
+                        // Our completions are drawn from the archive, so
+                        // we regenerate them for the new one.
+                        //
+                        line_editor = line_editor
+                            .with_completion_action_handler(completion(
+                                context,
+                            ));
+
+                        rval
                     }
+                    (Some(cmd @ ("archive" | "attach" | "detach")), ..) => {
+                        Err(anyhow::anyhow!(
+                            "invalid arguments to {} ('help' for help)",
+                            cmd
+                        ))
+                    }
+                    _ => eval(context, &buffer),
+                };
+
+                //
+                // An error in a command is reported, but we remain in the
+                // loop -- and remain attached.
+                //
+                if let Err(e) = rval {
+                    let cmd = buffer.split_whitespace().next().unwrap_or("");
+                    eprintln!("humility {} failed: {:?}", cmd, e);
                 }
             }
             Signal::CtrlD | Signal::CtrlC => {
@@ -99,31 +283,97 @@ fn repl(context: &mut humility::ExecutionContext) -> Result<()> {
     }
 }
 
+fn help() {
+    println!("Any humility subcommand may be run (e.g., 'tasks -s'), as may:");
+
+    for (name, description) in META_COMMANDS {
+        println!("  {:8} {}", name, description);
+    }
+}
+
+fn attach(
+    context: &mut humility::ExecutionContext,
+    probe: Option<&str>,
+) -> Result<()> {
+    detach(context);
+
+    if let Some(probe) = probe {
+        context.cli.probe = Some(probe.to_string());
+        context.cli.dump = None;
+    }
+
+    let hubris = match context.archive.as_ref() {
+        Some(hubris) => hubris,
+        None => bail!("no archive loaded (use 'archive' to load one)"),
+    };
+
+    let mut core = if context.cli.dump.is_some() {
+        humility_cmd::attach_dump(&context.cli, hubris)?
+    } else {
+        humility_cmd::attach_live(&context.cli, hubris)?
+    };
+
+    hubris.validate(&mut *core, HubrisValidate::ArchiveMatch)?;
+    context.core = Some(core);
+
+    Ok(())
+}
+
+fn detach(context: &mut humility::ExecutionContext) {
+    if context.core.take().is_some() {
+        humility::msg!("detached");
+    }
+}
+
+fn load(context: &mut humility::ExecutionContext, archive: &str) -> Result<()> {
+    let mut hubris = HubrisArchive::new()?;
+    hubris.load(archive, HubrisArchiveDoneness::Cook)?;
+
+    //
+    // Our core was validated against the old archive; we detach, and will
+    // attach against the new archive when next needed.
+    //
+    detach(context);
+
+    context.archive = Some(hubris);
+    context.cli.archive = Some(archive.to_string());
+    context.cli.dump = None;
+
+    humility::msg!("loaded archive {}", archive);
+
+    Ok(())
+}
+
 fn eval(
     context: &mut humility::ExecutionContext,
     user_input: &str,
-) -> Result<String> {
+) -> Result<()> {
     let mut input = vec!["humility"];
-    input.extend(user_input.split(' '));
+    input.extend(user_input.split_whitespace());
 
-    let (commands, _, cli) = match crate::parse_args(input) {
-        Some(s) => s,
-        None => return Ok(String::new()),
+    let (commands, clap, _, cli) = match crate::try_parse_args(input) {
+        Ok(Some(s)) => s,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            //
+            // This is a usage error (or a request for version information),
+            // which clap formats for us.
+            //
+            e.print()?;
+            return Ok(());
+        }
     };
 
     if let Some(s) = version(&cli) {
-        return Ok(s);
+        println!("{}", s);
+        return Ok(());
     }
 
     // merge the new subcommand into our existing CLI, so that we retain options
     // like the archive/dump, so that the user doesn't have to type them in again
     context.cli.cmd = cli.cmd;
 
-    if let Err(e) = cmd::subcommand(context, &commands) {
-        Ok(e.to_string())
-    } else {
-        Ok(String::new())
-    }
+    cmd::subcommand(context, &commands, &clap)
 }
 
 pub fn init() -> (Command, ClapCommand<'static>) {
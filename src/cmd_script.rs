@@ -0,0 +1,391 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility script`
+//!
+//! run a Rhai script against an attached session
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::cli::Subcommand;
+use humility::core::{Core, CORE_MAX_READSIZE};
+use humility::hubris::*;
+use humility::reflect::{self, Base, Value};
+use humility_cmd::idol;
+use humility_cmd::{Archive, Attach, Command, Validate};
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, Map, Scope, INT};
+
+use crate::cmd;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "script",
+    about = "run a Rhai script against an attached session"
+)]
+struct ScriptArgs {
+    /// sets timeout for Idol calls
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// script to run
+    script: String,
+
+    /// arguments to the script
+    args: Vec<String>,
+}
+
+//
+// Functions registered with Rhai must be 'static, so the execution context
+// is moved into a shared cell for the duration of the script.
+//
+type Session = Rc<RefCell<humility::ExecutionContext>>;
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn rhai_error(err: anyhow::Error) -> Box<EvalAltResult> {
+    format!("{:#}", err).into()
+}
+
+fn with_core<T>(
+    session: &Session,
+    f: impl FnOnce(&HubrisArchive, &mut dyn Core) -> Result<T>,
+) -> RhaiResult<T> {
+    let mut context = session.borrow_mut();
+    let context = &mut *context;
+    let hubris = context.archive.as_ref().unwrap();
+    let core = &mut **context.core.as_mut().unwrap();
+
+    f(hubris, core).map_err(rhai_error)
+}
+
+fn addr(val: INT) -> Result<u32> {
+    u32::try_from(val).map_err(|_| anyhow!("invalid address 0x{:x}", val))
+}
+
+fn length(val: INT) -> Result<usize> {
+    match usize::try_from(val) {
+        Ok(len) if len <= CORE_MAX_READSIZE => Ok(len),
+        Ok(_) => bail!("cannot read more than {} bytes", CORE_MAX_READSIZE),
+        Err(_) => bail!("invalid length {}", val),
+    }
+}
+
+fn base_dynamic(base: &Base) -> Dynamic {
+    match *base {
+        Base::I8(v) => Dynamic::from(v as INT),
+        Base::I16(v) => Dynamic::from(v as INT),
+        Base::I32(v) => Dynamic::from(v as INT),
+        Base::I64(v) => Dynamic::from(v as INT),
+        Base::U8(v) => Dynamic::from(v as INT),
+        Base::U16(v) => Dynamic::from(v as INT),
+        Base::U32(v) => Dynamic::from(v as INT),
+        Base::U64(v) => Dynamic::from(v as INT),
+        Base::Bool(v) => Dynamic::from(v),
+        Base::F32(v) => Dynamic::from(v as f64),
+        Base::F64(v) => Dynamic::from(v),
+        Base::U0 => Dynamic::UNIT,
+
+        //
+        // Rhai integers are 64 bits; larger values are represented as
+        // strings.
+        //
+        Base::I128(_) | Base::U128(_) => Dynamic::from(base.to_string()),
+    }
+}
+
+///
+/// Translates a value read from the target into its Rhai equivalent.
+///
+fn dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Base(base) => base_dynamic(base),
+        Value::Struct(s) => {
+            let mut map = Map::new();
+
+            for (name, v) in s.iter() {
+                map.insert(name.into(), dynamic(v));
+            }
+
+            Dynamic::from(map)
+        }
+        Value::Tuple(t) => {
+            Dynamic::from(t.iter().map(dynamic).collect::<Array>())
+        }
+        Value::Array(a) => {
+            Dynamic::from(a.iter().map(dynamic).collect::<Array>())
+        }
+        Value::Enum(e) => match e.contents() {
+            Some(contents) => {
+                let mut map = Map::new();
+                map.insert(e.disc().into(), dynamic(contents));
+                Dynamic::from(map)
+            }
+            None => Dynamic::from(e.disc().to_string()),
+        },
+        Value::Ptr(p) => Dynamic::from(p.addr() as INT),
+    }
+}
+
+fn readvar(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    name: &str,
+) -> Result<Dynamic> {
+    let variable = hubris.lookup_variable(name)?;
+    let mut buf = vec![0u8; variable.size];
+
+    core.op_start()?;
+    let rval = core.read_8(variable.addr, buf.as_mut_slice());
+    core.op_done()?;
+    rval?;
+
+    let ty = hubris.lookup_type(variable.goff)?;
+    Ok(dynamic(&reflect::load_value(hubris, &buf, ty, 0)?))
+}
+
+fn idol_call(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    timeout: u32,
+    name: &str,
+    args: &Map,
+) -> Result<Dynamic> {
+    //
+    // Our arguments are either strings or integers; we need to stringify
+    // them before we can borrow them as Idol arguments.
+    //
+    let mut strings = vec![];
+
+    for (arg, val) in args.iter() {
+        if let Some(val) = val.clone().try_cast::<INT>() {
+            strings.push((arg.to_string(), None, val));
+        } else if val.is::<rhai::ImmutableString>() {
+            strings.push((arg.to_string(), Some(val.to_string()), 0));
+        } else {
+            bail!("argument {} must be an integer or a string", arg);
+        }
+    }
+
+    let args = strings
+        .iter()
+        .map(|(arg, string, val)| {
+            (
+                arg.as_str(),
+                match string {
+                    Some(s) => idol::IdolArgument::String(s),
+                    None => idol::IdolArgument::Scalar(*val as u64),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+
+    Ok(dynamic(&idol::call(hubris, core, timeout, name, &args)?))
+}
+
+fn subcommand(session: &Session, args: Vec<String>) -> RhaiResult<()> {
+    let mut input = vec!["humility".to_string()];
+    input.extend(args);
+
+    let parsed =
+        crate::try_parse_args(input).map_err(|e| rhai_error(e.into()))?;
+
+    let (commands, clap, _, cli) = match parsed {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    //
+    // As with the REPL, we merge the subcommand into our existing CLI to
+    // retain the archive, probe, etc.
+    //
+    let mut context = session.borrow_mut();
+    context.cli.cmd = cli.cmd;
+
+    cmd::subcommand(&mut context, &commands, &clap).map_err(rhai_error)
+}
+
+fn engine(session: &Session, timeout: u32) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = session.clone();
+    engine.register_fn("read_word", move |a: INT| -> RhaiResult<INT> {
+        with_core(&s, |_, core| Ok(core.read_word_32(addr(a)?)? as INT))
+    });
+
+    let s = session.clone();
+    engine.register_fn(
+        "read_bytes",
+        move |a: INT, len: INT| -> RhaiResult<Blob> {
+            with_core(&s, |_, core| {
+                let mut buf = vec![0u8; length(len)?];
+                core.op_start()?;
+                let rval = core.read_8(addr(a)?, buf.as_mut_slice());
+                core.op_done()?;
+                rval?;
+                Ok(buf)
+            })
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn(
+        "write_word",
+        move |a: INT, val: INT| -> RhaiResult<()> {
+            with_core(&s, |_, core| {
+                let val = u32::try_from(val)
+                    .map_err(|_| anyhow!("invalid word 0x{:x}", val))?;
+                core.write_word_32(addr(a)?, val)
+            })
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn(
+        "write_bytes",
+        move |a: INT, data: Blob| -> RhaiResult<()> {
+            with_core(&s, |_, core| core.write_8(addr(a)?, &data))
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn("halt", move || with_core(&s, |_, core| core.halt()));
+
+    let s = session.clone();
+    engine.register_fn("run", move || with_core(&s, |_, core| core.run()));
+
+    let s = session.clone();
+    engine.register_fn("step", move || with_core(&s, |_, core| core.step()));
+
+    let s = session.clone();
+    engine.register_fn("reset", move || with_core(&s, |_, core| core.reset()));
+
+    let s = session.clone();
+    engine.register_fn("tasks", move || -> RhaiResult<Array> {
+        with_core(&s, |hubris, _| {
+            Ok((0..hubris.ntasks())
+                .map(|i| {
+                    hubris.task_name(i).unwrap_or("???").to_string().into()
+                })
+                .collect())
+        })
+    });
+
+    let s = session.clone();
+    engine.register_fn("task_id", move |name: &str| -> RhaiResult<INT> {
+        with_core(&s, |hubris, _| match hubris.lookup_task(name) {
+            Some(HubrisTask::Task(id)) => Ok(*id as INT),
+            _ => bail!("no such task: {}", name),
+        })
+    });
+
+    let s = session.clone();
+    engine.register_fn("variable", move |name: &str| -> RhaiResult<Map> {
+        with_core(&s, |hubris, _| {
+            let variable = hubris.lookup_variable(name)?;
+            let mut map = Map::new();
+            map.insert("addr".into(), Dynamic::from(variable.addr as INT));
+            map.insert("size".into(), Dynamic::from(variable.size as INT));
+            Ok(map)
+        })
+    });
+
+    let s = session.clone();
+    engine.register_fn("readvar", move |name: &str| -> RhaiResult<Dynamic> {
+        with_core(&s, |hubris, core| readvar(hubris, core, name))
+    });
+
+    let s = session.clone();
+    engine.register_fn("idol", move |name: &str| -> RhaiResult<Dynamic> {
+        with_core(&s, |hubris, core| {
+            idol_call(hubris, core, timeout, name, &Map::new())
+        })
+    });
+
+    let s = session.clone();
+    engine.register_fn(
+        "idol",
+        move |name: &str, args: Map| -> RhaiResult<Dynamic> {
+            with_core(&s, |hubris, core| {
+                idol_call(hubris, core, timeout, name, &args)
+            })
+        },
+    );
+
+    let s = session.clone();
+    engine.register_fn("humility", move |cmd: &str| -> RhaiResult<()> {
+        subcommand(&s, cmd.split_whitespace().map(String::from).collect())
+    });
+
+    let s = session.clone();
+    engine.register_fn("humility", move |args: Array| -> RhaiResult<()> {
+        subcommand(&s, args.iter().map(|a| a.to_string()).collect())
+    });
+
+    engine.register_fn("sleep", |ms: INT| {
+        std::thread::sleep(std::time::Duration::from_millis(ms.max(0) as u64));
+    });
+
+    engine
+}
+
+fn script(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let subargs = ScriptArgs::try_parse_from(subargs)?;
+
+    let placeholder = humility::ExecutionContext {
+        core: None,
+        history: vec![],
+        archive: None,
+        environment: None,
+        cli: context.cli.clone(),
+    };
+
+    let session =
+        Rc::new(RefCell::new(std::mem::replace(context, placeholder)));
+
+    let rval = {
+        let engine = engine(&session, subargs.timeout);
+        let mut scope = Scope::new();
+
+        let args = subargs.args.iter().cloned().map(Dynamic::from);
+        scope.push_constant("ARGS", args.collect::<Array>());
+
+        engine.run_file_with_scope(&mut scope, subargs.script.clone().into())
+    };
+
+    //
+    // Now that the engine (and with it, every reference to our session) has
+    // been dropped, we can restore our execution context.
+    //
+    match Rc::try_unwrap(session) {
+        Ok(session) => *context = session.into_inner(),
+        Err(_) => bail!("script session is still referenced"),
+    }
+
+    if let Err(err) = rval {
+        bail!("{}: {}", subargs.script, err);
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "script",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Match,
+            run: script,
+        },
+        ScriptArgs::command(),
+    )
+}
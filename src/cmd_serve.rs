@@ -0,0 +1,836 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility serve`
+//!
+//! serve an attached session to local clients
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::cli::Subcommand;
+use humility::core::CORE_MAX_READSIZE;
+use humility::hubris::*;
+use humility::reflect;
+use humility_cmd::idol;
+use humility_cmd::json::value_json;
+use humility_cmd::{Archive, Attach, Command, Validate};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::cmd;
+
+#[derive(Parser, Debug)]
+#[clap(name = "serve", about = "serve an attached session to local clients")]
+struct ServeArgs {
+    /// sets timeout for Idol calls
+    #[clap(
+        long, short = 'T', default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: u32,
+
+    /// path of the socket on which to listen
+    #[clap(
+        long,
+        short,
+        env = "HUMILITY_SOCKET",
+        default_value = "/tmp/humility.sock",
+        value_name = "path"
+    )]
+    socket: String,
+}
+
+//
+// JSON-RPC error codes
+//
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: serde_json::Value,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<serde_json::Value>,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        Self { code: SERVER_ERROR, message: format!("{:#}", err), data: None }
+    }
+}
+
+type RpcResult = Result<serde_json::Value, RpcError>;
+
+fn params<T: DeserializeOwned>(
+    params: serde_json::Value,
+) -> Result<T, RpcError> {
+    //
+    // Methods without parameters may be called without a params member.
+    //
+    let params = match params {
+        serde_json::Value::Null => json!({}),
+        params => params,
+    };
+
+    serde_json::from_value(params).map_err(|e| RpcError {
+        code: INVALID_PARAMS,
+        message: e.to_string(),
+        data: None,
+    })
+}
+
+#[derive(Deserialize)]
+struct HelloParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ReadParams {
+    addr: u32,
+    len: usize,
+}
+
+#[derive(Deserialize)]
+struct AddrParams {
+    addr: u32,
+}
+
+#[derive(Deserialize)]
+struct WriteParams {
+    addr: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct WriteWordParams {
+    addr: u32,
+    value: u32,
+}
+
+#[derive(Deserialize)]
+struct NameParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct CallParams {
+    op: String,
+    #[serde(default)]
+    args: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct CommandParams {
+    args: Vec<String>,
+}
+
+//
+// What a connection sends to the thread that owns the session:  either a
+// request (along with a channel on which to send the response), or an
+// indication that the client has disconnected.
+//
+enum Message {
+    Request { client: usize, line: String, reply: Sender<String> },
+    Disconnect { client: usize },
+}
+
+struct Pending {
+    client: usize,
+    line: String,
+    reply: Sender<String>,
+}
+
+//
+// Our standard output and standard error are redirected while a command's
+// output is being captured -- which affects every thread in the process.
+// Capturing holds this lock, as must any other thread that writes output.
+//
+#[cfg(unix)]
+static OUTPUT: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(unix)]
+fn report(msg: std::fmt::Arguments) {
+    let _output = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+    humility::msg!("{}", msg);
+}
+
+///
+/// Runs the specified function with our standard output and standard error
+/// redirected to a file, returning its result along with the output.
+///
+#[cfg(unix)]
+fn capture<T>(f: impl FnOnce() -> T) -> Result<(T, String)> {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+
+    let _output = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+
+    let path = std::env::temp_dir()
+        .join(format!("humility-serve.{}.out", std::process::id()));
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)?;
+
+    //
+    // We have no need for the file to have a name once we have it open.
+    //
+    std::fs::remove_file(&path)?;
+
+    std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
+
+    let fd = file.as_raw_fd();
+
+    let (stdout, stderr) = unsafe { (libc::dup(1), libc::dup(2)) };
+
+    if stdout < 0 || stderr < 0 {
+        bail!("failed to duplicate stdout/stderr");
+    }
+
+    unsafe {
+        libc::dup2(fd, 1);
+        libc::dup2(fd, 2);
+    }
+
+    let rval = f();
+
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+
+    unsafe {
+        libc::dup2(stdout, 1);
+        libc::dup2(stderr, 2);
+        libc::close(stdout);
+        libc::close(stderr);
+    }
+
+    let mut output = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut output)?;
+
+    Ok((rval, String::from_utf8_lossy(&output).to_string()))
+}
+
+#[cfg(not(unix))]
+fn capture<T>(_f: impl FnOnce() -> T) -> Result<(T, String)> {
+    bail!("capturing command output is only supported on Unix systems");
+}
+
+struct Server<'a> {
+    context: &'a mut humility::ExecutionContext,
+    timeout: u32,
+    names: HashMap<usize, String>,
+    lock: Option<usize>,
+}
+
+impl<'a> Server<'a> {
+    fn client(&self, client: usize) -> String {
+        match self.names.get(&client) {
+            Some(name) => format!("{} (client {})", name, client),
+            None => format!("client {}", client),
+        }
+    }
+
+    fn disconnect(&mut self, client: usize) {
+        if self.lock == Some(client) {
+            humility::msg!(
+                "{} disconnected; lock released",
+                self.client(client)
+            );
+            self.lock = None;
+        }
+
+        self.names.remove(&client);
+    }
+
+    fn handle(&mut self, client: usize, line: &str) -> String {
+        let (id, rval) = match serde_json::from_str::<Request>(line) {
+            Ok(request) => {
+                log::info!("{}: {}", self.client(client), request.method);
+                (request.id, self.call(client, &request.method, request.params))
+            }
+            Err(e) => (
+                serde_json::Value::Null,
+                Err(RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                    data: None,
+                }),
+            ),
+        };
+
+        let response = match rval {
+            Ok(result) => {
+                json!({ "jsonrpc": "2.0", "id": id, "result": result })
+            }
+            Err(err) => {
+                let mut error = json!({
+                    "code": err.code,
+                    "message": err.message,
+                });
+
+                if let Some(data) = err.data {
+                    error["data"] = data;
+                }
+
+                json!({ "jsonrpc": "2.0", "id": id, "error": error })
+            }
+        };
+
+        response.to_string()
+    }
+
+    fn core(
+        &mut self,
+    ) -> Result<(&HubrisArchive, &mut dyn humility::core::Core)> {
+        match (self.context.archive.as_ref(), self.context.core.as_mut()) {
+            (Some(hubris), Some(core)) => Ok((hubris, &mut **core)),
+            _ => bail!("not attached"),
+        }
+    }
+
+    fn call(
+        &mut self,
+        client: usize,
+        method: &str,
+        p: serde_json::Value,
+    ) -> RpcResult {
+        match method {
+            "hello" => {
+                let p: HelloParams = params(p)?;
+                self.names.insert(client, p.name);
+                humility::msg!("{} connected", self.client(client));
+
+                let cli = &self.context.cli;
+
+                Ok(json!({
+                    "client": client,
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "archive": cli.archive,
+                    "dump": cli.dump,
+                    "probe": cli.probe,
+                }))
+            }
+            "lock" => {
+                //
+                // Requests from other clients are held while the lock is
+                // held, so if we're here, the lock is either free or ours.
+                //
+                self.lock = Some(client);
+                Ok(serde_json::Value::Null)
+            }
+            "unlock" => {
+                if self.lock != Some(client) {
+                    return Err(anyhow!("lock is not held").into());
+                }
+
+                self.lock = None;
+                Ok(serde_json::Value::Null)
+            }
+            "halt" => {
+                self.core()?.1.halt()?;
+                Ok(serde_json::Value::Null)
+            }
+            "run" => {
+                self.core()?.1.run()?;
+                Ok(serde_json::Value::Null)
+            }
+            "step" => {
+                self.core()?.1.step()?;
+                Ok(serde_json::Value::Null)
+            }
+            "reset" => {
+                self.core()?.1.reset()?;
+                Ok(serde_json::Value::Null)
+            }
+            "read" => {
+                let p: ReadParams = params(p)?;
+
+                if p.len > CORE_MAX_READSIZE {
+                    return Err(RpcError {
+                        code: INVALID_PARAMS,
+                        message: format!(
+                            "cannot read more than {} bytes",
+                            CORE_MAX_READSIZE
+                        ),
+                        data: None,
+                    });
+                }
+
+                let (_, core) = self.core()?;
+                let mut buf = vec![0u8; p.len];
+
+                core.op_start()?;
+                let rval = core.read_8(p.addr, &mut buf);
+                core.op_done()?;
+                rval?;
+
+                Ok(json!(buf))
+            }
+            "read_word" => {
+                let p: AddrParams = params(p)?;
+                let (_, core) = self.core()?;
+
+                core.op_start()?;
+                let rval = core.read_word_32(p.addr);
+                core.op_done()?;
+
+                Ok(json!(rval?))
+            }
+            "write" => {
+                let p: WriteParams = params(p)?;
+                let (_, core) = self.core()?;
+
+                core.op_start()?;
+                let rval = core.write_8(p.addr, &p.data);
+                core.op_done()?;
+                rval?;
+
+                Ok(serde_json::Value::Null)
+            }
+            "write_word" => {
+                let p: WriteWordParams = params(p)?;
+                let (_, core) = self.core()?;
+
+                core.op_start()?;
+                let rval = core.write_word_32(p.addr, p.value);
+                core.op_done()?;
+                rval?;
+
+                Ok(serde_json::Value::Null)
+            }
+            "readvar" => {
+                let p: NameParams = params(p)?;
+                let (hubris, core) = self.core()?;
+                let variable = hubris.lookup_variable(&p.name)?;
+                let mut buf = vec![0u8; variable.size];
+
+                core.op_start()?;
+                let rval = core.read_8(variable.addr, &mut buf);
+                core.op_done()?;
+                rval?;
+
+                let ty = hubris.lookup_type(variable.goff)?;
+                Ok(value_json(&reflect::load_value(hubris, &buf, ty, 0)?))
+            }
+            "call" => {
+                let p: CallParams = params(p)?;
+                let timeout = self.timeout;
+                let mut args = vec![];
+
+                for (arg, val) in p.args.iter() {
+                    let val = if let Some(val) = val.as_u64() {
+                        idol::IdolArgument::Scalar(val)
+                    } else if let Some(s) = val.as_str() {
+                        idol::IdolArgument::String(s)
+                    } else {
+                        return Err(anyhow!(
+                            "argument {} must be an integer or a string",
+                            arg
+                        )
+                        .into());
+                    };
+
+                    args.push((arg.as_str(), val));
+                }
+
+                let (hubris, core) = self.core()?;
+                let rval = idol::call(hubris, core, timeout, &p.op, &args)?;
+
+                Ok(value_json(&rval))
+            }
+            "command" => {
+                let p: CommandParams = params(p)?;
+                self.command(p.args)
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method \"{}\"", method),
+                data: None,
+            }),
+        }
+    }
+
+    fn command(&mut self, args: Vec<String>) -> RpcResult {
+        let mut input = vec!["humility".to_string()];
+        input.extend(args);
+
+        let context = &mut *self.context;
+
+        let (rval, output) = capture(|| -> Result<()> {
+            let (commands, clap, _, cli) = match crate::try_parse_args(input) {
+                Ok(Some(s)) => s,
+                Ok(None) => return Ok(()),
+                Err(e) => bail!("{}", e),
+            };
+
+            //
+            // As with the REPL, we merge the subcommand into our existing CLI
+            // to retain the archive, probe, etc.
+            //
+            context.cli.cmd = cli.cmd;
+            cmd::subcommand(context, &commands, &clap)
+        })?;
+
+        match rval {
+            Ok(_) => Ok(json!({ "output": output })),
+            Err(err) => Err(RpcError {
+                code: SERVER_ERROR,
+                message: format!("{:#}", err),
+                data: Some(json!({ "output": output })),
+            }),
+        }
+    }
+
+    fn serve(&mut self, rx: std::sync::mpsc::Receiver<Message>) {
+        let mut pending: VecDeque<Pending> = VecDeque::new();
+
+        for msg in rx.iter() {
+            match msg {
+                Message::Request { client, line, reply } => {
+                    pending.push_back(Pending { client, line, reply });
+                }
+                Message::Disconnect { client } => {
+                    pending.retain(|p| p.client != client);
+                    self.disconnect(client);
+                }
+            }
+
+            //
+            // Process requests in the order received, holding those from
+            // clients other than the one holding the lock (if any).
+            //
+            loop {
+                let lock = self.lock;
+
+                let ndx = pending
+                    .iter()
+                    .position(|p| lock.map_or(true, |c| c == p.client));
+
+                let p = match ndx.and_then(|ndx| pending.remove(ndx)) {
+                    Some(p) => p,
+                    None => break,
+                };
+
+                let response = self.handle(p.client, &p.line);
+
+                //
+                // If the client has gone away, there is no one to tell.
+                //
+                let _ = p.reply.send(response);
+            }
+        }
+    }
+}
+
+//
+// Services a single connection, sending each line received to the thread
+// that owns the session and writing back its response.
+//
+fn connection(
+    client: usize,
+    reader: impl Read,
+    mut writer: impl Write,
+    tx: Sender<Message>,
+) {
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let (reply, rx) = channel();
+
+        if tx.send(Message::Request { client, line, reply }).is_err() {
+            break;
+        }
+
+        let response = match rx.recv() {
+            Ok(response) => response,
+            Err(_) => break,
+        };
+
+        if writeln!(writer, "{}", response).is_err() {
+            break;
+        }
+    }
+
+    let _ = tx.send(Message::Disconnect { client });
+}
+
+#[cfg(unix)]
+fn listen(socket: &str, tx: Sender<Message>) -> Result<()> {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    //
+    // If the socket already exists, it is either in use by another server
+    // (in which case we fail) or left over from one that has exited (in
+    // which case we remove it).
+    //
+    if std::path::Path::new(socket).exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("{} is already being served", socket);
+        }
+
+        std::fs::remove_file(socket)?;
+    }
+
+    //
+    // Any client that can connect to the socket has full control of the
+    // target, so we create it accessible only to our own user.  We do this
+    // by restricting our umask while binding (rather than by changing its
+    // permissions afterwards) to leave no window in which another user
+    // could connect.
+    //
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(mask) };
+    let listener = listener?;
+
+    thread::spawn(move || {
+        for (client, stream) in listener.incoming().enumerate() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    report(format_args!("failed to accept connection: {}", e));
+                    continue;
+                }
+            };
+
+            let reader = match stream.try_clone() {
+                Ok(reader) => reader,
+                Err(e) => {
+                    report(format_args!("failed to clone connection: {}", e));
+                    continue;
+                }
+            };
+
+            let tx = tx.clone();
+            thread::spawn(move || connection(client, reader, stream, tx));
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen(_socket: &str, _tx: Sender<Message>) -> Result<()> {
+    bail!("humility serve is only supported on Unix systems");
+}
+
+fn serve(context: &mut humility::ExecutionContext) -> Result<()> {
+    let Subcommand::Other(subargs) = context.cli.cmd.as_ref().unwrap();
+    let subargs = ServeArgs::try_parse_from(subargs)?;
+
+    let (tx, rx) = channel();
+    listen(&subargs.socket, tx)?;
+
+    humility::msg!("serving on {}", subargs.socket);
+
+    let mut server = Server {
+        context,
+        timeout: subargs.timeout,
+        names: HashMap::new(),
+        lock: None,
+    };
+
+    server.serve(rx);
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "serve",
+            archive: Archive::Required,
+            attach: Attach::Any,
+            validate: Validate::Match,
+            run: serve,
+        },
+        ServeArgs::command(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use humility::cli::Cli;
+
+    const DUMP: &str = "tests/cmd/cores/hubris.core.kiowa.0";
+
+    fn session() -> Result<humility::ExecutionContext> {
+        let mut hubris = HubrisArchive::new()?;
+        hubris.load_dump(DUMP, HubrisArchiveDoneness::Cook)?;
+        let core = humility::core::attach_dump(DUMP, &hubris)?;
+
+        Ok(humility::ExecutionContext {
+            core: Some(core),
+            history: vec![],
+            archive: Some(hubris),
+            environment: None,
+            cli: Cli::try_parse_from(["humility", "-d", DUMP])?,
+        })
+    }
+
+    fn request(server: &mut Server, line: &str) -> Result<serde_json::Value> {
+        Ok(serde_json::from_str(&server.handle(0, line))?)
+    }
+
+    fn server(context: &mut humility::ExecutionContext) -> Server<'_> {
+        Server { context, timeout: 5000, names: HashMap::new(), lock: None }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir()
+            .join(format!("humility-serve.{}.sock", std::process::id()));
+        let socket = path.to_str().unwrap();
+        let (tx, _rx) = channel();
+
+        listen(socket, tx)?;
+
+        let mode = std::fs::metadata(socket)?.permissions().mode();
+        std::fs::remove_file(socket)?;
+        assert_eq!(mode & 0o777, 0o600);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read() -> Result<()> {
+        let mut context = session()?;
+        let addr =
+            context.archive.as_ref().unwrap().lookup_variable("TICKS")?.addr;
+        let mut server = server(&mut context);
+
+        let response = request(
+            &mut server,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "read",
+                "params": { "addr": addr, "len": 8 },
+            })
+            .to_string(),
+        )?;
+
+        assert_eq!(response["id"], json!(1));
+        assert_eq!(response["result"], json!(0x1d67du64.to_le_bytes()));
+        assert!(response.get("error").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_read() -> Result<()> {
+        let mut context = session()?;
+        let mut server = server(&mut context);
+
+        let response = request(
+            &mut server,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "read",
+                "params": { "addr": 0, "len": CORE_MAX_READSIZE + 1 },
+            })
+            .to_string(),
+        )?;
+
+        assert_eq!(response["id"], json!(2));
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+        assert!(response.get("result").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_requests() -> Result<()> {
+        let mut context = session()?;
+        let mut server = server(&mut context);
+
+        let response = request(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":3,"method":"frobnicate"}"#,
+        )?;
+
+        assert_eq!(response["id"], json!(3));
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+
+        let response = request(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":4,"method":"read","params":{"addr":0}}"#,
+        )?;
+
+        assert_eq!(response["id"], json!(4));
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+
+        let response = request(&mut server, "this is not JSON")?;
+
+        assert_eq!(response["id"], serde_json::Value::Null);
+        assert_eq!(response["error"]["code"], json!(PARSE_ERROR));
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_retains_archive() -> Result<()> {
+        let mut context = session()?;
+        let mut server = server(&mut context);
+
+        let response = request(
+            &mut server,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 5,
+                "method": "command",
+                "params": { "args": ["doc", "nosuchcommand"] },
+            })
+            .to_string(),
+        )?;
+
+        assert_eq!(response["error"]["code"], json!(SERVER_ERROR));
+
+        let response = request(
+            &mut server,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 6,
+                "method": "readvar",
+                "params": { "name": "TICKS" },
+            })
+            .to_string(),
+        )?;
+
+        assert_eq!(response["result"], json!(0x1d67d));
+
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use std::collections::HashMap;
 use std::ffi::OsString;
 
 use clap::ArgMatches;
+use clap::Command as ClapCommand;
 use humility::cli::Cli;
 use humility::cli::Subcommand;
 use humility_cmd::Command;
@@ -17,9 +18,12 @@ use clap::Parser;
 
 mod cmd;
 mod cmd_repl;
+mod cmd_script;
+mod cmd_serve;
+mod targets;
 
 fn main() -> Result<()> {
-    let (commands, m, args) = match parse_args(&mut std::env::args_os()) {
+    let (commands, clap, m, args) = match parse_args(&mut std::env::args_os()) {
         Some(s) => s,
         None => std::process::exit(0),
     };
@@ -42,7 +46,13 @@ fn main() -> Result<()> {
         Subcommand::Other(v) => v[0].clone(),
     };
 
-    if let Err(err) = cmd::subcommand(&mut context, &commands) {
+    let rval = if args.all_targets || args.targets.is_some() {
+        targets::run(&context.cli)
+    } else {
+        cmd::subcommand(&mut context, &commands, &clap)
+    };
+
+    if let Err(err) = rval {
         eprintln!("humility {} failed: {:?}", subcmd, err);
         std::process::exit(1);
     }
@@ -62,9 +72,33 @@ pub(crate) fn version(cli: &Cli) -> Option<String> {
     }
 }
 
-pub(crate) fn parse_args<I, T>(
+//
+// Our commands, the clap command (with our subcommands grafted in) that
+// parsed our arguments, the matches for our arguments, and our parsed
+// arguments.
+//
+pub(crate) type Parsed =
+    (HashMap<&'static str, Command>, ClapCommand<'static>, ArgMatches, Cli);
+
+pub(crate) fn parse_args<I, T>(input: I) -> Option<Parsed>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    match try_parse_args(input) {
+        Ok(rval) => rval,
+        Err(e) => e.exit(),
+    }
+}
+
+///
+/// Like [`parse_args`], but returns an error rather than exiting if the
+/// arguments cannot be parsed (as when running within the REPL).  As with
+/// [`parse_args`], `None` is returned if help has been displayed.
+///
+pub(crate) fn try_parse_args<I, T>(
     input: I,
-) -> Option<(HashMap<&'static str, Command>, ArgMatches, Cli)>
+) -> Result<Option<Parsed>, clap::Error>
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
@@ -76,19 +110,19 @@ where
     // we parse our arguments again but relying on the
     // external_subcommand to directive to allow our subcommand to do any
     // parsing on its own.
-    let (commands, command) = cmd::init(Cli::command());
+    let (commands, mut command) = cmd::init(Cli::command());
 
     let input: Vec<_> = input.into_iter().collect();
     let input2 = input.clone();
 
-    let m = match command.try_get_matches_from(input.into_iter()) {
+    let m = match command.try_get_matches_from_mut(input.into_iter()) {
         Ok(m) => m,
         Err(e) => match e.kind() {
             clap::ErrorKind::DisplayHelp => {
                 e.print().unwrap();
-                return None;
+                return Ok(None);
             }
-            _ => e.exit(),
+            _ => return Err(e),
         },
     };
 
@@ -96,7 +130,9 @@ where
 
     // If we're here, we know that our arguments pass muster from the
     // Structopt/ Clap perspective.
-    Some((commands, m, Cli::parse_from(input2.into_iter())))
+    let cli = Cli::try_parse_from(input2.into_iter())?;
+
+    Ok(Some((commands, command, m, cli)))
 }
 
 #[test]
@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Support for running a subcommand against several targets within an
+// environment (via `--all-targets` or `--targets`).  Each target is run as
+// its own `humility` process (with `--target`), with each line of its output
+// prefixed with the name of the target.  Targets are run sequentially unless
+// `--parallel` is specified, in which case targets are run in parallel --
+// save for targets that share a probe, which are always run sequentially.
+//
+
+use anyhow::{bail, Context, Result};
+use humility::cli::{Cli, Subcommand};
+use humility::env::Environment;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+//
+// Returns the names of the targets that have been selected, along with the
+// probe of each.
+//
+fn targets(cli: &Cli, env: &str) -> Result<Vec<(String, String)>> {
+    let names = match &cli.targets {
+        Some(targets) => targets.clone(),
+        None => {
+            Environment::targets(env)?.into_iter().map(|(t, _)| t).collect()
+        }
+    };
+
+    if names.is_empty() {
+        bail!("no targets found in environment {}", env);
+    }
+
+    let mut rval: Vec<(String, String)> = vec![];
+
+    for name in names {
+        if rval.iter().any(|(t, _)| *t == name) {
+            continue;
+        }
+
+        let target = Environment::from_file(env, &name)?;
+        rval.push((name, target.probe));
+    }
+
+    Ok(rval)
+}
+
+//
+// Copies the specified input to the specified output a line at a time,
+// prefixing each line.  Each line is written with a single write, so lines
+// from targets running in parallel will not be interleaved.
+//
+fn prefixed(
+    input: impl Read,
+    prefix: &str,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(input);
+    let mut line = vec![];
+
+    loop {
+        line.clear();
+        line.extend_from_slice(prefix.as_bytes());
+
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+
+        output.write_all(&line)?;
+    }
+}
+
+fn run_target(
+    cli: &Cli,
+    env: &str,
+    target: &str,
+    width: usize,
+) -> Result<ExitStatus> {
+    let Subcommand::Other(subargs) = cli.cmd.as_ref().unwrap();
+    let mut cmd = Command::new(std::env::current_exe()?);
+
+    if cli.verbose {
+        cmd.arg("--verbose");
+    }
+
+    if cli.terse {
+        cmd.arg("--terse");
+    }
+
+    cmd.arg("--environment").arg(env).arg("--target").arg(target);
+
+    if let Some(archive_name) = &cli.archive_name {
+        cmd.arg("--archive-name").arg(archive_name);
+    }
+
+    cmd.args(subargs);
+
+    //
+    // The archive, dump and probe are determined by the target; we remove
+    // any that are set in our environment, lest they override (or conflict
+    // with) the target's.
+    //
+    for var in [
+        "HUMILITY_TARGET",
+        "HUMILITY_ARCHIVE",
+        "HUMILITY_DUMP",
+        "HUMILITY_PROBE",
+    ] {
+        cmd.env_remove(var);
+    }
+
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("failed to run target {}", target))?;
+
+    let prefix = format!("{:width$} | ", target, width = width);
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    thread::scope(|s| {
+        let err = s.spawn(|| prefixed(stderr, &prefix, std::io::stderr()));
+        let out = prefixed(stdout, &prefix, std::io::stdout());
+
+        err.join().unwrap().and(out)
+    })?;
+
+    Ok(child.wait()?)
+}
+
+//
+// Groups targets (by index) such that the targets in each group are run
+// sequentially, and the groups are run in parallel.  If we are not running
+// in parallel, this is a single group; if we are, it is a group per probe.
+//
+fn groups(targets: &[(String, String)], parallel: bool) -> Vec<Vec<usize>> {
+    let mut groups: Vec<(&str, Vec<usize>)> = vec![];
+
+    for (ndx, (_, probe)) in targets.iter().enumerate() {
+        let probe = if parallel { probe.as_str() } else { "" };
+
+        match groups.iter_mut().find(|(p, _)| *p == probe) {
+            Some((_, group)) => group.push(ndx),
+            None => groups.push((probe, vec![ndx])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+//
+// Returns the result of each target (in the order in which the targets were
+// specified) as it should be displayed, along with the number of targets
+// that failed.
+//
+fn summary<'a>(
+    targets: &'a [(String, String)],
+    mut results: Vec<(usize, Result<ExitStatus>)>,
+) -> (Vec<(&'a str, String)>, usize) {
+    results.sort_by_key(|(ndx, _)| *ndx);
+
+    let mut failed = 0;
+    let mut rval = vec![];
+
+    for (ndx, result) in results {
+        let result = match result {
+            Ok(status) if status.success() => "ok".to_string(),
+            Ok(status) => {
+                failed += 1;
+
+                match status.code() {
+                    Some(code) => format!("failed (status code {})", code),
+                    None => "failed (terminated by signal)".to_string(),
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                format!("failed ({})", err)
+            }
+        };
+
+        rval.push((targets[ndx].0.as_str(), result));
+    }
+
+    (rval, failed)
+}
+
+pub fn run(cli: &Cli) -> Result<()> {
+    let env = cli.environment.as_ref().unwrap();
+    let targets = targets(cli, env)?;
+    let width = targets.iter().map(|(t, _)| t.len()).max().unwrap();
+    let groups = groups(&targets, cli.parallel);
+
+    let results = thread::scope(|s| {
+        let threads = groups
+            .iter()
+            .map(|group| {
+                let targets = &targets;
+
+                s.spawn(move || {
+                    group
+                        .iter()
+                        .map(|ndx| {
+                            let target = &targets[*ndx].0;
+                            (*ndx, run_target(cli, env, target, width))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        threads.into_iter().flat_map(|t| t.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let total = results.len();
+    let (summary, failed) = summary(&targets, results);
+    let width = std::cmp::max(width, 15);
+
+    println!("{:width$} RESULT", "TARGET", width = width);
+
+    for (target, result) in summary {
+        println!("{:width$} {}", target, result, width = width);
+    }
+
+    if failed != 0 {
+        bail!("{} of {} targets failed", failed, total);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(targets: &[(&str, &str)]) -> Vec<(String, String)> {
+        targets.iter().map(|(t, p)| (t.to_string(), p.to_string())).collect()
+    }
+
+    #[test]
+    fn grouping() {
+        let targets = targets(&[
+            ("gimlet-a", "0483:374e:001"),
+            ("sidecar", "0483:374e:002"),
+            ("gimlet-b", "0483:374e:001"),
+            ("psc", "1fc9:0143:003"),
+        ]);
+
+        assert_eq!(groups(&targets, false), vec![vec![0, 1, 2, 3]]);
+        assert_eq!(groups(&targets, true), vec![vec![0, 2], vec![1], vec![3]]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn summarize() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let targets = targets(&[
+            ("gimlet", "0483:374e:001"),
+            ("sidecar", "0483:374e:002"),
+            ("psc", "1fc9:0143:003"),
+            ("gimletlet", "1fc9:0143:004"),
+        ]);
+
+        //
+        // Results arrive grouped by probe rather than in target order.
+        //
+        let results = vec![
+            (2, Ok(ExitStatus::from_raw(9))),
+            (0, Ok(ExitStatus::from_raw(0))),
+            (3, Err(anyhow::anyhow!("no such file"))),
+            (1, Ok(ExitStatus::from_raw(2 << 8))),
+        ];
+
+        let (summary, failed) = summary(&targets, results);
+
+        assert_eq!(failed, 3);
+        assert_eq!(
+            summary,
+            vec![
+                ("gimlet", "ok".to_string()),
+                ("sidecar", "failed (status code 2)".to_string()),
+                ("psc", "failed (terminated by signal)".to_string()),
+                ("gimletlet", "failed (no such file)".to_string()),
+            ]
+        );
+    }
+}